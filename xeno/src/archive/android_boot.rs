@@ -0,0 +1,589 @@
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+
+use crate::archive::{Entry, FileType};
+use crate::block::detect_decoder;
+use crate::utils::bytes::le32;
+use crate::utils::error::ArchiveError;
+
+const BOOT_MAGIC: &[u8; 8] = b"ANDROID!";
+const VENDOR_BOOT_MAGIC: &[u8; 8] = b"VNDRBOOT";
+
+/// Boot images from v3 on use a fixed page size.
+const BOOT_PAGE_SIZE: u64 = 4096;
+/// Size of a `vendor_ramdisk_table_entry_v4`.
+const VENDOR_RAMDISK_TABLE_ENTRY_SIZE: usize = 108;
+
+const CPIO_MAGIC: &[u8] = b"0707";
+const CPIO_TRAILER: &[u8] = b"TRAILER!!!";
+
+/// The part of a boot image an entry comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AndroidBootSection {
+    Kernel,
+    Ramdisk,
+    Second,
+    RecoveryDtbo,
+    Dtb,
+    Cmdline,
+    Bootconfig,
+    Signature,
+}
+
+#[derive(Debug, Clone)]
+enum AndroidBootData {
+    /// Offset of the section in the underlying reader.
+    Stored(u64),
+    /// Data taken from the header, such as the kernel command line.
+    Inline(Vec<u8>),
+}
+
+#[derive(Debug, Clone)]
+pub struct AndroidBootEntry {
+    path: PathBuf,
+    section: AndroidBootSection,
+    data: AndroidBootData,
+    size: u64,
+}
+
+impl AndroidBootEntry {
+    pub fn section(&self) -> AndroidBootSection {
+        self.section
+    }
+
+    /// Offset of the section in the underlying reader, `None` for data taken from the header.
+    pub fn offset(&self) -> Option<u64> {
+        match self.data {
+            AndroidBootData::Stored(offset) => Some(offset),
+            AndroidBootData::Inline(_) => None,
+        }
+    }
+}
+
+impl Entry for AndroidBootEntry {
+    fn file_type(&self) -> FileType {
+        FileType::RegularFile
+    }
+
+    fn hand_link(&self) -> Option<PathBuf> {
+        None
+    }
+
+    fn path_name(&self) -> std::io::Result<PathBuf> {
+        Ok(self.path.clone())
+    }
+
+    fn gid(&self) -> std::io::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    fn uid(&self) -> std::io::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Size of the section as stored, ramdisks before decompression.
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn sym_link(&self) -> Option<PathBuf> {
+        None
+    }
+}
+
+pub struct AndroidBootEntries {
+    current: usize,
+    total: usize,
+    inner: Vec<AndroidBootEntry>,
+}
+
+impl Iterator for AndroidBootEntries {
+    type Item = Result<AndroidBootEntry, ArchiveError>;
+
+    fn next(&mut self) -> Option<Result<AndroidBootEntry, ArchiveError>> {
+        if self.current >= self.total {
+            return None;
+        }
+
+        let entry = &self.inner[self.current];
+        self.current += 1;
+        Some(Ok(entry.to_owned()))
+    }
+}
+
+/// Android `boot.img`, `recovery.img` and `vendor_boot.img` with header versions 0 to 4.
+///
+/// Every section becomes an entry, the kernel command line included. `unpack_all` writes the
+/// sections as stored except for ramdisks, which are decompressed and unpacked from their cpio
+/// archives into a directory of the same name.
+pub struct AndroidBootImage<R: Read + Seek> {
+    inner: R,
+    vendor: bool,
+    header_version: u32,
+    page_size: u32,
+    os_version: u32,
+    name: String,
+    cmdline: String,
+    entries: Vec<AndroidBootEntry>,
+}
+
+impl<R> AndroidBootImage<R>
+where
+    R: Read + Seek,
+{
+    /// True for `vendor_boot` images, which carry the vendor ramdisks and the DTB.
+    pub fn is_vendor_boot(&self) -> bool {
+        self.vendor
+    }
+
+    pub fn header_version(&self) -> u32 {
+        self.header_version
+    }
+
+    pub fn page_size(&self) -> u32 {
+        self.page_size
+    }
+
+    /// The packed OS version and security patch level, 0 when unset.
+    pub fn os_version(&self) -> u32 {
+        self.os_version
+    }
+
+    /// The product name, only recorded by v0 to v2 boot images and vendor boot images.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The kernel command line, including the extra command line of v0 to v2 boot images.
+    pub fn cmdline(&self) -> &str {
+        &self.cmdline
+    }
+
+    pub fn entries(&mut self) -> Result<AndroidBootEntries, ArchiveError> {
+        Ok(AndroidBootEntries {
+            current: 0,
+            total: self.entries.len(),
+            inner: self.entries.clone(),
+        })
+    }
+
+    pub fn unpack_all(&mut self, to: impl AsRef<Path>) -> Result<(), ArchiveError> {
+        let to = to.as_ref();
+        if !to.exists() {
+            std::fs::create_dir_all(to)?;
+        }
+
+        let entries = self.entries()?;
+        let mut failures = vec![];
+        for entry in entries {
+            match entry {
+                Ok(entry) => {
+                    let path = to.join(&entry.path);
+                    let result = match entry.section {
+                        AndroidBootSection::Ramdisk => self.unpack_ramdisk(&entry, path),
+                        _ => self.unpack_file(&entry, path),
+                    };
+                    if let Err(e) = result {
+                        failures.push(e);
+                    }
+                }
+                Err(e) => {
+                    failures.push(e);
+                }
+            }
+        }
+
+        if !failures.is_empty() {
+            return Err(ArchiveError::ExtractFailed { sources: failures });
+        }
+
+        Ok(())
+    }
+
+    /// Writes the section as stored.
+    pub fn unpack_file(
+        &mut self,
+        entry: &AndroidBootEntry,
+        to: impl AsRef<Path>,
+    ) -> Result<(), ArchiveError> {
+        let data = self.read_data(entry)?;
+        let mut writer = std::fs::File::create(to)?;
+        writer.write_all(&data)?;
+        Ok(())
+    }
+
+    /// Decompresses a ramdisk and unpacks its cpio archives into the directory `to`.
+    ///
+    /// Ramdisks built from several fragments hold one cpio archive per fragment, they are
+    /// unpacked on top of each other like the kernel does.
+    pub fn unpack_ramdisk(
+        &mut self,
+        entry: &AndroidBootEntry,
+        to: impl AsRef<Path>,
+    ) -> Result<(), ArchiveError> {
+        if entry.section != AndroidBootSection::Ramdisk {
+            return Err(ArchiveError::GenericsError("entry is not a ramdisk"));
+        }
+
+        let to = to.as_ref();
+        std::fs::create_dir_all(to)?;
+        let data = self.read_data(entry)?;
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let cpio = if data.starts_with(CPIO_MAGIC) {
+            data
+        } else {
+            let mut cpio = vec![];
+            detect_decoder(data.as_slice())?.read_to_end(&mut cpio)?;
+            cpio
+        };
+
+        let mut failures = vec![];
+        let mut rest = cpio.as_slice();
+        while rest.starts_with(CPIO_MAGIC) {
+            for file in cpio_reader::iter_files(rest) {
+                if let Err(e) = unpack_cpio_entry(&file, to) {
+                    failures.push(e);
+                }
+            }
+            rest = next_cpio_archive(rest);
+        }
+
+        if !failures.is_empty() {
+            return Err(ArchiveError::ExtractFailed { sources: failures });
+        }
+
+        Ok(())
+    }
+
+    fn read_data(&mut self, entry: &AndroidBootEntry) -> Result<Vec<u8>, ArchiveError> {
+        match &entry.data {
+            AndroidBootData::Inline(data) => Ok(data.clone()),
+            AndroidBootData::Stored(offset) => {
+                let mut data = vec![0u8; entry.size as usize];
+                self.inner.seek(SeekFrom::Start(*offset))?;
+                self.inner.read_exact(&mut data)?;
+                Ok(data)
+            }
+        }
+    }
+
+    pub fn create_with_reader(mut rdr: R) -> Result<AndroidBootImage<R>, ArchiveError> {
+        // The image may be embedded in a larger firmware blob, offsets are relative to the start.
+        let base = rdr.stream_position()?;
+        let mut header = vec![];
+        (&mut rdr).take(BOOT_PAGE_SIZE).read_to_end(&mut header)?;
+        // Short images are padded so that missing optional fields read as zero.
+        header.resize(BOOT_PAGE_SIZE as usize, 0);
+
+        let image = match header.get(..8) {
+            Some(magic) if magic == BOOT_MAGIC => {
+                let version = le32(&header, 40);
+                if version >= 3 {
+                    parse_boot_v3(&header, base, rdr)?
+                } else {
+                    parse_boot_v0(&header, base, rdr)?
+                }
+            }
+            Some(magic) if magic == VENDOR_BOOT_MAGIC => parse_vendor_boot(&header, base, rdr)?,
+            _ => return Err(ArchiveError::GenericsError("not an Android boot image")),
+        };
+
+        Ok(image)
+    }
+}
+
+impl AndroidBootImage<BufReader<std::fs::File>> {
+    pub fn create_with_path(path: impl AsRef<Path>) -> Result<Self, ArchiveError> {
+        let rdr = BufReader::new(std::fs::File::open(path)?);
+        Self::create_with_reader(rdr)
+    }
+}
+
+/// Lays out sections back to back, each starting on a page boundary.
+struct Sections {
+    offset: u64,
+    page_size: u64,
+    entries: Vec<AndroidBootEntry>,
+}
+
+impl Sections {
+    fn new(base: u64, header_size: u64, page_size: u64) -> Result<Sections, ArchiveError> {
+        if page_size == 0 || !page_size.is_power_of_two() {
+            return Err(ArchiveError::GenericsError(
+                "Android boot image has an invalid page size",
+            ));
+        }
+
+        let mut sections = Sections {
+            offset: base,
+            page_size,
+            entries: vec![],
+        };
+        sections.skip(header_size);
+        Ok(sections)
+    }
+
+    fn skip(&mut self, size: u64) -> u64 {
+        let offset = self.offset;
+        self.offset += (size + self.page_size - 1) & !(self.page_size - 1);
+        offset
+    }
+
+    fn push(&mut self, name: &str, section: AndroidBootSection, size: u64) {
+        let offset = self.skip(size);
+        if size > 0 {
+            self.entries.push(AndroidBootEntry {
+                path: PathBuf::from(name),
+                section,
+                data: AndroidBootData::Stored(offset),
+                size,
+            });
+        }
+    }
+
+    fn push_inline(&mut self, name: &str, section: AndroidBootSection, data: &str) {
+        if !data.is_empty() {
+            self.entries.push(AndroidBootEntry {
+                path: PathBuf::from(name),
+                section,
+                size: data.len() as u64,
+                data: AndroidBootData::Inline(data.as_bytes().to_vec()),
+            });
+        }
+    }
+}
+
+fn parse_boot_v0<R: Read + Seek>(
+    header: &[u8],
+    base: u64,
+    rdr: R,
+) -> Result<AndroidBootImage<R>, ArchiveError> {
+    let page_size = le32(header, 36);
+    // Some vendors stored a DTB size in the version field of v0 images.
+    let version = match le32(header, 40) {
+        version @ 0..=2 => version,
+        _ => 0,
+    };
+    let mut cmdline = c_string(&header[64..576]);
+    cmdline.push_str(&c_string(&header[608..1632]));
+
+    let mut sections = Sections::new(base, 1632, u64::from(page_size))?;
+    sections.push("kernel", AndroidBootSection::Kernel, le32(header, 8).into());
+    sections.push(
+        "ramdisk",
+        AndroidBootSection::Ramdisk,
+        le32(header, 16).into(),
+    );
+    sections.push(
+        "second",
+        AndroidBootSection::Second,
+        le32(header, 24).into(),
+    );
+    if version >= 1 {
+        let size = le32(header, 1632).into();
+        sections.push("recovery_dtbo", AndroidBootSection::RecoveryDtbo, size);
+    }
+    if version >= 2 {
+        sections.push("dtb", AndroidBootSection::Dtb, le32(header, 1648).into());
+    }
+    sections.push_inline("cmdline", AndroidBootSection::Cmdline, &cmdline);
+
+    Ok(AndroidBootImage {
+        inner: rdr,
+        vendor: false,
+        header_version: version,
+        page_size,
+        os_version: le32(header, 44),
+        name: c_string(&header[48..64]),
+        cmdline,
+        entries: sections.entries,
+    })
+}
+
+fn parse_boot_v3<R: Read + Seek>(
+    header: &[u8],
+    base: u64,
+    rdr: R,
+) -> Result<AndroidBootImage<R>, ArchiveError> {
+    let version = le32(header, 40);
+    let cmdline = c_string(&header[44..1580]);
+
+    let header_size = u64::from(le32(header, 20)).max(1580);
+    let mut sections = Sections::new(base, header_size, BOOT_PAGE_SIZE)?;
+    sections.push("kernel", AndroidBootSection::Kernel, le32(header, 8).into());
+    sections.push(
+        "ramdisk",
+        AndroidBootSection::Ramdisk,
+        le32(header, 12).into(),
+    );
+    if version >= 4 {
+        let size = le32(header, 1580).into();
+        sections.push("boot_signature", AndroidBootSection::Signature, size);
+    }
+    sections.push_inline("cmdline", AndroidBootSection::Cmdline, &cmdline);
+
+    Ok(AndroidBootImage {
+        inner: rdr,
+        vendor: false,
+        header_version: version,
+        page_size: BOOT_PAGE_SIZE as u32,
+        os_version: le32(header, 16),
+        name: String::new(),
+        cmdline,
+        entries: sections.entries,
+    })
+}
+
+fn parse_vendor_boot<R: Read + Seek>(
+    header: &[u8],
+    base: u64,
+    mut rdr: R,
+) -> Result<AndroidBootImage<R>, ArchiveError> {
+    let version = le32(header, 8);
+    let page_size = le32(header, 12);
+    let ramdisk_size = le32(header, 24);
+    let cmdline = c_string(&header[28..2076]);
+
+    let header_size = u64::from(le32(header, 2096)).max(2112);
+    let mut sections = Sections::new(base, header_size, u64::from(page_size))?;
+    let ramdisk_offset = sections.skip(ramdisk_size.into());
+    sections.push("dtb", AndroidBootSection::Dtb, le32(header, 2100).into());
+
+    let mut ramdisks = vec![];
+    if version >= 4 {
+        let table_size = le32(header, 2112);
+        let count = le32(header, 2116) as usize;
+        let entry_size = le32(header, 2120) as usize;
+        if entry_size < VENDOR_RAMDISK_TABLE_ENTRY_SIZE
+            || count.saturating_mul(entry_size) > table_size as usize
+        {
+            return Err(ArchiveError::GenericsError(
+                "vendor ramdisk table is corrupted",
+            ));
+        }
+
+        let table_offset = sections.skip(table_size.into());
+        let mut table = vec![0u8; table_size as usize];
+        rdr.seek(SeekFrom::Start(table_offset))?;
+        rdr.read_exact(&mut table)?;
+        for (index, entry) in table.chunks_exact(entry_size).take(count).enumerate() {
+            let (size, offset) = (le32(entry, 0), le32(entry, 4));
+            if u64::from(offset) + u64::from(size) > u64::from(ramdisk_size) {
+                return Err(ArchiveError::GenericsError(
+                    "vendor ramdisk table is corrupted",
+                ));
+            }
+            let name = match c_string(&entry[12..44]) {
+                name if name.is_empty() => format!("vendor_ramdisk_{}", index),
+                name => format!("vendor_ramdisk_{}", name),
+            };
+            ramdisks.push(AndroidBootEntry {
+                path: PathBuf::from(name),
+                section: AndroidBootSection::Ramdisk,
+                data: AndroidBootData::Stored(ramdisk_offset + u64::from(offset)),
+                size: size.into(),
+            });
+        }
+
+        let size = le32(header, 2124).into();
+        sections.push("bootconfig", AndroidBootSection::Bootconfig, size);
+    } else if ramdisk_size > 0 {
+        ramdisks.push(AndroidBootEntry {
+            path: PathBuf::from("vendor_ramdisk"),
+            section: AndroidBootSection::Ramdisk,
+            data: AndroidBootData::Stored(ramdisk_offset),
+            size: ramdisk_size.into(),
+        });
+    }
+    sections.push_inline("cmdline", AndroidBootSection::Cmdline, &cmdline);
+
+    ramdisks.append(&mut sections.entries);
+    Ok(AndroidBootImage {
+        inner: rdr,
+        vendor: true,
+        header_version: version,
+        page_size,
+        os_version: 0,
+        name: c_string(&header[2080..2096]),
+        cmdline,
+        entries: ramdisks,
+    })
+}
+
+fn unpack_cpio_entry(file: &cpio_reader::Entry, to: &Path) -> Result<(), ArchiveError> {
+    // Ramdisk paths are relative to the root, anything escaping the target is dropped.
+    let path: PathBuf = Path::new(file.name())
+        .components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect();
+    if path.as_os_str().is_empty() {
+        return Ok(());
+    }
+    let path = to.join(path);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let mode = file.mode().bits();
+    match mode & 0o170000 {
+        0o040000 => {
+            std::fs::create_dir_all(&path)?;
+            return Ok(());
+        }
+        0o100000 => {
+            std::fs::File::create(&path)?.write_all(file.file())?;
+        }
+        0o120000 => {
+            let link = PathBuf::from(String::from_utf8_lossy(file.file()).into_owned());
+            log::debug!("symlink {} {}", path.display(), link.display());
+            cfg_if::cfg_if! {
+                if #[cfg(unix)] {
+                    std::os::unix::fs::symlink(&link, &path)?;
+                } else {
+                    std::os::windows::fs::symlink_file(&link, &path)?;
+                }
+            }
+            return Ok(());
+        }
+        _ => {
+            log::info!("[-] {} is a special file, not supported", file.name());
+            return Ok(());
+        }
+    }
+
+    cfg_if::cfg_if! {
+        if #[cfg(unix)] {
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode & 0o7777))?;
+        }
+    }
+    Ok(())
+}
+
+/// Skips past the trailer of the cpio archive at the start of `data` and its padding.
+fn next_cpio_archive(data: &[u8]) -> &[u8] {
+    let trailer = data
+        .windows(CPIO_TRAILER.len())
+        .position(|window| window == CPIO_TRAILER);
+    let rest = match trailer {
+        Some(position) => &data[position + CPIO_TRAILER.len()..],
+        None => return &[],
+    };
+    match rest
+        .windows(CPIO_MAGIC.len())
+        .position(|window| window == CPIO_MAGIC)
+    {
+        Some(position) => &rest[position..],
+        None => &[],
+    }
+}
+
+fn c_string(data: &[u8]) -> String {
+    let data = data.split(|c| *c == 0).next().unwrap_or_default();
+    String::from_utf8_lossy(data).into_owned()
+}
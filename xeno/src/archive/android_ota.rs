@@ -0,0 +1,614 @@
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use crate::archive::zip::ZipArchive;
+use crate::archive::{Entry, FileType};
+use crate::block::{create_decoder, BlockFormat};
+use crate::utils::bytes::{be32, be64};
+use crate::utils::error::ArchiveError;
+
+const PAYLOAD_MAGIC: &[u8; 4] = b"CrAU";
+const PAYLOAD_NAME: &str = "payload.bin";
+
+/// Marks an extent of a hole in old payloads, nothing gets written for it.
+const SPARSE_HOLE: u64 = u64::MAX;
+
+/// `InstallOperation.Type` from `update_metadata.proto`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadOperationType {
+    Replace,
+    ReplaceBz,
+    ReplaceXz,
+    Zero,
+    Discard,
+    /// An operation that patches the source build, such as `SOURCE_COPY` or `BSDIFF`.
+    Delta(u32),
+}
+
+impl PayloadOperationType {
+    fn from_proto(value: u64) -> PayloadOperationType {
+        match value {
+            0 => PayloadOperationType::Replace,
+            1 => PayloadOperationType::ReplaceBz,
+            6 => PayloadOperationType::Zero,
+            7 => PayloadOperationType::Discard,
+            8 => PayloadOperationType::ReplaceXz,
+            value => PayloadOperationType::Delta(value as u32),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Extent {
+    start_block: u64,
+    num_blocks: u64,
+}
+
+#[derive(Debug, Clone)]
+struct Operation {
+    kind: PayloadOperationType,
+    data_offset: u64,
+    data_length: u64,
+    dst_extents: Vec<Extent>,
+    data_sha256: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PayloadEntry {
+    path: PathBuf,
+    name: String,
+    size: u64,
+    hash: Option<Vec<u8>>,
+    operations: Vec<Operation>,
+}
+
+impl PayloadEntry {
+    /// The partition name, e.g. `system` or `boot`.
+    pub fn partition_name(&self) -> &str {
+        &self.name
+    }
+
+    /// SHA-256 of the new partition image, if the payload records it.
+    pub fn hash(&self) -> Option<&[u8]> {
+        self.hash.as_deref()
+    }
+
+    /// True if the partition can only be built by patching the source build.
+    pub fn is_delta(&self) -> bool {
+        self.operations
+            .iter()
+            .any(|op| matches!(op.kind, PayloadOperationType::Delta(_)))
+    }
+
+    pub fn operation_types(&self) -> Vec<PayloadOperationType> {
+        self.operations.iter().map(|op| op.kind).collect()
+    }
+}
+
+impl Entry for PayloadEntry {
+    fn file_type(&self) -> FileType {
+        FileType::RegularFile
+    }
+
+    fn hand_link(&self) -> Option<PathBuf> {
+        None
+    }
+
+    fn path_name(&self) -> std::io::Result<PathBuf> {
+        Ok(self.path.clone())
+    }
+
+    fn gid(&self) -> std::io::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    fn uid(&self) -> std::io::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Size of the partition image.
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn sym_link(&self) -> Option<PathBuf> {
+        None
+    }
+}
+
+pub struct PayloadEntries {
+    current: usize,
+    total: usize,
+    inner: Vec<PayloadEntry>,
+}
+
+impl Iterator for PayloadEntries {
+    type Item = Result<PayloadEntry, ArchiveError>;
+
+    fn next(&mut self) -> Option<Result<PayloadEntry, ArchiveError>> {
+        if self.current >= self.total {
+            return None;
+        }
+
+        let entry = &self.inner[self.current];
+        self.current += 1;
+        Some(Ok(entry.to_owned()))
+    }
+}
+
+/// Android A/B OTA payload (`payload.bin`, magic `CrAU`), on its own or inside the OTA zip.
+///
+/// Full OTAs are rebuilt into partition images. Incremental OTAs patch the source build, their
+/// partitions are listed but unpacking them fails.
+pub struct PayloadArchive<R: Read + Seek> {
+    inner: R,
+    major_version: u64,
+    minor_version: u32,
+    block_size: u64,
+    /// Offset of the data blobs in the underlying reader.
+    data_offset: u64,
+    entries: Vec<PayloadEntry>,
+}
+
+impl<R> PayloadArchive<R>
+where
+    R: Read + Seek,
+{
+    pub fn major_version(&self) -> u64 {
+        self.major_version
+    }
+
+    /// 0 for full payloads, the delta format version for incremental ones.
+    pub fn minor_version(&self) -> u32 {
+        self.minor_version
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    pub fn entries(&mut self) -> Result<PayloadEntries, ArchiveError> {
+        Ok(PayloadEntries {
+            current: 0,
+            total: self.entries.len(),
+            inner: self.entries.clone(),
+        })
+    }
+
+    pub fn unpack_all(&mut self, to: impl AsRef<Path>) -> Result<(), ArchiveError> {
+        let to = to.as_ref();
+        if !to.exists() {
+            std::fs::create_dir_all(to)?;
+        }
+
+        let entries = self.entries()?;
+        let mut failures = vec![];
+        for entry in entries {
+            match entry {
+                Ok(entry) => {
+                    let path = to.join(&entry.path);
+                    if let Err(e) = self.unpack_file(&entry, path) {
+                        failures.push(e);
+                    }
+                }
+                Err(e) => {
+                    failures.push(e);
+                }
+            }
+        }
+
+        if !failures.is_empty() {
+            return Err(ArchiveError::ExtractFailed { sources: failures });
+        }
+
+        Ok(())
+    }
+
+    /// Rebuilds the partition image, checking every operation's data hash along the way.
+    pub fn unpack_file(
+        &mut self,
+        entry: &PayloadEntry,
+        to: impl AsRef<Path>,
+    ) -> Result<(), ArchiveError> {
+        if entry.is_delta() {
+            return Err(ArchiveError::GenericsError2(format!(
+                "partition {} uses delta operations, which are not supported",
+                entry.name
+            )));
+        }
+
+        let payload_end = self.inner.seek(SeekFrom::End(0))?;
+        let mut writer = std::fs::File::create(to)?;
+        writer.set_len(entry.size)?;
+        for op in &entry.operations {
+            let format = match op.kind {
+                PayloadOperationType::Replace => None,
+                PayloadOperationType::ReplaceBz => Some(BlockFormat::Bzip2),
+                PayloadOperationType::ReplaceXz => Some(BlockFormat::Xz),
+                // The image starts out as zeros, which is all a zeroed or discarded range needs.
+                PayloadOperationType::Zero | PayloadOperationType::Discard => continue,
+                PayloadOperationType::Delta(_) => unreachable!(),
+            };
+
+            let start = self
+                .data_offset
+                .checked_add(op.data_offset)
+                .filter(|start| {
+                    start
+                        .checked_add(op.data_length)
+                        .is_some_and(|end| end <= payload_end)
+                })
+                .ok_or_else(|| {
+                    ArchiveError::GenericsError2(format!(
+                        "{} operation data lies outside the payload",
+                        entry.name
+                    ))
+                })?;
+            self.inner.seek(SeekFrom::Start(start))?;
+            let mut data = HashingReader {
+                inner: (&mut self.inner).take(op.data_length),
+                hasher: Sha256::new(),
+            };
+
+            let mut extents = ExtentWriter {
+                inner: &mut writer,
+                extents: &op.dst_extents,
+                block_size: self.block_size,
+                current: 0,
+                written: 0,
+            };
+            match format {
+                Some(format) => {
+                    create_decoder(format, &mut data)?.decode_to(&mut extents)?;
+                }
+                None => {
+                    std::io::copy(&mut data, &mut extents)?;
+                }
+            }
+            // whatever the decoder left unread still counts for the hash
+            std::io::copy(&mut data, &mut std::io::sink())?;
+            if let Some(hash) = &op.data_sha256 {
+                if data.hasher.finalize().as_slice() != hash.as_slice() {
+                    return Err(ArchiveError::ChecksumMismatch(format!(
+                        "{} operation data",
+                        entry.name
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Opens a bare `payload.bin`.
+    pub fn create_with_reader(mut rdr: R) -> Result<PayloadArchive<R>, ArchiveError> {
+        // The payload may be embedded in a larger file, offsets are relative to its start.
+        let base = rdr.stream_position()?;
+        let mut header = [0u8; 24];
+        rdr.read_exact(&mut header)?;
+        if &header[..4] != PAYLOAD_MAGIC {
+            return Err(ArchiveError::GenericsError("not an Android OTA payload"));
+        }
+
+        let major_version = be64(&header, 4);
+        let manifest_size = be64(&header, 12);
+        let (header_size, signature_size) = match major_version {
+            2 => (24, be32(&header, 20)),
+            _ => {
+                return Err(ArchiveError::GenericsError(
+                    "unsupported Android OTA payload version",
+                ))
+            }
+        };
+
+        let data_offset = (base + header_size)
+            .checked_add(manifest_size)
+            .and_then(|offset| offset.checked_add(u64::from(signature_size)))
+            .ok_or(ArchiveError::GenericsError(
+                "OTA payload manifest is corrupted",
+            ))?;
+
+        // The size comes from the header, so the manifest is read rather than allocated up front.
+        let mut manifest = vec![];
+        rdr.seek(SeekFrom::Start(base + header_size))?;
+        rdr.by_ref()
+            .take(manifest_size)
+            .read_to_end(&mut manifest)?;
+        if manifest.len() as u64 != manifest_size {
+            return Err(ArchiveError::GenericsError(
+                "OTA payload manifest is truncated",
+            ));
+        }
+        let manifest = parse_manifest(&manifest)?;
+
+        Ok(PayloadArchive {
+            inner: rdr,
+            major_version,
+            minor_version: manifest.minor_version,
+            block_size: manifest.block_size,
+            data_offset,
+            entries: manifest.entries,
+        })
+    }
+
+    /// Opens the `payload.bin` of an OTA zip, which is stored so it can be read in place.
+    pub fn create_with_zip(rdr: R) -> Result<PayloadArchive<R>, ArchiveError> {
+        let mut zip = ZipArchive::open(rdr, None)?;
+        let (offset, _) = zip.stored_range(PAYLOAD_NAME)?;
+        let mut rdr = zip.into_inner();
+        rdr.seek(SeekFrom::Start(offset))?;
+        Self::create_with_reader(rdr)
+    }
+}
+
+impl PayloadArchive<BufReader<std::fs::File>> {
+    /// Opens either a bare `payload.bin` or an OTA zip.
+    pub fn create_with_path(path: impl AsRef<Path>) -> Result<Self, ArchiveError> {
+        let mut rdr = BufReader::new(std::fs::File::open(path)?);
+        let mut magic = [0u8; 4];
+        rdr.read_exact(&mut magic)?;
+        rdr.rewind()?;
+        if &magic == PAYLOAD_MAGIC {
+            Self::create_with_reader(rdr)
+        } else {
+            Self::create_with_zip(rdr)
+        }
+    }
+}
+
+/// Hashes the operation data as it streams past.
+struct HashingReader<R: Read> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, into: &mut [u8]) -> std::io::Result<usize> {
+        let size = self.inner.read(into)?;
+        self.hasher.update(&into[..size]);
+        Ok(size)
+    }
+}
+
+/// Spreads written data over the destination extents of an operation.
+struct ExtentWriter<'a, W: Write + Seek> {
+    inner: &'a mut W,
+    extents: &'a [Extent],
+    block_size: u64,
+    current: usize,
+    /// Bytes written to the current extent.
+    written: u64,
+}
+
+impl<'a, W: Write + Seek> Write for ExtentWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            let extent = self.extents.get(self.current).ok_or_else(|| {
+                std::io::Error::other("operation data is larger than its destination")
+            })?;
+            let len = extent
+                .num_blocks
+                .checked_mul(self.block_size)
+                .ok_or_else(|| std::io::Error::other("operation extent is too large"))?;
+            if self.written >= len {
+                self.current += 1;
+                self.written = 0;
+                continue;
+            }
+
+            let size = buf.len().min((len - self.written) as usize);
+            if extent.start_block != SPARSE_HOLE {
+                let offset = extent
+                    .start_block
+                    .checked_mul(self.block_size)
+                    .and_then(|offset| offset.checked_add(self.written))
+                    .ok_or_else(|| std::io::Error::other("operation extent is too large"))?;
+                self.inner.seek(SeekFrom::Start(offset))?;
+                self.inner.write_all(&buf[..size])?;
+            }
+            self.written += size as u64;
+            return Ok(size);
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct Manifest {
+    block_size: u64,
+    minor_version: u32,
+    entries: Vec<PayloadEntry>,
+}
+
+/// Decodes the `DeltaArchiveManifest`, only the fields needed to rebuild partitions.
+fn parse_manifest(data: &[u8]) -> Result<Manifest, ArchiveError> {
+    let mut manifest = Manifest {
+        block_size: 4096,
+        minor_version: 0,
+        entries: vec![],
+    };
+    let mut partitions = vec![];
+    for field in ProtoFields::new(data) {
+        match field? {
+            (3, ProtoValue::Varint(value)) => manifest.block_size = value,
+            (12, ProtoValue::Varint(value)) => manifest.minor_version = value as u32,
+            (13, ProtoValue::Bytes(value)) => partitions.push(value),
+            _ => {}
+        }
+    }
+    if manifest.block_size == 0 {
+        return Err(ArchiveError::GenericsError("OTA payload block size is 0"));
+    }
+
+    for partition in partitions {
+        let mut name = String::new();
+        let mut size = None;
+        let mut hash = None;
+        let mut operations = vec![];
+        for field in ProtoFields::new(partition) {
+            match field? {
+                (1, ProtoValue::Bytes(value)) => name = String::from_utf8_lossy(value).into_owned(),
+                (7, ProtoValue::Bytes(value)) => {
+                    for field in ProtoFields::new(value) {
+                        match field? {
+                            (1, ProtoValue::Varint(value)) => size = Some(value),
+                            (2, ProtoValue::Bytes(value)) => hash = Some(value.to_vec()),
+                            _ => {}
+                        }
+                    }
+                }
+                (8, ProtoValue::Bytes(value)) => operations.push(parse_operation(value)?),
+                _ => {}
+            }
+        }
+
+        // Without a recorded size, the image ends with the last written block.
+        let size = match size {
+            Some(size) => size,
+            None => operations
+                .iter()
+                .flat_map(|op| &op.dst_extents)
+                .filter(|extent| extent.start_block != SPARSE_HOLE)
+                .map(|extent| {
+                    extent
+                        .start_block
+                        .checked_add(extent.num_blocks)
+                        .and_then(|end| end.checked_mul(manifest.block_size))
+                        .ok_or(ArchiveError::GenericsError(
+                            "OTA payload extent is too large",
+                        ))
+                })
+                .try_fold(0, |size, end| end.map(|end| size.max(end)))?,
+        };
+        manifest.entries.push(PayloadEntry {
+            path: PathBuf::from(format!("{}.img", name)),
+            name,
+            size,
+            hash,
+            operations,
+        });
+    }
+
+    Ok(manifest)
+}
+
+fn parse_operation(data: &[u8]) -> Result<Operation, ArchiveError> {
+    let mut op = Operation {
+        kind: PayloadOperationType::Replace,
+        data_offset: 0,
+        data_length: 0,
+        dst_extents: vec![],
+        data_sha256: None,
+    };
+    for field in ProtoFields::new(data) {
+        match field? {
+            (1, ProtoValue::Varint(value)) => op.kind = PayloadOperationType::from_proto(value),
+            (2, ProtoValue::Varint(value)) => op.data_offset = value,
+            (3, ProtoValue::Varint(value)) => op.data_length = value,
+            (6, ProtoValue::Bytes(value)) => {
+                let mut extent = Extent::default();
+                for field in ProtoFields::new(value) {
+                    match field? {
+                        (1, ProtoValue::Varint(value)) => extent.start_block = value,
+                        (2, ProtoValue::Varint(value)) => extent.num_blocks = value,
+                        _ => {}
+                    }
+                }
+                op.dst_extents.push(extent);
+            }
+            (8, ProtoValue::Bytes(value)) => op.data_sha256 = Some(value.to_vec()),
+            _ => {}
+        }
+    }
+
+    Ok(op)
+}
+
+enum ProtoValue<'a> {
+    Varint(u64),
+    /// A 32 or 64 bit field, none of the decoded messages use them.
+    Fixed,
+    Bytes(&'a [u8]),
+}
+
+/// Walks the fields of a protobuf message, there is no schema so unknown fields are skipped by
+/// the callers.
+struct ProtoFields<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> ProtoFields<'a> {
+    fn new(data: &'a [u8]) -> ProtoFields<'a> {
+        ProtoFields { data }
+    }
+
+    fn varint(&mut self) -> Option<u64> {
+        let mut value = 0u64;
+        for (index, byte) in self.data.iter().enumerate().take(10) {
+            value |= u64::from(byte & 0x7f) << (7 * index);
+            if byte & 0x80 == 0 {
+                self.data = &self.data[index + 1..];
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.data.len() < len {
+            return None;
+        }
+        let (value, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(value)
+    }
+
+    fn field(&mut self) -> Option<(u32, ProtoValue<'a>)> {
+        let key = self.varint()?;
+        let value = match key & 7 {
+            0 => ProtoValue::Varint(self.varint()?),
+            1 => {
+                self.bytes(8)?;
+                ProtoValue::Fixed
+            }
+            2 => {
+                let len = self.varint()?;
+                ProtoValue::Bytes(self.bytes(usize::try_from(len).ok()?)?)
+            }
+            5 => {
+                self.bytes(4)?;
+                ProtoValue::Fixed
+            }
+            _ => return None,
+        };
+        Some(((key >> 3) as u32, value))
+    }
+}
+
+impl<'a> Iterator for ProtoFields<'a> {
+    type Item = Result<(u32, ProtoValue<'a>), ArchiveError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+
+        match self.field() {
+            Some(field) => Some(Ok(field)),
+            None => {
+                self.data = &[];
+                Some(Err(ArchiveError::GenericsError(
+                    "OTA payload manifest is corrupted",
+                )))
+            }
+        }
+    }
+}
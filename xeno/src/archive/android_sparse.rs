@@ -0,0 +1,242 @@
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::utils::bytes::le32;
+use crate::utils::error::ArchiveError;
+
+const SPARSE_MAGIC: u32 = 0xed26_ff3a;
+const SPARSE_HEADER_SIZE: usize = 28;
+const CHUNK_HEADER_SIZE: usize = 12;
+
+const CHUNK_TYPE_RAW: u16 = 0xcac1;
+const CHUNK_TYPE_FILL: u16 = 0xcac2;
+const CHUNK_TYPE_DONT_CARE: u16 = 0xcac3;
+const CHUNK_TYPE_CRC32: u16 = 0xcac4;
+
+/// Where converted data goes. Files get holes for the unused parts of the image, plain
+/// writers get zeros.
+trait RawSink: Write {
+    fn skip(&mut self, len: u64) -> std::io::Result<()>;
+}
+
+struct Zeros<W: Write>(W);
+
+impl<W: Write> Write for Zeros<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<W: Write> RawSink for Zeros<W> {
+    fn skip(&mut self, len: u64) -> std::io::Result<()> {
+        std::io::copy(&mut std::io::repeat(0).take(len), &mut self.0)?;
+        Ok(())
+    }
+}
+
+impl RawSink for std::fs::File {
+    fn skip(&mut self, len: u64) -> std::io::Result<()> {
+        self.seek(SeekFrom::Current(len as i64))?;
+        Ok(())
+    }
+}
+
+/// Android sparse image (`img2simg`, fastboot), as shipped for `system.img` and friends.
+///
+/// `unpack_to` expands it back into the raw partition image, which filesystem backends such as
+/// `FatArchive` can then open. CRC32 chunks are verified along the way.
+pub struct AndroidSparseImage<R: Read> {
+    inner: R,
+    major_version: u16,
+    minor_version: u16,
+    chunk_header_size: usize,
+    block_size: u32,
+    total_blocks: u32,
+    total_chunks: u32,
+    image_checksum: u32,
+}
+
+impl<R: Read> AndroidSparseImage<R> {
+    pub fn major_version(&self) -> u16 {
+        self.major_version
+    }
+
+    pub fn minor_version(&self) -> u16 {
+        self.minor_version
+    }
+
+    pub fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    /// Number of blocks in the raw image.
+    pub fn total_blocks(&self) -> u32 {
+        self.total_blocks
+    }
+
+    pub fn total_chunks(&self) -> u32 {
+        self.total_chunks
+    }
+
+    /// CRC32 of the raw image as recorded in the header, usually 0 for unset.
+    pub fn image_checksum(&self) -> u32 {
+        self.image_checksum
+    }
+
+    /// Size of the raw image.
+    pub fn raw_size(&self) -> u64 {
+        u64::from(self.total_blocks) * u64::from(self.block_size)
+    }
+
+    /// Writes the raw image and returns its size.
+    pub fn unpack_to(&mut self, writer: impl Write) -> Result<u64, ArchiveError> {
+        self.convert(&mut Zeros(writer))
+    }
+
+    /// Writes the raw image to a file, leaving holes for the unused parts of the image.
+    pub fn unpack_file(&mut self, to: impl AsRef<Path>) -> Result<u64, ArchiveError> {
+        let mut writer = std::fs::File::create(to)?;
+        let size = self.convert(&mut writer)?;
+        writer.set_len(size)?;
+        Ok(size)
+    }
+
+    fn convert(&mut self, writer: &mut dyn RawSink) -> Result<u64, ArchiveError> {
+        let block_size = u64::from(self.block_size);
+        let mut crc = flate2::Crc::new();
+        let mut blocks = 0u64;
+        let mut header = vec![0u8; self.chunk_header_size];
+        for _ in 0..self.total_chunks {
+            self.inner.read_exact(&mut header)?;
+            let chunk_type = u16::from_le_bytes([header[0], header[1]]);
+            let chunk_blocks = u64::from(le32(&header, 4));
+            let total_size = u64::from(le32(&header, 8));
+            let data_size = total_size
+                .checked_sub(self.chunk_header_size as u64)
+                .ok_or(ArchiveError::GenericsError(
+                    "sparse image chunk is corrupted",
+                ))?;
+            let size = chunk_blocks * block_size;
+
+            match chunk_type {
+                CHUNK_TYPE_RAW => {
+                    if data_size != size {
+                        return Err(ArchiveError::GenericsError(
+                            "sparse image chunk is corrupted",
+                        ));
+                    }
+                    let mut buffer = vec![0u8; block_size as usize];
+                    for _ in 0..chunk_blocks {
+                        self.inner.read_exact(&mut buffer)?;
+                        crc.update(&buffer);
+                        writer.write_all(&buffer)?;
+                    }
+                }
+                CHUNK_TYPE_FILL => {
+                    if data_size != 4 {
+                        return Err(ArchiveError::GenericsError(
+                            "sparse image chunk is corrupted",
+                        ));
+                    }
+                    let mut pattern = [0u8; 4];
+                    self.inner.read_exact(&mut pattern)?;
+                    let buffer = pattern.repeat(block_size as usize / 4);
+                    for _ in 0..chunk_blocks {
+                        crc.update(&buffer);
+                        writer.write_all(&buffer)?;
+                    }
+                }
+                CHUNK_TYPE_DONT_CARE => {
+                    std::io::copy(&mut (&mut self.inner).take(data_size), &mut std::io::sink())?;
+                    let buffer = vec![0u8; block_size as usize];
+                    for _ in 0..chunk_blocks {
+                        crc.update(&buffer);
+                    }
+                    writer.skip(size)?;
+                }
+                CHUNK_TYPE_CRC32 => {
+                    let mut expected = [0u8; 4];
+                    self.inner.read_exact(&mut expected)?;
+                    if crc.sum() != u32::from_le_bytes(expected) {
+                        return Err(ArchiveError::ChecksumMismatch(
+                            "sparse image chunk".to_string(),
+                        ));
+                    }
+                    continue;
+                }
+                _ => {
+                    return Err(ArchiveError::GenericsError(
+                        "sparse image has an unknown chunk type",
+                    ))
+                }
+            }
+            blocks += chunk_blocks;
+        }
+
+        if blocks != u64::from(self.total_blocks) {
+            return Err(ArchiveError::GenericsError(
+                "sparse image does not cover every block",
+            ));
+        }
+        if self.image_checksum != 0 && crc.sum() != self.image_checksum {
+            return Err(ArchiveError::ChecksumMismatch("sparse image".to_string()));
+        }
+
+        writer.flush()?;
+        Ok(self.raw_size())
+    }
+
+    pub fn create_with_reader(mut rdr: R) -> Result<AndroidSparseImage<R>, ArchiveError> {
+        let mut header = [0u8; SPARSE_HEADER_SIZE];
+        rdr.read_exact(&mut header)?;
+        if le32(&header, 0) != SPARSE_MAGIC {
+            return Err(ArchiveError::GenericsError("not an Android sparse image"));
+        }
+
+        let le16 = |offset: usize| u16::from_le_bytes([header[offset], header[offset + 1]]);
+        let file_header_size = le16(8) as usize;
+        let chunk_header_size = le16(10) as usize;
+        let block_size = le32(&header, 12);
+        if le16(4) != 1
+            || file_header_size < SPARSE_HEADER_SIZE
+            || chunk_header_size < CHUNK_HEADER_SIZE
+            || block_size == 0
+            || !block_size.is_multiple_of(4)
+        {
+            return Err(ArchiveError::GenericsError(
+                "unsupported Android sparse image version",
+            ));
+        }
+
+        // Newer writers may extend the file header, the extra fields are skipped.
+        let extra = (file_header_size - SPARSE_HEADER_SIZE) as u64;
+        std::io::copy(&mut (&mut rdr).take(extra), &mut std::io::sink())?;
+
+        Ok(AndroidSparseImage {
+            inner: rdr,
+            major_version: le16(4),
+            minor_version: le16(6),
+            chunk_header_size,
+            block_size,
+            total_blocks: le32(&header, 16),
+            total_chunks: le32(&header, 20),
+            image_checksum: le32(&header, 24),
+        })
+    }
+}
+
+impl AndroidSparseImage<BufReader<std::fs::File>> {
+    pub fn create_with_path(path: impl AsRef<Path>) -> Result<Self, ArchiveError> {
+        let rdr = BufReader::new(std::fs::File::open(path)?);
+        Self::create_with_reader(rdr)
+    }
+}
+
+/// True if `magic` starts with the sparse image magic.
+pub fn is_sparse_image(magic: &[u8]) -> bool {
+    magic.len() >= 4 && le32(magic, 0) == SPARSE_MAGIC
+}
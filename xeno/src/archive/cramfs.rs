@@ -0,0 +1,478 @@
+use std::collections::HashSet;
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+
+use crate::archive::extract::{unpack_node, DeviceNode, ExtractNode, ExtractPolicy};
+use crate::archive::{Entry, FileType};
+use crate::utils::bytes::{le32, u32_at};
+use crate::utils::error::ArchiveError;
+
+const CRAMFS_MAGIC: u32 = 0x28cd_3d45;
+const CRAMFS_SIGNATURE: &[u8; 16] = b"Compressed ROMFS";
+const SUPERBLOCK_SIZE: usize = 76;
+const INODE_SIZE: usize = 12;
+/// mkcramfs can leave room for a boot loader in front of the superblock.
+const PADDED_OFFSET: usize = 512;
+
+const CRAMFS_FLAG_FSID_VERSION_2: u32 = 0x0000_0001;
+const CRAMFS_FLAG_EXT_BLOCK_POINTERS: u32 = 0x0000_0800;
+
+const CRAMFS_BLK_FLAG_UNCOMPRESSED: u32 = 1 << 31;
+const CRAMFS_BLK_FLAG_DIRECT_PTR: u32 = 1 << 30;
+const CRAMFS_BLK_FLAGS: u32 = CRAMFS_BLK_FLAG_UNCOMPRESSED | CRAMFS_BLK_FLAG_DIRECT_PTR;
+const CRAMFS_BLK_DIRECT_PTR_SHIFT: u32 = 2;
+const CRAMFS_BLOCK_SIZE: usize = 4096;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFSOCK: u32 = 0o140000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFREG: u32 = 0o100000;
+const S_IFBLK: u32 = 0o060000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFIFO: u32 = 0o010000;
+
+/// The packed `cramfs_inode`, with the bit fields laid out in the image's byte order.
+#[derive(Debug, Clone, Copy)]
+struct Inode {
+    mode: u32,
+    uid: u16,
+    size: u32,
+    gid: u8,
+    /// Length of the name following the inode, in bytes.
+    name_len: usize,
+    /// Position of the directory listing or block pointers in the image.
+    offset: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct CramfsEntry {
+    path: PathBuf,
+    inode: Inode,
+    /// The target of a symlink.
+    link: Option<PathBuf>,
+}
+
+impl Entry for CramfsEntry {
+    fn file_type(&self) -> FileType {
+        match self.inode.mode & S_IFMT {
+            S_IFREG => FileType::RegularFile,
+            S_IFDIR => FileType::Directory,
+            S_IFLNK => FileType::SymbolicLink,
+            S_IFCHR => FileType::CharacterDevice,
+            S_IFBLK => FileType::BlockDevice,
+            S_IFIFO => FileType::NamedPipe,
+            S_IFSOCK => FileType::Socket,
+            _ => FileType::Other,
+        }
+    }
+
+    fn hand_link(&self) -> Option<PathBuf> {
+        None
+    }
+
+    fn path_name(&self) -> std::io::Result<PathBuf> {
+        Ok(self.path.clone())
+    }
+
+    fn gid(&self) -> std::io::Result<Option<u64>> {
+        Ok(Some(u64::from(self.inode.gid)))
+    }
+
+    fn uid(&self) -> std::io::Result<Option<u64>> {
+        Ok(Some(u64::from(self.inode.uid)))
+    }
+
+    fn size(&self) -> u64 {
+        match self.device() {
+            Some(_) => 0,
+            None => u64::from(self.inode.size),
+        }
+    }
+
+    fn sym_link(&self) -> Option<PathBuf> {
+        self.link.clone()
+    }
+}
+
+impl CramfsEntry {
+    pub fn unix_mode(&self) -> u32 {
+        self.inode.mode & 0o7777
+    }
+
+    /// Major and minor number of a character or block device.
+    pub fn device(&self) -> Option<(u32, u32)> {
+        match self.inode.mode & S_IFMT {
+            // Devices keep the old 16 bit device number in the size field.
+            S_IFCHR | S_IFBLK => Some(((self.inode.size >> 8) & 0xff, self.inode.size & 0xff)),
+            _ => None,
+        }
+    }
+}
+
+pub struct CramfsEntries {
+    current: usize,
+    total: usize,
+    inner: Vec<CramfsEntry>,
+}
+
+impl Iterator for CramfsEntries {
+    type Item = Result<CramfsEntry, ArchiveError>;
+
+    fn next(&mut self) -> Option<Result<CramfsEntry, ArchiveError>> {
+        if self.current >= self.total {
+            return None;
+        }
+
+        let entry = &self.inner[self.current];
+        self.current += 1;
+        Some(Ok(entry.to_owned()))
+    }
+}
+
+/// cramfs image, written by mkcramfs on a host of either byte order.
+///
+/// Version 2 images get their CRC checked, and the extended block pointers (uncompressed and
+/// directly addressed blocks) from newer mkcramfs are understood.
+pub struct CramfsArchive {
+    data: Vec<u8>,
+    big_endian: bool,
+    flags: u32,
+    name: String,
+    edition: Option<u32>,
+    entries: Vec<CramfsEntry>,
+    /// Devices extracted as placeholders.
+    device_manifest: Vec<DeviceNode>,
+}
+
+impl CramfsArchive {
+    pub fn is_big_endian(&self) -> bool {
+        self.big_endian
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Edition number of a version 2 image.
+    pub fn edition(&self) -> Option<u32> {
+        self.edition
+    }
+
+    pub fn entries(&mut self) -> Result<CramfsEntries, ArchiveError> {
+        Ok(CramfsEntries {
+            current: 0,
+            total: self.entries.len(),
+            inner: self.entries.clone(),
+        })
+    }
+
+    /// Devices that could not be created and were extracted as empty placeholder files.
+    pub fn device_manifest(&self) -> &[DeviceNode] {
+        &self.device_manifest
+    }
+
+    pub fn unpack_all(&mut self, to: impl AsRef<Path>) -> Result<(), ArchiveError> {
+        let to = to.as_ref();
+        if !to.exists() {
+            std::fs::create_dir_all(to)?;
+        }
+
+        let entries = self.entries()?;
+        let policy = ExtractPolicy::default();
+        let mut failures = vec![];
+        for entry in entries {
+            match entry {
+                Ok(entry) => {
+                    if let Err(e) = self.unpack_entry(&entry, to, &policy) {
+                        failures.push(e);
+                    }
+                }
+                Err(e) => {
+                    failures.push(e);
+                }
+            }
+        }
+
+        if !failures.is_empty() {
+            return Err(ArchiveError::ExtractFailed { sources: failures });
+        }
+
+        Ok(())
+    }
+
+    fn unpack_entry(
+        &mut self,
+        entry: &CramfsEntry,
+        to: &Path,
+        policy: &ExtractPolicy,
+    ) -> Result<(), ArchiveError> {
+        let Some(path) = policy.destination(to, &entry.path)? else {
+            return Ok(());
+        };
+        // cramfs keeps no timestamps
+        if !policy.prepare(to, &path, entry.file_type(), 0)? {
+            return Ok(());
+        }
+        self.unpack_file(entry, path)
+    }
+
+    pub fn unpack_file(
+        &mut self,
+        entry: &CramfsEntry,
+        to: impl AsRef<Path>,
+    ) -> Result<(), ArchiveError> {
+        let mode = entry.inode.mode;
+        let placeholder = match (entry.file_type(), entry.device()) {
+            (FileType::RegularFile, _) => {
+                let mut data = Cursor::new(self.read_file(&entry.inode)?);
+                unpack_node(ExtractNode::File(&mut data), to, mode)?
+            }
+            (FileType::Directory, _) => unpack_node(ExtractNode::Directory, to, mode)?,
+            (FileType::SymbolicLink, _) => {
+                let link = entry.link.clone().unwrap_or_default();
+                unpack_node(ExtractNode::Symlink(&link), to, mode)?
+            }
+            (file_type @ (FileType::CharacterDevice | FileType::BlockDevice), Some(device)) => {
+                let owner = (u32::from(entry.inode.uid), u32::from(entry.inode.gid));
+                let device = DeviceNode::new(&entry.path, file_type, device, mode, owner);
+                unpack_node(ExtractNode::Device(device), to, mode)?
+            }
+            (FileType::NamedPipe, _) => unpack_node(ExtractNode::NamedPipe, to, mode)?,
+            _ => unpack_node(ExtractNode::Socket, to, mode)?,
+        };
+        if let Some(device) = placeholder {
+            self.device_manifest.push(device);
+        }
+        Ok(())
+    }
+
+    /// Decompresses the blocks of a file, following the kernel's reading of the block pointers.
+    fn read_file(&self, inode: &Inode) -> Result<Vec<u8>, ArchiveError> {
+        let size = inode.size as usize;
+        let blocks = size.div_ceil(CRAMFS_BLOCK_SIZE);
+        let extended = self.flags & CRAMFS_FLAG_EXT_BLOCK_POINTERS != 0;
+        let mut output = Vec::with_capacity(size);
+        // Blocks not addressed directly start where the previous one ended.
+        let mut block_start = inode.offset + blocks * 4;
+        for index in 0..blocks {
+            let want = (size - index * CRAMFS_BLOCK_SIZE).min(CRAMFS_BLOCK_SIZE);
+            let pointer = self.u32_at(inode.offset + index * 4)?;
+            let flags = if extended {
+                pointer & CRAMFS_BLK_FLAGS
+            } else {
+                0
+            };
+            let pointer = pointer & !flags;
+            let uncompressed = flags & CRAMFS_BLK_FLAG_UNCOMPRESSED != 0;
+
+            let (start, len) = if flags & CRAMFS_BLK_FLAG_DIRECT_PTR != 0 {
+                let start = (pointer << CRAMFS_BLK_DIRECT_PTR_SHIFT) as usize;
+                if uncompressed {
+                    block_start = start + CRAMFS_BLOCK_SIZE;
+                    (start, want)
+                } else {
+                    // Compressed direct blocks lead with their length.
+                    let len = self.u16_at(start)? as usize;
+                    block_start = start + 2 + len;
+                    (start + 2, len)
+                }
+            } else {
+                let end = pointer as usize;
+                let start = block_start;
+                block_start = end;
+                (start, end.saturating_sub(start))
+            };
+
+            let block = self
+                .data
+                .get(start..start + len)
+                .ok_or(ArchiveError::GenericsError("cramfs image is truncated"))?;
+            let before = output.len();
+            if len == 0 {
+                // A hole.
+            } else if uncompressed {
+                output.extend_from_slice(&block[..want.min(block.len())]);
+            } else {
+                flate2::read::ZlibDecoder::new(block)
+                    .take(want as u64)
+                    .read_to_end(&mut output)?;
+            }
+            output.resize(before + want, 0);
+        }
+
+        Ok(output)
+    }
+
+    pub fn create_with_reader(mut rdr: impl Read) -> Result<CramfsArchive, ArchiveError> {
+        let mut data = vec![];
+        rdr.read_to_end(&mut data)?;
+
+        let (start, big_endian) = [0, PADDED_OFFSET]
+            .into_iter()
+            .find_map(|start| {
+                let magic = data.get(start..start + 4)?;
+                match le32(magic, 0) {
+                    CRAMFS_MAGIC => Some((start, false)),
+                    m if m.swap_bytes() == CRAMFS_MAGIC => Some((start, true)),
+                    _ => None,
+                }
+            })
+            .ok_or(ArchiveError::GenericsError("not a cramfs image"))?;
+        let superblock = data
+            .get(start..start + SUPERBLOCK_SIZE)
+            .ok_or(ArchiveError::GenericsError("cramfs image is truncated"))?;
+        if &superblock[16..32] != CRAMFS_SIGNATURE {
+            return Err(ArchiveError::GenericsError("not a cramfs image"));
+        }
+
+        let size = u32_at(superblock, 4, big_endian) as usize;
+        let flags = u32_at(superblock, 8, big_endian);
+        let name = String::from_utf8_lossy(&superblock[48..64])
+            .trim_end_matches('\0')
+            .to_string();
+        let mut edition = None;
+        if flags & CRAMFS_FLAG_FSID_VERSION_2 != 0 {
+            // The CRC covers the image from the superblock on, with the CRC field zeroed.
+            let mut image = data
+                .get(start..size)
+                .ok_or(ArchiveError::GenericsError("cramfs image is truncated"))?
+                .to_vec();
+            let expected = u32_at(&image, 32, big_endian);
+            image[32..36].fill(0);
+            if crc32fast::hash(&image) != expected {
+                return Err(ArchiveError::ChecksumMismatch("cramfs image".to_string()));
+            }
+            edition = Some(u32_at(superblock, 36, big_endian));
+        }
+        let root = parse_inode(&superblock[64..], big_endian);
+
+        let mut archive = CramfsArchive {
+            data,
+            big_endian,
+            flags,
+            name,
+            edition,
+            entries: vec![],
+            device_manifest: vec![],
+        };
+        archive.entries = archive.build_tree(root)?;
+        Ok(archive)
+    }
+
+    pub fn create_with_path(path: impl AsRef<Path>) -> Result<CramfsArchive, ArchiveError> {
+        let rdr = std::io::BufReader::new(std::fs::File::open(path)?);
+        Self::create_with_reader(rdr)
+    }
+
+    /// Walks the directories from the root, depth first so parents come before children.
+    fn build_tree(&self, root: Inode) -> Result<Vec<CramfsEntry>, ArchiveError> {
+        let mut entries = vec![];
+        let mut visited = HashSet::from([root.offset]);
+        let mut stack = vec![(root, PathBuf::new())];
+        while let Some((dir, parent)) = stack.pop() {
+            let mut subdirs = vec![];
+            let mut position = dir.offset;
+            let end = dir.offset + dir.size as usize;
+            while position < end {
+                let header = self
+                    .data
+                    .get(position..position + INODE_SIZE)
+                    .ok_or(ArchiveError::GenericsError("cramfs image is truncated"))?;
+                let inode = parse_inode(header, self.big_endian);
+                let name_start = position + INODE_SIZE;
+                let name = self
+                    .data
+                    .get(name_start..name_start + inode.name_len)
+                    .ok_or(ArchiveError::GenericsError("cramfs image is truncated"))?;
+                position = name_start + inode.name_len;
+                if inode.name_len == 0 {
+                    return Err(ArchiveError::GenericsError("cramfs directory is corrupted"));
+                }
+
+                // Names are padded to four bytes with NULs.
+                let name = String::from_utf8_lossy(name);
+                let path = parent.join(name.trim_end_matches('\0'));
+                let link = match inode.mode & S_IFMT {
+                    S_IFLNK => {
+                        let target = self.read_file(&inode)?;
+                        Some(PathBuf::from(String::from_utf8_lossy(&target).into_owned()))
+                    }
+                    S_IFDIR => {
+                        // Empty directories point nowhere.
+                        if inode.size > 0 && visited.insert(inode.offset) {
+                            subdirs.push((inode, path.clone()));
+                        }
+                        None
+                    }
+                    _ => None,
+                };
+
+                entries.push(CramfsEntry { path, inode, link });
+            }
+            stack.extend(subdirs.into_iter().rev());
+        }
+
+        Ok(entries)
+    }
+
+    fn u16_at(&self, offset: usize) -> Result<u16, ArchiveError> {
+        let bytes = self
+            .data
+            .get(offset..offset + 2)
+            .ok_or(ArchiveError::GenericsError("cramfs image is truncated"))?;
+        Ok(match self.big_endian {
+            true => u16::from_be_bytes([bytes[0], bytes[1]]),
+            false => u16::from_le_bytes([bytes[0], bytes[1]]),
+        })
+    }
+
+    fn u32_at(&self, offset: usize) -> Result<u32, ArchiveError> {
+        let bytes = self
+            .data
+            .get(offset..offset + 4)
+            .ok_or(ArchiveError::GenericsError("cramfs image is truncated"))?;
+        Ok(u32_at(bytes, 0, self.big_endian))
+    }
+}
+
+fn parse_inode(data: &[u8], big_endian: bool) -> Inode {
+    let words = [
+        u32_at(data, 0, big_endian),
+        u32_at(data, 4, big_endian),
+        u32_at(data, 8, big_endian),
+    ];
+    // Bit fields are allocated from the least significant bit on little endian hosts and from
+    // the most significant one on big endian hosts.
+    let (mode, uid, size, gid, name_len, offset) = match big_endian {
+        true => (
+            words[0] >> 16,
+            words[0] & 0xffff,
+            words[1] >> 8,
+            words[1] & 0xff,
+            words[2] >> 26,
+            words[2] & 0x03ff_ffff,
+        ),
+        false => (
+            words[0] & 0xffff,
+            words[0] >> 16,
+            words[1] & 0x00ff_ffff,
+            words[1] >> 24,
+            words[2] & 0x3f,
+            words[2] >> 6,
+        ),
+    };
+
+    Inode {
+        mode,
+        uid: uid as u16,
+        size,
+        gid: gid as u8,
+        name_len: name_len as usize * 4,
+        offset: offset as usize * 4,
+    }
+}
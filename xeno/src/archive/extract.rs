@@ -0,0 +1,640 @@
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+
+use crate::archive::{ExtractOption, FileType};
+use crate::utils::error::ArchiveError;
+
+/// A filesystem node as the image backends hand it over for extraction.
+pub(crate) enum ExtractNode<'a> {
+    File(&'a mut dyn Read),
+    Directory,
+    /// Target exactly as stored in the image.
+    Symlink(&'a Path),
+    /// Path of the already extracted node to link to.
+    HardLink(&'a Path),
+    Device(DeviceNode),
+    NamedPipe,
+    Socket,
+}
+
+/// A character or block device from an image.
+///
+/// Without the privileges to create device nodes, extraction leaves an empty placeholder file
+/// and the archive keeps the device in its manifest. `Display` gives a line in the device table
+/// format of makedevs and genext2fs, so the manifest can be replayed when building an image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceNode {
+    path: PathBuf,
+    file_type: FileType,
+    major: u32,
+    minor: u32,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+}
+
+impl DeviceNode {
+    /// `path` is the location inside the image, `mode` holds the permission bits.
+    pub(crate) fn new(
+        path: impl Into<PathBuf>,
+        file_type: FileType,
+        (major, minor): (u32, u32),
+        mode: u32,
+        (uid, gid): (u32, u32),
+    ) -> DeviceNode {
+        DeviceNode {
+            path: path.into(),
+            file_type,
+            major,
+            minor,
+            mode: mode & 0o7777,
+            uid,
+            gid,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Either `FileType::CharacterDevice` or `FileType::BlockDevice`.
+    pub fn file_type(&self) -> FileType {
+        self.file_type
+    }
+
+    pub fn major(&self) -> u32 {
+        self.major
+    }
+
+    pub fn minor(&self) -> u32 {
+        self.minor
+    }
+
+    pub fn mode(&self) -> u32 {
+        self.mode
+    }
+
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    pub fn gid(&self) -> u32 {
+        self.gid
+    }
+}
+
+impl std::fmt::Display for DeviceNode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self.file_type {
+            FileType::BlockDevice => 'b',
+            _ => 'c',
+        };
+        write!(
+            f,
+            "{} {} {:o} {} {} {} {} - - -",
+            Path::new("/").join(&self.path).display(),
+            kind,
+            self.mode,
+            self.uid,
+            self.gid,
+            self.major,
+            self.minor
+        )
+    }
+}
+
+/// Creates `node` at `to`, with the permission bits of `mode` for files and directories.
+///
+/// Returns the device when only a placeholder could be created for it.
+pub(crate) fn unpack_node(
+    node: ExtractNode,
+    to: impl AsRef<Path>,
+    mode: u32,
+) -> Result<Option<DeviceNode>, ArchiveError> {
+    let to = to.as_ref();
+    match node {
+        ExtractNode::File(reader) => {
+            log::debug!("file {}", to.display());
+            let mut fd = std::fs::File::create(to)?;
+            std::io::copy(reader, &mut fd)?;
+        }
+        ExtractNode::Directory => {
+            log::debug!("path {}", to.display());
+            std::fs::create_dir_all(to)?;
+        }
+        ExtractNode::Symlink(link) => {
+            log::debug!("symlink {} {}", to.display(), link.display());
+            cfg_if::cfg_if! {
+                if #[cfg(unix)] {
+                    std::os::unix::fs::symlink(link, to)?;
+                } else {
+                    let target = to.parent().unwrap_or(Path::new("")).join(link);
+                    if target.is_dir() {
+                        std::os::windows::fs::symlink_dir(link, to)?;
+                    } else {
+                        std::os::windows::fs::symlink_file(link, to)?;
+                    }
+                }
+            }
+            return Ok(None);
+        }
+        ExtractNode::HardLink(target) => {
+            log::debug!("hard link {} {}", to.display(), target.display());
+            std::fs::hard_link(target, to)?;
+            return Ok(None);
+        }
+        ExtractNode::Device(device) => {
+            log::debug!("device {} {}", to.display(), device);
+            match mknod(to, &device) {
+                Ok(()) => return Ok(None),
+                Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                    log::info!(
+                        "[-] no permission to create {}, placeholder written",
+                        device
+                    );
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {
+                    log::info!("[-] device nodes not supported, placeholder for {}", device);
+                }
+                Err(e) => return Err(e.into()),
+            }
+            std::fs::File::create(to)?;
+            return Ok(Some(device));
+        }
+        ExtractNode::NamedPipe | ExtractNode::Socket => {
+            log::info!("[-] {} is a special file, not supported", to.display());
+            return Ok(None);
+        }
+    }
+
+    cfg_if::cfg_if! {
+        if #[cfg(unix)] {
+            let perms = std::fs::Permissions::from_mode(mode & 0o7777);
+            std::fs::set_permissions(to, perms)?;
+        } else {
+            let _ = mode;
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(unix)]
+fn mknod(to: &Path, device: &DeviceNode) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(to.as_os_str().as_bytes())?;
+    let kind = match device.file_type {
+        FileType::BlockDevice => libc::S_IFBLK,
+        _ => libc::S_IFCHR,
+    };
+    let mode = kind | device.mode as libc::mode_t;
+    let dev = libc::makedev(device.major as _, device.minor as _);
+    // SAFETY: `path` is a valid NUL terminated string that outlives the call.
+    if unsafe { libc::mknod(path.as_ptr(), mode, dev) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Ownership, timestamps and extended attributes of an extracted node.
+pub(crate) struct NodeMetadata<'a> {
+    pub(crate) mode: u32,
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+    /// Seconds since the epoch.
+    pub(crate) mtime: i64,
+    pub(crate) xattrs: &'a [(String, Vec<u8>)],
+}
+
+/// Applies `meta` to the node at `to` without following symlinks.
+///
+/// Ownership and extended attributes that need privileges are skipped with a log message.
+/// Ownership goes first, since `chown` clears the setuid bits and `security.capability`.
+pub(crate) fn restore_metadata(to: &Path, meta: &NodeMetadata) -> Result<(), ArchiveError> {
+    restore_owner(to, meta.uid, meta.gid)?;
+    restore_mode(to, meta.mode & 0o7777)?;
+    restore_xattrs(to, meta.xattrs)?;
+    restore_mtime(to, meta.mtime)
+}
+
+/// Sets access and modification time of the node at `to` without following symlinks.
+///
+/// Directories need this once more after their children are extracted.
+pub(crate) fn restore_mtime(to: &Path, mtime: i64) -> Result<(), ArchiveError> {
+    restore_times(to, (mtime, 0), (mtime, 0))
+}
+
+/// Sets access and modification time, as seconds and nanoseconds, without following symlinks.
+pub(crate) fn restore_times(
+    to: &Path,
+    atime: (i64, u32),
+    mtime: (i64, u32),
+) -> Result<(), ArchiveError> {
+    cfg_if::cfg_if! {
+        if #[cfg(unix)] {
+            use std::os::unix::ffi::OsStrExt;
+
+            let path = std::ffi::CString::new(to.as_os_str().as_bytes())
+                .map_err(std::io::Error::from)?;
+            let timespec = |(sec, nsec): (i64, u32)| libc::timespec {
+                tv_sec: sec as libc::time_t,
+                tv_nsec: nsec as _,
+            };
+            let times = [timespec(atime), timespec(mtime)];
+            // SAFETY: `path` is NUL terminated and `times` holds the two entries utimensat reads.
+            let ret = unsafe {
+                libc::utimensat(
+                    libc::AT_FDCWD,
+                    path.as_ptr(),
+                    times.as_ptr(),
+                    libc::AT_SYMLINK_NOFOLLOW,
+                )
+            };
+            if ret != 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+        } else {
+            let _ = (to, atime, mtime);
+        }
+    }
+    Ok(())
+}
+
+fn restore_owner(to: &Path, uid: u32, gid: u32) -> Result<(), ArchiveError> {
+    cfg_if::cfg_if! {
+        if #[cfg(unix)] {
+            match std::os::unix::fs::lchown(to, Some(uid), Some(gid)) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                    log::info!("[-] no permission to change the owner of {}", to.display());
+                }
+                Err(e) => return Err(e.into()),
+            }
+        } else {
+            let _ = (to, uid, gid);
+        }
+    }
+    Ok(())
+}
+
+/// Sets the permission bits of anything but a symlink, whose mode cannot change.
+fn restore_mode(to: &Path, mode: u32) -> Result<(), ArchiveError> {
+    cfg_if::cfg_if! {
+        if #[cfg(unix)] {
+            if !std::fs::symlink_metadata(to)?.file_type().is_symlink() {
+                let perms = std::fs::Permissions::from_mode(mode);
+                std::fs::set_permissions(to, perms)?;
+            }
+        } else {
+            let _ = (to, mode);
+        }
+    }
+    Ok(())
+}
+
+fn restore_xattrs(to: &Path, xattrs: &[(String, Vec<u8>)]) -> Result<(), ArchiveError> {
+    cfg_if::cfg_if! {
+        if #[cfg(unix)] {
+            for (name, value) in xattrs {
+                match set_xattr(to, name, value) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied
+                        || e.kind() == std::io::ErrorKind::Unsupported =>
+                    {
+                        log::info!("[-] could not set {} on {}: {}", name, to.display(), e);
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        } else {
+            let _ = (to, xattrs);
+        }
+    }
+    Ok(())
+}
+
+/// What a caller's [`ExtractOption`]s ask of an extraction.
+///
+/// Options about Mac metadata, file flags and sparse files are accepted and ignored. Paths with
+/// a `..` component are never extracted; `SecureNoDotDot` turns skipping them into an error.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct ExtractPolicy {
+    pub(crate) owner: bool,
+    pub(crate) permissions: bool,
+    pub(crate) time: bool,
+    pub(crate) xattrs: bool,
+    pub(crate) acls: bool,
+    no_overwrite: bool,
+    no_overwrite_newer: bool,
+    unlink: bool,
+    no_auto_dir: bool,
+    secure_symlinks: bool,
+    secure_no_dot_dot: bool,
+    secure_no_absolute_paths: bool,
+    /// Permission bits cleared without `Permissions`.
+    umask: u32,
+}
+
+impl ExtractPolicy {
+    pub(crate) fn new(options: &[ExtractOption]) -> ExtractPolicy {
+        let mut policy = ExtractPolicy {
+            umask: umask(),
+            ..Default::default()
+        };
+        for option in options {
+            match option {
+                ExtractOption::Owner => policy.owner = true,
+                ExtractOption::Permissions => policy.permissions = true,
+                ExtractOption::Time => policy.time = true,
+                ExtractOption::XAttr => policy.xattrs = true,
+                ExtractOption::ACL => policy.acls = true,
+                ExtractOption::NoOverwrite => policy.no_overwrite = true,
+                ExtractOption::NoOverwriteNewer => policy.no_overwrite_newer = true,
+                ExtractOption::Unlink => policy.unlink = true,
+                ExtractOption::NoAutoDir => policy.no_auto_dir = true,
+                ExtractOption::SecureSymlinks => policy.secure_symlinks = true,
+                ExtractOption::SecureNoDotDot => policy.secure_no_dot_dot = true,
+                ExtractOption::SecureNoAbsolutePaths => policy.secure_no_absolute_paths = true,
+                _ => {}
+            }
+        }
+        policy
+    }
+
+    /// Where `path` from the archive lands below `to`, or `None` when it is skipped.
+    pub(crate) fn destination(
+        &self,
+        to: &Path,
+        path: &Path,
+    ) -> Result<Option<PathBuf>, ArchiveError> {
+        let mut relative = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(part) => relative.push(part),
+                Component::CurDir => {}
+                Component::ParentDir if self.secure_no_dot_dot => {
+                    return Err(ArchiveError::GenericsError2(format!(
+                        "refusing to extract {}, it contains '..'",
+                        path.display()
+                    )));
+                }
+                Component::ParentDir => {
+                    log::info!("[-] skipping {}, it contains '..'", path.display());
+                    return Ok(None);
+                }
+                Component::RootDir | Component::Prefix(_) if self.secure_no_absolute_paths => {
+                    return Err(ArchiveError::GenericsError2(format!(
+                        "refusing to extract the absolute path {}",
+                        path.display()
+                    )));
+                }
+                Component::RootDir | Component::Prefix(_) => {}
+            }
+        }
+
+        if self.secure_symlinks {
+            let mut parent = to.to_path_buf();
+            for part in relative.parent().into_iter().flat_map(Path::iter) {
+                parent.push(part);
+                let is_symlink = std::fs::symlink_metadata(&parent)
+                    .is_ok_and(|meta| meta.file_type().is_symlink());
+                if !is_symlink {
+                    continue;
+                }
+                if !self.unlink {
+                    return Err(ArchiveError::GenericsError2(format!(
+                        "refusing to extract {} through the symlink {}",
+                        path.display(),
+                        parent.display()
+                    )));
+                }
+                std::fs::remove_file(&parent)?;
+            }
+        }
+        Ok(Some(to.join(relative)))
+    }
+
+    /// Where the hard link target `path` from the archive is below `to`, or `None` when it is
+    /// skipped. The target is resolved on disk, so a symlink extracted earlier cannot point the
+    /// link at a file outside of `to`.
+    pub(crate) fn link_target(
+        &self,
+        to: &Path,
+        path: &Path,
+    ) -> Result<Option<PathBuf>, ArchiveError> {
+        let Some(target) = self.destination(to, path)? else {
+            return Ok(None);
+        };
+        let parent = target.parent().unwrap_or(to);
+        if !parent.canonicalize()?.starts_with(to.canonicalize()?) {
+            return Err(ArchiveError::GenericsError2(format!(
+                "refusing to link to {} outside of {}",
+                path.display(),
+                to.display()
+            )));
+        }
+        Ok(Some(target))
+    }
+
+    /// Clears the way for a node of `file_type` at `to` below `root`, returning false when the
+    /// node is skipped because of what already exists there.
+    ///
+    /// Whatever the options, nothing is written through a symlink that leaves `root`, and an
+    /// existing file is unlinked rather than truncated, so a hard link extracted earlier cannot
+    /// redirect the write either.
+    pub(crate) fn prepare(
+        &self,
+        root: &Path,
+        to: &Path,
+        file_type: FileType,
+        mtime: i64,
+    ) -> Result<bool, ArchiveError> {
+        // the root directory of an image lands on `root` itself
+        let mut ancestor = match to == root {
+            true => root,
+            false => to.parent().unwrap_or(root),
+        };
+        while std::fs::symlink_metadata(ancestor).is_err() {
+            ancestor = ancestor.parent().unwrap_or(root);
+        }
+        if !ancestor.canonicalize()?.starts_with(root.canonicalize()?) {
+            return Err(ArchiveError::GenericsError2(format!(
+                "refusing to extract {} outside of {}",
+                to.display(),
+                root.display()
+            )));
+        }
+
+        if let Some(parent) = to.parent().filter(|parent| !parent.exists()) {
+            if self.no_auto_dir {
+                return Err(ArchiveError::GenericsError2(format!(
+                    "{} does not exist",
+                    parent.display()
+                )));
+            }
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let Ok(existing) = std::fs::symlink_metadata(to) else {
+            return Ok(true);
+        };
+        if self.no_overwrite {
+            log::info!("[-] {} exists, not overwritten", to.display());
+            return Ok(false);
+        }
+        if self.no_overwrite_newer {
+            let newer = existing
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+                .is_some_and(|since| since.as_secs() as i64 > mtime);
+            if newer {
+                log::info!("[-] {} is newer on disk, not overwritten", to.display());
+                return Ok(false);
+            }
+        }
+
+        if !existing.is_dir() {
+            std::fs::remove_file(to)?;
+        } else if file_type != FileType::Directory {
+            std::fs::remove_dir(to)?;
+        }
+        Ok(true)
+    }
+
+    /// Applies the parts of `meta` the options ask for, with `times` as the access and
+    /// modification time in seconds and nanoseconds.
+    ///
+    /// Without `Permissions` only the `0o777` bits are kept and the umask is obeyed.
+    pub(crate) fn restore(
+        &self,
+        to: &Path,
+        meta: &NodeMetadata,
+        times: ((i64, u32), (i64, u32)),
+    ) -> Result<(), ArchiveError> {
+        if self.owner {
+            restore_owner(to, meta.uid, meta.gid)?;
+        }
+        match self.permissions {
+            true => restore_mode(to, meta.mode & 0o7777)?,
+            false => restore_mode(to, meta.mode & 0o777 & !self.umask)?,
+        }
+        restore_xattrs(to, meta.xattrs)?;
+        if self.time {
+            restore_times(to, times.0, times.1)?;
+        }
+        Ok(())
+    }
+}
+
+/// Converts a POSIX.1e ACL in the text form star and libarchive store, e.g.
+/// `user::rw-,user:joe:r--:1000,group::r--,mask::r--,other::r--`, to the value of the
+/// `system.posix_acl_access` or `system.posix_acl_default` attribute.
+///
+/// Named entries need a numeric id, either as the name or as the trailing field.
+pub(crate) fn posix_acl_xattr(text: &str) -> Option<Vec<u8>> {
+    const ACL_VERSION: u32 = 2;
+    const UNDEFINED_ID: u32 = u32::MAX;
+
+    let mut entries = vec![];
+    for entry in text.split([',', '\n']) {
+        let entry = entry.split('#').next().unwrap_or_default().trim();
+        if entry.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = entry.split(':').collect();
+        let (tag, qualifier, perms) = match fields.as_slice() {
+            [tag, qualifier, perms, ..] => (*tag, *qualifier, *perms),
+            // short forms of mask and other leave out the empty qualifier
+            [tag, perms] => (*tag, "", *perms),
+            _ => return None,
+        };
+        let named = !qualifier.is_empty();
+        let tag: u16 = match (tag, named) {
+            ("user" | "u", false) => 0x01,
+            ("user" | "u", true) => 0x02,
+            ("group" | "g", false) => 0x04,
+            ("group" | "g", true) => 0x08,
+            ("mask" | "m", _) => 0x10,
+            ("other" | "o", _) => 0x20,
+            _ => return None,
+        };
+        let id = match named {
+            true => fields.get(3).unwrap_or(&qualifier).parse().ok()?,
+            false => UNDEFINED_ID,
+        };
+        let mut perm = 0u16;
+        for (bit, flag) in [(4, 'r'), (2, 'w'), (1, 'x')] {
+            if perms.contains(flag) {
+                perm |= bit;
+            }
+        }
+        entries.push((tag, id, perm));
+    }
+
+    entries.sort();
+    let mut value = ACL_VERSION.to_le_bytes().to_vec();
+    for (tag, id, perm) in entries {
+        value.extend_from_slice(&tag.to_le_bytes());
+        value.extend_from_slice(&perm.to_le_bytes());
+        value.extend_from_slice(&id.to_le_bytes());
+    }
+    Some(value)
+}
+
+/// The umask of the process, read from procfs where possible since `umask(2)` can only read
+/// it by setting it.
+#[cfg(unix)]
+fn umask() -> u32 {
+    let status = std::fs::read_to_string("/proc/self/status").unwrap_or_default();
+    let field = status.lines().find_map(|line| line.strip_prefix("Umask:"));
+    if let Some(mask) = field.and_then(|mask| u32::from_str_radix(mask.trim(), 8).ok()) {
+        return mask;
+    }
+    // SAFETY: umask cannot fail, the old mask is put back right away.
+    unsafe {
+        let mask = libc::umask(0o022);
+        libc::umask(mask);
+        mask as u32
+    }
+}
+
+#[cfg(not(unix))]
+fn umask() -> u32 {
+    0
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_xattr(to: &Path, name: &str, value: &[u8]) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(to.as_os_str().as_bytes())?;
+    let name = std::ffi::CString::new(name)?;
+    // SAFETY: both strings are NUL terminated and `value` is valid for `value.len()` bytes.
+    let ret = unsafe {
+        libc::lsetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+fn set_xattr(_to: &Path, _name: &str, _value: &[u8]) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(unix))]
+fn mknod(_to: &Path, _device: &DeviceNode) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
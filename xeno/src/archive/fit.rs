@@ -0,0 +1,370 @@
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use md5::Md5;
+use sha1::{Digest, Sha1};
+use sha2::{Sha256, Sha384, Sha512};
+
+use crate::archive::{Entry, FileType};
+use crate::block::{create_decoder, BlockFormat};
+use crate::utils::error::ArchiveError;
+use crate::utils::fdt;
+
+/// A `hash-*` node of a FIT image.
+#[derive(Debug, Clone)]
+pub struct FitHash {
+    algo: String,
+    value: Vec<u8>,
+}
+
+impl FitHash {
+    /// Algorithm name as written by mkimage, e.g. `crc32`, `sha1` or `sha256`.
+    pub fn algo(&self) -> &str {
+        &self.algo
+    }
+
+    pub fn value(&self) -> &[u8] {
+        &self.value
+    }
+}
+
+#[derive(Debug, Clone)]
+enum FitData {
+    /// Offset of the `data` property inside the device tree.
+    Inline(usize),
+    /// Offset of external data in the underlying reader.
+    External(u64),
+}
+
+#[derive(Debug, Clone)]
+pub struct FitEntry {
+    path: PathBuf,
+    description: Option<String>,
+    image_type: Option<String>,
+    arch: Option<String>,
+    os: Option<String>,
+    compression: Option<String>,
+    load: Option<u64>,
+    entry_point: Option<u64>,
+    hashes: Vec<FitHash>,
+    data: FitData,
+    size: u64,
+}
+
+impl FitEntry {
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    /// The `type` property, e.g. `kernel`, `ramdisk` or `flat_dt`.
+    pub fn image_type(&self) -> Option<&str> {
+        self.image_type.as_deref()
+    }
+
+    pub fn arch(&self) -> Option<&str> {
+        self.arch.as_deref()
+    }
+
+    pub fn os(&self) -> Option<&str> {
+        self.os.as_deref()
+    }
+
+    /// The `compression` property as written, `none` or missing for stored data.
+    pub fn compression(&self) -> Option<&str> {
+        self.compression.as_deref()
+    }
+
+    pub fn load_address(&self) -> Option<u64> {
+        self.load
+    }
+
+    pub fn entry_point(&self) -> Option<u64> {
+        self.entry_point
+    }
+
+    pub fn hashes(&self) -> &[FitHash] {
+        &self.hashes
+    }
+
+    fn block_format(&self) -> Result<Option<BlockFormat>, ArchiveError> {
+        let format = match self.compression.as_deref() {
+            None | Some("none") => return Ok(None),
+            Some("gzip") => BlockFormat::Gzip,
+            Some("bzip2") => BlockFormat::Bzip2,
+            Some("lzma") => BlockFormat::LzmaAlone,
+            Some("lzo") => BlockFormat::Lzop,
+            Some("lz4") => BlockFormat::Lz4,
+            Some("zstd") => BlockFormat::Zstd,
+            Some(_) => {
+                return Err(ArchiveError::GenericsError(
+                    "FIT image uses an unknown compression",
+                ))
+            }
+        };
+        Ok(Some(format))
+    }
+}
+
+impl Entry for FitEntry {
+    fn file_type(&self) -> FileType {
+        FileType::RegularFile
+    }
+
+    fn hand_link(&self) -> Option<PathBuf> {
+        None
+    }
+
+    fn path_name(&self) -> std::io::Result<PathBuf> {
+        Ok(self.path.clone())
+    }
+
+    fn gid(&self) -> std::io::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    fn uid(&self) -> std::io::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Size of the image data as stored, before decompression.
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn sym_link(&self) -> Option<PathBuf> {
+        None
+    }
+}
+
+pub struct FitEntries {
+    current: usize,
+    total: usize,
+    inner: Vec<FitEntry>,
+}
+
+impl Iterator for FitEntries {
+    type Item = Result<FitEntry, ArchiveError>;
+
+    fn next(&mut self) -> Option<Result<FitEntry, ArchiveError>> {
+        if self.current >= self.total {
+            return None;
+        }
+
+        let entry = &self.inner[self.current];
+        self.current += 1;
+        Some(Ok(entry.to_owned()))
+    }
+}
+
+/// U-Boot Flattened Image Tree: a device tree whose `/images` nodes carry kernels, ramdisks,
+/// device trees and firmware, either inline or as external data behind the tree.
+pub struct FitArchive<R: Read + Seek> {
+    inner: R,
+    blob: Vec<u8>,
+    description: Option<String>,
+    default_configuration: Option<String>,
+    entries: Vec<FitEntry>,
+}
+
+impl<R> FitArchive<R>
+where
+    R: Read + Seek,
+{
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    /// The `default` property of `/configurations`.
+    pub fn default_configuration(&self) -> Option<&str> {
+        self.default_configuration.as_deref()
+    }
+
+    pub fn entries(&mut self) -> Result<FitEntries, ArchiveError> {
+        Ok(FitEntries {
+            current: 0,
+            total: self.entries.len(),
+            inner: self.entries.clone(),
+        })
+    }
+
+    pub fn unpack_all(&mut self, to: impl AsRef<Path>) -> Result<(), ArchiveError> {
+        let to = to.as_ref();
+        if !to.exists() {
+            std::fs::create_dir_all(to)?;
+        }
+
+        let entries = self.entries()?;
+        let mut failures = vec![];
+        for entry in entries {
+            match entry {
+                Ok(entry) => {
+                    let path = to.join(&entry.path);
+                    if let Err(e) = self.unpack_file(&entry, path) {
+                        failures.push(e);
+                    }
+                }
+                Err(e) => {
+                    failures.push(e);
+                }
+            }
+        }
+
+        if !failures.is_empty() {
+            return Err(ArchiveError::ExtractFailed { sources: failures });
+        }
+
+        Ok(())
+    }
+
+    /// Writes the image data, decompressed according to its `compression` property.
+    pub fn unpack_file(
+        &mut self,
+        entry: &FitEntry,
+        to: impl AsRef<Path>,
+    ) -> Result<(), ArchiveError> {
+        let data = self.read_data(entry)?;
+        let mut writer = std::fs::File::create(to)?;
+        match entry.block_format()? {
+            Some(format) => {
+                create_decoder(format, data.as_slice())?.decode_to(&mut writer)?;
+            }
+            None => {
+                std::io::Write::write_all(&mut writer, &data)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the stored image data against every hash node with a known algorithm.
+    pub fn verify(&mut self, entry: &FitEntry) -> Result<(), ArchiveError> {
+        let data = self.read_data(entry)?;
+        for hash in &entry.hashes {
+            let digest = match hash.algo.as_str() {
+                "crc32" => {
+                    let mut crc = flate2::Crc::new();
+                    crc.update(&data);
+                    crc.sum().to_be_bytes().to_vec()
+                }
+                "md5" => Md5::digest(&data).to_vec(),
+                "sha1" => Sha1::digest(&data).to_vec(),
+                "sha256" => Sha256::digest(&data).to_vec(),
+                "sha384" => Sha384::digest(&data).to_vec(),
+                "sha512" => Sha512::digest(&data).to_vec(),
+                algo => {
+                    log::debug!("skipping unsupported FIT hash {}", algo);
+                    continue;
+                }
+            };
+
+            if digest != hash.value {
+                return Err(ArchiveError::ChecksumMismatch(format!(
+                    "{} {}",
+                    entry.path.display(),
+                    hash.algo
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_data(&mut self, entry: &FitEntry) -> Result<Vec<u8>, ArchiveError> {
+        match entry.data {
+            FitData::Inline(offset) => Ok(self.blob[offset..offset + entry.size as usize].to_vec()),
+            FitData::External(offset) => {
+                let mut data = vec![0u8; entry.size as usize];
+                self.inner.seek(SeekFrom::Start(offset))?;
+                self.inner.read_exact(&mut data)?;
+                Ok(data)
+            }
+        }
+    }
+
+    pub fn create_with_reader(mut rdr: R) -> Result<FitArchive<R>, ArchiveError> {
+        // The image may be embedded in a larger firmware blob, offsets are relative to the start.
+        let base = rdr.stream_position()?;
+        let mut header = [0u8; 8];
+        rdr.read_exact(&mut header)?;
+        let size =
+            fdt::total_size(&header).ok_or(ArchiveError::GenericsError("not a FIT image"))?;
+        let mut blob = header.to_vec();
+        blob.resize(size.max(header.len()), 0);
+        rdr.read_exact(&mut blob[header.len()..])?;
+
+        let tree = fdt::parse(&blob)?;
+        let images = tree
+            .root
+            .child("images")
+            .ok_or(ArchiveError::GenericsError("not a FIT image"))?;
+        // External data (`mkimage -E`) starts at the first aligned offset behind the tree.
+        let external = base + ((size as u64 + 3) & !3);
+
+        let mut entries = vec![];
+        for image in &images.children {
+            let (data, size) = match image.property("data") {
+                Some(prop) => (FitData::Inline(prop.offset), prop.len as u64),
+                None => {
+                    let size = tree.number(image, "data-size");
+                    let offset = match tree.number(image, "data-position") {
+                        Some(position) => Some(base + position),
+                        None => tree
+                            .number(image, "data-offset")
+                            .map(|offset| external + offset),
+                    };
+                    match (offset, size) {
+                        (Some(offset), Some(size)) => (FitData::External(offset), size),
+                        _ => return Err(ArchiveError::GenericsError("FIT image node has no data")),
+                    }
+                }
+            };
+
+            let hashes = image
+                .children
+                .iter()
+                .filter(|child| child.name.starts_with("hash"))
+                .filter_map(|child| {
+                    Some(FitHash {
+                        algo: tree.string(child, "algo")?,
+                        value: tree.value(child.property("value")?).to_vec(),
+                    })
+                })
+                .collect();
+
+            entries.push(FitEntry {
+                path: PathBuf::from(&image.name),
+                description: tree.string(image, "description"),
+                image_type: tree.string(image, "type"),
+                arch: tree.string(image, "arch"),
+                os: tree.string(image, "os"),
+                compression: tree.string(image, "compression"),
+                load: tree.number(image, "load"),
+                entry_point: tree.number(image, "entry"),
+                hashes,
+                data,
+                size,
+            });
+        }
+
+        let description = tree.string(&tree.root, "description");
+        let default_configuration = tree
+            .root
+            .child("configurations")
+            .and_then(|configurations| tree.string(configurations, "default"));
+        let archive = FitArchive {
+            inner: rdr,
+            blob,
+            description,
+            default_configuration,
+            entries,
+        };
+
+        Ok(archive)
+    }
+}
+
+impl FitArchive<BufReader<std::fs::File>> {
+    pub fn create_with_path(path: impl AsRef<Path>) -> Result<Self, ArchiveError> {
+        let rdr = BufReader::new(std::fs::File::open(path)?);
+        Self::create_with_reader(rdr)
+    }
+}
@@ -0,0 +1,631 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use time::PrimitiveDateTime;
+
+use crate::archive::extract::{unpack_node, ExtractNode, ExtractPolicy};
+use crate::archive::{Entry, FileType};
+use crate::block::lzo::LzoBlock;
+use crate::utils::bytes::{u16_at, u32_at};
+use crate::utils::error::ArchiveError;
+
+const JFFS2_MAGIC: u16 = 0x1985;
+const NODE_HEADER_SIZE: usize = 12;
+const DIRENT_SIZE: usize = 40;
+const INODE_SIZE: usize = 68;
+
+/// Set on every node until it gets marked obsolete.
+const JFFS2_NODE_ACCURATE: u16 = 0x2000;
+const JFFS2_NODETYPE_DIRENT: u16 = 0xe001;
+const JFFS2_NODETYPE_INODE: u16 = 0xe002;
+
+const JFFS2_COMPR_NONE: u8 = 0x00;
+const JFFS2_COMPR_ZERO: u8 = 0x01;
+const JFFS2_COMPR_RTIME: u8 = 0x02;
+const JFFS2_COMPR_ZLIB: u8 = 0x06;
+const JFFS2_COMPR_LZO: u8 = 0x07;
+const JFFS2_COMPR_LZMA: u8 = 0x08;
+
+/// The root directory has no inode node of its own.
+const ROOT_INO: u32 = 1;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFSOCK: u32 = 0o140000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFREG: u32 = 0o100000;
+const S_IFBLK: u32 = 0o060000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFIFO: u32 = 0o010000;
+
+/// A data node of an inode: a range of the file, compressed on its own.
+#[derive(Debug, Clone)]
+struct DataNode {
+    version: u32,
+    offset: u32,
+    dsize: u32,
+    compression: u8,
+    /// Position of the compressed data in the image.
+    start: usize,
+    csize: usize,
+}
+
+/// The latest metadata of an inode and all of its data nodes.
+#[derive(Debug, Clone, Default)]
+struct Inode {
+    version: u32,
+    mode: u32,
+    uid: u16,
+    gid: u16,
+    isize: u32,
+    atime: u32,
+    mtime: u32,
+    ctime: u32,
+    nodes: Vec<DataNode>,
+}
+
+#[derive(Debug, Clone)]
+struct Dirent {
+    version: u32,
+    ino: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Jffs2Entry {
+    path: PathBuf,
+    ino: u32,
+    mode: u32,
+    uid: u16,
+    gid: u16,
+    size: u64,
+    atime: u32,
+    mtime: u32,
+    ctime: u32,
+    /// The target of a symlink.
+    link: Option<PathBuf>,
+    /// The first path of an inode with several directory entries.
+    hard_link: Option<PathBuf>,
+    device: Option<(u32, u32)>,
+}
+
+impl Entry for Jffs2Entry {
+    fn file_type(&self) -> FileType {
+        if self.hard_link.is_some() {
+            return FileType::HardLink;
+        }
+
+        match self.mode & S_IFMT {
+            S_IFREG => FileType::RegularFile,
+            S_IFDIR => FileType::Directory,
+            S_IFLNK => FileType::SymbolicLink,
+            S_IFCHR => FileType::CharacterDevice,
+            S_IFBLK => FileType::BlockDevice,
+            S_IFIFO => FileType::NamedPipe,
+            S_IFSOCK => FileType::Socket,
+            _ => FileType::Other,
+        }
+    }
+
+    fn hand_link(&self) -> Option<PathBuf> {
+        self.hard_link.clone()
+    }
+
+    fn path_name(&self) -> std::io::Result<PathBuf> {
+        Ok(self.path.clone())
+    }
+
+    fn gid(&self) -> std::io::Result<Option<u64>> {
+        Ok(Some(u64::from(self.gid)))
+    }
+
+    fn uid(&self) -> std::io::Result<Option<u64>> {
+        Ok(Some(u64::from(self.uid)))
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn sym_link(&self) -> Option<PathBuf> {
+        self.link.clone()
+    }
+}
+
+impl Jffs2Entry {
+    pub fn inode(&self) -> u32 {
+        self.ino
+    }
+
+    pub fn unix_mode(&self) -> u32 {
+        self.mode & 0o7777
+    }
+
+    /// Major and minor number of a character or block device.
+    pub fn device(&self) -> Option<(u32, u32)> {
+        self.device
+    }
+
+    pub fn atime(&self) -> Option<PrimitiveDateTime> {
+        unix_time(self.atime)
+    }
+
+    pub fn mtime(&self) -> Option<PrimitiveDateTime> {
+        unix_time(self.mtime)
+    }
+
+    pub fn ctime(&self) -> Option<PrimitiveDateTime> {
+        unix_time(self.ctime)
+    }
+}
+
+pub struct Jffs2Entries {
+    current: usize,
+    total: usize,
+    inner: Vec<Jffs2Entry>,
+}
+
+impl Iterator for Jffs2Entries {
+    type Item = Result<Jffs2Entry, ArchiveError>;
+
+    fn next(&mut self) -> Option<Result<Jffs2Entry, ArchiveError>> {
+        if self.current >= self.total {
+            return None;
+        }
+
+        let entry = &self.inner[self.current];
+        self.current += 1;
+        Some(Ok(entry.to_owned()))
+    }
+}
+
+/// JFFS2 image as dumped from NOR flash.
+///
+/// The whole image is scanned for nodes, so erase block size and padding do not matter. Obsolete
+/// nodes and nodes with a bad CRC are skipped, and for every inode and directory entry the node
+/// with the highest version wins.
+pub struct Jffs2Archive {
+    data: Vec<u8>,
+    big_endian: bool,
+    inodes: HashMap<u32, Inode>,
+    entries: Vec<Jffs2Entry>,
+}
+
+impl Jffs2Archive {
+    pub fn is_big_endian(&self) -> bool {
+        self.big_endian
+    }
+
+    pub fn entries(&mut self) -> Result<Jffs2Entries, ArchiveError> {
+        Ok(Jffs2Entries {
+            current: 0,
+            total: self.entries.len(),
+            inner: self.entries.clone(),
+        })
+    }
+
+    pub fn unpack_all(&mut self, to: impl AsRef<Path>) -> Result<(), ArchiveError> {
+        let to = to.as_ref();
+        if !to.exists() {
+            std::fs::create_dir_all(to)?;
+        }
+
+        let entries = self.entries()?;
+        let policy = ExtractPolicy::default();
+        let mut failures = vec![];
+        for entry in entries {
+            match entry {
+                Ok(entry) => {
+                    if let Err(e) = self.unpack_entry(&entry, to, &policy) {
+                        failures.push(e);
+                    }
+                }
+                Err(e) => {
+                    failures.push(e);
+                }
+            }
+        }
+
+        if !failures.is_empty() {
+            return Err(ArchiveError::ExtractFailed { sources: failures });
+        }
+
+        Ok(())
+    }
+
+    fn unpack_entry(
+        &mut self,
+        entry: &Jffs2Entry,
+        to: &Path,
+        policy: &ExtractPolicy,
+    ) -> Result<(), ArchiveError> {
+        let Some(path) = policy.destination(to, &entry.path)? else {
+            return Ok(());
+        };
+        if !policy.prepare(to, &path, entry.file_type(), i64::from(entry.mtime))? {
+            return Ok(());
+        }
+        match &entry.hard_link {
+            Some(link) => {
+                let Some(target) = policy.link_target(to, link)? else {
+                    return Ok(());
+                };
+                unpack_node(ExtractNode::HardLink(&target), &path, 0)?;
+                Ok(())
+            }
+            None => self.unpack_file(entry, path),
+        }
+    }
+
+    pub fn unpack_file(
+        &mut self,
+        entry: &Jffs2Entry,
+        to: impl AsRef<Path>,
+    ) -> Result<(), ArchiveError> {
+        let to = to.as_ref();
+        match entry.file_type() {
+            FileType::Directory => {
+                unpack_node(ExtractNode::Directory, to, entry.mode)?;
+            }
+            FileType::SymbolicLink => {
+                let link = entry.link.clone().unwrap_or_default();
+                unpack_node(ExtractNode::Symlink(&link), to, entry.mode)?;
+            }
+            FileType::RegularFile | FileType::HardLink => {
+                let data = self.read_inode(entry.ino)?;
+                unpack_node(ExtractNode::File(&mut data.as_slice()), to, entry.mode)?;
+            }
+            _ => {
+                log::info!(
+                    "[-] {} is a special file, not supported",
+                    entry.path.display()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Replays the data nodes of an inode from oldest to newest.
+    fn read_inode(&self, ino: u32) -> Result<Vec<u8>, ArchiveError> {
+        let inode = match self.inodes.get(&ino) {
+            Some(inode) => inode,
+            None => return Ok(vec![]),
+        };
+
+        let mut data = vec![0u8; inode.isize as usize];
+        let mut nodes = inode.nodes.clone();
+        nodes.sort_by_key(|node| node.version);
+        for node in nodes {
+            let start = node.offset as usize;
+            if start >= data.len() {
+                continue;
+            }
+
+            let decoded = self.decode_node(&node)?;
+            let end = (start + decoded.len()).min(data.len());
+            data[start..end].copy_from_slice(&decoded[..end - start]);
+        }
+
+        Ok(data)
+    }
+
+    fn decode_node(&self, node: &DataNode) -> Result<Vec<u8>, ArchiveError> {
+        let input = &self.data[node.start..node.start + node.csize];
+        let dsize = node.dsize as usize;
+        let mut output = match node.compression {
+            JFFS2_COMPR_NONE => input.to_vec(),
+            JFFS2_COMPR_ZERO => vec![0u8; dsize],
+            JFFS2_COMPR_RTIME => rtime_decompress(input, dsize),
+            JFFS2_COMPR_ZLIB => {
+                let mut output = Vec::with_capacity(dsize);
+                flate2::read::ZlibDecoder::new(input)
+                    .take(dsize as u64)
+                    .read_to_end(&mut output)?;
+                output
+            }
+            JFFS2_COMPR_LZO => {
+                let mut output = Vec::with_capacity(dsize);
+                LzoBlock::create_with_size(input, dsize)?.read_to_end(&mut output)?;
+                output
+            }
+            JFFS2_COMPR_LZMA => {
+                // Raw LZMA with lc=0, lp=0, pb=0, so the header only needs a dictionary size.
+                let mut header = vec![0u8];
+                header.extend(0x0001_0000_u32.to_le_bytes());
+                let mut rdr = header.chain(input);
+                let mut rdr = std::io::BufReader::new(&mut rdr);
+                let options = lzma_rs::decompress::Options {
+                    unpacked_size: lzma_rs::decompress::UnpackedSize::UseProvided(Some(
+                        dsize as u64,
+                    )),
+                    ..Default::default()
+                };
+                let mut output = Vec::with_capacity(dsize);
+                lzma_rs::lzma_decompress_with_options(&mut rdr, &mut output, &options)
+                    .map_err(ArchiveError::LzmaError)?;
+                output
+            }
+            _ => {
+                return Err(ArchiveError::GenericsError(
+                    "JFFS2 node uses an unsupported compression",
+                ))
+            }
+        };
+
+        output.resize(dsize, 0);
+        Ok(output)
+    }
+
+    pub fn create_with_reader(mut rdr: impl Read) -> Result<Jffs2Archive, ArchiveError> {
+        let mut data = vec![];
+        rdr.read_to_end(&mut data)?;
+
+        let big_endian = (0..data.len().saturating_sub(NODE_HEADER_SIZE))
+            .step_by(4)
+            .find_map(|offset| {
+                [false, true]
+                    .into_iter()
+                    .find(|big_endian| node_header(&data[offset..], *big_endian).is_some())
+            })
+            .ok_or(ArchiveError::GenericsError("not a JFFS2 image"))?;
+
+        let mut archive = Jffs2Archive {
+            data,
+            big_endian,
+            inodes: HashMap::new(),
+            entries: vec![],
+        };
+        let dirents = archive.scan();
+        archive.entries = archive.build_tree(&dirents);
+        Ok(archive)
+    }
+
+    pub fn create_with_path(path: impl AsRef<Path>) -> Result<Jffs2Archive, ArchiveError> {
+        let rdr = std::fs::File::open(path)?;
+        Self::create_with_reader(rdr)
+    }
+
+    /// Collects the latest inode metadata and directory entries from every valid node.
+    fn scan(&mut self) -> BTreeMap<(u32, Vec<u8>), Dirent> {
+        let data = &self.data;
+        let big_endian = self.big_endian;
+        let u32_at = |offset: usize| u32_at(data, offset, big_endian);
+        let u16_at = |offset: usize| u16_at(data, offset, big_endian);
+
+        let mut dirents: BTreeMap<(u32, Vec<u8>), Dirent> = BTreeMap::new();
+        let mut offset = 0;
+        while offset + NODE_HEADER_SIZE <= data.len() {
+            let (node_type, len) = match node_header(&data[offset..], big_endian) {
+                Some(header) => header,
+                None => {
+                    offset += 4;
+                    continue;
+                }
+            };
+            let node = &data[offset..(offset + len).min(data.len())];
+            let next = offset + ((len + 3) & !3);
+
+            match node_type {
+                JFFS2_NODETYPE_DIRENT
+                    if node.len() >= DIRENT_SIZE
+                        && jffs2_crc(&node[..DIRENT_SIZE - 8]) == u32_at(offset + 32) =>
+                {
+                    let nsize = node[28] as usize;
+                    let name = match node.get(DIRENT_SIZE..DIRENT_SIZE + nsize) {
+                        Some(name) if jffs2_crc(name) == u32_at(offset + 36) => name.to_vec(),
+                        _ => {
+                            log::debug!("JFFS2 dirent at {:#x} has a bad name", offset);
+                            offset = next;
+                            continue;
+                        }
+                    };
+                    let dirent = Dirent {
+                        version: u32_at(offset + 16),
+                        ino: u32_at(offset + 20),
+                    };
+                    let key = (u32_at(offset + 12), name);
+                    if dirents.get(&key).is_none_or(|d| d.version < dirent.version) {
+                        dirents.insert(key, dirent);
+                    }
+                }
+                JFFS2_NODETYPE_INODE
+                    if node.len() >= INODE_SIZE
+                        && jffs2_crc(&node[..INODE_SIZE - 8]) == u32_at(offset + 64) =>
+                {
+                    let csize = u32_at(offset + 48) as usize;
+                    let start = offset + INODE_SIZE;
+                    match data.get(start..start + csize) {
+                        Some(compressed) if jffs2_crc(compressed) == u32_at(offset + 60) => {}
+                        _ => {
+                            log::debug!("JFFS2 inode node at {:#x} has bad data", offset);
+                            offset = next;
+                            continue;
+                        }
+                    }
+
+                    let version = u32_at(offset + 16);
+                    let inode = self.inodes.entry(u32_at(offset + 12)).or_default();
+                    if version >= inode.version {
+                        inode.version = version;
+                        inode.mode = u32_at(offset + 20);
+                        inode.uid = u16_at(offset + 24);
+                        inode.gid = u16_at(offset + 26);
+                        inode.isize = u32_at(offset + 28);
+                        inode.atime = u32_at(offset + 32);
+                        inode.mtime = u32_at(offset + 36);
+                        inode.ctime = u32_at(offset + 40);
+                    }
+                    inode.nodes.push(DataNode {
+                        version,
+                        offset: u32_at(offset + 44),
+                        dsize: u32_at(offset + 52),
+                        compression: data[offset + 56],
+                        start,
+                        csize,
+                    });
+                }
+                _ => {}
+            }
+            offset = next;
+        }
+
+        dirents
+    }
+
+    /// Walks the directory entries from the root, depth first so parents come before children.
+    fn build_tree(&self, dirents: &BTreeMap<(u32, Vec<u8>), Dirent>) -> Vec<Jffs2Entry> {
+        let mut children: HashMap<u32, Vec<(&[u8], u32)>> = HashMap::new();
+        for ((pino, name), dirent) in dirents {
+            // A dirent pointing at inode 0 records an unlink.
+            if dirent.ino != 0 {
+                children
+                    .entry(*pino)
+                    .or_default()
+                    .push((name.as_slice(), dirent.ino));
+            }
+        }
+
+        let mut entries = vec![];
+        let mut first_paths: HashMap<u32, PathBuf> = HashMap::new();
+        let mut visited = HashSet::from([ROOT_INO]);
+        let mut stack = vec![(ROOT_INO, PathBuf::new())];
+        while let Some((dir, parent)) = stack.pop() {
+            let mut subdirs = vec![];
+            for (name, ino) in children.get(&dir).into_iter().flatten() {
+                if matches!(*name, b"" | b"." | b"..") || name.contains(&b'/') {
+                    log::info!(
+                        "[-] skipping the JFFS2 name {:?}",
+                        String::from_utf8_lossy(name)
+                    );
+                    continue;
+                }
+                let path = parent.join(String::from_utf8_lossy(name).as_ref());
+                let inode = self.inodes.get(ino).cloned().unwrap_or_default();
+                let file_type = inode.mode & S_IFMT;
+                if file_type == S_IFDIR {
+                    if !visited.insert(*ino) {
+                        log::debug!("JFFS2 directory {} is linked twice", path.display());
+                        continue;
+                    }
+                    subdirs.push((*ino, path.clone()));
+                }
+
+                let hard_link = match first_paths.get(ino) {
+                    Some(first) if file_type != S_IFDIR => Some(first.clone()),
+                    _ => {
+                        first_paths.insert(*ino, path.clone());
+                        None
+                    }
+                };
+
+                let payload = || self.read_inode(*ino).unwrap_or_default();
+                let (link, device) = match file_type {
+                    S_IFLNK => {
+                        let target = String::from_utf8_lossy(&payload()).into_owned();
+                        (Some(PathBuf::from(target)), None)
+                    }
+                    S_IFCHR | S_IFBLK => (None, decode_device(&payload(), self.big_endian)),
+                    _ => (None, None),
+                };
+
+                entries.push(Jffs2Entry {
+                    path,
+                    ino: *ino,
+                    mode: inode.mode,
+                    uid: inode.uid,
+                    gid: inode.gid,
+                    size: u64::from(inode.isize),
+                    atime: inode.atime,
+                    mtime: inode.mtime,
+                    ctime: inode.ctime,
+                    link,
+                    hard_link,
+                    device,
+                });
+            }
+            stack.extend(subdirs.into_iter().rev());
+        }
+
+        entries
+    }
+}
+
+/// Node type and total length if a valid, non obsolete node header starts at `data`.
+fn node_header(data: &[u8], big_endian: bool) -> Option<(u16, usize)> {
+    if data.len() < NODE_HEADER_SIZE || u16_at(data, 0, big_endian) != JFFS2_MAGIC {
+        return None;
+    }
+
+    // Marking a node obsolete clears the accurate bit, the CRC still covers it set.
+    let node_type = u16_at(data, 2, big_endian);
+    let mut header = data[..8].to_vec();
+    let accurate = node_type | JFFS2_NODE_ACCURATE;
+    let accurate = match big_endian {
+        true => accurate.to_be_bytes(),
+        false => accurate.to_le_bytes(),
+    };
+    header[2..4].copy_from_slice(&accurate);
+    if jffs2_crc(&header) != u32_at(data, 8, big_endian) {
+        return None;
+    }
+
+    let len = u32_at(data, 4, big_endian) as usize;
+    if len < NODE_HEADER_SIZE {
+        return None;
+    }
+    if node_type & JFFS2_NODE_ACCURATE == 0 {
+        // Obsolete, skipped as a node of unknown type.
+        return Some((0, len));
+    }
+    Some((node_type, len))
+}
+
+/// Decodes the `jffs2_device_node` stored as the data of a device inode.
+fn decode_device(data: &[u8], big_endian: bool) -> Option<(u32, u32)> {
+    match data.len() {
+        2 => {
+            let dev = u32::from(u16_at(data, 0, big_endian));
+            Some(((dev >> 8) & 0xff, dev & 0xff))
+        }
+        4 => {
+            let dev = u32_at(data, 0, big_endian);
+            Some(((dev & 0xfff00) >> 8, (dev & 0xff) | ((dev >> 12) & 0xfff00)))
+        }
+        _ => None,
+    }
+}
+
+fn rtime_decompress(input: &[u8], dsize: usize) -> Vec<u8> {
+    let mut positions = [0usize; 256];
+    let mut output = Vec::with_capacity(dsize);
+    let mut pairs = input.chunks_exact(2);
+    while output.len() < dsize {
+        let (value, repeat) = match pairs.next() {
+            Some(pair) => (pair[0], pair[1] as usize),
+            None => break,
+        };
+        output.push(value);
+        let backoffs = positions[value as usize];
+        positions[value as usize] = output.len();
+        for position in backoffs..backoffs + repeat {
+            if output.len() >= dsize {
+                break;
+            }
+            output.push(output[position]);
+        }
+    }
+
+    output
+}
+
+/// The kernel's `crc32(0, ...)`, without the usual inversions.
+fn jffs2_crc(data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new_with_initial(0xffff_ffff);
+    hasher.update(data);
+    !hasher.finalize()
+}
+
+fn unix_time(time: u32) -> Option<PrimitiveDateTime> {
+    let dt = time::OffsetDateTime::from_unix_timestamp(i64::from(time)).ok();
+    dt.map(|dt| PrimitiveDateTime::new(dt.date(), dt.time()))
+}
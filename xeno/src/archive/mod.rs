@@ -1,17 +1,29 @@
 use std::{io::Read, path::PathBuf};
 
+pub mod android_boot;
+pub mod android_ota;
+pub mod android_sparse;
 mod apple_xar;
 mod cab;
 mod cpio;
+pub mod cramfs;
 mod dmg;
+pub mod extract;
 mod fat;
+pub mod fit;
+pub mod jffs2;
 mod lha;
 mod ntfs;
 mod rar;
+pub mod romfs;
 mod seven_zip;
-mod squashfs;
-mod tar;
-mod zip;
+pub mod squashfs;
+pub mod tar;
+pub mod ubi;
+pub mod ubifs;
+pub mod uimage;
+pub mod zimage;
+pub mod zip;
 
 pub enum ReadFormat {
     SevenZip,
@@ -32,7 +44,7 @@ pub enum ReadFormat {
     Dmg,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FileType {
     BlockDevice,
     SymbolicLink,
@@ -46,6 +58,7 @@ pub enum FileType {
     Other,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ExtractOption {
     // The user and group IDs should be set on the restored file. By default, the user and group
     // IDs are not restored.
@@ -61,14 +74,12 @@ pub enum ExtractOption {
     // The timestamps (mtime, ctime, and atime) should be restored. By default, they are ignored.
     // Note that restoring of atime is not currently supported.
     Time,
-    // Existing files on disk will not be overwritten. By default, existing regular files are
-    // truncated and overwritten; existing directories will have their permissions updated; other
-    // pre-existing objects are unlinked and recreated from scratch.
+    // Existing files on disk will not be overwritten. By default, existing files are unlinked and
+    // recreated from scratch; existing directories will have their permissions updated.
     NoOverwrite,
-    // Existing files on disk will be unlinked before any attempt to create them. In some cases,
-    // this can prove to be a significant performance improvement. By default, existing files are
-    // truncated and rewritten, but the file is not recreated. In particular, the default behavior
-    // does not break existing hard links.
+    // Existing files on disk are always unlinked before they are recreated, so that a hard link
+    // or symlink already on disk cannot redirect the write. With `SecureSymlinks`, this option
+    // also removes intermediate symlinks instead of refusing to extract through them.
     Unlink,
     // Attempt to restore ACLs. By default, extended ACLs are ignored.
     ACL,
@@ -0,0 +1,397 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+
+use crate::archive::extract::{unpack_node, DeviceNode, ExtractNode, ExtractPolicy};
+use crate::archive::{Entry, FileType};
+use crate::utils::bytes::be32;
+use crate::utils::error::ArchiveError;
+
+const ROMFS_MAGIC: &[u8; 8] = b"-rom1fs-";
+/// The superblock checksum covers at most this many bytes.
+const ROMFS_CHECKSUM_SIZE: usize = 512;
+const ROMFH_SIZE: usize = 16;
+
+const ROMFH_TYPE: u32 = 7;
+const ROMFH_EXEC: u32 = 8;
+const ROMFH_HRD: u32 = 0;
+const ROMFH_DIR: u32 = 1;
+const ROMFH_REG: u32 = 2;
+const ROMFH_SYM: u32 = 3;
+const ROMFH_BLK: u32 = 4;
+const ROMFH_CHR: u32 = 5;
+const ROMFH_SCK: u32 = 6;
+const ROMFH_FIF: u32 = 7;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFSOCK: u32 = 0o140000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFREG: u32 = 0o100000;
+const S_IFBLK: u32 = 0o060000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFIFO: u32 = 0o010000;
+
+/// romfs stores no permissions, these are the modes the kernel gives each file type.
+const ROMFS_MODES: [u32; 8] = [
+    0,
+    S_IFDIR | 0o644,
+    S_IFREG | 0o644,
+    S_IFLNK | 0o777,
+    S_IFBLK | 0o600,
+    S_IFCHR | 0o600,
+    S_IFSOCK | 0o644,
+    S_IFIFO | 0o644,
+];
+
+#[derive(Debug, Clone)]
+pub struct RomfsEntry {
+    path: PathBuf,
+    mode: u32,
+    /// Position and length of the file data.
+    start: usize,
+    size: usize,
+    /// The target of a symlink.
+    link: Option<PathBuf>,
+    /// The path of the file a hard link header points at.
+    hard_link: Option<PathBuf>,
+    device: Option<(u32, u32)>,
+}
+
+impl Entry for RomfsEntry {
+    fn file_type(&self) -> FileType {
+        if self.hard_link.is_some() {
+            return FileType::HardLink;
+        }
+
+        match self.mode & S_IFMT {
+            S_IFREG => FileType::RegularFile,
+            S_IFDIR => FileType::Directory,
+            S_IFLNK => FileType::SymbolicLink,
+            S_IFCHR => FileType::CharacterDevice,
+            S_IFBLK => FileType::BlockDevice,
+            S_IFIFO => FileType::NamedPipe,
+            S_IFSOCK => FileType::Socket,
+            _ => FileType::Other,
+        }
+    }
+
+    fn hand_link(&self) -> Option<PathBuf> {
+        self.hard_link.clone()
+    }
+
+    fn path_name(&self) -> std::io::Result<PathBuf> {
+        Ok(self.path.clone())
+    }
+
+    fn gid(&self) -> std::io::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    fn uid(&self) -> std::io::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    fn size(&self) -> u64 {
+        self.size as u64
+    }
+
+    fn sym_link(&self) -> Option<PathBuf> {
+        self.link.clone()
+    }
+}
+
+impl RomfsEntry {
+    pub fn unix_mode(&self) -> u32 {
+        self.mode & 0o7777
+    }
+
+    /// Major and minor number of a character or block device.
+    pub fn device(&self) -> Option<(u32, u32)> {
+        self.device
+    }
+}
+
+pub struct RomfsEntries {
+    current: usize,
+    total: usize,
+    inner: Vec<RomfsEntry>,
+}
+
+impl Iterator for RomfsEntries {
+    type Item = Result<RomfsEntry, ArchiveError>;
+
+    fn next(&mut self) -> Option<Result<RomfsEntry, ArchiveError>> {
+        if self.current >= self.total {
+            return None;
+        }
+
+        let entry = &self.inner[self.current];
+        self.current += 1;
+        Some(Ok(entry.to_owned()))
+    }
+}
+
+/// romfs image, as made by genromfs.
+///
+/// Hard links are listed after every other entry so their targets exist when they get
+/// extracted.
+pub struct RomfsArchive {
+    data: Vec<u8>,
+    name: String,
+    entries: Vec<RomfsEntry>,
+    /// Devices extracted as placeholders.
+    device_manifest: Vec<DeviceNode>,
+}
+
+impl RomfsArchive {
+    /// The volume name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn entries(&mut self) -> Result<RomfsEntries, ArchiveError> {
+        Ok(RomfsEntries {
+            current: 0,
+            total: self.entries.len(),
+            inner: self.entries.clone(),
+        })
+    }
+
+    /// Devices that could not be created and were extracted as empty placeholder files.
+    pub fn device_manifest(&self) -> &[DeviceNode] {
+        &self.device_manifest
+    }
+
+    pub fn unpack_all(&mut self, to: impl AsRef<Path>) -> Result<(), ArchiveError> {
+        let to = to.as_ref();
+        if !to.exists() {
+            std::fs::create_dir_all(to)?;
+        }
+
+        let entries = self.entries()?;
+        let policy = ExtractPolicy::default();
+        let mut failures = vec![];
+        for entry in entries {
+            match entry {
+                Ok(entry) => {
+                    if let Err(e) = self.unpack_entry(&entry, to, &policy) {
+                        failures.push(e);
+                    }
+                }
+                Err(e) => {
+                    failures.push(e);
+                }
+            }
+        }
+
+        if !failures.is_empty() {
+            return Err(ArchiveError::ExtractFailed { sources: failures });
+        }
+
+        Ok(())
+    }
+
+    fn unpack_entry(
+        &mut self,
+        entry: &RomfsEntry,
+        to: &Path,
+        policy: &ExtractPolicy,
+    ) -> Result<(), ArchiveError> {
+        let Some(path) = policy.destination(to, &entry.path)? else {
+            return Ok(());
+        };
+        // romfs keeps no timestamps
+        if !policy.prepare(to, &path, entry.file_type(), 0)? {
+            return Ok(());
+        }
+        match &entry.hard_link {
+            Some(link) => {
+                let Some(target) = policy.link_target(to, link)? else {
+                    return Ok(());
+                };
+                unpack_node(ExtractNode::HardLink(&target), path, entry.mode)?;
+                Ok(())
+            }
+            None => self.unpack_file(entry, path),
+        }
+    }
+
+    pub fn unpack_file(
+        &mut self,
+        entry: &RomfsEntry,
+        to: impl AsRef<Path>,
+    ) -> Result<(), ArchiveError> {
+        let mode = entry.mode;
+        let placeholder = match (entry.mode & S_IFMT, entry.device) {
+            (S_IFREG, _) => {
+                let mut data = Cursor::new(self.read_file(entry)?);
+                unpack_node(ExtractNode::File(&mut data), to, mode)?
+            }
+            (S_IFDIR, _) => unpack_node(ExtractNode::Directory, to, mode)?,
+            (S_IFLNK, _) => {
+                let link = entry.link.clone().unwrap_or_default();
+                unpack_node(ExtractNode::Symlink(&link), to, mode)?
+            }
+            (S_IFCHR | S_IFBLK, Some(device)) => {
+                // Devices belong to root, romfs has no owners.
+                let device = DeviceNode::new(&entry.path, entry.file_type(), device, mode, (0, 0));
+                unpack_node(ExtractNode::Device(device), to, mode)?
+            }
+            (S_IFIFO, _) => unpack_node(ExtractNode::NamedPipe, to, mode)?,
+            _ => unpack_node(ExtractNode::Socket, to, mode)?,
+        };
+        if let Some(device) = placeholder {
+            self.device_manifest.push(device);
+        }
+        Ok(())
+    }
+
+    fn read_file(&self, entry: &RomfsEntry) -> Result<&[u8], ArchiveError> {
+        self.data
+            .get(entry.start..entry.start + entry.size)
+            .ok_or(ArchiveError::GenericsError("romfs image is truncated"))
+    }
+
+    pub fn create_with_reader(mut rdr: impl Read) -> Result<RomfsArchive, ArchiveError> {
+        let mut data = vec![];
+        rdr.read_to_end(&mut data)?;
+        if data.len() < ROMFH_SIZE || &data[..8] != ROMFS_MAGIC {
+            return Err(ArchiveError::GenericsError("not a romfs image"));
+        }
+
+        let full_size = be32(&data, 8) as usize;
+        let checked = &data[..full_size.min(ROMFS_CHECKSUM_SIZE).min(data.len()) & !3];
+        let sum = checked
+            .chunks_exact(4)
+            .fold(0u32, |sum, word| sum.wrapping_add(be32(word, 0)));
+        if sum != 0 {
+            return Err(ArchiveError::ChecksumMismatch(
+                "romfs superblock".to_string(),
+            ));
+        }
+
+        let (name, first) = read_name(&data, ROMFH_SIZE)?;
+        let mut archive = RomfsArchive {
+            name,
+            entries: vec![],
+            device_manifest: vec![],
+            data,
+        };
+        archive.entries = archive.build_tree(first)?;
+        Ok(archive)
+    }
+
+    pub fn create_with_path(path: impl AsRef<Path>) -> Result<RomfsArchive, ArchiveError> {
+        let rdr = std::io::BufReader::new(std::fs::File::open(path)?);
+        Self::create_with_reader(rdr)
+    }
+
+    /// Walks the directories from the root, depth first so parents come before children.
+    fn build_tree(&self, root: usize) -> Result<Vec<RomfsEntry>, ArchiveError> {
+        let mut entries = vec![];
+        let mut hard_links = vec![];
+        let mut paths: HashMap<usize, usize> = HashMap::new();
+        let mut visited = HashSet::from([root]);
+        let mut stack = vec![(root, PathBuf::new())];
+        while let Some((first, parent)) = stack.pop() {
+            let mut subdirs = vec![];
+            let mut header = first;
+            let mut seen = HashSet::new();
+            while header != 0 && seen.insert(header) {
+                let fields = self
+                    .data
+                    .get(header..header + ROMFH_SIZE)
+                    .ok_or(ArchiveError::GenericsError("romfs image is truncated"))?;
+                let next = be32(fields, 0);
+                let spec = be32(fields, 4);
+                let size = be32(fields, 8) as usize;
+                let (name, start) = read_name(&self.data, header + ROMFH_SIZE)?;
+                let current = header;
+                header = (next & !0xf) as usize;
+                if name == "." || name == ".." {
+                    continue;
+                }
+
+                let kind = next & ROMFH_TYPE;
+                let mut mode = ROMFS_MODES[kind as usize];
+                if next & ROMFH_EXEC != 0 {
+                    mode |= 0o111;
+                }
+                let mut entry = RomfsEntry {
+                    path: parent.join(&name),
+                    mode,
+                    start,
+                    size,
+                    link: None,
+                    hard_link: None,
+                    device: None,
+                };
+                match kind {
+                    ROMFH_HRD => {
+                        hard_links.push((entry, spec as usize));
+                        continue;
+                    }
+                    ROMFH_DIR => {
+                        // Directories need to stay searchable for their children to extract.
+                        entry.mode |= 0o111;
+                        entry.size = 0;
+                        if visited.insert(spec as usize) {
+                            subdirs.push((spec as usize, entry.path.clone()));
+                        }
+                    }
+                    ROMFH_SYM => {
+                        let target = self.read_file(&entry)?;
+                        let target = String::from_utf8_lossy(target).into_owned();
+                        entry.link = Some(PathBuf::from(target));
+                    }
+                    ROMFH_BLK | ROMFH_CHR => {
+                        entry.device = Some((spec >> 16, spec & 0xffff));
+                        entry.size = 0;
+                    }
+                    ROMFH_REG | ROMFH_SCK | ROMFH_FIF => {}
+                    _ => unreachable!(),
+                }
+
+                paths.insert(current, entries.len());
+                entries.push(entry);
+            }
+            stack.extend(subdirs.into_iter().rev());
+        }
+
+        // Hard link headers carry no data of their own, they take everything from the target.
+        for (mut entry, target) in hard_links {
+            let target = match paths.get(&target) {
+                Some(target) => &entries[*target],
+                None => {
+                    log::debug!("romfs hard link {} is dangling", entry.path.display());
+                    continue;
+                }
+            };
+            if target.mode & S_IFMT == S_IFDIR {
+                continue;
+            }
+            entry.mode = target.mode;
+            entry.start = target.start;
+            entry.size = target.size;
+            entry.hard_link = Some(target.path.clone());
+            entries.push(entry);
+        }
+
+        Ok(entries)
+    }
+}
+
+/// A NUL terminated name padded to 16 bytes, and the offset right after it.
+fn read_name(data: &[u8], offset: usize) -> Result<(String, usize), ArchiveError> {
+    let rest = data
+        .get(offset..)
+        .ok_or(ArchiveError::GenericsError("romfs image is truncated"))?;
+    let len = rest
+        .iter()
+        .position(|b| *b == 0)
+        .ok_or(ArchiveError::GenericsError("romfs image is truncated"))?;
+    let name = String::from_utf8_lossy(&rest[..len]).into_owned();
+    let end = (offset + len + 1).next_multiple_of(ROMFH_SIZE);
+    Ok((name, end))
+}
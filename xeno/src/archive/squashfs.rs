@@ -1,37 +1,138 @@
-use std::io::{Read, Seek, BufRead, BufReader};
-use std::path::{Path, PathBuf, Component};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
 
+use backhand::compression::{
+    CompressionAction, CompressionOptions, Compressor, DefaultCompressor, Lz4,
+};
+use backhand::kind::{Endian, Kind, Magic};
 use backhand::{
-    FilesystemReader, InnerNode, SquashfsBlockDevice, SquashfsCharacterDevice, 
-    SquashfsDir, SquashfsSymlink, Node, SquashfsFileReader, Squashfs
+    BackhandError, BasicFile, DataSize, FilesystemCompressor, FilesystemReader, FilesystemWriter,
+    Fragment, InnerNode, Node, NodeHeader, Squashfs, SquashfsBlockDevice, SquashfsCharacterDevice,
+    SquashfsFileReader, SquashfsSymlink, MAX_BLOCK_SIZE, MIN_BLOCK_SIZE,
 };
+use time::PrimitiveDateTime;
 
-
-#[cfg(unix)]
-use std::os::unix::fs::PermissionsExt;
-
+use crate::archive::extract::{
+    restore_metadata, restore_mtime, unpack_node, DeviceNode, ExtractNode, ExtractPolicy,
+    NodeMetadata,
+};
 use crate::archive::{Entry, FileType};
+use crate::utils::bytes::{get_u16_at, get_u32_at, get_u64_at};
 use crate::utils::error::ArchiveError;
 
+const SQUASHFS_MAGIC: &[u8; 4] = b"hsqs";
+const NOT_SET: u64 = u64::MAX;
+const NO_XATTR: u32 = u32::MAX;
+const NO_FRAGMENT: u32 = u32::MAX;
+const METADATA_UNCOMPRESSED: u16 = 0x8000;
+
+/// Full attribute names, e.g. `security.capability`, with their raw values.
+type Xattrs = Vec<(String, Vec<u8>)>;
+
 pub struct SquashFSArchive {
     inner: FilesystemReader,
+    /// The image backhand reads from, shared with the file readers.
+    image: SharedImage,
+    entries: Vec<SquashFSEntry>,
+    /// Devices extracted as placeholders.
+    device_manifest: Vec<DeviceNode>,
 }
 
 #[derive(Debug, Clone)]
-pub struct SquashFSEntry<'a> {
-    inner: &'a Node<SquashfsFileReader>,
+pub struct SquashFSEntry {
+    path: PathBuf,
+    /// Position of the node in the filesystem reader.
+    index: usize,
+    inode: u32,
+    /// Type of the node itself, a hard link shares the type of its first path.
+    kind: FileType,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    mtime: u32,
+    size: u64,
+    /// The target of a symlink.
+    link: Option<PathBuf>,
+    /// The first path of an inode with several directory entries.
+    hard_link: Option<PathBuf>,
+    device: Option<(u32, u32)>,
+    xattrs: Xattrs,
+}
+
+impl Entry for SquashFSEntry {
+    fn file_type(&self) -> FileType {
+        match self.hard_link {
+            Some(_) => FileType::HardLink,
+            None => self.kind,
+        }
+    }
+
+    fn hand_link(&self) -> Option<PathBuf> {
+        self.hard_link.clone()
+    }
+
+    fn path_name(&self) -> std::io::Result<PathBuf> {
+        Ok(self.path.clone())
+    }
+
+    fn gid(&self) -> std::io::Result<Option<u64>> {
+        Ok(Some(u64::from(self.gid)))
+    }
+
+    fn uid(&self) -> std::io::Result<Option<u64>> {
+        Ok(Some(u64::from(self.uid)))
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn sym_link(&self) -> Option<PathBuf> {
+        self.link.clone()
+    }
 }
 
-pub struct SquashFSEntries<'a> {
+impl SquashFSEntry {
+    pub fn inode(&self) -> u32 {
+        self.inode
+    }
+
+    pub fn unix_mode(&self) -> u32 {
+        self.mode & 0o7777
+    }
+
+    /// Major and minor number of a character or block device.
+    pub fn device(&self) -> Option<(u32, u32)> {
+        self.device
+    }
+
+    pub fn mtime(&self) -> Option<PrimitiveDateTime> {
+        let dt = time::OffsetDateTime::from_unix_timestamp(i64::from(self.mtime));
+        dt.ok()
+            .map(|dt| PrimitiveDateTime::new(dt.date(), dt.time()))
+    }
+
+    /// Extended attributes as full names, e.g. `security.capability`, with their raw values.
+    pub fn xattrs(&self) -> &[(String, Vec<u8>)] {
+        &self.xattrs
+    }
+}
+
+pub struct SquashFSEntries {
     current: usize,
-    inner: Vec<SquashFSEntry<'a>>,
+    total: usize,
+    inner: Vec<SquashFSEntry>,
 }
 
-impl<'a> Iterator for SquashFSEntries<'a> {
-    type Item = Result<SquashFSEntry<'a>, ArchiveError>;
+impl Iterator for SquashFSEntries {
+    type Item = Result<SquashFSEntry, ArchiveError>;
 
-    fn next(&mut self) -> Option<Result<SquashFSEntry<'a>, ArchiveError>> {
-        if self.current >= self.inner.len() {
+    fn next(&mut self) -> Option<Result<SquashFSEntry, ArchiveError>> {
+        if self.current >= self.total {
             return None;
         }
 
@@ -43,15 +144,10 @@ impl<'a> Iterator for SquashFSEntries<'a> {
 
 impl SquashFSArchive {
     pub fn entries(&mut self) -> Result<SquashFSEntries, ArchiveError> {
-        let mut entries = self
-            .inner
-            .files()
-            .map(|n| SquashFSEntry { inner: n })
-            .collect::<Vec<_>>();
-
         Ok(SquashFSEntries {
             current: 0,
-            inner: entries,
+            total: self.entries.len(),
+            inner: self.entries.clone(),
         })
     }
 
@@ -61,78 +157,31 @@ impl SquashFSArchive {
             std::fs::create_dir_all(to)?;
         }
 
-        let (mut buf_read, mut buf_decompress) = self.inner.alloc_read_buffers();
-
+        let policy = ExtractPolicy::default();
         let mut failures = vec![];
-        for node in self.inner.files() {
-            let path = &node.fullpath;
-            let path = path.strip_prefix(Component::RootDir).unwrap_or(path);
-            match &node.inner {
-                InnerNode::File(file) => {
-                    let filepath = Path::new(&to).join(path);
-
-                    let mut fd = std::fs::File::create(&filepath)?;
-                    let file_reader = self
-                        .inner
-                        .file(&file.basic);
-                    let mut reader = file_reader
-                        .reader(&mut buf_read, &mut buf_decompress);
-
-                    log::debug!("file {}", path.display());
-                    if let Err(e) = std::io::copy(&mut reader, &mut fd)  {
-                        let err = ArchiveError::Io(e);
-                        failures.push(err);
-                    }
-                }
-                InnerNode::Symlink(SquashfsSymlink { link, .. }) => {
-                    let path: PathBuf = path.iter().skip(1).collect();
-                    log::debug!("symlink {} {}", path.display(), link.display());
-                    let filepath = to.join(path);
-                    let link = to.join(&link);
-
-                    cfg_if::cfg_if! {
-                        if #[cfg(unix)] {
-                            if let Err(e) = std::os::unix::fs::symlink(&link, &filepath) {
-                                let err = ArchiveError::Io(e);
-                                failures.push(err);
-                            }
-                        } else {
-                            let result = if filepath.is_dir() {
-                                std::os::windows::fs::symlink_dir(&link, &filepath)
-                            } else {
-                                std::os::windows::fs::symlink_file(&link, &filepath)
-                            };
-
-                            if let Err(e) = result {
-                                let err = ArchiveError::Io(e);
-                                failures.push(err);
-                            }
-                        }
-                    }
-                }
-                InnerNode::Dir(SquashfsDir { .. }) => {
-                    let path = Path::new(&to).join(path);
-                    let _ = std::fs::create_dir_all(&path);
-                    cfg_if::cfg_if! {
-                        if #[cfg(unix)] {
-                            let perms = std::fs::Permissions::from_mode(u32::from(node.header.permissions));
-                            if let Err(e) = std::fs::set_permissions(&path, perms) {
-                                let err = ArchiveError::Io(e);
-                                failures.push(err);
-                            }
-                        }
-                    }
-                }
-                InnerNode::CharacterDevice(SquashfsCharacterDevice {
-                    device_number: _,
-                }) => {
-                    log::info!("[-] character device not supported");
-                }
-                InnerNode::BlockDevice(SquashfsBlockDevice {
-                    device_number: _,
-                }) => {
-                    log::info!("[-] block device not supported");
-                }
+        let mut devices = vec![];
+        for entry in &self.entries {
+            match self.unpack_below(&policy, entry, to) {
+                Ok(Some(device)) => devices.push(device),
+                Ok(None) => {}
+                Err(e) => failures.push(e),
+            }
+        }
+        self.device_manifest.extend(devices);
+
+        // creating the children touched the directory times
+        let dirs = self
+            .entries
+            .iter()
+            .filter(|e| e.kind == FileType::Directory);
+        for entry in dirs.rev() {
+            let result = match policy.destination(to, &entry.path) {
+                Ok(Some(path)) => restore_mtime(&path, i64::from(entry.mtime)),
+                Ok(None) => Ok(()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                failures.push(e);
             }
         }
 
@@ -147,100 +196,1364 @@ impl SquashFSArchive {
         entry: &SquashFSEntry,
         to: impl AsRef<Path>,
     ) -> Result<(), ArchiveError> {
-        let to = to.as_ref();
-        let path = &entry.inner.fullpath;
-        let path = path.strip_prefix(Component::RootDir).unwrap_or(path);
-        
-        // alloc required space for file data readers
-        let (mut buf_read, mut buf_decompress) = self.inner.alloc_read_buffers();
-
-        match &entry.inner.inner {
-            InnerNode::File(file) => {
-                let filepath = Path::new(&to).join(path);
-                log::debug!("file {}", path.display());
-
-                // write to file
-                let mut fd = std::fs::File::create(&filepath)?;
-                let file_reader = self
-                    .inner
-                    .file(&file.basic);
-                let mut reader = file_reader
-                    .reader(&mut buf_read, &mut buf_decompress);
-                if let Err(e) = std::io::copy(&mut reader, &mut fd) {
-                    let err = ArchiveError::Io(e);
-                    return Err(err);
-                }
+        let Some(path) = self.prepare(&ExtractPolicy::default(), entry, to.as_ref())? else {
+            return Ok(());
+        };
+        if let Some(device) = self.unpack_entry(entry, &path)? {
+            self.device_manifest.push(device);
+        }
+        Ok(())
+    }
+
+    /// Extracts `entry` below `to`, linking it to its first path if it is a hard link.
+    fn unpack_below(
+        &self,
+        policy: &ExtractPolicy,
+        entry: &SquashFSEntry,
+        to: &Path,
+    ) -> Result<Option<DeviceNode>, ArchiveError> {
+        let Some(path) = self.prepare(policy, entry, to)? else {
+            return Ok(None);
+        };
+        let Some(target) = &entry.hard_link else {
+            return self.unpack_entry(entry, &path);
+        };
+        match policy.link_target(to, target)? {
+            Some(target) => unpack_node(ExtractNode::HardLink(&target), path, 0),
+            None => Ok(None),
+        }
+    }
+
+    /// Where `entry` lands below `to` once the way is clear, or `None` when it is skipped.
+    fn prepare(
+        &self,
+        policy: &ExtractPolicy,
+        entry: &SquashFSEntry,
+        to: &Path,
+    ) -> Result<Option<PathBuf>, ArchiveError> {
+        let Some(path) = policy.destination(to, &entry.path)? else {
+            return Ok(None);
+        };
+        let kind = match entry.hard_link {
+            Some(_) => FileType::HardLink,
+            None => entry.kind,
+        };
+        match policy.prepare(to, &path, kind, i64::from(entry.mtime))? {
+            true => Ok(Some(path)),
+            false => Ok(None),
+        }
+    }
+
+    /// Streams the contents of a regular file, or of the inode behind a hard link, a block at
+    /// a time.
+    pub fn read_entry(&self, entry: &SquashFSEntry) -> Result<impl Read + '_, ArchiveError> {
+        let node = self
+            .inner
+            .root
+            .nodes
+            .get(entry.index)
+            .filter(|node| relative_path(node) == entry.path)
+            .ok_or(ArchiveError::GenericsError(
+                "entry does not belong to this squashfs image",
+            ))?;
+        match &node.inner {
+            InnerNode::File(file) => Ok(FileReader::new(self, &file.basic)),
+            _ => Err(ArchiveError::GenericsError(
+                "only regular squashfs files have contents",
+            )),
+        }
+    }
+
+    /// Devices that could not be created and were extracted as empty placeholder files.
+    pub fn device_manifest(&self) -> &[DeviceNode] {
+        &self.device_manifest
+    }
+
+    /// Extracts `entry` to `filepath` with its metadata, returning the device if it became a
+    /// placeholder.
+    fn unpack_entry(
+        &self,
+        entry: &SquashFSEntry,
+        filepath: &Path,
+    ) -> Result<Option<DeviceNode>, ArchiveError> {
+        let mode = entry.mode;
+        let device = match (entry.kind, &entry.link, entry.device) {
+            (FileType::RegularFile, _, _) => {
+                let mut reader = self.read_entry(entry)?;
+                unpack_node(ExtractNode::File(&mut reader), filepath, mode)?
             }
-            InnerNode::Symlink(SquashfsSymlink { link, .. }) => {
-                let path: PathBuf = path.iter().skip(1).collect();
-                log::debug!("symlink {} {}", path.display(), link.display());
-                let filepath = to.join(path);
-                let link = to.join(&link);
-
-                cfg_if::cfg_if! {
-                    if #[cfg(unix)] {
-                        if let Err(e) = std::os::unix::fs::symlink(&link, &filepath) {
-                            let err = ArchiveError::Io(e);
-                            return Err(err);
-                        }
-                    } else {
-                        let result = if filepath.is_dir() {
-                            std::os::windows::fs::symlink_dir(&link, &filepath)
-                        } else {
-                            std::os::windows::fs::symlink_file(&link, &filepath)
-                        };
-
-                        if let Err(e) = result {
-                            let err = ArchiveError::Io(e);
-                            return Err(err);
-                        }
+            (FileType::SymbolicLink, Some(link), _) => {
+                unpack_node(ExtractNode::Symlink(link), filepath, mode)?
+            }
+            (FileType::Directory, _, _) => unpack_node(ExtractNode::Directory, filepath, mode)?,
+            (kind, _, Some(device)) => {
+                let owner = (entry.uid, entry.gid);
+                let device = DeviceNode::new(&entry.path, kind, device, mode, owner);
+                unpack_node(ExtractNode::Device(device), filepath, mode)?
+            }
+            _ => return Err(ArchiveError::GenericsError("unexpected squashfs node")),
+        };
+
+        let meta = NodeMetadata {
+            mode,
+            uid: entry.uid,
+            gid: entry.gid,
+            mtime: i64::from(entry.mtime),
+            xattrs: &entry.xattrs,
+        };
+        restore_metadata(filepath, &meta)?;
+        Ok(device)
+    }
+
+    pub fn create_with_reader(
+        rdr: impl Read + Seek + 'static,
+    ) -> Result<SquashFSArchive, ArchiveError> {
+        Self::create_with_reader_at_offset(rdr, 0)
+    }
+
+    /// Opens the image whose superblock starts `offset` bytes into `rdr`, e.g. a root
+    /// filesystem inside a firmware blob located with [`SquashFSArchive::find`].
+    pub fn create_with_reader_at_offset(
+        mut rdr: impl Read + Seek + 'static,
+        offset: u64,
+    ) -> Result<SquashFSArchive, ArchiveError> {
+        let probe = Self::probe(&mut rdr, offset)?
+            .ok_or(ArchiveError::GenericsError("no squashfs superblock found"))?;
+        let (major, minor) = probe.version;
+        if major != 4 {
+            return Err(ArchiveError::GenericsError2(format!(
+                "squashfs {major}.{minor} uses the unsupported 3.x layout, only 4.x can be read"
+            )));
+        }
+
+        let be = probe.big_endian;
+        let (magic, endian) = match be {
+            true => (Magic::Big, Endian::Big),
+            false => (Magic::Little, Endian::Little),
+        };
+        let image = ImageReader::new(rdr, offset, be)?;
+        let image = SharedImage(Rc::new(RefCell::new(image)));
+        let mut rdr = BufReader::new(image.clone());
+        let raw = RawTables::read(&mut rdr, be)?;
+        let metadata_endian = match raw.metadata_big_endian {
+            true => Endian::Big,
+            false => Endian::Little,
+        };
+
+        let kind = Kind::new(codec(Compressor::None))
+            .with_magic(magic)
+            .with_type_endian(endian)
+            .with_data_endian(metadata_endian)
+            .with_version(major, minor);
+        let squashfs = Squashfs::from_reader_with_offset_and_kind(rdr, 0, kind)
+            .map_err(ArchiveError::SquashfsError)?;
+        let mut tables = InodeTables::scan(&squashfs, &raw)?;
+        let inner = squashfs
+            .into_filesystem_reader()
+            .map_err(ArchiveError::SquashfsError)?;
+
+        let entries = inner
+            .files()
+            .enumerate()
+            .map(|(index, node)| tables.entry(&inner, index, node))
+            .collect();
+        let archive = SquashFSArchive {
+            inner,
+            image,
+            entries,
+            device_manifest: vec![],
+        };
+
+        Ok(archive)
+    }
+
+    pub fn create_with_path(path: impl AsRef<Path>) -> Result<SquashFSArchive, ArchiveError> {
+        let reader = std::fs::File::open(path)?;
+        Self::create_with_reader(reader)
+    }
+
+    /// Reads the superblock at `offset`, returning `None` unless it carries one of the
+    /// known magics, version 3.x or 4.x and plausible sizes for its layout. 3.x images are only
+    /// located, [`SquashFSArchive::create_with_reader_at_offset`] refuses them.
+    pub fn probe<R: Read + Seek>(
+        rdr: &mut R,
+        offset: u64,
+    ) -> Result<Option<SquashFSProbe>, ArchiveError> {
+        let mut superblock = vec![];
+        rdr.seek(SeekFrom::Start(offset))?;
+        rdr.by_ref().take(96).read_to_end(&mut superblock)?;
+        Ok(SquashFSProbe::parse(&superblock, offset))
+    }
+
+    /// Scans `rdr` for every plausible SquashFS 3.x or 4.x superblock, in offset order.
+    pub fn find<R: Read + Seek>(rdr: &mut R) -> Result<Vec<SquashFSProbe>, ArchiveError> {
+        const CHUNK: usize = 1 << 16;
+
+        let len = rdr.seek(SeekFrom::End(0))?;
+        let mut found = vec![];
+        let mut chunk = vec![0; CHUNK + 3];
+        let mut start = 0;
+        while start < len {
+            // overlap by three bytes so magics across a chunk boundary are seen
+            let size = (len - start).min(chunk.len() as u64) as usize;
+            rdr.seek(SeekFrom::Start(start))?;
+            rdr.read_exact(&mut chunk[..size])?;
+            for (index, window) in chunk[..size].windows(4).enumerate() {
+                if MAGICS.iter().any(|(magic, _)| window == *magic) {
+                    let offset = start + index as u64;
+                    if let Some(probe) = Self::probe(rdr, offset)? {
+                        found.push(probe);
                     }
                 }
             }
-            InnerNode::Dir(SquashfsDir { .. }) => {
-                let path = Path::new(&to).join(path);
-                log::debug!("path {}", path.display());
-                let _ = std::fs::create_dir_all(&path);
-                cfg_if::cfg_if! {
-                    if #[cfg(unix)] {
-                        let perms = std::fs::Permissions::from_mode(u32::from(entry.inner.header.permissions));
-                        if let Err(e) = std::fs::set_permissions(&path, perms) {
-                            let err = ArchiveError::Io(e);
-                            return Err(err);
+            start += CHUNK as u64;
+        }
+
+        Ok(found)
+    }
+}
+
+/// Magics seen in the wild and the byte order each usually announces. Vendors mostly
+/// renamed the magic of an otherwise standard image, `sqlz` and `qshs` mark LZMA ones.
+const MAGICS: [(&[u8; 4], bool); 7] = [
+    (SQUASHFS_MAGIC, false),
+    (b"sqsh", true),
+    (b"shsq", false),
+    (b"qshs", true),
+    (b"sqlz", true),
+    (b"hsqt", false),
+    (b"tqsh", true),
+];
+
+/// A superblock found by [`SquashFSArchive::probe`] or [`SquashFSArchive::find`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SquashFSProbe {
+    offset: u64,
+    magic: [u8; 4],
+    big_endian: bool,
+    version: (u16, u16),
+    compressor: Option<u16>,
+    bytes_used: u64,
+}
+
+impl SquashFSProbe {
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn magic(&self) -> &[u8; 4] {
+        &self.magic
+    }
+
+    pub fn is_big_endian(&self) -> bool {
+        self.big_endian
+    }
+
+    pub fn version(&self) -> (u16, u16) {
+        self.version
+    }
+
+    /// The superblock's compressor id (1 gzip, 2 lzma, 3 lzo, 4 xz, 5 lz4, 6 zstd), only
+    /// recorded from 4.0 on.
+    pub fn compressor(&self) -> Option<u16> {
+        self.compressor
+    }
+
+    /// Size of the image.
+    pub fn bytes_used(&self) -> u64 {
+        self.bytes_used
+    }
+
+    fn parse(superblock: &[u8], offset: u64) -> Option<SquashFSProbe> {
+        let magic: [u8; 4] = superblock.get(..4)?.try_into().ok()?;
+        let (_, announced) = MAGICS.iter().find(|(known, _)| **known == magic)?;
+        // some vendors swapped the byte order without changing the magic
+        let big_endian = [*announced, !*announced].into_iter().find(|be| {
+            get_u16_at(superblock, 28, *be).is_some_and(|major| (3..=4).contains(&major))
+        })?;
+        let be = big_endian;
+        let version = (
+            get_u16_at(superblock, 28, be)?,
+            get_u16_at(superblock, 30, be)?,
+        );
+
+        let (block_size, block_log, compressor, bytes_used) = match version.0 {
+            4 => (
+                get_u32_at(superblock, 12, be)?,
+                get_u16_at(superblock, 22, be)?,
+                Some(get_u16_at(superblock, 20, be)?),
+                get_u64_at(superblock, 40, be)?,
+            ),
+            // 3.x has no compressor field, mainline images are all gzip
+            _ => (
+                get_u32_at(superblock, 51, be)?,
+                get_u16_at(superblock, 34, be)?,
+                None,
+                get_u64_at(superblock, 63, be)?,
+            ),
+        };
+        let plausible = match compressor {
+            Some(id) => version.1 <= 1 && (1..=6).contains(&id),
+            None => version.1 <= 1 && block_log <= 16,
+        };
+        if !plausible || block_log >= 32 || block_size != 1 << block_log {
+            return None;
+        }
+
+        Some(SquashFSProbe {
+            offset,
+            magic,
+            big_endian,
+            version,
+            compressor,
+            bytes_used,
+        })
+    }
+}
+
+/// Any image source, boxed behind [`SharedImage`].
+trait ImageSource: Read + Seek {}
+
+impl<T: Read + Seek> ImageSource for T {}
+
+/// The image, shared by backhand and the [`FileReader`]s. Every user seeks before it reads.
+#[derive(Clone)]
+struct SharedImage(Rc<RefCell<dyn ImageSource>>);
+
+impl SharedImage {
+    fn read_at(&self, offset: u64, size: u32) -> std::io::Result<Vec<u8>> {
+        let mut image = self.0.borrow_mut();
+        let mut bytes = vec![0; size as usize];
+        image.seek(SeekFrom::Start(offset))?;
+        image.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+impl Read for SharedImage {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().read(buf)
+    }
+}
+
+impl Seek for SharedImage {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.0.borrow_mut().seek(pos)
+    }
+}
+
+/// Contents of a regular file, decompressed one data block at a time.
+struct FileReader<'a> {
+    archive: &'a SquashFSArchive,
+    blocks: std::slice::Iter<'a, DataSize>,
+    /// The fragment holding the tail of the file, with the tail's offset in it.
+    fragment: Option<(&'a Fragment, usize)>,
+    /// Image offset of the next data block.
+    position: u64,
+    remaining: u64,
+    block: Vec<u8>,
+    consumed: usize,
+}
+
+impl<'a> FileReader<'a> {
+    fn new(archive: &'a SquashFSArchive, file: &'a BasicFile) -> FileReader<'a> {
+        let fragment = archive
+            .inner
+            .fragments
+            .as_ref()
+            .and_then(|fragments| fragments.get(file.frag_index as usize))
+            .filter(|_| file.frag_index != NO_FRAGMENT);
+        FileReader {
+            archive,
+            blocks: file.block_sizes.iter(),
+            fragment: fragment.map(|fragment| (fragment, file.block_offset as usize)),
+            position: u64::from(file.blocks_start),
+            remaining: u64::from(file.file_size),
+            block: vec![],
+            consumed: 0,
+        }
+    }
+
+    /// Decompresses the next block or the fragment into `block`.
+    fn next_block(&mut self) -> Result<(), BackhandError> {
+        let fs = &self.archive.inner;
+        let block_size = u64::from(fs.block_size);
+        let expected = self.remaining.min(block_size) as usize;
+        let (raw, uncompressed, skip) = match (self.blocks.next(), self.fragment) {
+            // a sparse block, all zeros
+            (Some(size), _) if size.size() == 0 => (vec![0; expected], true, 0),
+            (Some(size), _) => {
+                let raw = self.archive.image.read_at(self.position, size.size())?;
+                self.position += u64::from(size.size());
+                (raw, size.uncompressed(), 0)
+            }
+            (None, Some((fragment, offset))) => {
+                self.fragment = None;
+                let size = fragment.size.size();
+                let raw = self.archive.image.read_at(fragment.start, size)?;
+                (raw, fragment.size.uncompressed(), offset)
+            }
+            (None, None) => (vec![], true, 0),
+        };
+
+        self.block.clear();
+        self.consumed = 0;
+        if uncompressed {
+            self.block.extend_from_slice(&raw);
+        } else {
+            self.block.reserve(block_size as usize);
+            codec(fs.compressor).decompress(&raw, &mut self.block, fs.compressor)?;
+        }
+        self.block.drain(..skip.min(self.block.len()));
+        self.block.truncate(expected);
+        Ok(())
+    }
+}
+
+impl Read for FileReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.remaining == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.consumed == self.block.len() {
+            self.next_block()?;
+            if self.block.is_empty() {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+
+        let size = buf.len().min(self.block.len() - self.consumed);
+        buf[..size].copy_from_slice(&self.block[self.consumed..self.consumed + size]);
+        self.consumed += size;
+        self.remaining -= size as u64;
+        Ok(size)
+    }
+}
+
+/// The image `offset` bytes into `inner`, with its magic replaced by the standard one for
+/// its byte order so backhand accepts vendor magics.
+struct ImageReader<R> {
+    inner: R,
+    offset: u64,
+    magic: [u8; 4],
+}
+
+impl<R: Seek> ImageReader<R> {
+    fn new(mut inner: R, offset: u64, big_endian: bool) -> std::io::Result<ImageReader<R>> {
+        inner.seek(SeekFrom::Start(offset))?;
+        let magic = match big_endian {
+            true => *b"sqsh",
+            false => *SQUASHFS_MAGIC,
+        };
+        Ok(ImageReader {
+            inner,
+            offset,
+            magic,
+        })
+    }
+}
+
+impl<R: Read + Seek> Read for ImageReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let position = self.stream_position()?;
+        let read = self.inner.read(buf)?;
+        if let Some(magic) = self.magic.get(position as usize..) {
+            for (byte, magic) in buf[..read].iter_mut().zip(magic) {
+                *byte = *magic;
+            }
+        }
+        Ok(read)
+    }
+}
+
+impl<R: Seek> Seek for ImageReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(start) => SeekFrom::Start(self.offset + start),
+            pos => pos,
+        };
+        let position = self.inner.seek(pos)?;
+        position.checked_sub(self.offset).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "seek before the start of the squashfs image",
+            )
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SquashFSCompression {
+    Gzip,
+    Xz,
+    Lzo,
+    Lz4,
+    Zstd,
+}
+
+/// Settings for [`SquashFSWriter`], the defaults match `mksquashfs`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SquashFSWriteOptions {
+    pub compression: SquashFSCompression,
+    /// A power of two from 4 KiB to 1 MiB.
+    pub block_size: u32,
+    /// Owner of every node, replacing the one from the source.
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Modification time of every node, replacing the one from the source. `Some(0)` is what
+    /// [`SquashFSWriteOptions::reproducible`] picks.
+    ///
+    /// The superblock gets it as well, or the epoch without it, so the image depends on nothing
+    /// but its input. Nodes are always written sorted by path. The root directory is always
+    /// dated to the epoch, backhand 0.12 has no way to set its time.
+    pub mtime: Option<u32>,
+}
+
+impl SquashFSWriteOptions {
+    /// The defaults with every node dated to the epoch, so the same tree always builds the same
+    /// image no matter when its files were touched.
+    pub fn reproducible() -> Self {
+        SquashFSWriteOptions {
+            mtime: Some(0),
+            ..Default::default()
+        }
+    }
+}
+
+impl Default for SquashFSWriteOptions {
+    fn default() -> Self {
+        SquashFSWriteOptions {
+            compression: SquashFSCompression::Gzip,
+            block_size: 128 * 1024,
+            uid: None,
+            gid: None,
+            mtime: None,
+        }
+    }
+}
+
+/// Builds a SquashFS 4 image from a host directory or the entries of another archive.
+///
+/// Nodes are collected first and the image is written by [`SquashFSWriter::finish`]. Files are
+/// only opened while their data is written.
+pub struct SquashFSWriter<'a, W: Write + Seek> {
+    inner: FilesystemWriter<'a>,
+    writer: W,
+    options: SquashFSWriteOptions,
+    /// Mirror of the id table, backhand takes the root owner as index into it.
+    ids: Vec<u32>,
+    root: (u16, u32, u32),
+}
+
+impl<'a, W> SquashFSWriter<'a, W>
+where
+    W: Write + Seek,
+{
+    pub fn create_with_writer(
+        writer: W,
+        options: SquashFSWriteOptions,
+    ) -> Result<SquashFSWriter<'a, W>, ArchiveError> {
+        let block_size = options.block_size;
+        if !block_size.is_power_of_two() || !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size)
+        {
+            return Err(ArchiveError::GenericsError(
+                "squashfs block size must be a power of two from 4 KiB to 1 MiB",
+            ));
+        }
+
+        let (compressor, compression_options) = match options.compression {
+            SquashFSCompression::Gzip => (Compressor::Gzip, None),
+            SquashFSCompression::Xz => (Compressor::Xz, None),
+            SquashFSCompression::Lzo => (Compressor::Lzo, None),
+            // the kernel refuses lz4 images without options
+            SquashFSCompression::Lz4 => (
+                Compressor::Lz4,
+                Some(CompressionOptions::Lz4(Lz4 {
+                    version: 1,
+                    flags: 0,
+                })),
+            ),
+            SquashFSCompression::Zstd => (Compressor::Zstd, None),
+        };
+        let compressor = FilesystemCompressor::new(compressor, compression_options)
+            .map_err(ArchiveError::SquashfsError)?;
+
+        let mut inner = FilesystemWriter::default();
+        inner.set_kind(Kind::new(codec(compressor_id(options.compression))));
+        inner.set_compressor(compressor);
+        inner.set_block_size(block_size);
+        inner.set_time(options.mtime.unwrap_or(0));
+        inner.set_only_root_id();
+
+        let root = (0o755, options.uid.unwrap_or(0), options.gid.unwrap_or(0));
+        Ok(SquashFSWriter {
+            inner,
+            writer,
+            options,
+            ids: vec![0],
+            root,
+        })
+    }
+
+    /// Adds everything below the host directory `dir`, which becomes the root of the image.
+    ///
+    /// Named pipes and sockets are skipped, backhand cannot write them.
+    pub fn push_dir_tree(&mut self, dir: impl AsRef<Path>) -> Result<(), ArchiveError> {
+        let dir = dir.as_ref();
+        let (mode, uid, gid, _) = host_metadata(&std::fs::metadata(dir)?);
+        self.root = (
+            mode as u16,
+            self.options.uid.unwrap_or(uid),
+            self.options.gid.unwrap_or(gid),
+        );
+
+        let mut stack = vec![PathBuf::new()];
+        while let Some(parent) = stack.pop() {
+            let mut children =
+                std::fs::read_dir(dir.join(&parent))?.collect::<Result<Vec<_>, _>>()?;
+            children.sort_by_key(|child| child.file_name());
+            // popped in reverse, so the subdirectories are walked in order
+            for child in children.iter().rev() {
+                let path = parent.join(child.file_name());
+                let host_path = child.path();
+                let metadata = std::fs::symlink_metadata(&host_path)?;
+                let (mode, uid, gid, mtime) = host_metadata(&metadata);
+                let header = self.header(mode, uid, gid, mtime)?;
+                let file_type = metadata.file_type();
+                let result = if file_type.is_dir() {
+                    stack.push(path.clone());
+                    self.inner.push_dir(&path, header)
+                } else if file_type.is_symlink() {
+                    let link = std::fs::read_link(&host_path)?;
+                    self.inner.push_symlink(link, &path, header)
+                } else if file_type.is_file() {
+                    let file = LazyFile::Closed(host_path);
+                    self.inner.push_file(file, &path, header)
+                } else {
+                    match host_device(&metadata) {
+                        Some((FileType::BlockDevice, dev)) => {
+                            self.inner.push_block_device(dev, &path, header)
+                        }
+                        Some((_, dev)) => self.inner.push_char_device(dev, &path, header),
+                        None => {
+                            log::info!("[-] {} is a special file, skipped", host_path.display());
+                            continue;
                         }
                     }
+                };
+                result.map_err(ArchiveError::SquashfsError)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds `entry` of another archive, with the permission bits `mode`, the modification time
+    /// `mtime` in seconds since the epoch and the file data read from `contents`.
+    ///
+    /// Parents have to come before their children, as every backend lists them. Hard links are
+    /// stored as copies. Devices carry no numbers in [`Entry`], add them with
+    /// [`SquashFSWriter::push_device`].
+    pub fn push_entry(
+        &mut self,
+        entry: &impl Entry,
+        mode: u32,
+        mtime: u32,
+        contents: impl Read + 'a,
+    ) -> Result<(), ArchiveError> {
+        let path = entry.path_name()?;
+        let uid = entry.uid()?.unwrap_or(0) as u32;
+        let gid = entry.gid()?.unwrap_or(0) as u32;
+        if path.as_os_str().is_empty() {
+            self.root = (
+                (mode & 0o7777) as u16,
+                self.options.uid.unwrap_or(uid),
+                self.options.gid.unwrap_or(gid),
+            );
+            return Ok(());
+        }
+
+        let header = self.header(mode, uid, gid, mtime)?;
+        match entry.file_type() {
+            FileType::Directory => self.inner.push_dir(&path, header),
+            FileType::RegularFile | FileType::HardLink => {
+                self.inner.push_file(contents, &path, header)
+            }
+            FileType::SymbolicLink => {
+                let link = entry.sym_link().unwrap_or_default();
+                self.inner.push_symlink(link, &path, header)
+            }
+            _ => {
+                log::info!("[-] {} is a special file, skipped", path.display());
+                return Ok(());
+            }
+        }
+        .map_err(ArchiveError::SquashfsError)
+    }
+
+    /// Adds a device, e.g. one from the manifest of an extraction without privileges. The
+    /// manifest keeps no times, the device is dated to the epoch unless the options set one.
+    pub fn push_device(&mut self, device: &DeviceNode) -> Result<(), ArchiveError> {
+        let header = self.header(device.mode(), device.uid(), device.gid(), 0)?;
+        let dev = encode_device(device.major(), device.minor());
+        let path = device.path();
+        match device.file_type() {
+            FileType::BlockDevice => self.inner.push_block_device(dev, path, header),
+            _ => self.inner.push_char_device(dev, path, header),
+        }
+        .map_err(ArchiveError::SquashfsError)
+    }
+
+    /// Writes the image and returns the writer.
+    pub fn finish(mut self) -> Result<W, ArchiveError> {
+        let (mode, uid, gid) = self.root;
+        let index = |id: u32| match self.ids.iter().position(|known| *known == id) {
+            Some(index) => index as u16,
+            None => {
+                log::info!("[-] root owner {} is used by no other node, root taken", id);
+                0
+            }
+        };
+        let (uid, gid) = (index(uid), index(gid));
+        self.inner.set_root_mode(mode);
+        self.inner.set_root_uid(uid);
+        self.inner.set_root_gid(gid);
+        self.inner
+            .write(&mut self.writer)
+            .map_err(ArchiveError::SquashfsError)?;
+        Ok(self.writer)
+    }
+
+    fn header(
+        &mut self,
+        mode: u32,
+        uid: u32,
+        gid: u32,
+        mtime: u32,
+    ) -> Result<NodeHeader, ArchiveError> {
+        let uid = self.options.uid.unwrap_or(uid);
+        let gid = self.options.gid.unwrap_or(gid);
+        let id = |id: u32| {
+            u16::try_from(id).map_err(|_| {
+                ArchiveError::GenericsError("squashfs writer only supports ids up to 65535")
+            })
+        };
+        let header = NodeHeader::new(
+            (mode & 0o7777) as u16,
+            id(uid)?,
+            id(gid)?,
+            self.options.mtime.unwrap_or(mtime),
+        );
+        // the order backhand adds them to its id table
+        for id in [gid, uid] {
+            if !self.ids.contains(&id) {
+                self.ids.push(id);
+            }
+        }
+        Ok(header)
+    }
+}
+
+impl<'a> SquashFSWriter<'a, File> {
+    pub fn create_with_path(
+        path: impl AsRef<Path>,
+        options: SquashFSWriteOptions,
+    ) -> Result<Self, ArchiveError> {
+        let writer = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Self::create_with_writer(writer, options)
+    }
+}
+
+/// A host file that is opened on the first read and closed at its end.
+enum LazyFile {
+    Closed(PathBuf),
+    Open(File),
+    Done,
+}
+
+impl Read for LazyFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = match self {
+            LazyFile::Closed(path) => {
+                let mut file = File::open(path)?;
+                let read = file.read(buf)?;
+                *self = LazyFile::Open(file);
+                read
+            }
+            LazyFile::Open(file) => file.read(buf)?,
+            LazyFile::Done => return Ok(0),
+        };
+        if read == 0 && !buf.is_empty() {
+            *self = LazyFile::Done;
+        }
+        Ok(read)
+    }
+}
+
+/// Permission bits, owner and modification time of a host file.
+fn host_metadata(metadata: &std::fs::Metadata) -> (u32, u32, u32, u32) {
+    cfg_if::cfg_if! {
+        if #[cfg(unix)] {
+            use std::os::unix::fs::MetadataExt;
+
+            let mtime = u32::try_from(metadata.mtime().max(0)).unwrap_or(u32::MAX);
+            (metadata.mode() & 0o7777, metadata.uid(), metadata.gid(), mtime)
+        } else {
+            let mode = if metadata.is_dir() { 0o755 } else { 0o644 };
+            let mtime = metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+                .map_or(0, |time| u32::try_from(time.as_secs()).unwrap_or(u32::MAX));
+            (mode, 0, 0, mtime)
+        }
+    }
+}
+
+/// Type and SquashFS device number of a host device node.
+fn host_device(metadata: &std::fs::Metadata) -> Option<(FileType, u32)> {
+    cfg_if::cfg_if! {
+        if #[cfg(unix)] {
+            use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+            let file_type = metadata.file_type();
+            let file_type = if file_type.is_block_device() {
+                FileType::BlockDevice
+            } else if file_type.is_char_device() {
+                FileType::CharacterDevice
+            } else {
+                return None;
+            };
+            let rdev = metadata.rdev() as libc::dev_t;
+            let (major, minor) = (libc::major(rdev) as u32, libc::minor(rdev) as u32);
+            Some((file_type, encode_device(major, minor)))
+        } else {
+            let _ = metadata;
+            None
+        }
+    }
+}
+
+fn compressor_id(compression: SquashFSCompression) -> Compressor {
+    match compression {
+        SquashFSCompression::Gzip => Compressor::Gzip,
+        SquashFSCompression::Xz => Compressor::Xz,
+        SquashFSCompression::Lzo => Compressor::Lzo,
+        SquashFSCompression::Lz4 => Compressor::Lz4,
+        SquashFSCompression::Zstd => Compressor::Zstd,
+    }
+}
+
+fn relative_path(node: &Node<SquashfsFileReader>) -> &Path {
+    let path = &node.fullpath;
+    path.strip_prefix(Component::RootDir).unwrap_or(path)
+}
+
+/// Splits a device number in the kernel's `new_encode_dev` format.
+fn decode_device(dev: u32) -> (u32, u32) {
+    ((dev & 0xfff00) >> 8, (dev & 0xff) | ((dev >> 12) & 0xfff00))
+}
+
+fn encode_device(major: u32, minor: u32) -> u32 {
+    (minor & 0xff) | ((major & 0xfff) << 8) | ((minor & !0xff) << 12)
+}
+
+/// The metadata tables behind the data blocks, as stored in the image.
+///
+/// backhand drops inode numbers and xattrs when it builds the `FilesystemReader`, so the inode,
+/// directory and xattr tables are walked here once more.
+struct RawTables {
+    /// Image offset of `bytes`.
+    start: u64,
+    bytes: Vec<u8>,
+    big_endian: bool,
+    /// Byte order of the metadata block headers, little endian on AVM's big endian images.
+    metadata_big_endian: bool,
+    root_inode: u64,
+    inode_table: u64,
+    dir_table: u64,
+    xattr_table: u64,
+}
+
+impl RawTables {
+    /// Reads the tables of a SquashFS 4 image and rewinds `rdr`.
+    fn read<R: Read + Seek>(rdr: &mut R, big_endian: bool) -> Result<RawTables, ArchiveError> {
+        let mut superblock = [0; 96];
+        rdr.seek(SeekFrom::Start(0))?;
+        rdr.read_exact(&mut superblock)?;
+
+        let field = |offset| get_u64_at(&superblock, offset, big_endian).unwrap_or(NOT_SET);
+        let bytes_used = field(40);
+        let xattr_table = field(56);
+        let inode_table = field(64);
+        let mut start = inode_table;
+        if xattr_table != NOT_SET {
+            let mut kv_start = [0; 8];
+            rdr.seek(SeekFrom::Start(xattr_table))?;
+            rdr.read_exact(&mut kv_start)?;
+            start = start.min(get_u64_at(&kv_start, 0, big_endian).unwrap_or(NOT_SET));
+        }
+
+        let mut bytes = vec![];
+        rdr.seek(SeekFrom::Start(start))?;
+        rdr.by_ref()
+            .take(bytes_used.saturating_sub(start))
+            .read_to_end(&mut bytes)?;
+        rdr.seek(SeekFrom::Start(0))?;
+
+        let mut tables = RawTables {
+            start,
+            bytes,
+            big_endian,
+            metadata_big_endian: big_endian,
+            root_inode: field(32),
+            inode_table,
+            dir_table: field(72),
+            xattr_table,
+        };
+        // a metadata block holds at most 8 KiB, which only one byte order can give
+        let plausible = |big_endian| {
+            get_u16_at(tables.slice(inode_table, inode_table + 2), 0, big_endian)
+                .is_some_and(|header| (1..=8192).contains(&(header & !METADATA_UNCOMPRESSED)))
+        };
+        if !plausible(big_endian) && plausible(!big_endian) {
+            tables.metadata_big_endian = !big_endian;
+        }
+        Ok(tables)
+    }
+
+    /// Bytes between the image offsets `from` and `to`.
+    fn slice(&self, from: u64, to: u64) -> &[u8] {
+        let from = from.saturating_sub(self.start).min(self.bytes.len() as u64) as usize;
+        let to = to.saturating_sub(self.start).min(self.bytes.len() as u64) as usize;
+        &self.bytes[from..to.max(from)]
+    }
+}
+
+/// Decompressed metadata blocks, addressed by block offset and offset into the block.
+struct Metadata {
+    bytes: Vec<u8>,
+    blocks: HashMap<u64, usize>,
+}
+
+impl Metadata {
+    fn decompress(
+        raw: &[u8],
+        compressor: Compressor,
+        big_endian: bool,
+    ) -> Result<Metadata, ArchiveError> {
+        let mut metadata = Metadata {
+            bytes: vec![],
+            blocks: HashMap::new(),
+        };
+        let mut offset = 0;
+        while let Some(header) = get_u16_at(raw, offset, big_endian) {
+            let size = usize::from(header & !METADATA_UNCOMPRESSED);
+            let block =
+                raw.get(offset + 2..offset + 2 + size)
+                    .ok_or(ArchiveError::GenericsError(
+                        "squashfs metadata is truncated",
+                    ))?;
+            metadata.blocks.insert(offset as u64, metadata.bytes.len());
+            if header & METADATA_UNCOMPRESSED != 0 {
+                metadata.bytes.extend_from_slice(block);
+            } else {
+                let mut out = Vec::with_capacity(8192);
+                codec(compressor)
+                    .decompress(block, &mut out, compressor)
+                    .map_err(ArchiveError::SquashfsError)?;
+                metadata.bytes.extend_from_slice(&out);
+            }
+            offset += 2 + size;
+        }
+        Ok(metadata)
+    }
+
+    fn from_blocks(blocks: &[(u64, Vec<u8>)]) -> Metadata {
+        let mut metadata = Metadata {
+            bytes: vec![],
+            blocks: HashMap::new(),
+        };
+        for (offset, block) in blocks {
+            metadata.blocks.insert(*offset, metadata.bytes.len());
+            metadata.bytes.extend_from_slice(block);
+        }
+        metadata
+    }
+
+    /// Bytes from `offset` into the block at `block`, running on into the following blocks.
+    fn at(&self, block: u64, offset: u64) -> Result<&[u8], ArchiveError> {
+        self.blocks
+            .get(&block)
+            .and_then(|start| self.bytes.get(start + offset as usize..))
+            .ok_or(ArchiveError::GenericsError(
+                "squashfs metadata reference is invalid",
+            ))
+    }
+
+    /// Same as [`Metadata::at`] for a `block << 16 | offset` reference.
+    fn at_ref(&self, reference: u64) -> Result<&[u8], ArchiveError> {
+        self.at(reference >> 16, reference & 0xffff)
+    }
+}
+
+/// What `FilesystemReader` does not keep, keyed by path inside the image.
+#[derive(Default)]
+struct InodeTables {
+    inodes: HashMap<PathBuf, u32>,
+    /// Later paths of an inode with several directory entries, pointing at its first path.
+    hard_links: HashMap<PathBuf, PathBuf>,
+    xattrs: HashMap<PathBuf, Xattrs>,
+}
+
+impl InodeTables {
+    fn scan(squashfs: &Squashfs, raw: &RawTables) -> Result<InodeTables, ArchiveError> {
+        let compressor = squashfs.superblock.compressor;
+        let be = raw.big_endian;
+        let inodes = raw.slice(raw.inode_table, raw.dir_table);
+        let inodes = Metadata::decompress(inodes, compressor, raw.metadata_big_endian)?;
+        let dirs = Metadata::from_blocks(&squashfs.dir_blocks);
+        let xattrs = read_xattrs(raw, compressor)?;
+
+        // (path, inode number, is a directory, xattr index)
+        let mut nodes = vec![];
+        let mut visited = HashSet::new();
+        let mut stack = vec![(PathBuf::new(), raw.root_inode)];
+        while let Some((path, reference)) = stack.pop() {
+            let inode = inodes.at_ref(reference)?;
+            let kind = get_u16_at(inode, 0, be).ok_or(TRUNCATED_INODE)?;
+            let number = get_u32_at(inode, 12, be).ok_or(TRUNCATED_INODE)?;
+            if is_dir(kind) && !visited.insert(number) {
+                log::debug!("squashfs directory {} is linked twice", path.display());
+                continue;
+            }
+            nodes.push((
+                path.clone(),
+                number,
+                is_dir(kind),
+                xattr_index(inode, kind, be)?,
+            ));
+            if !is_dir(kind) {
+                continue;
+            }
+
+            let (block_index, size, block_offset) = match kind {
+                1 => (
+                    get_u32_at(inode, 16, be),
+                    get_u16_at(inode, 24, be).map(u32::from),
+                    get_u16_at(inode, 26, be),
+                ),
+                _ => (
+                    get_u32_at(inode, 24, be),
+                    get_u32_at(inode, 20, be),
+                    get_u16_at(inode, 34, be),
+                ),
+            };
+            let (block_index, size, block_offset) = block_index
+                .zip(size)
+                .zip(block_offset)
+                .map(|((b, s), o)| (b, s, o))
+                .ok_or(TRUNCATED_INODE)?;
+            // the size counts the "." and ".." entries that are not stored
+            let Some(size) = (size as usize).checked_sub(3).filter(|size| *size > 0) else {
+                continue;
+            };
+            let listing = dirs.at(u64::from(block_index), u64::from(block_offset))?;
+            let listing = listing.get(..size).ok_or(TRUNCATED_DIR)?;
+            for (name, reference) in parse_dir(listing, be)? {
+                stack.push((path.join(name), reference));
+            }
+        }
+
+        let mut tables = InodeTables::default();
+        nodes.sort();
+        let mut first_paths = HashMap::new();
+        for (path, number, dir, xattr) in nodes {
+            tables.inodes.insert(path.clone(), number);
+            if let Some(list) = xattrs.get(xattr as usize).filter(|_| xattr != NO_XATTR) {
+                tables.xattrs.insert(path.clone(), list.clone());
+            }
+            if dir {
+                continue;
+            }
+            match first_paths.get(&number) {
+                Some(first) => {
+                    tables.hard_links.insert(path, PathBuf::clone(first));
+                }
+                None => {
+                    first_paths.insert(number, path);
                 }
             }
-            InnerNode::CharacterDevice(SquashfsCharacterDevice {
-                device_number: _,
-            }) => {
-                log::info!("[-] character device not supported");
+        }
+        Ok(tables)
+    }
+
+    /// Builds the entry of the `index`th node, taking its hard link and xattrs.
+    fn entry(
+        &mut self,
+        fs: &FilesystemReader,
+        index: usize,
+        node: &Node<SquashfsFileReader>,
+    ) -> SquashFSEntry {
+        let path = relative_path(node).to_path_buf();
+        let id = |index: u16| {
+            fs.id_table
+                .get(usize::from(index))
+                .map_or(u32::from(index), |id| id.num)
+        };
+        let (kind, size, link, device) = match &node.inner {
+            InnerNode::File(file) => (
+                FileType::RegularFile,
+                u64::from(file.basic.file_size),
+                None,
+                None,
+            ),
+            InnerNode::Symlink(SquashfsSymlink { link }) => {
+                (FileType::SymbolicLink, 0, Some(link.clone()), None)
             }
-            InnerNode::BlockDevice(SquashfsBlockDevice {
-                device_number: _,
-            }) => {
-                log::info!("[-] block device not supported");
+            InnerNode::Dir(_) => (FileType::Directory, 0, None, None),
+            InnerNode::CharacterDevice(SquashfsCharacterDevice { device_number }) => (
+                FileType::CharacterDevice,
+                0,
+                None,
+                Some(decode_device(*device_number)),
+            ),
+            InnerNode::BlockDevice(SquashfsBlockDevice { device_number }) => (
+                FileType::BlockDevice,
+                0,
+                None,
+                Some(decode_device(*device_number)),
+            ),
+        };
+
+        SquashFSEntry {
+            inode: self.inodes.get(&path).copied().unwrap_or_default(),
+            hard_link: self.hard_links.remove(&path),
+            xattrs: self.xattrs.remove(&path).unwrap_or_default(),
+            path,
+            index,
+            kind,
+            mode: u32::from(node.header.permissions),
+            uid: id(node.header.uid),
+            gid: id(node.header.gid),
+            mtime: node.header.mtime,
+            size,
+            link,
+            device,
+        }
+    }
+}
+
+const TRUNCATED_INODE: ArchiveError = ArchiveError::GenericsError("squashfs inode is truncated");
+const TRUNCATED_DIR: ArchiveError = ArchiveError::GenericsError("squashfs directory is truncated");
+
+fn is_dir(kind: u16) -> bool {
+    kind == 1 || kind == 8
+}
+
+/// The xattr index of the extended inode types.
+fn xattr_index(inode: &[u8], kind: u16, big_endian: bool) -> Result<u32, ArchiveError> {
+    let offset = match kind {
+        // directory, file
+        8 => 36,
+        9 => 52,
+        // symlink, the index follows the target
+        10 => 24 + get_u32_at(inode, 20, big_endian).ok_or(TRUNCATED_INODE)? as usize,
+        // block and character device
+        11 | 12 => 24,
+        // fifo and socket
+        13 | 14 => 20,
+        _ => return Ok(NO_XATTR),
+    };
+    get_u32_at(inode, offset, big_endian).ok_or(TRUNCATED_INODE)
+}
+
+/// Names and inode references of a directory listing.
+fn parse_dir(mut listing: &[u8], be: bool) -> Result<Vec<(String, u64)>, ArchiveError> {
+    let mut entries = vec![];
+    while !listing.is_empty() {
+        let count = get_u32_at(listing, 0, be).ok_or(TRUNCATED_DIR)?;
+        let start = get_u32_at(listing, 4, be).ok_or(TRUNCATED_DIR)?;
+        listing = listing.get(12..).ok_or(TRUNCATED_DIR)?;
+        for _ in 0..=count {
+            let offset = get_u16_at(listing, 0, be).ok_or(TRUNCATED_DIR)?;
+            let name_size = usize::from(get_u16_at(listing, 6, be).ok_or(TRUNCATED_DIR)?) + 1;
+            let name = listing.get(8..8 + name_size).ok_or(TRUNCATED_DIR)?;
+            let name = String::from_utf8_lossy(name).into_owned();
+            entries.push((name, u64::from(start) << 16 | u64::from(offset)));
+            listing = &listing[8 + name_size..];
+        }
+    }
+    Ok(entries)
+}
+
+/// Key/value lists of the xattr table, by xattr index.
+fn read_xattrs(raw: &RawTables, compressor: Compressor) -> Result<Vec<Xattrs>, ArchiveError> {
+    const TRUNCATED: ArchiveError =
+        ArchiveError::GenericsError("squashfs xattr table is truncated");
+
+    if raw.xattr_table == NOT_SET {
+        return Ok(vec![]);
+    }
+    let be = raw.big_endian;
+    let header = raw.slice(raw.xattr_table, raw.xattr_table + 24);
+    let kv_start = get_u64_at(header, 0, be).ok_or(TRUNCATED)?;
+    let count = get_u32_at(header, 8, be).ok_or(TRUNCATED)?;
+    if count == 0 {
+        return Ok(vec![]);
+    }
+    let ids_start = get_u64_at(header, 16, be).ok_or(TRUNCATED)?;
+    let kv = raw.slice(kv_start, ids_start);
+    let kv = Metadata::decompress(kv, compressor, raw.metadata_big_endian)?;
+    let ids = raw.slice(ids_start, raw.xattr_table);
+    let ids = Metadata::decompress(ids, compressor, raw.metadata_big_endian)?;
+
+    let mut lists = vec![];
+    for id in ids.bytes.chunks_exact(16).take(count as usize) {
+        let reference = get_u64_at(id, 0, be).ok_or(TRUNCATED)?;
+        let pairs = get_u32_at(id, 8, be).ok_or(TRUNCATED)?;
+        let mut data = kv.at_ref(reference)?;
+        let mut list = vec![];
+        for _ in 0..pairs {
+            let kind = get_u16_at(data, 0, be).ok_or(TRUNCATED)?;
+            let name_size = usize::from(get_u16_at(data, 2, be).ok_or(TRUNCATED)?);
+            let name = data.get(4..4 + name_size).ok_or(TRUNCATED)?;
+            data = &data[4 + name_size..];
+            let value_size = get_u32_at(data, 0, be).ok_or(TRUNCATED)? as usize;
+            let mut value = data.get(4..4 + value_size).ok_or(TRUNCATED)?;
+            data = &data[4 + value_size..];
+            if kind & 0x100 != 0 {
+                // the value is stored once elsewhere, this is a reference to it
+                let stored = kv.at_ref(get_u64_at(value, 0, be).ok_or(TRUNCATED)?)?;
+                let size = get_u32_at(stored, 0, be).ok_or(TRUNCATED)? as usize;
+                value = stored.get(4..4 + size).ok_or(TRUNCATED)?;
             }
+            let prefix = match kind & 0xff {
+                0 => "user.",
+                1 => "trusted.",
+                2 => "security.",
+                _ => {
+                    log::info!("[-] unknown xattr prefix {}", kind & 0xff);
+                    continue;
+                }
+            };
+            let name = format!("{}{}", prefix, String::from_utf8_lossy(name));
+            list.push((name, value.to_vec()));
         }
-        Ok(())
+        lists.push(list);
     }
+    Ok(lists)
+}
 
-    pub fn create_with_reader(
-        rdr: impl Read + Seek + 'static,
-    ) -> Result<SquashFSArchive, ArchiveError> {
-        let inner = 
-            Squashfs::from_reader(BufReader::new(rdr))
-                .map_err(ArchiveError::SquashfsError)?
-                .into_filesystem_reader()
-                .map_err(ArchiveError::SquashfsError)?;
+/// backhand's codecs, plus lz4 and a zstd encoder that works, which backhand 0.12 lacks.
+///
+/// Decompression goes by the compressor the caller names, `compressor` picks the encoder.
+struct Codec {
+    compressor: Compressor,
+}
 
-        let archive = SquashFSArchive { inner };
+static CODECS: [Codec; 7] = [
+    Codec {
+        compressor: Compressor::None,
+    },
+    Codec {
+        compressor: Compressor::Gzip,
+    },
+    Codec {
+        compressor: Compressor::Lzma,
+    },
+    Codec {
+        compressor: Compressor::Lzo,
+    },
+    Codec {
+        compressor: Compressor::Xz,
+    },
+    Codec {
+        compressor: Compressor::Lz4,
+    },
+    Codec {
+        compressor: Compressor::Zstd,
+    },
+];
 
-        Ok(archive)
+fn codec(compressor: Compressor) -> &'static Codec {
+    &CODECS[compressor as usize]
+}
+
+impl CompressionAction for Codec {
+    fn decompress(
+        &self,
+        bytes: &[u8],
+        out: &mut Vec<u8>,
+        compressor: Compressor,
+    ) -> Result<(), BackhandError> {
+        match compressor {
+            Compressor::Lz4 => {
+                let data = lz4::block::decompress(bytes, Some(MAX_BLOCK_SIZE as i32))?;
+                out.extend_from_slice(&data);
+                Ok(())
+            }
+            Compressor::Lzma => lzma_decompress(bytes, out),
+            // vendor kernels kept the gzip id for their LZMA, which never starts like zlib
+            Compressor::Gzip if bytes.first() == Some(&LZMA_PROPERTIES) => {
+                lzma_decompress(bytes, out)
+            }
+            _ => DefaultCompressor.decompress(bytes, out, compressor),
+        }
     }
 
-    pub fn create_with_path(path: impl AsRef<Path>) -> Result<SquashFSArchive, ArchiveError> {
-        let reader = std::fs::File::open(path)?;
-        Self::create_with_reader(reader)
+    fn compress(
+        &self,
+        bytes: &[u8],
+        fc: FilesystemCompressor,
+        block_size: u32,
+    ) -> Result<Vec<u8>, BackhandError> {
+        match self.compressor {
+            Compressor::Lz4 => Ok(lz4::block::compress(bytes, None, false)?),
+            Compressor::Zstd => Ok(zstd::bulk::compress(
+                bytes,
+                zstd::DEFAULT_COMPRESSION_LEVEL,
+            )?),
+            _ => DefaultCompressor.compress(bytes, fc, block_size),
+        }
+    }
+}
+
+/// lc=3, lp=0, pb=2, what every squashfs LZMA patch used.
+const LZMA_PROPERTIES: u8 = 0x5d;
+
+/// LZMA with the 13 byte header of lzma-alone, or with only the 5 byte properties and no
+/// end marker as some vendor patches wrote it.
+fn lzma_decompress(bytes: &[u8], out: &mut Vec<u8>) -> Result<(), BackhandError> {
+    use lzma_rs::decompress::{Options, UnpackedSize};
+
+    let start = out.len();
+    let mut error = None;
+    // without the size, the start of the stream could pass for a size of zero
+    let size = get_u64_at(bytes, 5, false);
+    let has_size = size
+        .is_some_and(|size| size == u64::MAX || (1..=u64::from(MAX_BLOCK_SIZE)).contains(&size));
+    let attempts = [
+        (UnpackedSize::ReadFromHeader, false),
+        (UnpackedSize::UseProvided(None), true),
+    ];
+    for (unpacked_size, allow_incomplete) in attempts.into_iter().skip(usize::from(!has_size)) {
+        out.truncate(start);
+        let options = Options {
+            unpacked_size,
+            memlimit: Some(64 << 20),
+            allow_incomplete,
+        };
+        match lzma_rs::lzma_decompress_with_options(&mut &bytes[..], out, &options) {
+            Ok(()) => return Ok(()),
+            Err(err) => error = Some(err),
+        }
     }
+
+    let error = error.map_or_else(String::new, |err| err.to_string());
+    Err(std::io::Error::new(std::io::ErrorKind::InvalidData, error).into())
 }
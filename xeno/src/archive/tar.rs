@@ -1,39 +1,93 @@
-use core::arch;
-use std::io::{Read, Seek};
-use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
-use bzip2::read::BzDecoder;
-use lzma_rs::lzma_decompress;
+use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+use base64::Engine;
 use tar::Archive as TarArchiveInner;
-use zstd::Decoder as ZstdDecoder;
+use time::PrimitiveDateTime;
 
-use crate::archive::{Entry, FileType};
+use crate::archive::extract::{
+    posix_acl_xattr, restore_times, unpack_node, DeviceNode, ExtractNode, ExtractPolicy,
+    NodeMetadata,
+};
+use crate::archive::{Entry, ExtractOption, FileType};
+use crate::block::brotli::BrotliBlock;
+use crate::block::bzip2::{Bzip2Block, Bzip2Encoder};
+use crate::block::gzip::{GzipBlock, GzipEncoder};
+use crate::block::lz4::Lz4Block;
+use crate::block::lzma::LzmaAloneBlock;
+use crate::block::snappy::SnappyBlock;
+use crate::block::xz::{XzBlock, XzEncoder};
+use crate::block::zstd::{ZstdBlock, ZstdEncoder};
+use crate::block::{create_encoder, BlockDecoder, BlockEncoder, BlockFormat, FromReader};
 use crate::utils::error::ArchiveError;
 
-pub struct TarArchive<R: Read> {
-    inner: TarArchiveInner<R>,
+/// A plain tarball, read through the same code as the compressed ones.
+pub type TarArchive<R> = CompressedTar<Uncompressed<R>>;
+
+/// The identity codec, so a plain tarball is a [`CompressedTar`] like any other.
+pub struct Uncompressed<R> {
+    inner: R,
 }
 
-pub struct TarGzArchive<R: Read> {
-    inner: TarArchiveInner<R>,
+impl<R: Read> Read for Uncompressed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf)
+    }
 }
 
-pub struct TarBz2Archive<R: Read> {
-    inner: TarArchiveInner<R>,
+impl<R: Read> FromReader<R> for Uncompressed<R> {
+    fn from_reader(rdr: R) -> Result<Self, ArchiveError> {
+        Ok(Uncompressed { inner: rdr })
+    }
 }
 
-pub struct TarZstdArchive<R: Read> {
-    inner: TarArchiveInner<R>,
+/// A tar archive wrapped in any of the `block/` codecs, decoded as a stream.
+pub struct CompressedTar<D: Read> {
+    inner: TarArchiveInner<Tap<D>>,
+    extensions: Arc<Mutex<Extensions>>,
+    /// Devices extracted as placeholders.
+    device_manifest: Vec<DeviceNode>,
 }
 
+pub type TarGzArchive<R> = CompressedTar<GzipBlock<R>>;
+pub type TarBz2Archive<R> = CompressedTar<Bzip2Block<R>>;
+pub type TarXzArchive<R> = CompressedTar<XzBlock<R>>;
+pub type TarLzmaArchive<R> = CompressedTar<LzmaAloneBlock<R>>;
+pub type TarLz4Archive<R> = CompressedTar<Lz4Block<R>>;
+pub type TarZstdArchive<R> = CompressedTar<ZstdBlock<R>>;
+pub type TarBrotliArchive<R> = CompressedTar<BrotliBlock<R>>;
+pub type TarSnappyArchive<R> = CompressedTar<SnappyBlock<R>>;
+/// Picks the codec by sniffing the head of the stream.
+pub type TarDetectArchive<'a> = CompressedTar<Box<dyn BlockDecoder + 'a>>;
+
 pub struct TarEntry<'a, R: Read> {
-    inner: tar::Entry<'a, R>,
+    inner: tar::Entry<'a, Tap<R>>,
+    /// PAX records of the global headers so far and of the entry, later ones win.
+    pax: Vec<(String, Vec<u8>)>,
+    sparse: Option<Sparse>,
+}
+
+/// Data regions of a GNU sparse file.
+struct Sparse {
+    /// `(offset, length)` of every region holding data.
+    regions: Vec<(u64, u64)>,
+    real_size: u64,
+    /// PAX sparse files store the regions back to back, which the tar crate does not expand.
+    packed: bool,
+    /// Bytes of extension blocks or entry data taken by the map before the regions.
+    map_size: u64,
+    /// Offset in the expanded file of the next read.
+    position: u64,
 }
 
 impl<'a, R: Read> Entry for TarEntry<'a, R> {
     fn file_type(&self) -> FileType {
         match self.inner.header().entry_type() {
-            tar::EntryType::Regular => FileType::RegularFile,
+            tar::EntryType::Regular | tar::EntryType::GNUSparse => FileType::RegularFile,
             tar::EntryType::Link => FileType::HardLink,
             tar::EntryType::Symlink => FileType::SymbolicLink,
             tar::EntryType::Block => FileType::BlockDevice,
@@ -54,12 +108,19 @@ impl<'a, R: Read> Entry for TarEntry<'a, R> {
     }
 
     fn path_name(&self) -> std::io::Result<PathBuf> {
+        // PAX sparse files live under a made up name in the header
+        if let Some(name) = self.pax_str("GNU.sparse.name") {
+            return Ok(PathBuf::from(name));
+        }
         let path = self.inner.path()?;
         Ok(path.as_ref().to_path_buf())
     }
 
     fn size(&self) -> u64 {
-        self.inner.size()
+        match &self.sparse {
+            Some(sparse) => sparse.real_size,
+            None => self.inner.size(),
+        }
     }
 
     fn gid(&self) -> std::io::Result<Option<u64>> {
@@ -82,14 +143,202 @@ impl<'a, R: Read> Entry for TarEntry<'a, R> {
     }
 }
 
+impl<'a, R: Read> TarEntry<'a, R> {
+    pub fn unix_mode(&self) -> std::io::Result<u32> {
+        Ok(self.inner.header().mode()? & 0o7777)
+    }
+
+    /// Modification time, with the fractional seconds of a PAX header.
+    pub fn mtime(&self) -> Option<PrimitiveDateTime> {
+        self.times().1.and_then(unix_time)
+    }
+
+    /// Access time, only stored by PAX and GNU headers.
+    pub fn atime(&self) -> Option<PrimitiveDateTime> {
+        self.times().0.and_then(unix_time)
+    }
+
+    /// Status change time, only stored by PAX and GNU headers.
+    pub fn ctime(&self) -> Option<PrimitiveDateTime> {
+        self.time("ctime", |gnu| gnu.ctime()).and_then(unix_time)
+    }
+
+    pub fn username(&self) -> Option<String> {
+        match self.pax_str("uname") {
+            Some(name) => Some(name.to_string()),
+            None => self
+                .inner
+                .header()
+                .username()
+                .ok()
+                .flatten()
+                .map(str::to_string),
+        }
+    }
+
+    pub fn groupname(&self) -> Option<String> {
+        match self.pax_str("gname") {
+            Some(name) => Some(name.to_string()),
+            None => self
+                .inner
+                .header()
+                .groupname()
+                .ok()
+                .flatten()
+                .map(str::to_string),
+        }
+    }
+
+    /// Major and minor number of a character or block device.
+    pub fn device(&self) -> Option<(u32, u32)> {
+        match self.file_type() {
+            FileType::CharacterDevice | FileType::BlockDevice => {
+                let header = self.inner.header();
+                let major = header.device_major().ok().flatten()?;
+                let minor = header.device_minor().ok().flatten()?;
+                Some((major, minor))
+            }
+            _ => None,
+        }
+    }
+
+    /// PAX records that apply to this entry, from the global headers before it and its own.
+    pub fn pax_extensions(&self) -> &[(String, Vec<u8>)] {
+        &self.pax
+    }
+
+    /// Extended attributes from the `SCHILY.xattr.` records of GNU tar and star and the
+    /// `LIBARCHIVE.xattr.` ones of bsdtar, as full names with their raw values.
+    pub fn xattrs(&self) -> Vec<(String, Vec<u8>)> {
+        let mut xattrs: Vec<(String, Vec<u8>)> = vec![];
+        for (key, value) in &self.pax {
+            let xattr = if let Some(name) = key.strip_prefix("SCHILY.xattr.") {
+                Some((name.to_string(), value.clone()))
+            } else if let Some(name) = key.strip_prefix("LIBARCHIVE.xattr.") {
+                let name = percent_encoding::percent_decode_str(name).decode_utf8();
+                let value = BASE64.decode(value.trim_ascii_end());
+                name.ok()
+                    .zip(value.ok())
+                    .map(|(name, value)| (name.into_owned(), value))
+            } else {
+                None
+            };
+            let Some((name, value)) = xattr else {
+                continue;
+            };
+            match xattrs.iter_mut().find(|(known, _)| *known == name) {
+                Some(known) => known.1 = value,
+                None => xattrs.push((name, value)),
+            }
+        }
+        xattrs
+    }
+
+    /// The access ACL in the POSIX.1e text form, e.g. `user::rw-,group::r--,other::r--`.
+    pub fn acl_access(&self) -> Option<&str> {
+        self.pax_str("SCHILY.acl.access")
+    }
+
+    /// The default ACL of a directory, in the POSIX.1e text form.
+    pub fn acl_default(&self) -> Option<&str> {
+        self.pax_str("SCHILY.acl.default")
+    }
+
+    /// `(offset, length)` of the regions of a GNU sparse file that hold data, everything
+    /// else reads as zeros.
+    pub fn sparse_map(&self) -> Option<&[(u64, u64)]> {
+        self.sparse.as_ref().map(|sparse| sparse.regions.as_slice())
+    }
+
+    fn pax_value(&self, key: &str) -> Option<&[u8]> {
+        self.pax
+            .iter()
+            .rev()
+            .find(|(known, _)| known == key)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    fn pax_str(&self, key: &str) -> Option<&str> {
+        self.pax_value(key)
+            .and_then(|value| std::str::from_utf8(value).ok())
+    }
+
+    /// A time from the PAX record `key`, or from the GNU header.
+    fn time(
+        &self,
+        key: &str,
+        gnu: impl Fn(&tar::GnuHeader) -> std::io::Result<u64>,
+    ) -> Option<(i64, u32)> {
+        if let Some(time) = self.pax_str(key).and_then(pax_time) {
+            return Some(time);
+        }
+        let time = gnu(self.inner.header().as_gnu()?).ok()?;
+        Some((time as i64, 0)).filter(|_| time != 0)
+    }
+
+    /// Access and modification time, as seconds and nanoseconds.
+    fn times(&self) -> (Option<Timestamp>, Option<Timestamp>) {
+        let mtime = match self.pax_str("mtime").and_then(pax_time) {
+            Some(time) => Some(time),
+            None => self
+                .inner
+                .header()
+                .mtime()
+                .ok()
+                .map(|time| (time as i64, 0)),
+        };
+        (self.time("atime", |gnu| gnu.atime()), mtime)
+    }
+}
+
 impl<'a, R: Read> Read for TarEntry<'a, R> {
     fn read(&mut self, into: &mut [u8]) -> std::io::Result<usize> {
-        self.inner.read(into)
+        match self.sparse.as_mut().filter(|sparse| sparse.packed) {
+            Some(sparse) => sparse.read(&mut self.inner, into),
+            None => self.inner.read(into),
+        }
+    }
+}
+
+impl Sparse {
+    /// Reads the expanded file from `data`, the regions stored back to back.
+    fn read(&mut self, data: &mut impl Read, into: &mut [u8]) -> std::io::Result<usize> {
+        if into.is_empty() || self.position >= self.real_size {
+            return Ok(0);
+        }
+
+        let position = self.position;
+        let region = self
+            .regions
+            .iter()
+            .find(|(offset, length)| offset + length > position);
+        let read = match region {
+            Some(&(offset, length)) if offset <= position => {
+                let size = into.len().min((offset + length - position) as usize);
+                let read = data.read(&mut into[..size])?;
+                if read == 0 {
+                    return Err(std::io::ErrorKind::UnexpectedEof.into());
+                }
+                read
+            }
+            // a hole up to the next region or the end of the file
+            _ => {
+                let end = region.map_or(self.real_size, |(offset, _)| *offset);
+                let size = into.len().min((end - position) as usize);
+                into[..size].fill(0);
+                size
+            }
+        };
+        self.position += read as u64;
+        Ok(read)
     }
 }
 
 pub struct TarEntries<'a, R: Read> {
-    inner: tar::Entries<'a, R>,
+    inner: tar::Entries<'a, Tap<R>>,
+    extensions: Arc<Mutex<Extensions>>,
+    /// Records of the global PAX headers seen so far.
+    globals: Vec<(String, Vec<u8>)>,
 }
 
 impl<'a, R: Read> Iterator for TarEntries<'a, R> {
@@ -97,156 +346,1766 @@ impl<'a, R: Read> Iterator for TarEntries<'a, R> {
 
     fn next(&mut self) -> Option<Result<TarEntry<'a, R>, ArchiveError>> {
         self.inner.next().map(|result| {
-            result
-                .map(|e| TarEntry { inner: e })
-                .map_err(ArchiveError::Io)
+            let mut inner = result?;
+            let mut pax = self.globals.clone();
+            if let Some(extensions) = inner.pax_extensions()? {
+                let records = extensions.filter_map(|extension| {
+                    let extension = extension.ok()?;
+                    let key = extension.key().ok()?.to_string();
+                    Some((key, extension.value_bytes().to_vec()))
+                });
+                pax.extend(records);
+            }
+            if inner.header().entry_type() == tar::EntryType::XGlobalHeader {
+                self.globals = pax.clone();
+            }
+            let sparse = match inner.header().entry_type() {
+                tar::EntryType::GNUSparse => gnu_sparse(&inner, &self.extensions.lock().unwrap())?,
+                _ => pax_sparse(&mut inner, &pax)?,
+            };
+            Ok(TarEntry { inner, pax, sparse })
         })
     }
 }
 
-impl<R> TarArchive<R>
+/// Regions of an old GNU sparse file: four in the header and the rest in the extension
+/// blocks right after it, which the tar crate reads without handing them out.
+fn gnu_sparse<R: Read>(
+    inner: &tar::Entry<Tap<R>>,
+    extensions: &Extensions,
+) -> std::io::Result<Option<Sparse>> {
+    let header = inner.header();
+    let Some(gnu) = header.as_gnu() else {
+        return Ok(None);
+    };
+    let mut regions = vec![];
+    let mut map_size = 0;
+    let mut add = |blocks: &[tar::GnuSparseHeader]| -> std::io::Result<()> {
+        for block in blocks.iter().filter(|block| !block.is_empty()) {
+            regions.push((block.offset()?, block.length()?));
+        }
+        Ok(())
+    };
+    add(&gnu.sparse)?;
+    if gnu.is_extended() {
+        // the blocks must follow this header and end the chain
+        let start = inner.raw_header_position() + BLOCK_SIZE as u64;
+        let last = extensions.blocks.rchunks_exact(BLOCK_SIZE).next();
+        if extensions.start != start || last.is_none_or(|block| block[504] != 0) {
+            log::info!("[-] sparse map of {:?} is incomplete", header.path());
+            return Ok(None);
+        }
+        for block in extensions.blocks.chunks_exact(BLOCK_SIZE) {
+            let mut ext = tar::GnuExtSparseHeader::new();
+            ext.as_mut_bytes().copy_from_slice(block);
+            add(ext.sparse())?;
+            map_size += BLOCK_SIZE as u64;
+        }
+    }
+
+    Ok(Some(Sparse {
+        regions,
+        real_size: gnu.real_size()?,
+        packed: false,
+        map_size,
+        position: 0,
+    }))
+}
+
+/// Regions of a PAX sparse file: in the records for formats 0.0 and 0.1, at the start of the
+/// data for format 1.0.
+fn pax_sparse<R: Read>(
+    inner: &mut tar::Entry<Tap<R>>,
+    pax: &[(String, Vec<u8>)],
+) -> std::io::Result<Option<Sparse>> {
+    let value = |key: &str| {
+        pax.iter()
+            .rev()
+            .find(|(known, _)| known == key)
+            .and_then(|(_, value)| std::str::from_utf8(value).ok())
+    };
+    let number = |text: &str| {
+        text.trim().parse::<u64>().map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "bad number in sparse map")
+        })
+    };
+    let Some(real_size) = value("GNU.sparse.realsize").or(value("GNU.sparse.size")) else {
+        return Ok(None);
+    };
+
+    let mut numbers = vec![];
+    let mut map_size = 0;
+    if value("GNU.sparse.major") == Some("1") {
+        // a count and the pairs, one decimal number per line, padded to a block
+        let mut read = 0;
+        let mut line = vec![];
+        let mut count = None;
+        while count.is_none_or(|count| numbers.len() < count * 2) {
+            let mut byte = [0];
+            inner.read_exact(&mut byte)?;
+            read += 1;
+            if byte[0] != b'\n' {
+                line.push(byte[0]);
+                continue;
+            }
+            let text = String::from_utf8_lossy(&line).into_owned();
+            line.clear();
+            match count {
+                None => count = Some(number(&text)? as usize),
+                Some(_) => numbers.push(number(&text)?),
+            }
+        }
+        let padding = (BLOCK_SIZE - read % BLOCK_SIZE) % BLOCK_SIZE;
+        std::io::copy(
+            &mut inner.by_ref().take(padding as u64),
+            &mut std::io::sink(),
+        )?;
+        map_size = (read + padding) as u64;
+    } else if let Some(map) = value("GNU.sparse.map") {
+        for text in map.split(',').filter(|text| !text.is_empty()) {
+            numbers.push(number(text)?);
+        }
+    } else {
+        for (key, value) in pax {
+            if key == "GNU.sparse.offset" || key == "GNU.sparse.numbytes" {
+                numbers.push(number(&String::from_utf8_lossy(value))?);
+            }
+        }
+    }
+
+    Ok(Some(Sparse {
+        regions: numbers
+            .chunks_exact(2)
+            .map(|pair| (pair[0], pair[1]))
+            .collect(),
+        real_size: number(real_size)?,
+        packed: true,
+        map_size,
+        position: 0,
+    }))
+}
+
+const BLOCK_SIZE: usize = 512;
+/// How many extension blocks [`Tap`] keeps, enough for the sparse map of any sane file.
+const EXTENSIONS_CAPACITY: usize = 1 << 20;
+
+/// bsdtar writes its xattr values in base64 without padding.
+const BASE64: GeneralPurpose = GeneralPurpose::new(
+    &base64::alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// Extension blocks of the last old GNU sparse header read through [`Tap`].
+#[derive(Default)]
+struct Extensions {
+    /// Stream offset of the first block, right after the header.
+    start: u64,
+    blocks: Vec<u8>,
+}
+
+/// Watches the blocks read for an old GNU sparse header and keeps the extension blocks
+/// that follow it, everything else passes through untouched.
+struct Tap<R> {
+    inner: R,
+    /// Stream offset of the next byte, in the same terms as the tar crate's positions.
+    position: u64,
+    /// The start of the current block when a read ended inside it, `None` after seeking
+    /// into the middle of one.
+    partial: Option<Vec<u8>>,
+    /// Whether the last block seen asks for another extension block.
+    extending: bool,
+    extensions: Arc<Mutex<Extensions>>,
+}
+
+impl<R> Tap<R> {
+    fn new(inner: R, extensions: Arc<Mutex<Extensions>>) -> Tap<R> {
+        Tap {
+            inner,
+            position: 0,
+            partial: Some(vec![]),
+            extending: false,
+            extensions,
+        }
+    }
+
+    fn block(&mut self, block: &[u8], start: u64) {
+        if self.extending {
+            let mut extensions = self.extensions.lock().unwrap();
+            extensions.blocks.extend_from_slice(block);
+            // the flag sits after the 21 regions of an extension block
+            self.extending = block[504] != 0 && extensions.blocks.len() < EXTENSIONS_CAPACITY;
+        } else if block[156] == b'S' && block[482] != 0 && checksum_matches(block) {
+            let mut extensions = self.extensions.lock().unwrap();
+            extensions.start = start + BLOCK_SIZE as u64;
+            extensions.blocks.clear();
+            self.extending = true;
+        }
+    }
+}
+
+/// Whether `block` carries a valid header checksum, so entry data is not taken for one.
+fn checksum_matches(block: &[u8]) -> bool {
+    let field = String::from_utf8_lossy(&block[148..156]);
+    let Ok(stored) = u32::from_str_radix(field.trim_matches(|c| c == ' ' || c == '\0'), 8) else {
+        return false;
+    };
+    let sum = block[..148]
+        .iter()
+        .chain(&block[156..])
+        .map(|byte| *byte as u32)
+        .sum::<u32>();
+    sum + 8 * b' ' as u32 == stored
+}
+
+impl<R: Seek> Seek for Tap<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.position = self.inner.seek(pos)?;
+        self.partial = self
+            .position
+            .is_multiple_of(BLOCK_SIZE as u64)
+            .then(Vec::new);
+        self.extending = false;
+        Ok(self.position)
+    }
+}
+
+impl<R: Read> Read for Tap<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        let mut data = &buf[..read];
+        while !data.is_empty() {
+            let offset = (self.position % BLOCK_SIZE as u64) as usize;
+            let take = (BLOCK_SIZE - offset).min(data.len());
+            let start = self.position - offset as u64;
+            if offset == 0 && take == BLOCK_SIZE {
+                self.block(&data[..BLOCK_SIZE], start);
+            } else {
+                if offset == 0 {
+                    self.partial = Some(vec![]);
+                }
+                if let Some(partial) = &mut self.partial {
+                    partial.extend_from_slice(&data[..take]);
+                }
+                if offset + take == BLOCK_SIZE {
+                    if let Some(block) = self.partial.take() {
+                        self.block(&block, start);
+                    }
+                }
+            }
+            self.position += take as u64;
+            data = &data[take..];
+        }
+        Ok(read)
+    }
+}
+
+/// Parses a PAX time, seconds since the epoch with an optional fraction, e.g. `-1.25`.
+fn pax_time(text: &str) -> Option<(i64, u32)> {
+    let (seconds, fraction) = text.trim().split_once('.').unwrap_or((text.trim(), ""));
+    let negative = seconds.starts_with('-');
+    let seconds: i64 = seconds.parse().ok()?;
+    if fraction.is_empty() {
+        return Some((seconds, 0));
+    }
+    if !fraction.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let digits = &fraction[..fraction.len().min(9)];
+    let nanos = digits.parse::<u32>().ok()? * 10u32.pow(9 - digits.len() as u32);
+    match (negative, nanos) {
+        (true, 0) | (false, _) => Some((seconds, nanos)),
+        (true, nanos) => Some((seconds - 1, 1_000_000_000 - nanos)),
+    }
+}
+
+fn unix_time((seconds, nanos): (i64, u32)) -> Option<PrimitiveDateTime> {
+    let nanos = i128::from(seconds) * 1_000_000_000 + i128::from(nanos);
+    let dt = time::OffsetDateTime::from_unix_timestamp_nanos(nanos);
+    dt.ok()
+        .map(|dt| PrimitiveDateTime::new(dt.date(), dt.time()))
+}
+
+/// Extracts every entry below `to` as `options` ask, see [`ExtractOption`].
+fn unpack_entries<R: Read>(
+    entries: TarEntries<R>,
+    to: &Path,
+    options: &[ExtractOption],
+    device_manifest: &mut Vec<DeviceNode>,
+) -> Result<(), ArchiveError> {
+    let policy = ExtractPolicy::new(options);
+    if !to.exists() {
+        std::fs::create_dir_all(to)?;
+    }
+
+    let mut failures = vec![];
+    let mut dirs = vec![];
+    for entry in entries {
+        let result = entry.and_then(|mut entry| {
+            let times = unpack_entry(&mut entry, to, &policy, device_manifest)?;
+            if let Some((path, times)) = times.filter(|(path, _)| path.is_dir()) {
+                dirs.push((path, times));
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            failures.push(e);
+        }
+    }
+
+    // creating the children touched the directory times
+    if policy.time {
+        for (path, (atime, mtime)) in dirs.into_iter().rev() {
+            if let Err(e) = restore_times(&path, atime, mtime) {
+                failures.push(e);
+            }
+        }
+    }
+
+    if !failures.is_empty() {
+        return Err(ArchiveError::ExtractFailed { sources: failures });
+    }
+    Ok(())
+}
+
+/// Seconds and nanoseconds since the epoch.
+type Timestamp = (i64, u32);
+type Times = (Timestamp, Timestamp);
+
+/// Owner ids, with the blank fields some writers leave read as root like GNU tar does.
+fn owner<R: Read>(entry: &TarEntry<R>) -> (u32, u32) {
+    let header = entry.inner.header();
+    let uid = header.uid().unwrap_or(0) as u32;
+    let gid = header.gid().unwrap_or(0) as u32;
+    (uid, gid)
+}
+
+/// Extracts one entry, returning where it went with its access and modification time.
+fn unpack_entry<R: Read>(
+    entry: &mut TarEntry<R>,
+    to: &Path,
+    policy: &ExtractPolicy,
+    device_manifest: &mut Vec<DeviceNode>,
+) -> Result<Option<(PathBuf, Times)>, ArchiveError> {
+    let file_type = entry.file_type();
+    if file_type == FileType::Other {
+        return Ok(None);
+    }
+    let path = entry.path_name()?;
+    let Some(filepath) = policy.destination(to, &path)? else {
+        return Ok(None);
+    };
+    let (atime, mtime) = entry.times();
+    let mtime = mtime.unwrap_or_default();
+    let atime = atime.unwrap_or(mtime);
+    if !policy.prepare(to, &filepath, file_type, mtime.0)? {
+        return Ok(None);
+    }
+
+    if file_type == FileType::HardLink {
+        // links share the metadata of their target, their own mode is often left blank
+        let target = entry.hand_link().unwrap_or_default();
+        let Some(target) = policy.link_target(to, &target)? else {
+            return Ok(None);
+        };
+        unpack_node(ExtractNode::HardLink(&target), &filepath, 0)?;
+        return Ok(None);
+    }
+
+    let mode = entry.inner.header().mode()?;
+    let device = match file_type {
+        FileType::SymbolicLink => {
+            let link = entry.sym_link().unwrap_or_default();
+            unpack_node(ExtractNode::Symlink(&link), &filepath, mode)?
+        }
+        FileType::Directory => unpack_node(ExtractNode::Directory, &filepath, mode)?,
+        FileType::CharacterDevice | FileType::BlockDevice => {
+            let device = entry.device().unwrap_or_default();
+            let owner = owner(entry);
+            let device = DeviceNode::new(&path, file_type, device, mode, owner);
+            unpack_node(ExtractNode::Device(device), &filepath, mode)?
+        }
+        FileType::NamedPipe => {
+            // nothing is created for them, so there is nothing to restore either
+            unpack_node(ExtractNode::NamedPipe, &filepath, mode)?;
+            return Ok(None);
+        }
+        _ => unpack_node(ExtractNode::File(entry), &filepath, mode)?,
+    };
+    if let Some(device) = device {
+        device_manifest.push(device);
+    }
+
+    let mut xattrs = vec![];
+    if policy.xattrs {
+        xattrs = entry.xattrs();
+    }
+    if policy.acls {
+        let acls = [
+            ("system.posix_acl_access", entry.acl_access()),
+            ("system.posix_acl_default", entry.acl_default()),
+        ];
+        for (name, text) in acls {
+            let Some(text) = text else {
+                continue;
+            };
+            match posix_acl_xattr(text) {
+                Some(value) => xattrs.push((name.to_string(), value)),
+                None => log::info!("[-] cannot restore the ACL {} of {}", text, path.display()),
+            }
+        }
+    }
+    let (uid, gid) = owner(entry);
+    let meta = NodeMetadata {
+        mode,
+        uid,
+        gid,
+        mtime: mtime.0,
+        xattrs: &xattrs,
+    };
+    policy.restore(&filepath, &meta, (atime, mtime))?;
+    Ok(Some((filepath, (atime, mtime))))
+}
+
+impl<D> CompressedTar<D>
 where
-    R: Read,
+    D: Read,
 {
-    pub fn entries(&mut self) -> std::io::Result<TarEntries<R>> {
+    pub fn entries(&mut self) -> std::io::Result<TarEntries<'_, D>> {
         let inner = self.inner.entries()?;
-        Ok(TarEntries { inner })
+        Ok(TarEntries {
+            inner,
+            extensions: self.extensions.clone(),
+            globals: vec![],
+        })
     }
 
+    /// Extracts everything below `to`, keeping modification times as
+    /// `unpack_all_with_options(to, &[ExtractOption::Time])` does.
     pub fn unpack_all(&mut self, to: impl AsRef<Path>) -> Result<(), ArchiveError> {
-        self.inner.unpack(to).map_err(ArchiveError::Io)
+        self.unpack_all_with_options(to, &[ExtractOption::Time])
+    }
+
+    /// Extracts everything below `to`, restoring ownership, permissions, times, xattrs and
+    /// ACLs as `options` ask.
+    pub fn unpack_all_with_options(
+        &mut self,
+        to: impl AsRef<Path>,
+        options: &[ExtractOption],
+    ) -> Result<(), ArchiveError> {
+        let mut device_manifest = vec![];
+        let entries = self.entries()?;
+        let result = unpack_entries(entries, to.as_ref(), options, &mut device_manifest);
+        self.device_manifest.extend(device_manifest);
+        result
     }
 
     pub fn unpack_file(
         &mut self,
-        entry: &mut TarEntry<R>,
+        entry: &mut TarEntry<D>,
         to: impl AsRef<Path>,
     ) -> Result<(), ArchiveError> {
         let mut writer = std::fs::File::create(to)?;
-        let _ = std::io::copy(&mut entry.inner, &mut writer)?;
+        let _ = std::io::copy(entry, &mut writer)?;
         Ok(())
     }
 
-    pub fn create_with_reader(reader: impl Read) -> Result<TarArchive<impl Read>, ArchiveError> {
-        let archive = tar::Archive::new(reader);
-        Ok(TarArchive { inner: archive })
+    /// Devices that could not be created and were extracted as empty placeholder files.
+    pub fn device_manifest(&self) -> &[DeviceNode] {
+        &self.device_manifest
+    }
+
+    pub fn create_with_decoder(decoder: D) -> Result<CompressedTar<D>, ArchiveError> {
+        let extensions = Arc::new(Mutex::new(Extensions::default()));
+        let reader = Tap::new(decoder, extensions.clone());
+        Ok(CompressedTar {
+            inner: tar::Archive::new(reader),
+            extensions,
+            device_manifest: vec![],
+        })
     }
 
-    pub fn create_with_path(path: impl AsRef<Path>) -> Result<TarArchive<impl Read>, ArchiveError> {
-        let reader = std::fs::File::open(path)?;
+    pub fn create_with_reader<R: Read>(reader: R) -> Result<CompressedTar<D>, ArchiveError>
+    where
+        D: FromReader<R>,
+    {
+        let decoder = D::from_reader(reader)?;
+        Self::create_with_decoder(decoder)
+    }
+
+    pub fn create_with_path(path: impl AsRef<Path>) -> Result<CompressedTar<D>, ArchiveError>
+    where
+        D: FromReader<BufReader<File>>,
+    {
+        let reader = BufReader::new(std::fs::File::open(path)?);
         Self::create_with_reader(reader)
     }
 }
 
-impl<R> TarGzArchive<R>
-where
-    R: Read,
-{
-    pub fn entries(&mut self) -> std::io::Result<TarEntries<R>> {
-        let inner = self.inner.entries()?;
-        Ok(TarEntries { inner })
+impl<R: Read> TarZstdArchive<R> {
+    /// Opens a tarball whose zstd frames may need one of `dictionaries`, see
+    /// `ZstdBlock::create_with_dictionaries`.
+    pub fn create_with_dictionaries(
+        reader: R,
+        dictionaries: impl IntoIterator<Item = impl Into<Vec<u8>>>,
+    ) -> Result<TarZstdArchive<R>, ArchiveError> {
+        let decoder = ZstdBlock::create_with_dictionaries(reader, dictionaries)?;
+        Self::create_with_decoder(decoder)
     }
+}
 
-    pub fn unpack_all(&mut self, to: impl AsRef<Path>) -> Result<(), ArchiveError> {
-        self.inner.unpack(to).map_err(ArchiveError::Io)
+/// Where an entry of an uncompressed tarball lives, see [`TarIndex`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TarIndexEntry {
+    path: PathBuf,
+    file_type: FileType,
+    /// Target of a hard or symbolic link.
+    link: Option<PathBuf>,
+    mode: u32,
+    uid: u64,
+    gid: u64,
+    mtime: Timestamp,
+    header_offset: u64,
+    data_offset: u64,
+    stored_size: u64,
+    size: u64,
+    sparse: Option<Vec<(u64, u64)>>,
+}
+
+impl Entry for TarIndexEntry {
+    fn file_type(&self) -> FileType {
+        self.file_type
     }
 
-    pub fn unpack_file(
-        &mut self,
-        entry: &mut TarEntry<R>,
-        to: impl AsRef<Path>,
-    ) -> Result<(), ArchiveError> {
-        let mut writer = std::fs::File::create(to)?;
-        let _ = std::io::copy(&mut entry.inner, &mut writer)?;
+    fn hand_link(&self) -> Option<PathBuf> {
+        self.link
+            .clone()
+            .filter(|_| self.file_type == FileType::HardLink)
+    }
+
+    fn path_name(&self) -> std::io::Result<PathBuf> {
+        Ok(self.path.clone())
+    }
+
+    fn gid(&self) -> std::io::Result<Option<u64>> {
+        Ok(Some(self.gid))
+    }
+
+    fn uid(&self) -> std::io::Result<Option<u64>> {
+        Ok(Some(self.uid))
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn sym_link(&self) -> Option<PathBuf> {
+        self.link
+            .clone()
+            .filter(|_| self.file_type == FileType::SymbolicLink)
+    }
+}
+
+impl TarIndexEntry {
+    pub fn unix_mode(&self) -> u32 {
+        self.mode
+    }
+
+    /// Modification time, with the fractional seconds of a PAX header.
+    pub fn mtime(&self) -> Option<PrimitiveDateTime> {
+        unix_time(self.mtime)
+    }
+
+    /// Offset of the entry's own header, after any PAX or GNU long name headers for it.
+    pub fn header_offset(&self) -> u64 {
+        self.header_offset
+    }
+
+    /// Offset of the contents, past the map of a sparse file.
+    pub fn data_offset(&self) -> u64 {
+        self.data_offset
+    }
+
+    /// Bytes of contents in the archive, less than [`Entry::size`] for sparse files.
+    pub fn stored_size(&self) -> u64 {
+        self.stored_size
+    }
+
+    /// `(offset, length)` of the regions of a sparse file that hold data, stored back to back
+    /// from [`TarIndexEntry::data_offset`].
+    pub fn sparse_map(&self) -> Option<&[(u64, u64)]> {
+        self.sparse.as_deref()
+    }
+}
+
+pub struct TarIndexEntries {
+    current: usize,
+    total: usize,
+    inner: Vec<TarIndexEntry>,
+}
+
+impl Iterator for TarIndexEntries {
+    type Item = Result<TarIndexEntry, ArchiveError>;
+
+    fn next(&mut self) -> Option<Result<TarIndexEntry, ArchiveError>> {
+        if self.current >= self.total {
+            return None;
+        }
+
+        let entry = &self.inner[self.current];
+        self.current += 1;
+        Some(Ok(entry.to_owned()))
+    }
+}
+
+/// The offsets of every entry of an uncompressed tarball, built in one pass that seeks over
+/// the contents and saved next to the archive so [`IndexedTar`] never has to scan it again.
+///
+/// Offsets are relative to the first header of the archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TarIndex {
+    /// Bytes from the first header to the end of the source, to catch a stale index.
+    archive_size: u64,
+    entries: Vec<TarIndexEntry>,
+    /// Position in `entries` of every path, the last entry wins like it does on extraction.
+    paths: HashMap<PathBuf, usize>,
+}
+
+impl TarIndex {
+    /// Indexes the archive starting at the current position of `reader`.
+    pub fn build<R: Read + Seek>(mut reader: R) -> Result<TarIndex, ArchiveError> {
+        let base = reader.stream_position()?;
+        let archive_size = reader.seek(SeekFrom::End(0))? - base;
+        reader.seek(SeekFrom::Start(base))?;
+
+        let extensions = Arc::new(Mutex::new(Extensions::default()));
+        let window = Window {
+            inner: reader,
+            base,
+        };
+        let reader = Tap::new(window, extensions.clone());
+        let mut archive = tar::Archive::new(reader);
+        let entries = TarEntries {
+            inner: archive.entries_with_seek()?,
+            extensions,
+            globals: vec![],
+        };
+
+        let mut index = vec![];
+        for entry in entries {
+            let entry = entry?;
+            let header = entry.inner.header();
+            if header.entry_type() == tar::EntryType::XGlobalHeader {
+                continue;
+            }
+            let (uid, gid) = owner(&entry);
+            let (stored_size, map_size) = match &entry.sparse {
+                Some(sparse) => (
+                    sparse.regions.iter().map(|(_, length)| length).sum(),
+                    sparse.map_size,
+                ),
+                None => (entry.inner.size(), 0),
+            };
+            index.push(TarIndexEntry {
+                path: entry.path_name()?,
+                file_type: entry.file_type(),
+                link: entry.hand_link().or_else(|| entry.sym_link()),
+                mode: header.mode().unwrap_or(0) & 0o7777,
+                uid: uid.into(),
+                gid: gid.into(),
+                mtime: entry.times().1.unwrap_or((0, 0)),
+                header_offset: entry.inner.raw_header_position(),
+                data_offset: entry.inner.raw_file_position() + map_size,
+                stored_size,
+                size: entry.size(),
+                sparse: entry.sparse.as_ref().map(|sparse| sparse.regions.clone()),
+            });
+        }
+        Ok(TarIndex::new(archive_size, index))
+    }
+
+    /// Reads an index saved by [`TarIndex::write_to`].
+    pub fn read_from(mut reader: impl Read) -> Result<TarIndex, ArchiveError> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if magic != *TAR_INDEX_MAGIC {
+            return Err(ArchiveError::GenericsError("not a tar index"));
+        }
+        let version = read_u32(&mut reader)?;
+        if version != TAR_INDEX_VERSION {
+            return Err(ArchiveError::GenericsError2(format!(
+                "tar index version {version} is not supported"
+            )));
+        }
+
+        let archive_size = read_u64(&mut reader)?;
+        let count = read_u64(&mut reader)?;
+        let mut entries = vec![];
+        for _ in 0..count {
+            let file_type = file_type_from_code(read_u8(&mut reader)?)
+                .ok_or(ArchiveError::GenericsError("bad file type in tar index"))?;
+            let mode = read_u32(&mut reader)?;
+            let uid = read_u64(&mut reader)?;
+            let gid = read_u64(&mut reader)?;
+            let mtime = (read_u64(&mut reader)? as i64, read_u32(&mut reader)?);
+            let header_offset = read_u64(&mut reader)?;
+            let data_offset = read_u64(&mut reader)?;
+            let stored_size = read_u64(&mut reader)?;
+            let size = read_u64(&mut reader)?;
+            let path = read_path(&mut reader)?;
+            let link = match read_u8(&mut reader)? {
+                0 => None,
+                _ => Some(read_path(&mut reader)?),
+            };
+            let sparse = match read_u8(&mut reader)? {
+                0 => None,
+                _ => {
+                    let regions = read_u64(&mut reader)?;
+                    let mut sparse = vec![];
+                    for _ in 0..regions {
+                        sparse.push((read_u64(&mut reader)?, read_u64(&mut reader)?));
+                    }
+                    Some(sparse)
+                }
+            };
+            entries.push(TarIndexEntry {
+                path,
+                file_type,
+                link,
+                mode,
+                uid,
+                gid,
+                mtime,
+                header_offset,
+                data_offset,
+                stored_size,
+                size,
+                sparse,
+            });
+        }
+        Ok(TarIndex::new(archive_size, entries))
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<TarIndex, ArchiveError> {
+        let reader = BufReader::new(File::open(path)?);
+        TarIndex::read_from(reader)
+    }
+
+    /// Writes the index in a little endian binary form for [`TarIndex::read_from`].
+    pub fn write_to(&self, mut writer: impl Write) -> std::io::Result<()> {
+        writer.write_all(TAR_INDEX_MAGIC)?;
+        writer.write_all(&TAR_INDEX_VERSION.to_le_bytes())?;
+        writer.write_all(&self.archive_size.to_le_bytes())?;
+        writer.write_all(&(self.entries.len() as u64).to_le_bytes())?;
+        for entry in &self.entries {
+            writer.write_all(&[file_type_code(entry.file_type)])?;
+            writer.write_all(&entry.mode.to_le_bytes())?;
+            writer.write_all(&entry.uid.to_le_bytes())?;
+            writer.write_all(&entry.gid.to_le_bytes())?;
+            writer.write_all(&entry.mtime.0.to_le_bytes())?;
+            writer.write_all(&entry.mtime.1.to_le_bytes())?;
+            writer.write_all(&entry.header_offset.to_le_bytes())?;
+            writer.write_all(&entry.data_offset.to_le_bytes())?;
+            writer.write_all(&entry.stored_size.to_le_bytes())?;
+            writer.write_all(&entry.size.to_le_bytes())?;
+            write_path(&mut writer, &entry.path)?;
+            match &entry.link {
+                Some(link) => {
+                    writer.write_all(&[1])?;
+                    write_path(&mut writer, link)?;
+                }
+                None => writer.write_all(&[0])?,
+            }
+            match &entry.sparse {
+                Some(sparse) => {
+                    writer.write_all(&[1])?;
+                    writer.write_all(&(sparse.len() as u64).to_le_bytes())?;
+                    for (offset, length) in sparse {
+                        writer.write_all(&offset.to_le_bytes())?;
+                        writer.write_all(&length.to_le_bytes())?;
+                    }
+                }
+                None => writer.write_all(&[0])?,
+            }
+        }
         Ok(())
     }
 
-    pub fn create_with_reader(reader: impl Read) -> Result<TarGzArchive<impl Read>, ArchiveError> {
-        let reader = flate2::read::GzDecoder::new(reader);
-        let archive = tar::Archive::new(reader);
-        Ok(TarGzArchive { inner: archive })
+    pub fn save(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_to(&mut writer)?;
+        writer.flush()
     }
 
-    pub fn create_with_path(
-        path: impl AsRef<Path>,
-    ) -> Result<TarGzArchive<impl Read>, ArchiveError> {
-        let reader = std::fs::File::open(path)?;
-        Self::create_with_reader(reader)
+    /// The entry extracted at `path`, where `./` prefixes and trailing slashes do not matter.
+    pub fn get(&self, path: impl AsRef<Path>) -> Option<&TarIndexEntry> {
+        let index = self.paths.get(&index_key(path.as_ref()))?;
+        Some(&self.entries[*index])
+    }
+
+    pub fn entries(&self) -> TarIndexEntries {
+        TarIndexEntries {
+            current: 0,
+            total: self.entries.len(),
+            inner: self.entries.clone(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn new(archive_size: u64, entries: Vec<TarIndexEntry>) -> TarIndex {
+        let paths = entries
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| (index_key(&entry.path), index))
+            .collect();
+        TarIndex {
+            archive_size,
+            entries,
+            paths,
+        }
     }
 }
 
-impl<R> TarBz2Archive<R>
+/// An uncompressed tarball on a seekable source, opening files through a [`TarIndex`].
+///
+/// Compressed tarballs cannot be indexed since their streams cannot be entered midway.
+pub struct IndexedTar<R: Read + Seek> {
+    inner: R,
+    /// Position of the first header in `inner`.
+    base: u64,
+    index: TarIndex,
+    /// Devices extracted as placeholders.
+    device_manifest: Vec<DeviceNode>,
+}
+
+impl<R> IndexedTar<R>
 where
-    R: Read,
+    R: Read + Seek,
 {
-    pub fn entries(&mut self) -> std::io::Result<TarEntries<R>> {
-        let inner = self.inner.entries()?;
-        Ok(TarEntries { inner })
+    /// Indexes the archive starting at the current position of `reader`.
+    pub fn create_with_reader(mut reader: R) -> Result<IndexedTar<R>, ArchiveError> {
+        let base = reader.stream_position()?;
+        let index = TarIndex::build(&mut reader)?;
+        Ok(IndexedTar {
+            inner: reader,
+            base,
+            index,
+            device_manifest: vec![],
+        })
     }
 
-    pub fn unpack_all(&mut self, to: impl AsRef<Path>) -> Result<(), ArchiveError> {
-        self.inner.unpack(to).map_err(ArchiveError::Io)
+    /// Opens the archive starting at the current position of `reader` with an index built
+    /// for it earlier, failing if the archive changed size since.
+    pub fn create_with_index(
+        mut reader: R,
+        index: TarIndex,
+    ) -> Result<IndexedTar<R>, ArchiveError> {
+        let base = reader.stream_position()?;
+        let archive_size = reader.seek(SeekFrom::End(0))? - base;
+        if archive_size != index.archive_size {
+            return Err(ArchiveError::GenericsError(
+                "tar index was built for another archive",
+            ));
+        }
+        Ok(IndexedTar {
+            inner: reader,
+            base,
+            index,
+            device_manifest: vec![],
+        })
+    }
+
+    pub fn index(&self) -> &TarIndex {
+        &self.index
+    }
+
+    pub fn entries(&mut self) -> Result<TarIndexEntries, ArchiveError> {
+        Ok(self.index.entries())
+    }
+
+    /// Opens the contents of the file at `path`, or of the file a hard link there points to.
+    pub fn open_file(&mut self, path: impl AsRef<Path>) -> Result<impl Read + '_, ArchiveError> {
+        let path = path.as_ref();
+        let mut entry = self.index.get(path);
+        if let Some(target) = entry.and_then(|entry| entry.hand_link()) {
+            entry = self.index.get(target);
+        }
+        let Some(entry) = entry else {
+            return Err(ArchiveError::GenericsError2(format!(
+                "{} is not in the tar index",
+                path.display()
+            )));
+        };
+        if entry.file_type != FileType::RegularFile {
+            return Err(ArchiveError::GenericsError2(format!(
+                "{} is not a regular file",
+                path.display()
+            )));
+        }
+
+        let sparse = entry.sparse.clone().map(|regions| Sparse {
+            regions,
+            real_size: entry.size,
+            packed: true,
+            map_size: 0,
+            position: 0,
+        });
+        let stored_size = entry.stored_size;
+        self.inner
+            .seek(SeekFrom::Start(self.base + entry.data_offset))?;
+        Ok(IndexedFile {
+            data: (&mut self.inner).take(stored_size),
+            sparse,
+        })
     }
 
     pub fn unpack_file(
         &mut self,
-        entry: &mut TarEntry<R>,
+        path: impl AsRef<Path>,
         to: impl AsRef<Path>,
     ) -> Result<(), ArchiveError> {
+        let mut reader = self.open_file(path)?;
         let mut writer = std::fs::File::create(to)?;
-        let _ = std::io::copy(&mut entry.inner, &mut writer)?;
+        let _ = std::io::copy(&mut reader, &mut writer)?;
         Ok(())
     }
 
-    pub fn create_with_reader(reader: impl Read) -> Result<TarBz2Archive<impl Read>, ArchiveError> {
-        let reader = BzDecoder::new(reader);
-        let archive = tar::Archive::new(reader);
-        Ok(TarBz2Archive { inner: archive })
+    /// Extracts everything below `to`, keeping modification times as
+    /// `unpack_all_with_options(to, &[ExtractOption::Time])` does.
+    pub fn unpack_all(&mut self, to: impl AsRef<Path>) -> Result<(), ArchiveError> {
+        self.unpack_all_with_options(to, &[ExtractOption::Time])
     }
 
+    /// Extracts everything below `to` in one sequential pass, see
+    /// [`TarArchive::unpack_all_with_options`].
+    pub fn unpack_all_with_options(
+        &mut self,
+        to: impl AsRef<Path>,
+        options: &[ExtractOption],
+    ) -> Result<(), ArchiveError> {
+        self.inner.seek(SeekFrom::Start(self.base))?;
+        let mut archive = TarArchive::<&mut R>::create_with_reader(&mut self.inner)?;
+        let result = archive.unpack_all_with_options(to, options);
+        self.device_manifest.extend(archive.device_manifest);
+        result
+    }
+
+    /// Devices that could not be created and were extracted as empty placeholder files.
+    pub fn device_manifest(&self) -> &[DeviceNode] {
+        &self.device_manifest
+    }
+
+    pub fn into_index(self) -> TarIndex {
+        self.index
+    }
+}
+
+impl IndexedTar<BufReader<File>> {
     pub fn create_with_path(
         path: impl AsRef<Path>,
-    ) -> Result<TarBz2Archive<impl Read>, ArchiveError> {
-        let reader = std::fs::File::open(path)?;
+    ) -> Result<IndexedTar<BufReader<File>>, ArchiveError> {
+        let reader = BufReader::new(File::open(path)?);
         Self::create_with_reader(reader)
     }
 }
 
-impl<R> TarZstdArchive<R>
+/// The contents of one file of an [`IndexedTar`].
+struct IndexedFile<R> {
+    data: std::io::Take<R>,
+    sparse: Option<Sparse>,
+}
+
+impl<R: Read> Read for IndexedFile<R> {
+    fn read(&mut self, into: &mut [u8]) -> std::io::Result<usize> {
+        match &mut self.sparse {
+            Some(sparse) => sparse.read(&mut self.data, into),
+            None => self.data.read(into),
+        }
+    }
+}
+
+/// `inner` from `base` on, so the tar reader sees the archive start at 0 when it seeks.
+struct Window<R> {
+    inner: R,
+    base: u64,
+}
+
+impl<R: Read> Read for Window<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<R: Seek> Seek for Window<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(start) => SeekFrom::Start(self.base + start),
+            pos => pos,
+        };
+        let position = self.inner.seek(pos)?;
+        position.checked_sub(self.base).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "seek before the start of the tar archive",
+            )
+        })
+    }
+}
+
+const TAR_INDEX_MAGIC: &[u8; 8] = b"XTARIDX\0";
+const TAR_INDEX_VERSION: u32 = 1;
+
+/// `path` without `.` components, the way lookups in a [`TarIndex`] compare paths.
+fn index_key(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| *component != Component::CurDir)
+        .collect()
+}
+
+fn file_type_code(file_type: FileType) -> u8 {
+    match file_type {
+        FileType::RegularFile => 0,
+        FileType::HardLink => 1,
+        FileType::SymbolicLink => 2,
+        FileType::CharacterDevice => 3,
+        FileType::BlockDevice => 4,
+        FileType::Directory => 5,
+        FileType::NamedPipe => 6,
+        FileType::Socket => 7,
+        FileType::Mount => 8,
+        FileType::Other => 9,
+    }
+}
+
+fn file_type_from_code(code: u8) -> Option<FileType> {
+    let file_type = match code {
+        0 => FileType::RegularFile,
+        1 => FileType::HardLink,
+        2 => FileType::SymbolicLink,
+        3 => FileType::CharacterDevice,
+        4 => FileType::BlockDevice,
+        5 => FileType::Directory,
+        6 => FileType::NamedPipe,
+        7 => FileType::Socket,
+        8 => FileType::Mount,
+        9 => FileType::Other,
+        _ => return None,
+    };
+    Some(file_type)
+}
+
+fn read_u8(reader: &mut impl Read) -> std::io::Result<u8> {
+    let mut bytes = [0; 1];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_u32(reader: &mut impl Read) -> std::io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> std::io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_path(reader: &mut impl Read) -> std::io::Result<PathBuf> {
+    let size = read_u32(reader)?;
+    let mut bytes = vec![];
+    reader.take(size.into()).read_to_end(&mut bytes)?;
+    if bytes.len() != size as usize {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    cfg_if::cfg_if! {
+        if #[cfg(unix)] {
+            use std::os::unix::ffi::OsStringExt;
+            Ok(PathBuf::from(std::ffi::OsString::from_vec(bytes)))
+        } else {
+            Ok(PathBuf::from(String::from_utf8_lossy(&bytes).into_owned()))
+        }
+    }
+}
+
+fn write_path(writer: &mut impl Write, path: &Path) -> std::io::Result<()> {
+    cfg_if::cfg_if! {
+        if #[cfg(unix)] {
+            use std::os::unix::ffi::OsStrExt;
+            let bytes = path.as_os_str().as_bytes();
+        } else {
+            let path = path.to_string_lossy();
+            let bytes = path.as_bytes();
+        }
+    }
+    let size = u32::try_from(bytes.len())
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "path too long"))?;
+    writer.write_all(&size.to_le_bytes())?;
+    writer.write_all(bytes)
+}
+
+/// Header layout written by [`TarWriter`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TarFormat {
+    /// POSIX.1-1988, paths up to 255 bytes and no sparse files.
+    Ustar,
+    /// GNU tar, long names in `L` and `K` entries and old GNU sparse headers.
+    Gnu,
+    /// POSIX.1-2001, long names and large numbers in PAX records and PAX 1.0 sparse files.
+    Pax,
+}
+
+/// Settings for [`TarWriter`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TarWriteOptions {
+    pub format: TarFormat,
+    /// Owner of every entry, replacing the one from the source.
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Owner names of every entry, none are written without them.
+    pub username: Option<String>,
+    pub groupname: Option<String>,
+    /// Modification time of every entry, replacing the one from the source.
+    ///
+    /// Together with the owner and [`TarWriteOptions::normalize_mode`] the archive depends on
+    /// nothing but the contents of its input. Directories are always walked sorted by name.
+    pub mtime: Option<u64>,
+    /// Writes directories and executables as 0o755 and everything else as 0o644.
+    pub normalize_mode: bool,
+    /// Stores runs of zero blocks in host files as holes, ignored for ustar.
+    pub sparse: bool,
+}
+
+impl Default for TarWriteOptions {
+    fn default() -> Self {
+        TarWriteOptions {
+            format: TarFormat::Pax,
+            uid: None,
+            gid: None,
+            username: None,
+            groupname: None,
+            mtime: None,
+            normalize_mode: false,
+            sparse: false,
+        }
+    }
+}
+
+pub type TarGzWriter<W> = TarWriter<GzipEncoder<W>>;
+pub type TarBz2Writer<W> = TarWriter<Bzip2Encoder<W>>;
+pub type TarXzWriter<W> = TarWriter<XzEncoder<W>>;
+pub type TarZstdWriter<W> = TarWriter<ZstdEncoder<W>>;
+
+/// Writes a tarball from a host directory or the entries of another archive.
+///
+/// Entries are written as they are pushed, wrap `W` in one of the `block/` encoders for a
+/// compressed tarball.
+pub struct TarWriter<W: Write> {
+    inner: tar::Builder<W>,
+    options: TarWriteOptions,
+    /// Path of the first host file seen for every device and inode with several links.
+    links: HashMap<(u64, u64), PathBuf>,
+}
+
+/// One entry as it goes into the archive, before [`TarWriteOptions`] apply.
+struct TarNode {
+    path: PathBuf,
+    entry_type: tar::EntryType,
+    mode: u32,
+    uid: u64,
+    gid: u64,
+    mtime: u64,
+    /// Size of the contents, expanded for sparse files.
+    size: u64,
+    link: Option<PathBuf>,
+    device: Option<(u32, u32)>,
+    /// Data regions of a sparse file, the contents hold them back to back.
+    sparse: Option<Vec<(u64, u64)>>,
+}
+
+impl TarNode {
+    fn new(path: PathBuf, entry_type: tar::EntryType, mode: u32) -> TarNode {
+        TarNode {
+            path,
+            entry_type,
+            mode,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+            size: 0,
+            link: None,
+            device: None,
+            sparse: None,
+        }
+    }
+}
+
+impl<W> TarWriter<W>
 where
-    R: Read,
+    W: Write,
 {
-    pub fn entries(&mut self) -> std::io::Result<TarEntries<R>> {
-        let inner = self.inner.entries()?;
-        Ok(TarEntries { inner })
+    pub fn create_with_writer(
+        writer: W,
+        options: TarWriteOptions,
+    ) -> Result<TarWriter<W>, ArchiveError> {
+        Ok(TarWriter {
+            inner: tar::Builder::new(writer),
+            options,
+            links: HashMap::new(),
+        })
     }
 
-    pub fn unpack_all(&mut self, to: impl AsRef<Path>) -> Result<(), ArchiveError> {
-        self.inner.unpack(to).map_err(ArchiveError::Io)
+    /// Adds everything below the host directory `dir`, with paths relative to it.
+    ///
+    /// Files with several links become hard links to the first of their paths. Sockets are
+    /// skipped, tar cannot store them.
+    pub fn push_dir_tree(&mut self, dir: impl AsRef<Path>) -> Result<(), ArchiveError> {
+        let dir = dir.as_ref();
+        let mut stack = vec![];
+        let children = |parent: &Path, stack: &mut Vec<PathBuf>| -> std::io::Result<()> {
+            let mut names = std::fs::read_dir(dir.join(parent))?
+                .map(|child| child.map(|child| child.file_name()))
+                .collect::<Result<Vec<_>, _>>()?;
+            names.sort();
+            // popped in reverse, so everything is written in order with parents first
+            stack.extend(names.iter().rev().map(|name| parent.join(name)));
+            Ok(())
+        };
+
+        children(Path::new(""), &mut stack)?;
+        while let Some(path) = stack.pop() {
+            let host_path = dir.join(&path);
+            let metadata = std::fs::symlink_metadata(&host_path)?;
+            if metadata.is_dir() {
+                children(&path, &mut stack)?;
+            }
+            self.push_host_node(path, &host_path, &metadata)?;
+        }
+        Ok(())
     }
 
-    pub fn unpack_file(
+    /// Adds `entry` of another archive, with the permission bits `mode`, the modification time
+    /// `mtime` in seconds since the epoch and the file data read from `contents`.
+    ///
+    /// Devices carry no numbers in [`Entry`], add them with [`TarWriter::push_device`].
+    pub fn push_entry(
         &mut self,
-        entry: &mut TarEntry<R>,
-        to: impl AsRef<Path>,
+        entry: &impl Entry,
+        mode: u32,
+        mtime: u64,
+        contents: impl Read,
     ) -> Result<(), ArchiveError> {
-        let mut writer = std::fs::File::create(to)?;
-        let _ = std::io::copy(&mut entry.inner, &mut writer)?;
-        Ok(())
+        let path = entry.path_name()?;
+        let (entry_type, link) = match entry.file_type() {
+            FileType::Directory => (tar::EntryType::Directory, None),
+            FileType::RegularFile => (tar::EntryType::Regular, None),
+            FileType::HardLink => (tar::EntryType::Link, entry.hand_link()),
+            FileType::SymbolicLink => (tar::EntryType::Symlink, entry.sym_link()),
+            FileType::NamedPipe => (tar::EntryType::Fifo, None),
+            _ => {
+                log::info!("[-] {} is a special file, skipped", path.display());
+                return Ok(());
+            }
+        };
+
+        let mut node = TarNode::new(path, entry_type, mode);
+        node.uid = entry.uid()?.unwrap_or(0);
+        node.gid = entry.gid()?.unwrap_or(0);
+        node.mtime = mtime;
+        node.link = link;
+        if entry_type == tar::EntryType::Regular {
+            node.size = entry.size();
+        }
+        self.append(node, contents)
+    }
+
+    /// Adds a device, e.g. one from the manifest of an extraction without privileges.
+    pub fn push_device(&mut self, device: &DeviceNode) -> Result<(), ArchiveError> {
+        let entry_type = match device.file_type() {
+            FileType::BlockDevice => tar::EntryType::Block,
+            _ => tar::EntryType::Char,
+        };
+        let mut node = TarNode::new(device.path().to_path_buf(), entry_type, device.mode());
+        node.uid = device.uid().into();
+        node.gid = device.gid().into();
+        node.device = Some((device.major(), device.minor()));
+        self.append(node, std::io::empty())
     }
 
-    pub fn create_with_reader(
-        reader: impl Read,
-    ) -> Result<TarZstdArchive<impl Read>, ArchiveError> {
-        let reader = ZstdDecoder::new(reader)?;
-        let archive = tar::Archive::new(reader);
-        Ok(TarZstdArchive { inner: archive })
+    /// Writes the end of archive marker and returns the writer.
+    pub fn finish(self) -> Result<W, ArchiveError> {
+        let writer = self.inner.into_inner()?;
+        Ok(writer)
+    }
+
+    fn push_host_node(
+        &mut self,
+        path: PathBuf,
+        host_path: &Path,
+        metadata: &std::fs::Metadata,
+    ) -> Result<(), ArchiveError> {
+        let (mode, uid, gid, mtime) = host_metadata(metadata);
+        let file_type = metadata.file_type();
+        let entry_type = if file_type.is_dir() {
+            tar::EntryType::Directory
+        } else if file_type.is_symlink() {
+            tar::EntryType::Symlink
+        } else if file_type.is_file() {
+            tar::EntryType::Regular
+        } else {
+            match host_special(metadata) {
+                Some((entry_type, device)) => {
+                    let mut node = TarNode::new(path, entry_type, mode);
+                    (node.uid, node.gid, node.mtime) = (uid, gid, mtime);
+                    node.device = device;
+                    return self.append(node, std::io::empty());
+                }
+                None => {
+                    log::info!("[-] {} is a special file, skipped", host_path.display());
+                    return Ok(());
+                }
+            }
+        };
+
+        let mut node = TarNode::new(path, entry_type, mode);
+        (node.uid, node.gid, node.mtime) = (uid, gid, mtime);
+        match entry_type {
+            tar::EntryType::Symlink => {
+                node.link = Some(std::fs::read_link(host_path)?);
+                self.append(node, std::io::empty())
+            }
+            tar::EntryType::Regular => {
+                if let Some(key) = host_link_key(metadata) {
+                    if let Some(first) = self.links.get(&key) {
+                        node.entry_type = tar::EntryType::Link;
+                        node.link = Some(first.clone());
+                        return self.append(node, std::io::empty());
+                    }
+                    self.links.insert(key, node.path.clone());
+                }
+
+                let mut file = File::open(host_path)?;
+                node.size = metadata.len();
+                if self.options.sparse && self.options.format != TarFormat::Ustar {
+                    if let Some(regions) = host_regions(&mut file, node.size)? {
+                        node.sparse = Some(regions.clone());
+                        let data = RegionReader {
+                            inner: file,
+                            regions: regions.into_iter(),
+                            remaining: 0,
+                        };
+                        return self.append(node, data);
+                    }
+                    file.seek(SeekFrom::Start(0))?;
+                }
+                self.append(node, file)
+            }
+            _ => self.append(node, std::io::empty()),
+        }
+    }
+
+    fn append(&mut self, node: TarNode, contents: impl Read) -> Result<(), ArchiveError> {
+        let options = &self.options;
+        let format = options.format;
+        let ustar_error = |what: &str| {
+            ArchiveError::GenericsError2(format!(
+                "{} of {} does not fit in a ustar header",
+                what,
+                node.path.display()
+            ))
+        };
+        let archive_path = node
+            .path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+        if !archive_path || node.path.as_os_str().is_empty() {
+            return Err(ArchiveError::GenericsError2(format!(
+                "refusing to write {}, archive paths must be relative and without `..`",
+                node.path.display()
+            )));
+        }
+
+        let mut header = match format {
+            TarFormat::Gnu => tar::Header::new_gnu(),
+            TarFormat::Ustar | TarFormat::Pax => tar::Header::new_ustar(),
+        };
+        let mut pax: Vec<(&str, Vec<u8>)> = vec![];
+        let mut long_names = vec![];
+        header.set_entry_type(node.entry_type);
+        header.set_mode(match options.normalize_mode {
+            true => normalized_mode(node.entry_type, node.mode),
+            false => node.mode & 0o7777,
+        });
+
+        let mut path = node.path.clone();
+        // what the header announces and what `contents` hold, they differ for sparse files
+        let mut size = node.size;
+        let mut stored = node.size;
+        // the extension blocks or map of a sparse file written ahead of the contents
+        let mut map = vec![];
+        if let Some(regions) = &node.sparse {
+            stored = regions.iter().map(|(_, length)| length).sum();
+            match format {
+                TarFormat::Gnu => {
+                    header.set_entry_type(tar::EntryType::GNUSparse);
+                    map = gnu_sparse_map(&mut header, regions, node.size)?;
+                    size = stored;
+                }
+                TarFormat::Pax => {
+                    pax.push(("GNU.sparse.major", b"1".to_vec()));
+                    pax.push(("GNU.sparse.minor", b"0".to_vec()));
+                    pax.push(("GNU.sparse.name", path_bytes(&node.path)));
+                    pax.push(("GNU.sparse.realsize", node.size.to_string().into_bytes()));
+                    map = pax_sparse_map(regions);
+                    size = map.len() as u64 + stored;
+                    // what readers without sparse support extract the packed data to
+                    let name = node.path.file_name().unwrap_or_default();
+                    path = node.path.with_file_name("GNUSparseFile.0").join(name);
+                }
+                TarFormat::Ustar => {
+                    return Err(ArchiveError::GenericsError(
+                        "ustar archives cannot hold sparse files",
+                    ))
+                }
+            }
+        }
+
+        let uid = options.uid.map_or(node.uid, u64::from);
+        let gid = options.gid.map_or(node.gid, u64::from);
+        let mtime = options.mtime.unwrap_or(node.mtime);
+        header.set_uid(uid);
+        header.set_gid(gid);
+        header.set_mtime(mtime);
+        header.set_size(size);
+        let numbers = [
+            ("uid", uid, MAX_OCTAL_ID),
+            ("gid", gid, MAX_OCTAL_ID),
+            ("mtime", mtime, MAX_OCTAL_NUMBER),
+            ("size", size, MAX_OCTAL_NUMBER),
+        ];
+        for (key, value, max) in numbers.into_iter().filter(|(_, value, max)| value > max) {
+            match format {
+                TarFormat::Ustar => return Err(ustar_error(key)),
+                TarFormat::Pax => pax.push((key, value.to_string().into_bytes())),
+                // GNU headers store them in base-256
+                TarFormat::Gnu => log::debug!("{} {} is above {}", key, value, max),
+            }
+        }
+
+        if header.set_path(&path).is_err() {
+            let bytes = path_bytes(&path);
+            match format {
+                TarFormat::Ustar => return Err(ustar_error("path")),
+                TarFormat::Gnu => long_names.push((tar::EntryType::GNULongName, bytes.clone())),
+                TarFormat::Pax => pax.push(("path", bytes.clone())),
+            }
+            let name = &mut header.as_old_mut().name;
+            let size = bytes.len().min(name.len());
+            name[..size].copy_from_slice(&bytes[..size]);
+        }
+        if let Some(link) = &node.link {
+            if header.set_link_name(link).is_err() {
+                let bytes = path_bytes(link);
+                match format {
+                    TarFormat::Ustar => return Err(ustar_error("link")),
+                    TarFormat::Gnu => long_names.push((tar::EntryType::GNULongLink, bytes.clone())),
+                    TarFormat::Pax => pax.push(("linkpath", bytes.clone())),
+                }
+                let name = &mut header.as_old_mut().linkname;
+                let size = bytes.len().min(name.len());
+                name[..size].copy_from_slice(&bytes[..size]);
+            }
+        }
+        let names = [
+            ("uname", options.username.as_deref()),
+            ("gname", options.groupname.as_deref()),
+        ];
+        for (key, name) in names {
+            let Some(name) = name else {
+                continue;
+            };
+            let set = match key {
+                "uname" => header.set_username(name),
+                _ => header.set_groupname(name),
+            };
+            if set.is_err() {
+                match format {
+                    TarFormat::Pax => pax.push((key, name.as_bytes().to_vec())),
+                    _ => {
+                        return Err(ArchiveError::GenericsError2(format!(
+                            "owner name {} is too long for the tar header",
+                            name
+                        )))
+                    }
+                }
+            }
+        }
+        if let Some((major, minor)) = node.device {
+            header.set_device_major(major)?;
+            header.set_device_minor(minor)?;
+        }
+
+        for (entry_type, bytes) in long_names {
+            let mut long = tar::Header::new_gnu();
+            long.as_old_mut().name[..13].copy_from_slice(b"././@LongLink");
+            long.set_entry_type(entry_type);
+            long.set_mode(0o644);
+            long.set_size(bytes.len() as u64 + 1);
+            long.set_cksum();
+            self.inner.append(&long, bytes.chain(&[0][..]))?;
+        }
+        if !pax.is_empty() {
+            let records = pax.iter().map(|(key, value)| (*key, value.as_slice()));
+            self.inner.append_pax_extensions(records)?;
+        }
+        header.set_cksum();
+        let data = Exact {
+            inner: contents.take(stored),
+            remaining: stored,
+        };
+        self.inner.append(&header, map.as_slice().chain(data))?;
+        Ok(())
     }
+}
 
+impl TarWriter<File> {
     pub fn create_with_path(
         path: impl AsRef<Path>,
-    ) -> Result<TarZstdArchive<impl Read>, ArchiveError> {
-        let reader = std::fs::File::open(path)?;
-        Self::create_with_reader(reader)
+        options: TarWriteOptions,
+    ) -> Result<Self, ArchiveError> {
+        let writer = std::fs::File::create(path)?;
+        Self::create_with_writer(writer, options)
+    }
+}
+
+impl<'a> TarWriter<Box<dyn BlockEncoder + 'a>> {
+    /// Writes the tarball through the encoder for `format`, see `create_encoder`.
+    pub fn create_with_encoder(
+        format: BlockFormat,
+        writer: impl Write + 'a,
+        level: Option<u32>,
+        options: TarWriteOptions,
+    ) -> Result<Self, ArchiveError> {
+        let encoder = create_encoder(format, writer, level)?;
+        Self::create_with_writer(encoder, options)
+    }
+
+    /// Writes the end of archive marker and terminates the compressed stream.
+    pub fn close(self) -> Result<(), ArchiveError> {
+        self.finish()?.close()
+    }
+}
+
+/// Writes the regions of an old GNU sparse file into `header` and returns the extension
+/// blocks for those that do not fit.
+fn gnu_sparse_map(
+    header: &mut tar::Header,
+    regions: &[(u64, u64)],
+    real_size: u64,
+) -> std::io::Result<Vec<u8>> {
+    let set = |blocks: &mut [tar::GnuSparseHeader], regions: &[(u64, u64)]| {
+        for (block, (offset, length)) in blocks.iter_mut().zip(regions) {
+            block.set_offset(*offset);
+            block.set_length(*length);
+        }
+    };
+
+    let gnu = header
+        .as_gnu_mut()
+        .ok_or_else(|| std::io::Error::other("sparse files need a GNU header"))?;
+    let (head, rest) = regions.split_at(regions.len().min(gnu.sparse.len()));
+    set(&mut gnu.sparse, head);
+    gnu.set_real_size(real_size);
+    gnu.set_is_extended(!rest.is_empty());
+
+    let mut map = vec![];
+    let mut chunks = rest.chunks(GNU_EXT_SPARSE_REGIONS).peekable();
+    while let Some(chunk) = chunks.next() {
+        let mut ext = tar::GnuExtSparseHeader::new();
+        set(ext.sparse_mut(), chunk);
+        ext.set_is_extended(chunks.peek().is_some());
+        map.extend_from_slice(ext.as_bytes());
+    }
+    Ok(map)
+}
+
+/// The map of a PAX 1.0 sparse file: the count and the pairs, one decimal number per line,
+/// padded to a block.
+fn pax_sparse_map(regions: &[(u64, u64)]) -> Vec<u8> {
+    let mut map = format!("{}\n", regions.len());
+    for (offset, length) in regions {
+        map.push_str(&format!("{}\n{}\n", offset, length));
+    }
+    let mut map = map.into_bytes();
+    map.resize(map.len().div_ceil(BLOCK_SIZE) * BLOCK_SIZE, 0);
+    map
+}
+
+/// Data regions of a host file in whole blocks, `None` when it has no block of zeros.
+fn host_regions(file: &mut File, size: u64) -> std::io::Result<Option<Vec<(u64, u64)>>> {
+    let mut reader = BufReader::new(file);
+    let mut regions: Vec<(u64, u64)> = vec![];
+    let mut block = [0; BLOCK_SIZE];
+    let mut offset = 0;
+    while offset < size {
+        let length = (size - offset).min(BLOCK_SIZE as u64);
+        reader.read_exact(&mut block[..length as usize])?;
+        if block[..length as usize].iter().any(|byte| *byte != 0) {
+            match regions.last_mut() {
+                Some((start, known)) if *start + *known == offset => *known += length,
+                _ => regions.push((offset, length)),
+            }
+        }
+        offset += length;
+    }
+
+    let stored: u64 = regions.iter().map(|(_, length)| length).sum();
+    if stored == size {
+        return Ok(None);
+    }
+    // GNU tar takes the end of the last region as the size, so a trailing hole gets marked
+    if regions.last().map_or(0, |(offset, length)| offset + length) < size {
+        regions.push((size, 0));
+    }
+    Ok(Some(regions))
+}
+
+/// Reads the regions of a file back to back.
+struct RegionReader<R> {
+    inner: R,
+    regions: std::vec::IntoIter<(u64, u64)>,
+    /// Bytes left in the current region.
+    remaining: u64,
+}
+
+impl<R: Read + Seek> Read for RegionReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.remaining == 0 {
+            let Some((offset, length)) = self.regions.next() else {
+                return Ok(0);
+            };
+            self.inner.seek(SeekFrom::Start(offset))?;
+            self.remaining = length;
+        }
+        let size = buf.len().min(self.remaining as usize);
+        let read = self.inner.read(&mut buf[..size])?;
+        if read == 0 && size != 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+/// Fails instead of ending early, so the contents always match the size in the header.
+struct Exact<R> {
+    inner: std::io::Take<R>,
+    remaining: u64,
+}
+
+impl<R: Read> Read for Exact<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if read == 0 && self.remaining != 0 && !buf.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "entry contents are shorter than its size",
+            ));
+        }
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+/// Largest number in the 7 octal digits of a ustar id field.
+const MAX_OCTAL_ID: u64 = 0o7777777;
+/// Largest number in the 11 octal digits of a ustar size or time field.
+const MAX_OCTAL_NUMBER: u64 = 0o77777777777;
+/// Regions in a GNU sparse extension block.
+const GNU_EXT_SPARSE_REGIONS: usize = 21;
+
+fn normalized_mode(entry_type: tar::EntryType, mode: u32) -> u32 {
+    match entry_type {
+        tar::EntryType::Directory => 0o755,
+        tar::EntryType::Symlink => 0o777,
+        _ if mode & 0o111 != 0 => 0o755,
+        _ => 0o644,
+    }
+}
+
+fn path_bytes(path: &Path) -> Vec<u8> {
+    cfg_if::cfg_if! {
+        if #[cfg(unix)] {
+            use std::os::unix::ffi::OsStrExt;
+            path.as_os_str().as_bytes().to_vec()
+        } else {
+            path.to_string_lossy().replace('\\', "/").into_bytes()
+        }
+    }
+}
+
+/// Permission bits, owner and modification time of a host file.
+fn host_metadata(metadata: &std::fs::Metadata) -> (u32, u64, u64, u64) {
+    cfg_if::cfg_if! {
+        if #[cfg(unix)] {
+            use std::os::unix::fs::MetadataExt;
+
+            let mtime = metadata.mtime().max(0) as u64;
+            (metadata.mode() & 0o7777, metadata.uid().into(), metadata.gid().into(), mtime)
+        } else {
+            let mode = if metadata.is_dir() { 0o755 } else { 0o644 };
+            let mtime = metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+                .map_or(0, |time| time.as_secs());
+            (mode, 0, 0, mtime)
+        }
+    }
+}
+
+/// Entry type and device numbers of a host device node or named pipe.
+fn host_special(metadata: &std::fs::Metadata) -> Option<(tar::EntryType, Option<(u32, u32)>)> {
+    cfg_if::cfg_if! {
+        if #[cfg(unix)] {
+            use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+            let file_type = metadata.file_type();
+            let rdev = metadata.rdev() as libc::dev_t;
+            let device = Some((libc::major(rdev) as u32, libc::minor(rdev) as u32));
+            if file_type.is_block_device() {
+                Some((tar::EntryType::Block, device))
+            } else if file_type.is_char_device() {
+                Some((tar::EntryType::Char, device))
+            } else if file_type.is_fifo() {
+                Some((tar::EntryType::Fifo, None))
+            } else {
+                None
+            }
+        } else {
+            let _ = metadata;
+            None
+        }
+    }
+}
+
+/// Device and inode of a host file with more than one link.
+fn host_link_key(metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    cfg_if::cfg_if! {
+        if #[cfg(unix)] {
+            use std::os::unix::fs::MetadataExt;
+
+            Some((metadata.dev(), metadata.ino())).filter(|_| metadata.nlink() > 1)
+        } else {
+            let _ = metadata;
+            None
+        }
     }
 }
@@ -0,0 +1,401 @@
+use std::collections::BTreeMap;
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::archive::ubifs::UbifsArchive;
+use crate::archive::{Entry, FileType};
+use crate::utils::bytes::{be32, be64};
+use crate::utils::error::ArchiveError;
+
+const UBI_EC_HDR_MAGIC: &[u8; 4] = b"UBI#";
+const UBI_VID_HDR_MAGIC: &[u8; 4] = b"UBI!";
+const UBI_HDR_SIZE: usize = 64;
+
+const UBI_LAYOUT_VOLUME_ID: u32 = 0x7fff_efff;
+const UBI_VTBL_RECORD_SIZE: usize = 172;
+const UBI_MAX_VOLUMES: usize = 128;
+
+const UBI_VID_DYNAMIC: u8 = 1;
+const UBI_VID_STATIC: u8 = 2;
+
+/// Smallest and largest physical erase block sizes tried when probing an image.
+const MIN_PEB_SIZE: u64 = 16 * 1024;
+const MAX_PEB_SIZE: u64 = 4 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UbiVolumeType {
+    Dynamic,
+    Static,
+}
+
+/// A logical erase block as found on flash.
+#[derive(Debug, Clone, Copy)]
+struct Leb {
+    sqnum: u64,
+    /// Offset of the data in the underlying reader.
+    offset: u64,
+    /// Bytes used in the block, only meaningful for static volumes.
+    data_size: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct UbiEntry {
+    path: PathBuf,
+    vol_id: u32,
+    vol_type: UbiVolumeType,
+    /// Usable bytes per logical erase block, after the volume's alignment padding.
+    leb_size: u64,
+    lebs: Vec<Option<Leb>>,
+    size: u64,
+}
+
+impl UbiEntry {
+    pub fn volume_id(&self) -> u32 {
+        self.vol_id
+    }
+
+    pub fn volume_type(&self) -> UbiVolumeType {
+        self.vol_type
+    }
+
+    pub fn leb_size(&self) -> u64 {
+        self.leb_size
+    }
+
+    /// Number of logical erase blocks up to the last mapped one.
+    pub fn leb_count(&self) -> usize {
+        self.lebs.len()
+    }
+}
+
+impl Entry for UbiEntry {
+    fn file_type(&self) -> FileType {
+        FileType::RegularFile
+    }
+
+    fn hand_link(&self) -> Option<PathBuf> {
+        None
+    }
+
+    fn path_name(&self) -> std::io::Result<PathBuf> {
+        Ok(self.path.clone())
+    }
+
+    fn gid(&self) -> std::io::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    fn uid(&self) -> std::io::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn sym_link(&self) -> Option<PathBuf> {
+        None
+    }
+}
+
+pub struct UbiEntries {
+    current: usize,
+    total: usize,
+    inner: Vec<UbiEntry>,
+}
+
+impl Iterator for UbiEntries {
+    type Item = Result<UbiEntry, ArchiveError>;
+
+    fn next(&mut self) -> Option<Result<UbiEntry, ArchiveError>> {
+        if self.current >= self.total {
+            return None;
+        }
+
+        let entry = &self.inner[self.current];
+        self.current += 1;
+        Some(Ok(entry.to_owned()))
+    }
+}
+
+/// Raw UBI image, e.g. from `ubinize` or a NAND dump without OOB data.
+///
+/// Logical volumes are rebuilt from the volume ID headers, taking the copy with the highest
+/// sequence number for every logical erase block. Unmapped blocks of dynamic volumes read as
+/// erased flash.
+pub struct UbiArchive<R: Read + Seek> {
+    inner: R,
+    peb_size: u64,
+    image_seq: u32,
+    entries: Vec<UbiEntry>,
+}
+
+impl<R> UbiArchive<R>
+where
+    R: Read + Seek,
+{
+    pub fn peb_size(&self) -> u64 {
+        self.peb_size
+    }
+
+    pub fn image_sequence(&self) -> u32 {
+        self.image_seq
+    }
+
+    pub fn entries(&mut self) -> Result<UbiEntries, ArchiveError> {
+        Ok(UbiEntries {
+            current: 0,
+            total: self.entries.len(),
+            inner: self.entries.clone(),
+        })
+    }
+
+    pub fn unpack_all(&mut self, to: impl AsRef<Path>) -> Result<(), ArchiveError> {
+        let to = to.as_ref();
+        if !to.exists() {
+            std::fs::create_dir_all(to)?;
+        }
+
+        let entries = self.entries()?;
+        let mut failures = vec![];
+        for entry in entries {
+            match entry {
+                Ok(entry) => {
+                    let path = to.join(&entry.path);
+                    if let Err(e) = self.unpack_file(&entry, path) {
+                        failures.push(e);
+                    }
+                }
+                Err(e) => {
+                    failures.push(e);
+                }
+            }
+        }
+
+        if !failures.is_empty() {
+            return Err(ArchiveError::ExtractFailed { sources: failures });
+        }
+
+        Ok(())
+    }
+
+    /// Writes the volume image.
+    pub fn unpack_file(
+        &mut self,
+        entry: &UbiEntry,
+        to: impl AsRef<Path>,
+    ) -> Result<(), ArchiveError> {
+        let mut writer = std::io::BufWriter::new(std::fs::File::create(to)?);
+        self.read_volume(entry, &mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Opens the UBIFS filesystem in a volume.
+    pub fn open_ubifs(&mut self, entry: &UbiEntry) -> Result<UbifsArchive, ArchiveError> {
+        let mut volume = Vec::with_capacity(entry.size as usize);
+        self.read_volume(entry, &mut volume)?;
+        UbifsArchive::create_with_reader(Cursor::new(volume))
+    }
+
+    fn read_volume(
+        &mut self,
+        entry: &UbiEntry,
+        writer: &mut dyn Write,
+    ) -> Result<(), ArchiveError> {
+        let erased = vec![0xffu8; entry.leb_size as usize];
+        for leb in &entry.lebs {
+            match leb {
+                Some(leb) => {
+                    let size = match entry.vol_type {
+                        UbiVolumeType::Static => u64::from(leb.data_size),
+                        UbiVolumeType::Dynamic => entry.leb_size,
+                    };
+                    self.inner.seek(SeekFrom::Start(leb.offset))?;
+                    let copied = std::io::copy(&mut (&mut self.inner).take(size), writer)?;
+                    if copied != size {
+                        return Err(ArchiveError::GenericsError("UBI image is truncated"));
+                    }
+                }
+                None => writer.write_all(&erased)?,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn create_with_reader(mut rdr: R) -> Result<UbiArchive<R>, ArchiveError> {
+        // The image may be embedded in a larger flash dump, offsets are relative to the start.
+        let base = rdr.stream_position()?;
+        let end = rdr.seek(SeekFrom::End(0))?;
+        let first = read_ec_header(&mut rdr, base)?
+            .ok_or(ArchiveError::GenericsError("not a UBI image"))?;
+
+        // The erase block size is not recorded anywhere, it is the distance to the next header.
+        let mut peb_size = end - base;
+        let mut candidate = MIN_PEB_SIZE;
+        while candidate <= MAX_PEB_SIZE && base + candidate < end {
+            if read_ec_header(&mut rdr, base + candidate)?.is_some() {
+                peb_size = candidate;
+                break;
+            }
+            candidate *= 2;
+        }
+        if peb_size <= u64::from(first.data_offset) {
+            return Err(ArchiveError::GenericsError("UBI image is truncated"));
+        }
+
+        // (volume, lnum) to the newest copy of that block.
+        let mut blocks: BTreeMap<(u32, u32), Leb> = BTreeMap::new();
+        let mut peb = base;
+        while peb + peb_size <= end {
+            let ec = match read_ec_header(&mut rdr, peb)? {
+                Some(ec) => ec,
+                None => {
+                    peb += peb_size;
+                    continue;
+                }
+            };
+            if let Some(vid) = read_vid_header(&mut rdr, peb + u64::from(ec.vid_hdr_offset))? {
+                let leb = Leb {
+                    sqnum: vid.sqnum,
+                    offset: peb + u64::from(ec.data_offset),
+                    data_size: vid.data_size,
+                };
+                let key = (vid.vol_id, vid.lnum);
+                if blocks.get(&key).is_none_or(|old| old.sqnum < leb.sqnum) {
+                    blocks.insert(key, leb);
+                }
+            }
+            peb += peb_size;
+        }
+
+        let leb_size = peb_size - u64::from(first.data_offset);
+        let layout = blocks
+            .get(&(UBI_LAYOUT_VOLUME_ID, 0))
+            .or_else(|| blocks.get(&(UBI_LAYOUT_VOLUME_ID, 1)))
+            .copied()
+            .ok_or(ArchiveError::GenericsError("UBI image has no volume table"))?;
+        let mut table = vec![0u8; (leb_size as usize).min(UBI_VTBL_RECORD_SIZE * UBI_MAX_VOLUMES)];
+        rdr.seek(SeekFrom::Start(layout.offset))?;
+        rdr.read_exact(&mut table)?;
+
+        let mut entries = vec![];
+        for (vol_id, record) in table.chunks_exact(UBI_VTBL_RECORD_SIZE).enumerate() {
+            let vol_id = vol_id as u32;
+            if be32(record, 0) == 0 || ubi_crc(&record[..168]) != be32(record, 168) {
+                continue;
+            }
+
+            let data_pad = u64::from(be32(record, 8));
+            let vol_type = match record[12] {
+                UBI_VID_DYNAMIC => UbiVolumeType::Dynamic,
+                UBI_VID_STATIC => UbiVolumeType::Static,
+                _ => continue,
+            };
+            let name_len = (u16::from_be_bytes([record[14], record[15]]) as usize).min(127);
+            let name = String::from_utf8_lossy(&record[16..16 + name_len]).into_owned();
+
+            let count = blocks
+                .range((vol_id, 0)..=(vol_id, u32::MAX))
+                .map(|((_, lnum), _)| *lnum as usize + 1)
+                .max()
+                .unwrap_or_default();
+            let lebs: Vec<Option<Leb>> = (0..count)
+                .map(|lnum| blocks.get(&(vol_id, lnum as u32)).copied())
+                .collect();
+            let usable = leb_size.saturating_sub(data_pad);
+            let size = match vol_type {
+                UbiVolumeType::Static => {
+                    lebs.iter().flatten().map(|l| u64::from(l.data_size)).sum()
+                }
+                UbiVolumeType::Dynamic => usable * count as u64,
+            };
+
+            entries.push(UbiEntry {
+                path: PathBuf::from(name),
+                vol_id,
+                vol_type,
+                leb_size: usable,
+                lebs,
+                size,
+            });
+        }
+
+        Ok(UbiArchive {
+            inner: rdr,
+            peb_size,
+            image_seq: first.image_seq,
+            entries,
+        })
+    }
+}
+
+impl UbiArchive<BufReader<std::fs::File>> {
+    pub fn create_with_path(path: impl AsRef<Path>) -> Result<Self, ArchiveError> {
+        let rdr = BufReader::new(std::fs::File::open(path)?);
+        Self::create_with_reader(rdr)
+    }
+}
+
+struct EcHeader {
+    vid_hdr_offset: u32,
+    data_offset: u32,
+    image_seq: u32,
+}
+
+struct VidHeader {
+    vol_id: u32,
+    lnum: u32,
+    data_size: u32,
+    sqnum: u64,
+}
+
+fn read_header(
+    rdr: &mut (impl Read + Seek),
+    offset: u64,
+    magic: &[u8; 4],
+) -> Result<Option<[u8; UBI_HDR_SIZE]>, ArchiveError> {
+    let mut header = [0u8; UBI_HDR_SIZE];
+    rdr.seek(SeekFrom::Start(offset))?;
+    match rdr.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    if &header[..4] != magic || ubi_crc(&header[..60]) != be32(&header, 60) {
+        return Ok(None);
+    }
+    Ok(Some(header))
+}
+
+fn read_ec_header(
+    rdr: &mut (impl Read + Seek),
+    offset: u64,
+) -> Result<Option<EcHeader>, ArchiveError> {
+    Ok(
+        read_header(rdr, offset, UBI_EC_HDR_MAGIC)?.map(|header| EcHeader {
+            vid_hdr_offset: be32(&header, 16),
+            data_offset: be32(&header, 20),
+            image_seq: be32(&header, 24),
+        }),
+    )
+}
+
+fn read_vid_header(
+    rdr: &mut (impl Read + Seek),
+    offset: u64,
+) -> Result<Option<VidHeader>, ArchiveError> {
+    Ok(
+        read_header(rdr, offset, UBI_VID_HDR_MAGIC)?.map(|header| VidHeader {
+            vol_id: be32(&header, 8),
+            lnum: be32(&header, 12),
+            data_size: be32(&header, 20),
+            sqnum: be64(&header, 40),
+        }),
+    )
+}
+
+/// UBI checksums start from all ones and skip the final inversion.
+fn ubi_crc(data: &[u8]) -> u32 {
+    !crc32fast::hash(data)
+}
@@ -0,0 +1,608 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use time::PrimitiveDateTime;
+
+use crate::archive::extract::{unpack_node, ExtractNode, ExtractPolicy};
+use crate::archive::{Entry, FileType};
+use crate::block::lzo::LzoBlock;
+use crate::utils::bytes::{le16, le32, le64};
+use crate::utils::error::ArchiveError;
+
+const UBIFS_NODE_MAGIC: u32 = 0x0610_1831;
+const UBIFS_CH_SIZE: usize = 24;
+
+const UBIFS_INO_NODE: u8 = 0;
+const UBIFS_DATA_NODE: u8 = 1;
+const UBIFS_DENT_NODE: u8 = 2;
+const UBIFS_SB_NODE: u8 = 6;
+const UBIFS_MST_NODE: u8 = 7;
+const UBIFS_IDX_NODE: u8 = 9;
+
+const UBIFS_INO_NODE_SIZE: usize = 160;
+const UBIFS_DENT_NODE_SIZE: usize = 56;
+const UBIFS_DATA_NODE_SIZE: usize = 48;
+/// Branch of an index node: lnum, offs, len and an 8 byte key in the simple key format.
+const UBIFS_BRANCH_SIZE: usize = 20;
+
+/// The superblock lives in LEB 0, followed by two copies of the master node area.
+const UBIFS_MST_LNUM: u64 = 1;
+const UBIFS_BLOCK_SIZE: u64 = 4096;
+
+const UBIFS_COMPR_NONE: u16 = 0;
+const UBIFS_COMPR_LZO: u16 = 1;
+const UBIFS_COMPR_ZLIB: u16 = 2;
+const UBIFS_COMPR_ZSTD: u16 = 3;
+
+/// Limits the walk over a corrupted index that points back at itself.
+const MAX_INDEX_LEVEL: u16 = 64;
+
+const ROOT_INO: u64 = 1;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFSOCK: u32 = 0o140000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFREG: u32 = 0o100000;
+const S_IFBLK: u32 = 0o060000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFIFO: u32 = 0o010000;
+
+/// Where a leaf node of the index lives in the volume.
+#[derive(Debug, Clone, Copy)]
+struct NodeRef {
+    lnum: u32,
+    offs: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Inode {
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    atime: i64,
+    mtime: i64,
+    ctime: i64,
+    /// Symlink target or device number, stored inline in the inode node.
+    data: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct UbifsEntry {
+    path: PathBuf,
+    inum: u64,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    atime: i64,
+    mtime: i64,
+    ctime: i64,
+    /// The target of a symlink.
+    link: Option<PathBuf>,
+    /// The first path of an inode with several directory entries.
+    hard_link: Option<PathBuf>,
+    device: Option<(u32, u32)>,
+}
+
+impl Entry for UbifsEntry {
+    fn file_type(&self) -> FileType {
+        if self.hard_link.is_some() {
+            return FileType::HardLink;
+        }
+
+        match self.mode & S_IFMT {
+            S_IFREG => FileType::RegularFile,
+            S_IFDIR => FileType::Directory,
+            S_IFLNK => FileType::SymbolicLink,
+            S_IFCHR => FileType::CharacterDevice,
+            S_IFBLK => FileType::BlockDevice,
+            S_IFIFO => FileType::NamedPipe,
+            S_IFSOCK => FileType::Socket,
+            _ => FileType::Other,
+        }
+    }
+
+    fn hand_link(&self) -> Option<PathBuf> {
+        self.hard_link.clone()
+    }
+
+    fn path_name(&self) -> std::io::Result<PathBuf> {
+        Ok(self.path.clone())
+    }
+
+    fn gid(&self) -> std::io::Result<Option<u64>> {
+        Ok(Some(u64::from(self.gid)))
+    }
+
+    fn uid(&self) -> std::io::Result<Option<u64>> {
+        Ok(Some(u64::from(self.uid)))
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn sym_link(&self) -> Option<PathBuf> {
+        self.link.clone()
+    }
+}
+
+impl UbifsEntry {
+    pub fn inode(&self) -> u64 {
+        self.inum
+    }
+
+    pub fn unix_mode(&self) -> u32 {
+        self.mode & 0o7777
+    }
+
+    /// Major and minor number of a character or block device.
+    pub fn device(&self) -> Option<(u32, u32)> {
+        self.device
+    }
+
+    pub fn atime(&self) -> Option<PrimitiveDateTime> {
+        unix_time(self.atime)
+    }
+
+    pub fn mtime(&self) -> Option<PrimitiveDateTime> {
+        unix_time(self.mtime)
+    }
+
+    pub fn ctime(&self) -> Option<PrimitiveDateTime> {
+        unix_time(self.ctime)
+    }
+}
+
+pub struct UbifsEntries {
+    current: usize,
+    total: usize,
+    inner: Vec<UbifsEntry>,
+}
+
+impl Iterator for UbifsEntries {
+    type Item = Result<UbifsEntry, ArchiveError>;
+
+    fn next(&mut self) -> Option<Result<UbifsEntry, ArchiveError>> {
+        if self.current >= self.total {
+            return None;
+        }
+
+        let entry = &self.inner[self.current];
+        self.current += 1;
+        Some(Ok(entry.to_owned()))
+    }
+}
+
+/// UBIFS volume image, as read from a UBI volume or produced by mkfs.ubifs.
+///
+/// Files are found by walking the index tree from the newest master node, so only the state of
+/// the last commit is visible; nodes still in the journal are not replayed.
+pub struct UbifsArchive {
+    data: Vec<u8>,
+    leb_size: u64,
+    default_compr: u16,
+    inodes: HashMap<u64, Inode>,
+    /// Data nodes of every inode, by block number.
+    blocks: HashMap<u64, Vec<(u32, NodeRef)>>,
+    entries: Vec<UbifsEntry>,
+}
+
+impl UbifsArchive {
+    pub fn leb_size(&self) -> u64 {
+        self.leb_size
+    }
+
+    /// Compressor mkfs.ubifs was told to use, the nodes themselves may differ.
+    pub fn default_compression(&self) -> u16 {
+        self.default_compr
+    }
+
+    pub fn entries(&mut self) -> Result<UbifsEntries, ArchiveError> {
+        Ok(UbifsEntries {
+            current: 0,
+            total: self.entries.len(),
+            inner: self.entries.clone(),
+        })
+    }
+
+    pub fn unpack_all(&mut self, to: impl AsRef<Path>) -> Result<(), ArchiveError> {
+        let to = to.as_ref();
+        if !to.exists() {
+            std::fs::create_dir_all(to)?;
+        }
+
+        let entries = self.entries()?;
+        let policy = ExtractPolicy::default();
+        let mut failures = vec![];
+        for entry in entries {
+            match entry {
+                Ok(entry) => {
+                    if let Err(e) = self.unpack_entry(&entry, to, &policy) {
+                        failures.push(e);
+                    }
+                }
+                Err(e) => {
+                    failures.push(e);
+                }
+            }
+        }
+
+        if !failures.is_empty() {
+            return Err(ArchiveError::ExtractFailed { sources: failures });
+        }
+
+        Ok(())
+    }
+
+    fn unpack_entry(
+        &mut self,
+        entry: &UbifsEntry,
+        to: &Path,
+        policy: &ExtractPolicy,
+    ) -> Result<(), ArchiveError> {
+        let Some(path) = policy.destination(to, &entry.path)? else {
+            return Ok(());
+        };
+        if !policy.prepare(to, &path, entry.file_type(), entry.mtime)? {
+            return Ok(());
+        }
+        match &entry.hard_link {
+            Some(link) => {
+                let Some(target) = policy.link_target(to, link)? else {
+                    return Ok(());
+                };
+                unpack_node(ExtractNode::HardLink(&target), &path, 0)?;
+                Ok(())
+            }
+            None => self.unpack_file(entry, path),
+        }
+    }
+
+    pub fn unpack_file(
+        &mut self,
+        entry: &UbifsEntry,
+        to: impl AsRef<Path>,
+    ) -> Result<(), ArchiveError> {
+        let to = to.as_ref();
+        match entry.file_type() {
+            FileType::Directory => {
+                unpack_node(ExtractNode::Directory, to, entry.mode)?;
+            }
+            FileType::SymbolicLink => {
+                let link = entry.link.clone().unwrap_or_default();
+                unpack_node(ExtractNode::Symlink(&link), to, entry.mode)?;
+            }
+            FileType::RegularFile | FileType::HardLink => {
+                let data = self.read_inode(entry.inum)?;
+                unpack_node(ExtractNode::File(&mut data.as_slice()), to, entry.mode)?;
+            }
+            _ => {
+                log::info!(
+                    "[-] {} is a special file, not supported",
+                    entry.path.display()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Assembles a file from its data nodes, blocks without a node are holes.
+    fn read_inode(&self, inum: u64) -> Result<Vec<u8>, ArchiveError> {
+        let size = self.inodes.get(&inum).map(|i| i.size).unwrap_or_default() as usize;
+        let mut data = vec![0u8; size];
+        for (block, node) in self.blocks.get(&inum).into_iter().flatten() {
+            let start = (u64::from(*block) * UBIFS_BLOCK_SIZE) as usize;
+            if start >= data.len() {
+                continue;
+            }
+
+            let node = self
+                .node(*node)
+                .filter(|n| n[20] == UBIFS_DATA_NODE && n.len() >= UBIFS_DATA_NODE_SIZE)
+                .ok_or(ArchiveError::GenericsError("UBIFS data node is corrupted"))?;
+            let decoded = decode_block(node)?;
+            let end = (start + decoded.len()).min(data.len());
+            data[start..end].copy_from_slice(&decoded[..end - start]);
+        }
+
+        Ok(data)
+    }
+
+    /// The node at `node`, if its header and CRC are valid.
+    fn node(&self, node: NodeRef) -> Option<&[u8]> {
+        let start = u64::from(node.lnum) * self.leb_size + u64::from(node.offs);
+        node_at(&self.data, start as usize)
+    }
+
+    pub fn create_with_reader(mut rdr: impl Read) -> Result<UbifsArchive, ArchiveError> {
+        let mut data = vec![];
+        rdr.read_to_end(&mut data)?;
+
+        let sb = node_at(&data, 0)
+            .filter(|n| n[20] == UBIFS_SB_NODE && n.len() >= 86)
+            .ok_or(ArchiveError::GenericsError("not a UBIFS image"))?;
+        if sb[27] != 0 {
+            return Err(ArchiveError::GenericsError(
+                "UBIFS image uses an unsupported key format",
+            ));
+        }
+        let leb_size = u64::from(le32(sb, 36));
+        let default_compr = le16(sb, 84);
+        if leb_size == 0 {
+            return Err(ArchiveError::GenericsError("not a UBIFS image"));
+        }
+
+        // Both master LEBs hold a log of master nodes, the one with the highest sqnum is current.
+        let mut master: Option<(u64, NodeRef)> = None;
+        for lnum in [UBIFS_MST_LNUM, UBIFS_MST_LNUM + 1] {
+            let leb_start = (lnum * leb_size) as usize;
+            let leb_end = (leb_start + leb_size as usize).min(data.len());
+            let mut offset = leb_start;
+            while offset + UBIFS_CH_SIZE <= leb_end {
+                match node_at(&data, offset) {
+                    Some(node) if node[20] == UBIFS_MST_NODE && node.len() >= 60 => {
+                        let sqnum = le64(node, 8);
+                        if master.is_none_or(|(best, _)| best < sqnum) {
+                            let root = NodeRef {
+                                lnum: le32(node, 48),
+                                offs: le32(node, 52),
+                            };
+                            master = Some((sqnum, root));
+                        }
+                        offset += align8(node.len());
+                    }
+                    _ => offset += 8,
+                }
+            }
+        }
+        let (_, root) = master.ok_or(ArchiveError::GenericsError(
+            "UBIFS image has no master node",
+        ))?;
+
+        let mut archive = UbifsArchive {
+            data,
+            leb_size,
+            default_compr,
+            inodes: HashMap::new(),
+            blocks: HashMap::new(),
+            entries: vec![],
+        };
+        let dents = archive.walk_index(root)?;
+        archive.entries = archive.build_tree(dents);
+        Ok(archive)
+    }
+
+    pub fn create_with_path(path: impl AsRef<Path>) -> Result<UbifsArchive, ArchiveError> {
+        let rdr = std::io::BufReader::new(std::fs::File::open(path)?);
+        Self::create_with_reader(rdr)
+    }
+
+    /// Collects inodes and data nodes from the leaves of the index, and returns the directory
+    /// entries as (parent, name, inode).
+    fn walk_index(&mut self, root: NodeRef) -> Result<Vec<(u64, Vec<u8>, u64)>, ArchiveError> {
+        let mut dents = vec![];
+        let mut stack = vec![(root, MAX_INDEX_LEVEL)];
+        while let Some((idx, depth)) = stack.pop() {
+            let node = self
+                .node(idx)
+                .filter(|n| n[20] == UBIFS_IDX_NODE && n.len() >= 28)
+                .ok_or(ArchiveError::GenericsError("UBIFS index node is corrupted"))?;
+            let child_cnt = le16(node, 24) as usize;
+            let level = le16(node, 26);
+            if level >= depth {
+                return Err(ArchiveError::GenericsError("UBIFS index is too deep"));
+            }
+
+            let branches: Vec<NodeRef> = node[28..]
+                .chunks_exact(UBIFS_BRANCH_SIZE)
+                .take(child_cnt)
+                .map(|branch| NodeRef {
+                    lnum: le32(branch, 0),
+                    offs: le32(branch, 4),
+                })
+                .collect();
+            if level > 0 {
+                stack.extend(branches.into_iter().rev().map(|branch| (branch, level)));
+                continue;
+            }
+
+            for branch in branches {
+                let leaf = match self.node(branch) {
+                    Some(leaf) => leaf,
+                    None => {
+                        log::debug!("UBIFS node at {}:{} is corrupted", branch.lnum, branch.offs);
+                        continue;
+                    }
+                };
+                let inum = u64::from(le32(leaf, 24));
+                match leaf[20] {
+                    UBIFS_INO_NODE if leaf.len() >= UBIFS_INO_NODE_SIZE => {
+                        let data_len = le32(leaf, 112) as usize;
+                        let inline = leaf
+                            .get(UBIFS_INO_NODE_SIZE..UBIFS_INO_NODE_SIZE + data_len)
+                            .unwrap_or_default();
+                        let inode = Inode {
+                            mode: le32(leaf, 104),
+                            uid: le32(leaf, 96),
+                            gid: le32(leaf, 100),
+                            size: le64(leaf, 48),
+                            atime: le64(leaf, 56) as i64,
+                            ctime: le64(leaf, 64) as i64,
+                            mtime: le64(leaf, 72) as i64,
+                            data: inline.to_vec(),
+                        };
+                        self.inodes.insert(inum, inode);
+                    }
+                    UBIFS_DATA_NODE if leaf.len() >= UBIFS_DATA_NODE_SIZE => {
+                        let block = le32(leaf, 28) & 0x1fff_ffff;
+                        self.blocks.entry(inum).or_default().push((block, branch));
+                    }
+                    UBIFS_DENT_NODE if leaf.len() >= UBIFS_DENT_NODE_SIZE => {
+                        let nlen = le16(leaf, 50) as usize;
+                        let name = leaf
+                            .get(UBIFS_DENT_NODE_SIZE..UBIFS_DENT_NODE_SIZE + nlen)
+                            .unwrap_or_default();
+                        dents.push((inum, name.to_vec(), le64(leaf, 40)));
+                    }
+                    // Extended attribute entries and their inodes.
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(dents)
+    }
+
+    /// Walks the directory entries from the root, depth first so parents come before children.
+    fn build_tree(&self, mut dents: Vec<(u64, Vec<u8>, u64)>) -> Vec<UbifsEntry> {
+        // The index orders entries by name hash.
+        dents.sort();
+        let mut children: HashMap<u64, Vec<(&[u8], u64)>> = HashMap::new();
+        for (parent, name, inum) in &dents {
+            children
+                .entry(*parent)
+                .or_default()
+                .push((name.as_slice(), *inum));
+        }
+
+        let mut entries = vec![];
+        let mut first_paths: HashMap<u64, PathBuf> = HashMap::new();
+        let mut visited = HashSet::from([ROOT_INO]);
+        let mut stack = vec![(ROOT_INO, PathBuf::new())];
+        while let Some((dir, parent)) = stack.pop() {
+            let mut subdirs = vec![];
+            for (name, inum) in children.get(&dir).into_iter().flatten() {
+                if matches!(*name, b"" | b"." | b"..") || name.contains(&b'/') {
+                    log::info!(
+                        "[-] skipping the UBIFS name {:?}",
+                        String::from_utf8_lossy(name)
+                    );
+                    continue;
+                }
+                let path = parent.join(String::from_utf8_lossy(name).as_ref());
+                let inode = self.inodes.get(inum).cloned().unwrap_or_default();
+                let file_type = inode.mode & S_IFMT;
+                if file_type == S_IFDIR {
+                    if !visited.insert(*inum) {
+                        log::debug!("UBIFS directory {} is linked twice", path.display());
+                        continue;
+                    }
+                    subdirs.push((*inum, path.clone()));
+                }
+
+                let hard_link = match first_paths.get(inum) {
+                    Some(first) if file_type != S_IFDIR => Some(first.clone()),
+                    _ => {
+                        first_paths.insert(*inum, path.clone());
+                        None
+                    }
+                };
+
+                let (link, device) = match file_type {
+                    S_IFLNK => {
+                        let target = String::from_utf8_lossy(&inode.data).into_owned();
+                        (Some(PathBuf::from(target)), None)
+                    }
+                    S_IFCHR | S_IFBLK => (None, decode_device(&inode.data)),
+                    _ => (None, None),
+                };
+
+                entries.push(UbifsEntry {
+                    path,
+                    inum: *inum,
+                    mode: inode.mode,
+                    uid: inode.uid,
+                    gid: inode.gid,
+                    size: inode.size,
+                    atime: inode.atime,
+                    mtime: inode.mtime,
+                    ctime: inode.ctime,
+                    link,
+                    hard_link,
+                    device,
+                });
+            }
+            stack.extend(subdirs.into_iter().rev());
+        }
+
+        entries
+    }
+}
+
+/// The node starting at `offset`, if its header and CRC are valid.
+fn node_at(data: &[u8], offset: usize) -> Option<&[u8]> {
+    let header = data.get(offset..offset + UBIFS_CH_SIZE)?;
+    if le32(header, 0) != UBIFS_NODE_MAGIC {
+        return None;
+    }
+
+    let len = le32(header, 16) as usize;
+    if len < UBIFS_CH_SIZE {
+        return None;
+    }
+    let node = data.get(offset..offset + len)?;
+    if ubifs_crc(&node[8..]) != le32(node, 4) {
+        return None;
+    }
+    Some(node)
+}
+
+fn decode_block(node: &[u8]) -> Result<Vec<u8>, ArchiveError> {
+    let size = le32(node, 40) as usize;
+    let input = &node[UBIFS_DATA_NODE_SIZE..];
+    let mut output = Vec::with_capacity(size);
+    match le16(node, 44) {
+        UBIFS_COMPR_NONE => output.extend_from_slice(input),
+        UBIFS_COMPR_LZO => {
+            LzoBlock::create_with_size(input, size)?.read_to_end(&mut output)?;
+        }
+        UBIFS_COMPR_ZLIB => {
+            // Raw deflate, without the zlib header.
+            flate2::read::DeflateDecoder::new(input)
+                .take(size as u64)
+                .read_to_end(&mut output)?;
+        }
+        UBIFS_COMPR_ZSTD => {
+            zstd::stream::read::Decoder::new(input)?
+                .take(size as u64)
+                .read_to_end(&mut output)?;
+        }
+        _ => {
+            return Err(ArchiveError::GenericsError(
+                "UBIFS data node uses an unsupported compression",
+            ))
+        }
+    }
+
+    output.resize(size, 0);
+    Ok(output)
+}
+
+/// Decodes the `ubifs_dev_desc` stored inline in a device inode.
+fn decode_device(data: &[u8]) -> Option<(u32, u32)> {
+    if data.len() != 4 && data.len() != 8 {
+        return None;
+    }
+
+    // The huge encoding only adds zero high bits for any device Linux can address.
+    let dev = le32(data, 0);
+    Some(((dev & 0xfff00) >> 8, (dev & 0xff) | ((dev >> 12) & 0xfff00)))
+}
+
+/// The kernel's `crc32(0xffffffff, ...)`, without the final inversion.
+fn ubifs_crc(data: &[u8]) -> u32 {
+    !crc32fast::hash(data)
+}
+
+fn align8(len: usize) -> usize {
+    (len + 7) & !7
+}
+
+fn unix_time(time: i64) -> Option<PrimitiveDateTime> {
+    let dt = time::OffsetDateTime::from_unix_timestamp(time).ok();
+    dt.map(|dt| PrimitiveDateTime::new(dt.date(), dt.time()))
+}
@@ -0,0 +1,363 @@
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::archive::{Entry, FileType};
+use crate::block::{create_decoder, BlockFormat};
+use crate::utils::bytes::be32;
+use crate::utils::error::ArchiveError;
+
+const UIMAGE_MAGIC: u32 = 0x2705_1956;
+const HEADER_SIZE: usize = 64;
+
+const IH_TYPE_KERNEL: u8 = 2;
+const IH_TYPE_RAMDISK: u8 = 3;
+const IH_TYPE_MULTI: u8 = 4;
+const IH_TYPE_FIRMWARE: u8 = 5;
+const IH_TYPE_SCRIPT: u8 = 6;
+const IH_TYPE_FLATDT: u8 = 8;
+
+/// The 64 byte big endian header of a U-Boot legacy image.
+#[derive(Debug, Clone)]
+pub struct UImageHeader {
+    header_crc: u32,
+    time: u32,
+    size: u32,
+    load: u32,
+    entry_point: u32,
+    data_crc: u32,
+    os: u8,
+    arch: u8,
+    image_type: u8,
+    compression: u8,
+    name: String,
+}
+
+impl UImageHeader {
+    fn parse(header: &[u8; HEADER_SIZE]) -> Result<UImageHeader, ArchiveError> {
+        let be32 = |offset: usize| be32(header, offset);
+        if be32(0) != UIMAGE_MAGIC {
+            return Err(ArchiveError::GenericsError("not a uImage"));
+        }
+
+        // The header checksum is computed with its own field zeroed.
+        let mut zeroed = *header;
+        zeroed[4..8].fill(0);
+        let mut crc = flate2::Crc::new();
+        crc.update(&zeroed);
+        if crc.sum() != be32(4) {
+            return Err(ArchiveError::ChecksumMismatch("uImage header".to_string()));
+        }
+
+        let name = &header[32..];
+        let name = name.split(|c| *c == 0).next().unwrap_or_default();
+        Ok(UImageHeader {
+            header_crc: be32(4),
+            time: be32(8),
+            size: be32(12),
+            load: be32(16),
+            entry_point: be32(20),
+            data_crc: be32(24),
+            os: header[28],
+            arch: header[29],
+            image_type: header[30],
+            compression: header[31],
+            name: String::from_utf8_lossy(name).into_owned(),
+        })
+    }
+
+    pub fn header_crc(&self) -> u32 {
+        self.header_crc
+    }
+
+    /// Creation time in seconds since the epoch.
+    pub fn time(&self) -> u32 {
+        self.time
+    }
+
+    /// Size of the image data following the header.
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn load_address(&self) -> u32 {
+        self.load
+    }
+
+    pub fn entry_point(&self) -> u32 {
+        self.entry_point
+    }
+
+    pub fn data_crc(&self) -> u32 {
+        self.data_crc
+    }
+
+    /// `IH_OS_*`, e.g. 5 for Linux.
+    pub fn os(&self) -> u8 {
+        self.os
+    }
+
+    /// `IH_ARCH_*`, e.g. 2 for ARM.
+    pub fn arch(&self) -> u8 {
+        self.arch
+    }
+
+    /// `IH_TYPE_*`, e.g. 2 for a kernel or 4 for a multi-file image.
+    pub fn image_type(&self) -> u8 {
+        self.image_type
+    }
+
+    /// The raw `IH_COMP_*` value, see `compression` for the decoded form.
+    pub fn compression_type(&self) -> u8 {
+        self.compression
+    }
+
+    /// The codec of the payload, `Ok(None)` when it is stored uncompressed.
+    pub fn compression(&self) -> Result<Option<BlockFormat>, ArchiveError> {
+        let format = match self.compression {
+            0 => return Ok(None),
+            1 => BlockFormat::Gzip,
+            2 => BlockFormat::Bzip2,
+            3 => BlockFormat::LzmaAlone,
+            4 => BlockFormat::Lzop,
+            5 => BlockFormat::Lz4,
+            6 => BlockFormat::Zstd,
+            _ => {
+                return Err(ArchiveError::GenericsError(
+                    "uImage uses an unknown compression type",
+                ))
+            }
+        };
+        Ok(Some(format))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// U-Boot legacy image (`mkimage -A ... -T ...`): a 64 byte header in front of a single,
+/// possibly compressed payload, or a list of payloads for multi-file images.
+///
+/// Both checksums are verified when the image is opened.
+pub struct UImageArchive<R: Read + Seek> {
+    inner: R,
+    header: UImageHeader,
+    entries: Vec<UImageEntry>,
+}
+
+#[derive(Debug, Clone)]
+pub struct UImageEntry {
+    path: PathBuf,
+    offset: u64,
+    size: u64,
+    compression: Option<BlockFormat>,
+}
+
+impl UImageEntry {
+    /// Offset of the payload in the underlying reader.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// The codec the payload gets decompressed with on unpacking, if any.
+    pub fn compression(&self) -> Option<BlockFormat> {
+        self.compression
+    }
+}
+
+impl Entry for UImageEntry {
+    fn file_type(&self) -> FileType {
+        FileType::RegularFile
+    }
+
+    fn hand_link(&self) -> Option<PathBuf> {
+        None
+    }
+
+    fn path_name(&self) -> std::io::Result<PathBuf> {
+        Ok(self.path.clone())
+    }
+
+    fn gid(&self) -> std::io::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    fn uid(&self) -> std::io::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Size of the payload as stored, before decompression.
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn sym_link(&self) -> Option<PathBuf> {
+        None
+    }
+}
+
+pub struct UImageEntries {
+    current: usize,
+    total: usize,
+    inner: Vec<UImageEntry>,
+}
+
+impl Iterator for UImageEntries {
+    type Item = Result<UImageEntry, ArchiveError>;
+
+    fn next(&mut self) -> Option<Result<UImageEntry, ArchiveError>> {
+        if self.current >= self.total {
+            return None;
+        }
+
+        let entry = &self.inner[self.current];
+        self.current += 1;
+        Some(Ok(entry.to_owned()))
+    }
+}
+
+impl<R> UImageArchive<R>
+where
+    R: Read + Seek,
+{
+    pub fn header(&self) -> &UImageHeader {
+        &self.header
+    }
+
+    pub fn entries(&mut self) -> Result<UImageEntries, ArchiveError> {
+        Ok(UImageEntries {
+            current: 0,
+            total: self.entries.len(),
+            inner: self.entries.clone(),
+        })
+    }
+
+    pub fn unpack_all(&mut self, to: impl AsRef<Path>) -> Result<(), ArchiveError> {
+        let to = to.as_ref();
+        if !to.exists() {
+            std::fs::create_dir_all(to)?;
+        }
+
+        let entries = self.entries()?;
+        let mut failures = vec![];
+        for entry in entries {
+            match entry {
+                Ok(entry) => {
+                    let path = to.join(&entry.path);
+                    if let Err(e) = self.unpack_file(&entry, path) {
+                        failures.push(e);
+                    }
+                }
+                Err(e) => {
+                    failures.push(e);
+                }
+            }
+        }
+
+        if !failures.is_empty() {
+            return Err(ArchiveError::ExtractFailed { sources: failures });
+        }
+
+        Ok(())
+    }
+
+    /// Writes the payload, decompressed according to the header.
+    pub fn unpack_file(
+        &mut self,
+        entry: &UImageEntry,
+        to: impl AsRef<Path>,
+    ) -> Result<(), ArchiveError> {
+        self.inner.seek(SeekFrom::Start(entry.offset))?;
+        let mut reader = (&mut self.inner).take(entry.size);
+        let mut writer = std::fs::File::create(to)?;
+        match entry.compression {
+            Some(format) => {
+                create_decoder(format, reader)?.decode_to(&mut writer)?;
+            }
+            None => {
+                let _ = std::io::copy(&mut reader, &mut writer)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn create_with_reader(mut rdr: R) -> Result<UImageArchive<R>, ArchiveError> {
+        // The image may be embedded in a larger firmware blob, offsets are relative to the start.
+        let base = rdr.stream_position()?;
+        let mut header = [0u8; HEADER_SIZE];
+        rdr.read_exact(&mut header)?;
+        let header = UImageHeader::parse(&header)?;
+
+        let mut crc = flate2::Crc::new();
+        let mut data = vec![];
+        (&mut rdr)
+            .take(u64::from(header.size))
+            .read_to_end(&mut data)?;
+        if data.len() != header.size as usize {
+            return Err(ArchiveError::GenericsError("uImage data is truncated"));
+        }
+        crc.update(&data);
+        if crc.sum() != header.data_crc {
+            return Err(ArchiveError::ChecksumMismatch("uImage data".to_string()));
+        }
+
+        let compression = header.compression()?;
+        let data_offset = base + HEADER_SIZE as u64;
+        let entries = match header.image_type {
+            IH_TYPE_MULTI | IH_TYPE_SCRIPT => {
+                // A zero terminated list of big endian sizes, then the payloads, each padded to
+                // four bytes. Only the first payload, the kernel, is compressed.
+                let sizes: Vec<u64> = data
+                    .chunks_exact(4)
+                    .map(|size| u64::from(u32::from_be_bytes(size.try_into().unwrap())))
+                    .take_while(|size| *size != 0)
+                    .collect();
+                let mut offset = data_offset + (sizes.len() as u64 + 1) * 4;
+                let mut entries = vec![];
+                for (index, size) in sizes.into_iter().enumerate() {
+                    if offset + size > data_offset + data.len() as u64 {
+                        return Err(ArchiveError::GenericsError("uImage data is truncated"));
+                    }
+                    entries.push(UImageEntry {
+                        path: PathBuf::from(format!("image-{}", index)),
+                        offset,
+                        size,
+                        compression: if index == 0 { compression } else { None },
+                    });
+                    offset += (size + 3) & !3;
+                }
+                entries
+            }
+            image_type => {
+                let name = match image_type {
+                    IH_TYPE_KERNEL => "kernel",
+                    IH_TYPE_RAMDISK => "ramdisk",
+                    IH_TYPE_FIRMWARE => "firmware",
+                    IH_TYPE_FLATDT => "fdt",
+                    _ => "image",
+                };
+                vec![UImageEntry {
+                    path: PathBuf::from(name),
+                    offset: data_offset,
+                    size: data.len() as u64,
+                    compression,
+                }]
+            }
+        };
+
+        let archive = UImageArchive {
+            inner: rdr,
+            header,
+            entries,
+        };
+
+        Ok(archive)
+    }
+}
+
+impl UImageArchive<BufReader<std::fs::File>> {
+    pub fn create_with_path(path: impl AsRef<Path>) -> Result<Self, ArchiveError> {
+        let rdr = BufReader::new(std::fs::File::open(path)?);
+        Self::create_with_reader(rdr)
+    }
+}
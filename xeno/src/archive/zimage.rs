@@ -0,0 +1,261 @@
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::archive::{Entry, FileType};
+use crate::block::{create_decoder, BlockFormat};
+use crate::utils::error::ArchiveError;
+
+const ZIMAGE_MAGIC: u32 = 0x016f_2818;
+const ZIMAGE_MAGIC_OFFSET: usize = 0x24;
+
+/// Codecs the kernel build can compress a zImage payload with, in the order they are tried.
+/// Formats with a short magic come last since stray matches in the decompressor code are more
+/// likely for them.
+const PAYLOAD_FORMATS: [BlockFormat; 7] = [
+    BlockFormat::Gzip,
+    BlockFormat::Xz,
+    BlockFormat::Zstd,
+    BlockFormat::Lz4,
+    BlockFormat::Lzop,
+    BlockFormat::Bzip2,
+    BlockFormat::LzmaAlone,
+];
+
+/// ARM zImage: the kernel decompressor with the compressed kernel embedded somewhere in it.
+///
+/// The payload is found by scanning for compression magics and test decoding each candidate,
+/// like the kernel's `extract-vmlinux` does. The build appends the decompressed size to the
+/// payload, so decoders usually stumble over trailing data; once a candidate has produced
+/// output, such errors end the payload instead of failing it.
+pub struct ZImageArchive {
+    data: Vec<u8>,
+    big_endian: bool,
+    start: u32,
+    end: u32,
+    entry: ZImageEntry,
+}
+
+#[derive(Debug, Clone)]
+pub struct ZImageEntry {
+    path: PathBuf,
+    offset: u64,
+    size: u64,
+    compression: BlockFormat,
+}
+
+impl ZImageEntry {
+    /// Offset of the compressed kernel inside the zImage.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn compression(&self) -> BlockFormat {
+        self.compression
+    }
+}
+
+impl Entry for ZImageEntry {
+    fn file_type(&self) -> FileType {
+        FileType::RegularFile
+    }
+
+    fn hand_link(&self) -> Option<PathBuf> {
+        None
+    }
+
+    fn path_name(&self) -> std::io::Result<PathBuf> {
+        Ok(self.path.clone())
+    }
+
+    fn gid(&self) -> std::io::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    fn uid(&self) -> std::io::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Size of the decompressed kernel.
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn sym_link(&self) -> Option<PathBuf> {
+        None
+    }
+}
+
+pub struct ZImageEntries {
+    current: usize,
+    total: usize,
+    inner: Vec<ZImageEntry>,
+}
+
+impl Iterator for ZImageEntries {
+    type Item = Result<ZImageEntry, ArchiveError>;
+
+    fn next(&mut self) -> Option<Result<ZImageEntry, ArchiveError>> {
+        if self.current >= self.total {
+            return None;
+        }
+
+        let entry = &self.inner[self.current];
+        self.current += 1;
+        Some(Ok(entry.to_owned()))
+    }
+}
+
+impl ZImageArchive {
+    /// True for kernels built for big endian ARM, the header fields follow the kernel.
+    pub fn is_big_endian(&self) -> bool {
+        self.big_endian
+    }
+
+    /// Start address the zImage was linked at, 0 for position independent images.
+    pub fn start(&self) -> u32 {
+        self.start
+    }
+
+    pub fn end(&self) -> u32 {
+        self.end
+    }
+
+    pub fn entries(&mut self) -> Result<ZImageEntries, ArchiveError> {
+        Ok(ZImageEntries {
+            current: 0,
+            total: 1,
+            inner: vec![self.entry.clone()],
+        })
+    }
+
+    pub fn unpack_all(&mut self, to: impl AsRef<Path>) -> Result<(), ArchiveError> {
+        let to = to.as_ref();
+        if !to.exists() {
+            std::fs::create_dir_all(to)?;
+        }
+
+        let entry = self.entry.clone();
+        self.unpack_file(&entry, to.join(&entry.path))
+    }
+
+    pub fn unpack_file(
+        &mut self,
+        entry: &ZImageEntry,
+        to: impl AsRef<Path>,
+    ) -> Result<(), ArchiveError> {
+        let payload = &self.data[entry.offset as usize..];
+        let mut writer = std::fs::File::create(to)?;
+        decode_payload(entry.compression, payload, &mut writer)?;
+        Ok(())
+    }
+
+    pub fn create_with_reader(mut rdr: impl Read) -> Result<ZImageArchive, ArchiveError> {
+        let mut data = vec![];
+        rdr.read_to_end(&mut data)?;
+
+        let field = |offset: usize, big_endian: bool| -> Option<u32> {
+            let bytes = data.get(offset..offset + 4)?.try_into().ok()?;
+            Some(match big_endian {
+                true => u32::from_be_bytes(bytes),
+                false => u32::from_le_bytes(bytes),
+            })
+        };
+        let big_endian = match field(ZIMAGE_MAGIC_OFFSET, false) {
+            Some(ZIMAGE_MAGIC) => false,
+            _ if field(ZIMAGE_MAGIC_OFFSET, true) == Some(ZIMAGE_MAGIC) => true,
+            _ => return Err(ArchiveError::GenericsError("not an ARM zImage")),
+        };
+        let start = field(0x28, big_endian).unwrap_or_default();
+        let end = field(0x2c, big_endian).unwrap_or_default();
+
+        let entry = find_payload(&data).ok_or(ArchiveError::GenericsError(
+            "no compressed kernel found in the zImage",
+        ))?;
+        let archive = ZImageArchive {
+            data,
+            big_endian,
+            start,
+            end,
+            entry,
+        };
+
+        Ok(archive)
+    }
+
+    pub fn create_with_path(path: impl AsRef<Path>) -> Result<ZImageArchive, ArchiveError> {
+        let rdr = std::fs::File::open(path)?;
+        Self::create_with_reader(rdr)
+    }
+}
+
+fn find_payload(data: &[u8]) -> Option<ZImageEntry> {
+    for format in PAYLOAD_FORMATS {
+        for offset in 0..data.len() {
+            let candidate = &data[offset..];
+            if BlockFormat::detect(candidate) != Some(format) || !plausible(format, candidate) {
+                continue;
+            }
+
+            match decode_payload(format, candidate, &mut std::io::sink()) {
+                Ok(size) if size > 0 => {
+                    log::debug!("zImage payload: {:?} at {:#x}", format, offset);
+                    return Some(ZImageEntry {
+                        path: PathBuf::from("Image"),
+                        offset: offset as u64,
+                        size,
+                        compression: format,
+                    });
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    None
+}
+
+/// Extra checks for formats whose magic is short enough to show up in code by chance.
+fn plausible(format: BlockFormat, data: &[u8]) -> bool {
+    match format {
+        // The first block starts with the digits of pi.
+        BlockFormat::Bzip2 => data.get(4..10) == Some(&[0x31, 0x41, 0x59, 0x26, 0x53, 0x59][..]),
+        // The kernel compresses from a pipe, so the size is unknown, otherwise it has to be sane.
+        BlockFormat::LzmaAlone => match data.get(5..13) {
+            Some(size) => {
+                let size = u64::from_le_bytes(size.try_into().unwrap());
+                size == u64::MAX || size < 1 << 30
+            }
+            None => false,
+        },
+        _ => true,
+    }
+}
+
+/// Decodes as much of the payload as possible, errors after the first decoded bytes are taken
+/// as the start of the trailing data.
+fn decode_payload(
+    format: BlockFormat,
+    payload: &[u8],
+    writer: &mut dyn Write,
+) -> Result<u64, ArchiveError> {
+    let mut decoder = create_decoder(format, payload)?;
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut total = 0;
+    loop {
+        match decoder.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(size) => {
+                writer.write_all(&buffer[..size])?;
+                total += size as u64;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) if total > 0 => {
+                log::debug!("zImage payload ends after {} bytes: {}", total, e);
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(total)
+}
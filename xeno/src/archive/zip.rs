@@ -1,13 +1,56 @@
-use std::io::{Read, Seek};
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom};
 use std::path::Path;
 use std::path::PathBuf;
 
+use time::PrimitiveDateTime;
+use zip::result::ZipError;
+
+use crate::archive::extract::{unpack_node, ExtractNode, ExtractPolicy};
 use crate::archive::{Entry, FileType};
+use crate::utils::bytes::{le16, le32, le64};
 use crate::utils::error::ArchiveError;
 
+const S_IFMT: u32 = 0o170000;
+const S_IFLNK: u32 = 0o120000;
+const EXTENDED_TIMESTAMP: u16 = 0x5455;
+/// Longest symlink target read back from an entry, `PATH_MAX` on Linux.
+const MAX_LINK_SIZE: u64 = 4096;
+
 pub struct ZipArchive<R: Read> {
     inner: zip::ZipArchive<R>,
     password: Option<Vec<u8>>,
+    damaged: Vec<ZipDamage>,
+}
+
+/// Compression method of a zip entry, the real one for AES-encrypted entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZipCompression {
+    Stored,
+    Deflated,
+    Deflate64,
+    Bzip2,
+    Lzma,
+    Zstd,
+    Xz,
+    Other(u16),
+}
+
+impl From<zip::CompressionMethod> for ZipCompression {
+    #[allow(deprecated)]
+    fn from(method: zip::CompressionMethod) -> Self {
+        match method {
+            zip::CompressionMethod::Stored => ZipCompression::Stored,
+            zip::CompressionMethod::Deflated => ZipCompression::Deflated,
+            zip::CompressionMethod::Bzip2 => ZipCompression::Bzip2,
+            zip::CompressionMethod::Zstd => ZipCompression::Zstd,
+            zip::CompressionMethod::Unsupported(9) => ZipCompression::Deflate64,
+            zip::CompressionMethod::Unsupported(14) => ZipCompression::Lzma,
+            zip::CompressionMethod::Unsupported(95) => ZipCompression::Xz,
+            zip::CompressionMethod::Unsupported(method) => ZipCompression::Other(method),
+            _ => ZipCompression::Other(99),
+        }
+    }
 }
 
 pub struct ZipEntry {
@@ -17,10 +60,21 @@ pub struct ZipEntry {
     size: u64,
     path: PathBuf,
     mode: Option<u32>,
+    link: Option<PathBuf>,
+    compressed_size: u64,
+    compression: ZipCompression,
+    crc32: u32,
+    dos_mtime: zip::DateTime,
+    unix_mtime: Option<i64>,
+    comment: String,
+    encrypted: bool,
 }
 
 impl Entry for ZipEntry {
     fn file_type(&self) -> FileType {
+        if self.is_symlink() {
+            return FileType::SymbolicLink;
+        }
         if self.is_dir {
             return FileType::Directory;
         }
@@ -52,7 +106,7 @@ impl Entry for ZipEntry {
     }
 
     fn sym_link(&self) -> Option<PathBuf> {
-        None
+        self.link.clone()
     }
 }
 
@@ -60,6 +114,57 @@ impl ZipEntry {
     pub fn unix_mode(&self) -> Option<u32> {
         self.mode
     }
+
+    /// Modification time, from the extended timestamp extra field when there is one and from
+    /// the DOS date and time otherwise.
+    pub fn mtime(&self) -> Option<PrimitiveDateTime> {
+        match self.unix_mtime {
+            Some(mtime) => time::OffsetDateTime::from_unix_timestamp(mtime)
+                .ok()
+                .map(|dt| PrimitiveDateTime::new(dt.date(), dt.time())),
+            None => self.dos_mtime(),
+        }
+    }
+
+    /// The DOS date and time of the entry, in the unknown time zone of the machine that made it.
+    pub fn dos_mtime(&self) -> Option<PrimitiveDateTime> {
+        let dt = self.dos_mtime.to_time().ok();
+        dt.map(|dt| PrimitiveDateTime::new(dt.date(), dt.time()))
+    }
+
+    pub fn compressed_size(&self) -> u64 {
+        self.compressed_size
+    }
+
+    pub fn compression(&self) -> ZipCompression {
+        self.compression
+    }
+
+    /// CRC32 of the uncompressed data, zero for WinZip AE-2 entries which leave it out.
+    pub fn crc32(&self) -> u32 {
+        self.crc32
+    }
+
+    pub fn comment(&self) -> &str {
+        &self.comment
+    }
+
+    /// Whether the entry is encrypted with ZipCrypto or WinZip AES.
+    pub fn encrypted(&self) -> bool {
+        self.encrypted
+    }
+
+    /// Modification time in seconds since the epoch, taking the DOS time as UTC.
+    fn unix_time(&self) -> i64 {
+        self.unix_mtime.unwrap_or_else(|| {
+            let dt = self.dos_mtime.to_time().ok();
+            dt.map_or(0, |dt| dt.unix_timestamp())
+        })
+    }
+
+    fn is_symlink(&self) -> bool {
+        self.mode.is_some_and(|mode| mode & S_IFMT == S_IFLNK)
+    }
 }
 
 pub struct ZipEntries<'a, R> {
@@ -80,37 +185,134 @@ where
             return None;
         }
 
-        let entry = match &self.password {
-            Some(password) => match self.inner.by_index_decrypt(self.current, password) {
-                Ok(result) => result.ok(),
-                _ => None,
-            },
-            _ => self.inner.by_index(self.current).ok(),
-        };
-
-        let entry = if let Some(entry) = entry {
-            ZipEntry {
-                index: self.current,
+        let index = self.current;
+        self.current += 1;
+        // The raw entry lists encrypted entries and unsupported compression methods too.
+        let mut entry = match self.inner.by_index_raw(index) {
+            Ok(entry) => ZipEntry {
+                index,
                 is_dir: entry.is_dir(),
                 is_file: entry.is_file(),
                 size: entry.size(),
                 path: PathBuf::from(entry.name()),
                 mode: entry.unix_mode(),
-            }
-        } else {
-            return None;
+                link: None,
+                compressed_size: entry.compressed_size(),
+                compression: entry.compression().into(),
+                crc32: entry.crc32(),
+                dos_mtime: entry.last_modified(),
+                unix_mtime: extended_mtime(entry.extra_data()),
+                comment: entry.comment().to_string(),
+                encrypted: false,
+            },
+            Err(err) => return Some(Err(ArchiveError::ZipError(err))),
         };
+        entry.encrypted = matches!(
+            self.inner.by_index(index),
+            Err(ZipError::UnsupportedArchive(msg)) if msg == ZipError::PASSWORD_REQUIRED
+        );
+        if entry.is_symlink() {
+            entry.link = read_link(self.inner, &entry, self.password.as_deref()).ok();
+        }
 
-        self.current += 1;
         Some(Ok(entry))
     }
 }
 
+/// Opens entry `index` for reading, decrypting it with `password` when it is encrypted with
+/// ZipCrypto or WinZip AES.
+fn open_entry<'a, R: Read + Seek>(
+    inner: &'a mut zip::ZipArchive<R>,
+    index: usize,
+    password: Option<&[u8]>,
+) -> Result<zip::read::ZipFile<'a>, ArchiveError> {
+    let file = match password {
+        Some(password) => inner.by_index_decrypt(index, password),
+        None => inner.by_index(index).map(Ok),
+    };
+    match file {
+        Ok(Ok(file)) => Ok(file),
+        Ok(Err(_)) => Err(ArchiveError::InvalidPassword),
+        Err(ZipError::UnsupportedArchive(msg)) if msg == ZipError::PASSWORD_REQUIRED => {
+            Err(ArchiveError::Encrypted)
+        }
+        Err(err) => Err(ArchiveError::ZipError(err)),
+    }
+}
+
+/// Info-ZIP stores the target of a unix symlink as the contents of the entry.
+fn read_link<R: Read + Seek>(
+    inner: &mut zip::ZipArchive<R>,
+    entry: &ZipEntry,
+    password: Option<&[u8]>,
+) -> Result<PathBuf, ArchiveError> {
+    let file = open_entry(inner, entry.index, password)?;
+    let mut target = vec![];
+    if let Err(e) = file.take(MAX_LINK_SIZE).read_to_end(&mut target) {
+        return Err(read_error(entry, e));
+    }
+
+    cfg_if::cfg_if! {
+        if #[cfg(unix)] {
+            use std::os::unix::ffi::OsStringExt;
+            Ok(PathBuf::from(std::ffi::OsString::from_vec(target)))
+        } else {
+            Ok(PathBuf::from(String::from_utf8_lossy(&target).into_owned()))
+        }
+    }
+}
+
+/// A ZipCrypto header only checks one byte of the password and the AES one two, so a wrong
+/// password can get past them and only show up as a CRC, HMAC or decompression error.
+fn read_error(entry: &ZipEntry, e: std::io::Error) -> ArchiveError {
+    match entry.encrypted {
+        true => ArchiveError::InvalidPassword,
+        false => ArchiveError::Io(e),
+    }
+}
+
+/// Remembers whether reading failed, as opposed to writing what was read.
+struct EntryReader<R> {
+    inner: R,
+    failed: bool,
+}
+
+impl<R: Read> Read for EntryReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf);
+        self.failed |= read.is_err();
+        read
+    }
+}
+
+/// Modification time of an extended timestamp (0x5455) extra field, the central directory
+/// copy only carries the modification time.
+fn extended_mtime(extra: &[u8]) -> Option<i64> {
+    let (_, data) = extra_fields(extra).find(|(kind, _)| *kind == EXTENDED_TIMESTAMP)?;
+    if data.len() < 5 || data[0] & 1 == 0 {
+        return None;
+    }
+    Some(i64::from(i32::from_le_bytes([
+        data[1], data[2], data[3], data[4],
+    ])))
+}
+
+/// The `(id, data)` fields of an extra field block, up to the first malformed one.
+fn extra_fields(mut extra: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        let kind = u16::from_le_bytes([*extra.first()?, *extra.get(1)?]);
+        let size = usize::from(u16::from_le_bytes([*extra.get(2)?, *extra.get(3)?]));
+        let data = extra.get(4..4 + size)?;
+        extra = &extra[4 + size..];
+        Some((kind, data))
+    })
+}
+
 impl<R> ZipArchive<R>
 where
     R: Read + Seek,
 {
-    pub fn entries(&mut self) -> Result<ZipEntries<R>, ArchiveError> {
+    pub fn entries(&mut self) -> Result<ZipEntries<'_, R>, ArchiveError> {
         let total = self.inner.len();
 
         Ok(ZipEntries {
@@ -121,24 +323,111 @@ where
         })
     }
 
+    /// The archive comment, in whatever encoding the writer used.
+    pub fn comment(&self) -> &[u8] {
+        self.inner.comment()
+    }
+
+    /// Extracts every entry below `to`, decrypting with the archive password.
+    ///
+    /// A missing or wrong password stops the extraction with [`ArchiveError::Encrypted`] or
+    /// [`ArchiveError::InvalidPassword`], other failures are collected.
     pub fn unpack_all(&mut self, to: impl AsRef<Path>) -> Result<(), ArchiveError> {
-        self.inner.extract(to).map_err(ArchiveError::ZipError)
+        let to = to.as_ref();
+        if !to.exists() {
+            std::fs::create_dir_all(to)?;
+        }
+
+        let entries = self.entries()?.collect::<Result<Vec<_>, _>>()?;
+        let policy = ExtractPolicy::default();
+        let mut failures = vec![];
+        for entry in &entries {
+            let result = match policy.destination(to, &entry.path) {
+                Ok(Some(path)) => policy
+                    .prepare(to, &path, entry.file_type(), entry.unix_time())
+                    .and_then(|create| match create {
+                        true => self.unpack_entry(entry, &path),
+                        false => Ok(()),
+                    }),
+                Ok(None) => Ok(()),
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) => {}
+                Err(e @ (ArchiveError::Encrypted | ArchiveError::InvalidPassword)) => {
+                    return Err(e)
+                }
+                Err(e) => failures.push(e),
+            }
+        }
+
+        if !failures.is_empty() {
+            return Err(ArchiveError::ExtractFailed { sources: failures });
+        }
+        Ok(())
     }
 
+    /// Extracts `entry` to the path `to`, decrypting it with the archive password.
     pub fn unpack_file(
         &mut self,
         entry: &ZipEntry,
         to: impl AsRef<Path>,
     ) -> Result<(), ArchiveError> {
-        let mut reader = self
-            .inner
-            .by_index(entry.index)
-            .map_err(ArchiveError::ZipError)?;
-        let mut writer = std::fs::File::create(to)?;
-        let _ = std::io::copy(&mut reader, &mut writer).map_err(ArchiveError::Io)?;
+        self.unpack_entry(entry, to.as_ref())
+    }
+
+    fn unpack_entry(&mut self, entry: &ZipEntry, to: &Path) -> Result<(), ArchiveError> {
+        let password = self.password.as_deref();
+        match entry.file_type() {
+            FileType::Directory => {
+                unpack_node(ExtractNode::Directory, to, entry.mode.unwrap_or(0o755))?;
+            }
+            FileType::SymbolicLink => {
+                let link = read_link(&mut self.inner, entry, password)?;
+                unpack_node(ExtractNode::Symlink(&link), to, 0)?;
+            }
+            _ => {
+                let file = open_entry(&mut self.inner, entry.index, password)?;
+                let mut reader = EntryReader {
+                    inner: file,
+                    failed: false,
+                };
+                let mode = entry.mode.unwrap_or(0o644);
+                match unpack_node(ExtractNode::File(&mut reader), to, mode) {
+                    Err(ArchiveError::Io(e)) if reader.failed => return Err(read_error(entry, e)),
+                    result => result?,
+                };
+            }
+        }
         Ok(())
     }
 
+    /// Offset and size of a stored entry in the underlying reader, for containers such as
+    /// Android OTA payloads that are read in place instead of being extracted first.
+    pub(crate) fn stored_range(&mut self, name: &str) -> Result<(u64, u64), ArchiveError> {
+        let file = self.inner.by_name(name).map_err(ArchiveError::ZipError)?;
+        if file.compression() != zip::CompressionMethod::Stored {
+            return Err(ArchiveError::GenericsError2(format!(
+                "{} is compressed and cannot be read in place",
+                name
+            )));
+        }
+        Ok((file.data_start(), file.size()))
+    }
+
+    pub(crate) fn into_inner(self) -> R {
+        self.inner.into_inner()
+    }
+
+    pub(crate) fn open(rdr: R, password: Option<Vec<u8>>) -> Result<ZipArchive<R>, ArchiveError> {
+        let inner = zip::ZipArchive::new(rdr).map_err(ArchiveError::ZipError)?;
+        Ok(ZipArchive {
+            inner,
+            password,
+            damaged: vec![],
+        })
+    }
+
     pub fn create_with_path(
         path: impl AsRef<Path>,
         password: Option<Vec<u8>>,
@@ -152,7 +441,476 @@ where
         password: Option<Vec<u8>>,
     ) -> Result<ZipArchive<impl Read + Seek>, ArchiveError> {
         let inner = zip::ZipArchive::new(rdr).map_err(ArchiveError::ZipError)?;
-        let archive = ZipArchive { inner, password };
+        let archive = ZipArchive {
+            inner,
+            password,
+            damaged: vec![],
+        };
         Ok(archive)
     }
+
+    /// Opens a zip whose central directory is cut off or corrupt, rebuilding it from the local
+    /// headers like `zip -FF` does. Data in front of the first entry, such as the stub of a
+    /// self-extracting executable, is skipped.
+    ///
+    /// Entries whose data is incomplete are left out and listed by [`ZipArchive::damaged`].
+    pub fn recover_with_path(
+        path: impl AsRef<Path>,
+        password: Option<Vec<u8>>,
+    ) -> Result<ZipArchive<impl Read + Seek>, ArchiveError> {
+        let reader = std::fs::File::open(path)?;
+        Self::recover_with_reader(reader, password)
+    }
+
+    /// See [`ZipArchive::recover_with_path`].
+    pub fn recover_with_reader(
+        rdr: impl Read + Seek,
+        password: Option<Vec<u8>>,
+    ) -> Result<ZipArchive<impl Read + Seek>, ArchiveError> {
+        let (reader, damaged) = salvage(rdr)?;
+        let inner = zip::ZipArchive::new(reader).map_err(ArchiveError::ZipError)?;
+        let archive = ZipArchive {
+            inner,
+            password,
+            damaged,
+        };
+        Ok(archive)
+    }
+
+    /// Entries a recovering open found a local header for but could not salvage, always empty
+    /// for archives opened from their central directory.
+    pub fn damaged(&self) -> &[ZipDamage] {
+        &self.damaged
+    }
+}
+
+const LOCAL_HEADER: &[u8; 4] = b"PK\x03\x04";
+const CENTRAL_HEADER: &[u8; 4] = b"PK\x01\x02";
+const DATA_DESCRIPTOR: &[u8; 4] = b"PK\x07\x08";
+const END_OF_CENTRAL_DIRECTORY: &[u8; 4] = b"PK\x05\x06";
+const ZIP64_END_OF_CENTRAL_DIRECTORY: &[u8; 4] = b"PK\x06\x06";
+const ZIP64_END_LOCATOR: &[u8; 4] = b"PK\x06\x07";
+const ZIP64_EXTRA: u16 = 0x0001;
+/// Sizes and offsets from here on are kept in the zip64 extra field.
+const ZIP64_LIMIT: u64 = 0xffff_ffff;
+const FLAG_DATA_DESCRIPTOR: u16 = 1 << 3;
+
+/// An entry [`ZipArchive::recover_with_reader`] found a local header for but could not salvage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZipDamage {
+    path: PathBuf,
+    offset: u64,
+    reason: &'static str,
+}
+
+impl ZipDamage {
+    /// Name from the local header, empty when the header itself is cut off.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Offset of the local header in the reader.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn reason(&self) -> &str {
+        self.reason
+    }
+}
+
+/// An entry found by its local header, with the sizes and CRC of its data descriptor.
+struct LocalRecord {
+    offset: u64,
+    end: u64,
+    needed: u16,
+    flags: u16,
+    method: u16,
+    time: u16,
+    date: u16,
+    crc: u32,
+    compressed: u64,
+    size: u64,
+    name: Vec<u8>,
+    extra: Vec<u8>,
+}
+
+/// What a surviving central directory record adds to the local header.
+struct CentralRecord {
+    made_by: u16,
+    internal: u16,
+    external: u32,
+    extra: Vec<u8>,
+    comment: Vec<u8>,
+}
+
+/// The scanned archive followed by the central directory rebuilt for it.
+struct Salvaged<R> {
+    inner: R,
+    len: u64,
+    tail: Vec<u8>,
+    pos: u64,
+}
+
+impl<R: Read + Seek> Read for Salvaged<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        // the inner reader is kept at `pos` while it is below `len`
+        if self.pos < self.len {
+            let size = (self.len - self.pos).min(buf.len() as u64) as usize;
+            let read = self.inner.read(&mut buf[..size])?;
+            self.pos += read as u64;
+            return Ok(read);
+        }
+
+        let start = ((self.pos - self.len) as usize).min(self.tail.len());
+        let size = (self.tail.len() - start).min(buf.len());
+        buf[..size].copy_from_slice(&self.tail[start..start + size]);
+        self.pos += size as u64;
+        Ok(size)
+    }
+}
+
+impl<R: Read + Seek> Seek for Salvaged<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let total = self.len + self.tail.len() as u64;
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => total.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        let pos = pos.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "seek before the start")
+        })?;
+        if pos < self.len {
+            self.inner.seek(SeekFrom::Start(pos))?;
+        }
+        self.pos = pos;
+        Ok(pos)
+    }
+}
+
+/// Walks the local headers of `rdr` and appends a central directory listing the entries whose
+/// data is all there.
+fn salvage<R: Read + Seek>(mut rdr: R) -> Result<(Salvaged<R>, Vec<ZipDamage>), ArchiveError> {
+    let len = rdr.seek(SeekFrom::End(0))?;
+    let mut locals = vec![];
+    let mut centrals = HashMap::new();
+    let mut comment = vec![];
+    let mut damaged = vec![];
+
+    let signatures = [LOCAL_HEADER, CENTRAL_HEADER, END_OF_CENTRAL_DIRECTORY];
+    let mut pos = 0;
+    while let Some((offset, signature)) = find_signature(&mut rdr, pos, len, &signatures)? {
+        pos = offset + 4;
+        if signature == CENTRAL_HEADER {
+            if let Some((name, central)) = read_central(&mut rdr, offset, len)? {
+                centrals.insert(name, central);
+            }
+        } else if signature == END_OF_CENTRAL_DIRECTORY {
+            let header = read_at(&mut rdr, offset, 22, len)?;
+            let size = header.map_or(0, |header| le16(&header, 20));
+            if let Some(found) = read_at(&mut rdr, offset + 22, usize::from(size), len)? {
+                comment = found;
+            }
+        } else {
+            match read_local(&mut rdr, offset, len)? {
+                Ok(local) => {
+                    pos = local.end;
+                    locals.push(local);
+                }
+                Err(damage) => {
+                    log::info!(
+                        "[-] {} at {:#x}: {}",
+                        damage.path.display(),
+                        offset,
+                        damage.reason
+                    );
+                    damaged.push(damage);
+                }
+            }
+        }
+    }
+
+    let tail = central_directory(&locals, &centrals, &comment, len);
+    rdr.seek(SeekFrom::Start(0))?;
+    let reader = Salvaged {
+        inner: rdr,
+        len,
+        tail,
+        pos: 0,
+    };
+    Ok((reader, damaged))
+}
+
+fn read_local<R: Read + Seek>(
+    rdr: &mut R,
+    offset: u64,
+    len: u64,
+) -> std::io::Result<Result<LocalRecord, ZipDamage>> {
+    let damage = |path: &[u8], reason| ZipDamage {
+        path: PathBuf::from(String::from_utf8_lossy(path).into_owned()),
+        offset,
+        reason,
+    };
+    let Some(header) = read_at(rdr, offset, 30, len)? else {
+        return Ok(Err(damage(b"", "the local header is cut off")));
+    };
+    let name_size = usize::from(le16(&header, 26));
+    let extra_size = usize::from(le16(&header, 28));
+    let Some(mut name) = read_at(rdr, offset + 30, name_size + extra_size, len)? else {
+        return Ok(Err(damage(b"", "the local header is cut off")));
+    };
+    let extra = name.split_off(name_size);
+
+    let flags = le16(&header, 6);
+    let mut crc = le32(&header, 14);
+    let mut compressed = u64::from(le32(&header, 18));
+    let mut size = u64::from(le32(&header, 22));
+    let zip64 = extra_fields(&extra).find(|(kind, _)| *kind == ZIP64_EXTRA);
+    if let Some((_, data)) = zip64.filter(|(_, data)| data.len() >= 16) {
+        // the local copy holds both sizes, whichever overflowed
+        size = le64(data, 0);
+        compressed = le64(data, 8);
+    }
+
+    let data = offset + 30 + (name_size + extra_size) as u64;
+    let end = if flags & FLAG_DATA_DESCRIPTOR != 0 {
+        match find_descriptor(rdr, data, len, zip64.is_some())? {
+            Some(descriptor) => {
+                (crc, compressed, size) = (descriptor.0, descriptor.1, descriptor.2);
+                descriptor.3
+            }
+            None => return Ok(Err(damage(&name, "no data descriptor matches the data"))),
+        }
+    } else {
+        match data.checked_add(compressed).filter(|end| *end <= len) {
+            Some(end) => end,
+            None => return Ok(Err(damage(&name, "the data is cut off"))),
+        }
+    };
+
+    Ok(Ok(LocalRecord {
+        offset,
+        end,
+        needed: le16(&header, 4),
+        flags,
+        method: le16(&header, 8),
+        time: le16(&header, 10),
+        date: le16(&header, 12),
+        crc,
+        compressed,
+        size,
+        name,
+        extra,
+    }))
+}
+
+/// Finds the data descriptor ending the data that starts at `data`, as `(crc, compressed size,
+/// size, end)`. The size it records is what tells a descriptor from the same bytes inside the
+/// data, and the signature in front of it is optional, so the bytes before each following
+/// header are tried too. Entries with a zip64 extra field have 8 byte sizes in it.
+fn find_descriptor<R: Read + Seek>(
+    rdr: &mut R,
+    data: u64,
+    len: u64,
+    zip64: bool,
+) -> std::io::Result<Option<(u32, u64, u64, u64)>> {
+    let size = if zip64 { 20 } else { 12 };
+    let signatures = [
+        DATA_DESCRIPTOR,
+        LOCAL_HEADER,
+        CENTRAL_HEADER,
+        END_OF_CENTRAL_DIRECTORY,
+        ZIP64_END_OF_CENTRAL_DIRECTORY,
+    ];
+    let mut from = data;
+    while let Some((at, signature)) = find_signature(rdr, from, len, &signatures)? {
+        from = at + 1;
+        let (end, fields) = match signature == DATA_DESCRIPTOR {
+            true => (at, at + 4),
+            false => (at.wrapping_sub(size), at.wrapping_sub(size)),
+        };
+        if end < data || end > at {
+            continue;
+        }
+        let Some(descriptor) = read_at(rdr, fields, size as usize, len)? else {
+            continue;
+        };
+        let crc = le32(&descriptor, 0);
+        let sizes = match zip64 {
+            true => (le64(&descriptor, 4), le64(&descriptor, 12)),
+            false => (
+                u64::from(le32(&descriptor, 4)),
+                u64::from(le32(&descriptor, 8)),
+            ),
+        };
+        if sizes.0 == end - data {
+            return Ok(Some((crc, sizes.0, sizes.1, fields + size)));
+        }
+    }
+    Ok(None)
+}
+
+/// The name of a central directory record and what it adds to the local header.
+fn read_central<R: Read + Seek>(
+    rdr: &mut R,
+    offset: u64,
+    len: u64,
+) -> std::io::Result<Option<(Vec<u8>, CentralRecord)>> {
+    let Some(header) = read_at(rdr, offset, 46, len)? else {
+        return Ok(None);
+    };
+    let name_size = usize::from(le16(&header, 28));
+    let extra_size = usize::from(le16(&header, 30));
+    let comment_size = usize::from(le16(&header, 32));
+    let size = name_size + extra_size + comment_size;
+    let Some(mut name) = read_at(rdr, offset + 46, size, len)? else {
+        return Ok(None);
+    };
+    let mut extra = name.split_off(name_size);
+    let comment = extra.split_off(extra_size);
+    let central = CentralRecord {
+        made_by: le16(&header, 4),
+        internal: le16(&header, 36),
+        external: le32(&header, 38),
+        extra,
+        comment,
+    };
+    Ok(Some((name, central)))
+}
+
+/// A central directory, zip64 when it has to be, for `locals` as if it started at `offset`.
+fn central_directory(
+    locals: &[LocalRecord],
+    centrals: &HashMap<Vec<u8>, CentralRecord>,
+    comment: &[u8],
+    offset: u64,
+) -> Vec<u8> {
+    let mut out = vec![];
+    for local in locals {
+        let central = centrals.get(&local.name);
+        let zip64 = [local.size, local.compressed, local.offset]
+            .iter()
+            .any(|value| *value >= ZIP64_LIMIT);
+        let extra = central.map_or(&local.extra, |central| &central.extra);
+        let mut extra: Vec<u8> = extra_fields(extra)
+            .filter(|(kind, _)| *kind != ZIP64_EXTRA)
+            .flat_map(|(kind, data)| {
+                let header = [kind.to_le_bytes(), (data.len() as u16).to_le_bytes()];
+                header.concat().into_iter().chain(data.iter().copied())
+            })
+            .collect();
+        if zip64 {
+            extra.extend(ZIP64_EXTRA.to_le_bytes());
+            extra.extend(24u16.to_le_bytes());
+            extra.extend(local.size.to_le_bytes());
+            extra.extend(local.compressed.to_le_bytes());
+            extra.extend(local.offset.to_le_bytes());
+        }
+        let narrow = |value: u64| match zip64 {
+            true => ZIP64_LIMIT as u32,
+            false => value as u32,
+        };
+        let comment = central.map_or(&[][..], |central| &central.comment);
+
+        out.extend(CENTRAL_HEADER);
+        out.extend(
+            central
+                .map_or(local.needed, |central| central.made_by)
+                .to_le_bytes(),
+        );
+        for field in [
+            local.needed,
+            local.flags,
+            local.method,
+            local.time,
+            local.date,
+        ] {
+            out.extend(field.to_le_bytes());
+        }
+        out.extend(local.crc.to_le_bytes());
+        out.extend(narrow(local.compressed).to_le_bytes());
+        out.extend(narrow(local.size).to_le_bytes());
+        out.extend((local.name.len() as u16).to_le_bytes());
+        out.extend((extra.len() as u16).to_le_bytes());
+        out.extend((comment.len() as u16).to_le_bytes());
+        out.extend(0u16.to_le_bytes());
+        out.extend(central.map_or(0, |central| central.internal).to_le_bytes());
+        out.extend(central.map_or(0, |central| central.external).to_le_bytes());
+        out.extend(narrow(local.offset).to_le_bytes());
+        out.extend(&local.name);
+        out.extend(&extra);
+        out.extend(comment);
+    }
+
+    let count = locals.len() as u64;
+    let size = out.len() as u64;
+    let zip64 = count >= 0xffff || size >= ZIP64_LIMIT || offset >= ZIP64_LIMIT;
+    if zip64 {
+        let end = offset + size;
+        out.extend(ZIP64_END_OF_CENTRAL_DIRECTORY);
+        out.extend(44u64.to_le_bytes());
+        out.extend(45u16.to_le_bytes());
+        out.extend(45u16.to_le_bytes());
+        out.extend([0u8; 8]);
+        for field in [count, count, size, offset] {
+            out.extend(field.to_le_bytes());
+        }
+        out.extend(ZIP64_END_LOCATOR);
+        out.extend(0u32.to_le_bytes());
+        out.extend(end.to_le_bytes());
+        out.extend(1u32.to_le_bytes());
+    }
+    out.extend(END_OF_CENTRAL_DIRECTORY);
+    out.extend([0u8; 4]);
+    for _ in 0..2 {
+        out.extend((count.min(0xffff) as u16).to_le_bytes());
+    }
+    out.extend((size.min(ZIP64_LIMIT) as u32).to_le_bytes());
+    out.extend((offset.min(ZIP64_LIMIT) as u32).to_le_bytes());
+    out.extend((comment.len() as u16).to_le_bytes());
+    out.extend(comment);
+    out
+}
+
+/// Offset and signature of the first of `signatures` at or after `from`.
+fn find_signature<R: Read + Seek>(
+    rdr: &mut R,
+    from: u64,
+    len: u64,
+    signatures: &[&'static [u8; 4]],
+) -> std::io::Result<Option<(u64, &'static [u8; 4])>> {
+    const CHUNK: usize = 1 << 16;
+
+    let mut chunk = vec![0; CHUNK + 3];
+    let mut start = from;
+    while start < len {
+        // overlap by three bytes so signatures across a chunk boundary are seen
+        let size = (len - start).min(chunk.len() as u64) as usize;
+        rdr.seek(SeekFrom::Start(start))?;
+        rdr.read_exact(&mut chunk[..size])?;
+        for (index, window) in chunk[..size].windows(4).enumerate() {
+            if let Some(signature) = signatures.iter().find(|signature| window == **signature) {
+                return Ok(Some((start + index as u64, signature)));
+            }
+        }
+        start += CHUNK as u64;
+    }
+    Ok(None)
+}
+
+/// `size` bytes at `offset`, or `None` when the reader ends first.
+fn read_at<R: Read + Seek>(
+    rdr: &mut R,
+    offset: u64,
+    size: usize,
+    len: u64,
+) -> std::io::Result<Option<Vec<u8>>> {
+    if offset.checked_add(size as u64).is_none_or(|end| end > len) {
+        return Ok(None);
+    }
+    let mut buf = vec![0; size];
+    rdr.seek(SeekFrom::Start(offset))?;
+    rdr.read_exact(&mut buf)?;
+    Ok(Some(buf))
 }
@@ -1,10 +1,15 @@
+use std::fs::File;
 use std::io::{BufReader, Read, Write};
 use std::path::Path;
 
+use brotli::Decompressor;
+
+use crate::block::{BlockDecoder, BlockEncoder, BlockFormat, CountingReader, FromReader};
 use crate::utils::error::ArchiveError;
 
 pub struct BrotliBlock<R: Read> {
-    inner: R,
+    inner: Decompressor<CountingReader<R>>,
+    total_out: u64,
 }
 
 impl<R> BrotliBlock<R>
@@ -13,19 +18,135 @@ where
 {
     pub fn unpack_to(&mut self, to: impl AsRef<Path>) -> Result<(), ArchiveError> {
         let mut writer = std::fs::File::create(to)?;
-        brotli::BrotliDecompress(&mut self.inner, &mut writer)?;
+        self.decode_to(&mut writer)?;
 
         Ok(())
     }
 
-    pub fn create_with_reader(rdr: impl Read) -> Result<BrotliBlock<impl Read>, ArchiveError> {
-        let block = BrotliBlock { inner: rdr };
+    pub fn create_with_reader(rdr: R) -> Result<BrotliBlock<R>, ArchiveError> {
+        let inner = Decompressor::new(CountingReader::new(rdr), 4096);
+        let block = BrotliBlock {
+            inner,
+            total_out: 0,
+        };
 
         Ok(block)
     }
+}
 
-    pub fn create_with_path(path: impl AsRef<Path>) -> Result<BrotliBlock<impl Read>, ArchiveError> {
+impl BrotliBlock<BufReader<File>> {
+    pub fn create_with_path(path: impl AsRef<Path>) -> Result<Self, ArchiveError> {
         let rdr = BufReader::new(std::fs::File::open(path)?);
         Self::create_with_reader(rdr)
     }
 }
+
+impl<R: Read> Read for BrotliBlock<R> {
+    fn read(&mut self, into: &mut [u8]) -> std::io::Result<usize> {
+        let size = self.inner.read(into)?;
+        self.total_out += size as u64;
+        Ok(size)
+    }
+}
+
+impl<R: Read> BlockDecoder for BrotliBlock<R> {
+    fn format(&self) -> BlockFormat {
+        BlockFormat::Brotli
+    }
+
+    fn compressed_size(&self) -> u64 {
+        self.inner.get_ref().count()
+    }
+
+    fn uncompressed_size(&self) -> u64 {
+        self.total_out
+    }
+}
+
+impl<R: Read> FromReader<R> for BrotliBlock<R> {
+    fn from_reader(rdr: R) -> Result<Self, ArchiveError> {
+        Self::create_with_reader(rdr)
+    }
+}
+
+/// `level` is the brotli quality, from 0 to 11, higher levels are clamped to 11.
+pub struct BrotliEncoder<W: Write> {
+    inner: brotli::CompressorWriter<Checked<W>>,
+}
+
+/// Keeps a copy of the first error of `inner`, brotli swallows the ones of the final block.
+struct Checked<W: Write> {
+    inner: W,
+    error: Option<std::io::Error>,
+}
+
+impl<W: Write> Write for Checked<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.write(buf).inspect_err(|e| self.keep(e))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush().inspect_err(|e| self.keep(e))
+    }
+}
+
+impl<W: Write> Checked<W> {
+    fn keep(&mut self, e: &std::io::Error) {
+        if self.error.is_none() && e.kind() != std::io::ErrorKind::Interrupted {
+            self.error = Some(std::io::Error::new(e.kind(), e.to_string()));
+        }
+    }
+}
+
+impl<W> BrotliEncoder<W>
+where
+    W: Write,
+{
+    pub fn create_with_writer(writer: W, level: u32) -> Result<BrotliEncoder<W>, ArchiveError> {
+        let writer = Checked {
+            inner: writer,
+            error: None,
+        };
+        let inner = brotli::CompressorWriter::new(writer, 4096, level.min(11), 22);
+        let encoder = BrotliEncoder { inner };
+
+        Ok(encoder)
+    }
+
+    /// Writes out whatever is still buffered plus the stream trailer and returns the writer.
+    pub fn finish(self) -> Result<W, ArchiveError> {
+        let writer = self.inner.into_inner();
+        match writer.error {
+            Some(e) => Err(e.into()),
+            None => Ok(writer.inner),
+        }
+    }
+}
+
+impl BrotliEncoder<File> {
+    pub fn create_with_path(path: impl AsRef<Path>, level: u32) -> Result<Self, ArchiveError> {
+        let writer = std::fs::File::create(path)?;
+        Self::create_with_writer(writer, level)
+    }
+}
+
+impl<W: Write> Write for BrotliEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> BlockEncoder for BrotliEncoder<W> {
+    fn format(&self) -> BlockFormat {
+        BlockFormat::Brotli
+    }
+
+    fn close(self: Box<Self>) -> Result<(), ArchiveError> {
+        self.finish()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,122 @@
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+
+use bzip2::read::MultiBzDecoder;
+
+use crate::block::{BlockDecoder, BlockEncoder, BlockFormat, CountingReader, FromReader};
+use crate::utils::error::ArchiveError;
+
+pub struct Bzip2Block<R: Read> {
+    inner: MultiBzDecoder<CountingReader<R>>,
+    total_out: u64,
+}
+
+impl<R> Bzip2Block<R>
+where
+    R: Read,
+{
+    pub fn unpack_to(&mut self, to: impl AsRef<Path>) -> Result<(), ArchiveError> {
+        let mut writer = std::fs::File::create(to)?;
+        self.decode_to(&mut writer)?;
+
+        Ok(())
+    }
+
+    pub fn create_with_reader(rdr: R) -> Result<Bzip2Block<R>, ArchiveError> {
+        // pbzip2 and friends emit several bzip2 streams back to back, decode all of them.
+        let inner = MultiBzDecoder::new(CountingReader::new(rdr));
+        let block = Bzip2Block {
+            inner,
+            total_out: 0,
+        };
+
+        Ok(block)
+    }
+}
+
+impl Bzip2Block<BufReader<File>> {
+    pub fn create_with_path(path: impl AsRef<Path>) -> Result<Self, ArchiveError> {
+        let rdr = BufReader::new(std::fs::File::open(path)?);
+        Self::create_with_reader(rdr)
+    }
+}
+
+impl<R: Read> Read for Bzip2Block<R> {
+    fn read(&mut self, into: &mut [u8]) -> std::io::Result<usize> {
+        let size = self.inner.read(into)?;
+        self.total_out += size as u64;
+        Ok(size)
+    }
+}
+
+impl<R: Read> BlockDecoder for Bzip2Block<R> {
+    fn format(&self) -> BlockFormat {
+        BlockFormat::Bzip2
+    }
+
+    fn compressed_size(&self) -> u64 {
+        self.inner.get_ref().count()
+    }
+
+    fn uncompressed_size(&self) -> u64 {
+        self.total_out
+    }
+}
+
+impl<R: Read> FromReader<R> for Bzip2Block<R> {
+    fn from_reader(rdr: R) -> Result<Self, ArchiveError> {
+        Self::create_with_reader(rdr)
+    }
+}
+
+/// `level` is the block size in 100k units, from 1 to 9. Levels outside are clamped.
+pub struct Bzip2Encoder<W: Write> {
+    inner: bzip2::write::BzEncoder<W>,
+}
+
+impl<W> Bzip2Encoder<W>
+where
+    W: Write,
+{
+    pub fn create_with_writer(writer: W, level: u32) -> Result<Bzip2Encoder<W>, ArchiveError> {
+        let inner = bzip2::write::BzEncoder::new(writer, bzip2::Compression::new(level.clamp(1, 9)));
+        let encoder = Bzip2Encoder { inner };
+
+        Ok(encoder)
+    }
+
+    /// Writes out whatever is still buffered plus the stream trailer and returns the writer.
+    pub fn finish(self) -> Result<W, ArchiveError> {
+        let writer = self.inner.finish()?;
+        Ok(writer)
+    }
+}
+
+impl Bzip2Encoder<File> {
+    pub fn create_with_path(path: impl AsRef<Path>, level: u32) -> Result<Self, ArchiveError> {
+        let writer = std::fs::File::create(path)?;
+        Self::create_with_writer(writer, level)
+    }
+}
+
+impl<W: Write> Write for Bzip2Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> BlockEncoder for Bzip2Encoder<W> {
+    fn format(&self) -> BlockFormat {
+        BlockFormat::Bzip2
+    }
+
+    fn close(self: Box<Self>) -> Result<(), ArchiveError> {
+        self.finish()?;
+        Ok(())
+    }
+}
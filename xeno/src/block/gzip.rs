@@ -1,50 +1,206 @@
+use std::fs::File;
 use std::io::{BufReader, Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
-use flate2::read::GzDecoder;
+use flate2::read::MultiGzDecoder;
 
+use crate::block::{BlockDecoder, BlockEncoder, BlockFormat, CountingReader, FromReader};
 use crate::utils::error::ArchiveError;
 
+/// Decodes every member of a gzip file, like `gunzip` does for concatenated files.
 pub struct GzipBlock<R: Read> {
-    inner: GzDecoder<R>,
+    inner: MultiGzDecoder<CountingReader<R>>,
+    total_out: u64,
+}
+
+/// The member header of a gzip stream (RFC 1952).
+#[derive(Debug, Clone)]
+pub struct GzipHeader {
+    filename: Option<Vec<u8>>,
+    comment: Option<Vec<u8>>,
+    extra: Option<Vec<u8>>,
+    mtime: u32,
+    os: u8,
+}
+
+impl GzipHeader {
+    /// The original file name (`FNAME`), as raw bytes.
+    pub fn filename(&self) -> Option<&[u8]> {
+        self.filename.as_deref()
+    }
+
+    /// The file comment (`FCOMMENT`), as raw bytes.
+    pub fn comment(&self) -> Option<&[u8]> {
+        self.comment.as_deref()
+    }
+
+    /// The raw `FEXTRA` field, a list of subfields with a two byte ID and a length.
+    pub fn extra(&self) -> Option<&[u8]> {
+        self.extra.as_deref()
+    }
+
+    /// Modification time of the original file in seconds since the epoch, 0 if not recorded.
+    pub fn mtime(&self) -> u32 {
+        self.mtime
+    }
+
+    /// The operating system the stream was written on, 255 if unknown.
+    pub fn operating_system(&self) -> u8 {
+        self.os
+    }
+
+    pub fn modified(&self) -> Option<SystemTime> {
+        if self.mtime == 0 {
+            return None;
+        }
+
+        Some(SystemTime::UNIX_EPOCH + Duration::from_secs(u64::from(self.mtime)))
+    }
 }
 
 impl<R> GzipBlock<R>
 where
     R: Read,
 {
+    /// Decodes to the file `to`. When `to` is a directory the output is named after the
+    /// original file name and gets its modification time from the header, like `gunzip -N`.
     pub fn unpack_to(&mut self, to: impl AsRef<Path>) -> Result<(), ArchiveError> {
-        let mut writer = std::fs::File::create(to)?;
-        let mut buf = [0u8; 4096];
-        loop {
-            let bytes_read = match self.inner.read(&mut buf) {
-                Ok(size) => size,
-                Err(e) => {
-                    let err = ArchiveError::Io(e);
-                    return Err(err);
-                }
-            };
-
-            if bytes_read == 0 {
-                break;
-            }
-
-            writer.write_all(&buf[..bytes_read])?;
+        let to = to.as_ref();
+        if !to.is_dir() {
+            let mut writer = std::fs::File::create(to)?;
+            self.decode_to(&mut writer)?;
+            return Ok(());
+        }
+
+        let header = self
+            .header()
+            .ok_or(ArchiveError::GenericsError("invalid gzip header"))?;
+        let filename = header
+            .filename()
+            .map(|name| PathBuf::from(String::from_utf8_lossy(name).into_owned()))
+            .ok_or(ArchiveError::GenericsError(
+                "gzip header has no original file name",
+            ))?;
+        // Only keep the final component so a crafted FNAME cannot escape `to`.
+        let filename = filename.file_name().ok_or(ArchiveError::GenericsError(
+            "invalid original file name in gzip header",
+        ))?;
+
+        let mut writer = std::fs::File::create(to.join(filename))?;
+        self.decode_to(&mut writer)?;
+        if let Some(modified) = header.modified() {
+            writer.set_modified(modified)?;
         }
 
         Ok(())
     }
 
-    pub fn create_with_reader(rdr: impl Read) -> Result<GzipBlock<impl Read>, ArchiveError> {
-        let reader = GzDecoder::new(rdr);
+    /// The header of the member being decoded. For concatenated files this changes as the
+    /// stream advances; right after creation it is the header of the first member.
+    pub fn header(&self) -> Option<GzipHeader> {
+        self.inner.header().map(|header| GzipHeader {
+            filename: header.filename().map(|v| v.to_vec()),
+            comment: header.comment().map(|v| v.to_vec()),
+            extra: header.extra().map(|v| v.to_vec()),
+            mtime: header.mtime(),
+            os: header.operating_system(),
+        })
+    }
 
-        let block = GzipBlock { inner: reader };
+    pub fn create_with_reader(rdr: R) -> Result<GzipBlock<R>, ArchiveError> {
+        let inner = MultiGzDecoder::new(CountingReader::new(rdr));
+        let block = GzipBlock {
+            inner,
+            total_out: 0,
+        };
 
         Ok(block)
     }
+}
 
-    pub fn create_with_path(path: impl AsRef<Path>) -> Result<GzipBlock<impl Read>, ArchiveError> {
+impl GzipBlock<BufReader<File>> {
+    pub fn create_with_path(path: impl AsRef<Path>) -> Result<Self, ArchiveError> {
         let rdr = BufReader::new(std::fs::File::open(path)?);
         Self::create_with_reader(rdr)
     }
 }
+
+impl<R: Read> Read for GzipBlock<R> {
+    fn read(&mut self, into: &mut [u8]) -> std::io::Result<usize> {
+        let size = self.inner.read(into)?;
+        self.total_out += size as u64;
+        Ok(size)
+    }
+}
+
+impl<R: Read> BlockDecoder for GzipBlock<R> {
+    fn format(&self) -> BlockFormat {
+        BlockFormat::Gzip
+    }
+
+    fn compressed_size(&self) -> u64 {
+        self.inner.get_ref().count()
+    }
+
+    fn uncompressed_size(&self) -> u64 {
+        self.total_out
+    }
+}
+
+impl<R: Read> FromReader<R> for GzipBlock<R> {
+    fn from_reader(rdr: R) -> Result<Self, ArchiveError> {
+        Self::create_with_reader(rdr)
+    }
+}
+
+/// `level` ranges from 0 (store) to 9 (best), higher levels are clamped to 9.
+pub struct GzipEncoder<W: Write> {
+    inner: flate2::write::GzEncoder<W>,
+}
+
+impl<W> GzipEncoder<W>
+where
+    W: Write,
+{
+    pub fn create_with_writer(writer: W, level: u32) -> Result<GzipEncoder<W>, ArchiveError> {
+        let inner = flate2::write::GzEncoder::new(writer, flate2::Compression::new(level.min(9)));
+        let encoder = GzipEncoder { inner };
+
+        Ok(encoder)
+    }
+
+    /// Writes out whatever is still buffered plus the stream trailer and returns the writer.
+    pub fn finish(self) -> Result<W, ArchiveError> {
+        let writer = self.inner.finish()?;
+        Ok(writer)
+    }
+}
+
+impl GzipEncoder<File> {
+    pub fn create_with_path(path: impl AsRef<Path>, level: u32) -> Result<Self, ArchiveError> {
+        let writer = std::fs::File::create(path)?;
+        Self::create_with_writer(writer, level)
+    }
+}
+
+impl<W: Write> Write for GzipEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> BlockEncoder for GzipEncoder<W> {
+    fn format(&self) -> BlockFormat {
+        BlockFormat::Gzip
+    }
+
+    fn close(self: Box<Self>) -> Result<(), ArchiveError> {
+        self.finish()?;
+        Ok(())
+    }
+}
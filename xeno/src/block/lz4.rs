@@ -1,12 +1,125 @@
-use std::io::{BufReader, Read, Write};
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read, Write};
 use std::path::Path;
 
 use lz4::Decoder;
 
+use crate::block::{
+    is_skippable_magic, read_frame_magic, read_skippable_frame, BlockDecoder, BlockEncoder,
+    BlockFormat, CountingReader, FromReader, SkippableFrame,
+};
 use crate::utils::error::ArchiveError;
 
+const LZ4_MAGIC: u32 = 0x184d_2204;
+const LEGACY_MAGIC: u32 = 0x184c_2102;
+/// Every block of the legacy format decodes to 8 MiB, only the last one may be shorter.
+const LEGACY_BLOCK_SIZE: usize = 8 << 20;
+/// Each input byte of an lz4 block stretches a match by at most 255 bytes, so a raw block never
+/// decodes to more than `MAX_RATIO` times its own size.
+const MAX_RATIO: usize = 256;
+
+type FrameReader<R> = FrameBounds<CountingReader<R>>;
+
+enum FrameStage {
+    Header,
+    BlockSize,
+    End,
+}
+
+/// Passes through exactly one lz4 frame by following its block headers, then reports the end
+/// of the stream. `lz4::Decoder` reads past the end of the frame otherwise, which would eat
+/// into whatever frame comes next.
+struct FrameBounds<R> {
+    inner: R,
+    pending: Cursor<Vec<u8>>,
+    remaining: u64,
+    stage: FrameStage,
+    block_checksum: bool,
+    content_checksum: bool,
+}
+
+impl<R: Read> FrameBounds<R> {
+    /// Starts a frame whose magic has already been read from `inner`.
+    fn new(inner: R) -> FrameBounds<R> {
+        FrameBounds {
+            inner,
+            pending: Cursor::new(LZ4_MAGIC.to_le_bytes().to_vec()),
+            remaining: 0,
+            stage: FrameStage::Header,
+            block_checksum: false,
+            content_checksum: false,
+        }
+    }
+
+    fn read_pending(&mut self, size: usize) -> std::io::Result<&[u8]> {
+        let mut pending = vec![0u8; size];
+        self.inner.read_exact(&mut pending)?;
+        self.pending = Cursor::new(pending);
+        Ok(self.pending.get_ref())
+    }
+}
+
+impl<R: Read> Read for FrameBounds<R> {
+    fn read(&mut self, into: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let size = self.pending.read(into)?;
+            if size > 0 {
+                return Ok(size);
+            }
+
+            if self.remaining > 0 {
+                let size = (&mut self.inner).take(self.remaining).read(into)?;
+                self.remaining -= size as u64;
+                return Ok(size);
+            }
+
+            match self.stage {
+                FrameStage::Header => {
+                    // FLG and BD, then the optional content size and dictionary ID, then HC.
+                    let flg = self.read_pending(2)?[0];
+                    self.block_checksum = flg & 0x10 != 0;
+                    self.content_checksum = flg & 0x04 != 0;
+                    self.remaining = 1;
+                    if flg & 0x08 != 0 {
+                        self.remaining += 8;
+                    }
+                    if flg & 0x01 != 0 {
+                        self.remaining += 4;
+                    }
+                    self.stage = FrameStage::BlockSize;
+                }
+                FrameStage::BlockSize => {
+                    let size = self.read_pending(4)?;
+                    let size = u32::from_le_bytes(size.try_into().unwrap());
+                    if size == 0 {
+                        self.remaining = if self.content_checksum { 4 } else { 0 };
+                        self.stage = FrameStage::End;
+                    } else {
+                        self.remaining = u64::from(size & 0x7fff_ffff);
+                        if self.block_checksum {
+                            self.remaining += 4;
+                        }
+                    }
+                }
+                FrameStage::End => return Ok(0),
+            }
+        }
+    }
+}
+
+enum Lz4State<R: Read> {
+    Idle(CountingReader<R>),
+    Frame(Decoder<FrameReader<R>>),
+    Legacy(CountingReader<R>, Cursor<Vec<u8>>),
+    Done(CountingReader<R>),
+}
+
+/// Decodes every frame of an lz4 stream, including the legacy format written by `lz4 -l` and
+/// used for Linux kernel images. Skippable frames are skipped and kept as metadata.
 pub struct Lz4Block<R: Read> {
-    inner: Decoder<R>,
+    state: Option<Lz4State<R>>,
+    skippable_frames: Vec<SkippableFrame>,
+    total_out: u64,
 }
 
 impl<R> Lz4Block<R>
@@ -15,21 +128,343 @@ where
 {
     pub fn unpack_to(&mut self, to: impl AsRef<Path>) -> Result<(), ArchiveError> {
         let mut writer = std::fs::File::create(to)?;
-        std::io::copy(&mut self.inner, &mut writer)?;
+        self.decode_to(&mut writer)?;
 
         Ok(())
     }
 
-    pub fn create_with_reader(rdr: impl Read) -> Result<Lz4Block<impl Read>, ArchiveError> {
-        let reader = Decoder::new(rdr)?;
+    /// Skippable frames seen so far. The whole list is only known once the stream has been
+    /// decoded to the end.
+    pub fn skippable_frames(&self) -> &[SkippableFrame] {
+        &self.skippable_frames
+    }
 
-        let block = Lz4Block { inner: reader };
+    pub fn create_with_reader(rdr: R) -> Result<Lz4Block<R>, ArchiveError> {
+        let block = Lz4Block {
+            state: Some(Lz4State::Idle(CountingReader::new(rdr))),
+            skippable_frames: vec![],
+            total_out: 0,
+        };
 
         Ok(block)
     }
 
-    pub fn create_with_path(path: impl AsRef<Path>) -> Result<Lz4Block<impl Read>, ArchiveError> {
+    fn next_frame(&mut self, mut rdr: CountingReader<R>) -> std::io::Result<Lz4State<R>> {
+        match read_frame_magic(&mut rdr)? {
+            Some(magic) => self.open_frame(magic, rdr),
+            None => Ok(Lz4State::Done(rdr)),
+        }
+    }
+
+    fn open_frame(
+        &mut self,
+        magic: u32,
+        mut rdr: CountingReader<R>,
+    ) -> std::io::Result<Lz4State<R>> {
+        match magic {
+            LZ4_MAGIC => Ok(Lz4State::Frame(Decoder::new(FrameBounds::new(rdr))?)),
+            LEGACY_MAGIC => Ok(Lz4State::Legacy(rdr, Cursor::new(vec![]))),
+            magic if is_skippable_magic(magic) => {
+                let frame = read_skippable_frame(magic, &mut rdr)?;
+                self.skippable_frames.push(frame);
+                Ok(Lz4State::Idle(rdr))
+            }
+            magic => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unknown lz4 frame magic {:#010x}", magic),
+            )),
+        }
+    }
+
+    /// Decodes the next block of a legacy frame. The format has no end marker, the frame ends
+    /// at the end of the stream or wherever the next frame magic shows up.
+    fn next_legacy_block(
+        &mut self,
+        mut rdr: CountingReader<R>,
+        mut buffer: Vec<u8>,
+    ) -> std::io::Result<Lz4State<R>> {
+        let size = match read_frame_magic(&mut rdr)? {
+            None | Some(0) => return Ok(Lz4State::Done(rdr)),
+            Some(LEGACY_MAGIC) => return Ok(Lz4State::Legacy(rdr, Cursor::new(buffer))),
+            Some(magic) if magic == LZ4_MAGIC || is_skippable_magic(magic) => {
+                return self.open_frame(magic, rdr)
+            }
+            Some(size) => size as usize,
+        };
+
+        // Worst case expansion of incompressible data, LZ4_COMPRESSBOUND in lz4.h.
+        if size > LEGACY_BLOCK_SIZE + LEGACY_BLOCK_SIZE / 255 + 16 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("lz4 legacy block of {} bytes is too large", size),
+            ));
+        }
+
+        let mut compressed = vec![0u8; size];
+        rdr.read_exact(&mut compressed)?;
+        buffer.resize(LEGACY_BLOCK_SIZE, 0);
+        let size = lz4::block::decompress_to_buffer(
+            &compressed,
+            Some(LEGACY_BLOCK_SIZE as i32),
+            &mut buffer,
+        )?;
+        buffer.truncate(size);
+
+        Ok(Lz4State::Legacy(rdr, Cursor::new(buffer)))
+    }
+}
+
+impl Lz4Block<BufReader<File>> {
+    pub fn create_with_path(path: impl AsRef<Path>) -> Result<Self, ArchiveError> {
         let rdr = BufReader::new(std::fs::File::open(path)?);
         Self::create_with_reader(rdr)
     }
 }
+
+impl<R: Read> Read for Lz4Block<R> {
+    fn read(&mut self, into: &mut [u8]) -> std::io::Result<usize> {
+        if into.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            let state = match self.state.take() {
+                Some(Lz4State::Frame(mut decoder)) => {
+                    let size = decoder.read(into)?;
+                    if size > 0 {
+                        self.state = Some(Lz4State::Frame(decoder));
+                        self.total_out += size as u64;
+                        return Ok(size);
+                    }
+
+                    let (rdr, result) = decoder.finish();
+                    if result.is_err() {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::UnexpectedEof,
+                            "lz4 frame is truncated",
+                        ));
+                    }
+                    self.next_frame(rdr.inner)?
+                }
+                Some(Lz4State::Legacy(rdr, mut block)) => {
+                    let size = block.read(into)?;
+                    if size > 0 {
+                        self.state = Some(Lz4State::Legacy(rdr, block));
+                        self.total_out += size as u64;
+                        return Ok(size);
+                    }
+
+                    self.next_legacy_block(rdr, block.into_inner())?
+                }
+                Some(Lz4State::Idle(rdr)) => self.next_frame(rdr)?,
+                Some(Lz4State::Done(rdr)) => {
+                    self.state = Some(Lz4State::Done(rdr));
+                    return Ok(0);
+                }
+                None => {
+                    return Err(std::io::Error::other(
+                        "lz4 stream is unusable after a previous error",
+                    ))
+                }
+            };
+            self.state = Some(state);
+        }
+    }
+}
+
+impl<R: Read> BlockDecoder for Lz4Block<R> {
+    fn format(&self) -> BlockFormat {
+        BlockFormat::Lz4
+    }
+
+    fn compressed_size(&self) -> u64 {
+        match &self.state {
+            Some(Lz4State::Idle(rdr))
+            | Some(Lz4State::Legacy(rdr, _))
+            | Some(Lz4State::Done(rdr)) => rdr.count(),
+            Some(Lz4State::Frame(decoder)) => decoder.reader().inner.count(),
+            None => 0,
+        }
+    }
+
+    fn uncompressed_size(&self) -> u64 {
+        self.total_out
+    }
+}
+
+impl<R: Read> FromReader<R> for Lz4Block<R> {
+    fn from_reader(rdr: R) -> Result<Self, ArchiveError> {
+        Self::create_with_reader(rdr)
+    }
+}
+
+/// A single raw lz4 block, without any frame around it. The decompressed size is either given
+/// by the caller or taken from a little endian u32 in front of the block, the layout
+/// `lz4::block::compress` and many firmware tools write. The block is decoded in one go on the
+/// first read.
+pub struct RawLz4Block<R: Read> {
+    inner: Option<CountingReader<R>>,
+    size: Option<usize>,
+    decoded: Cursor<Vec<u8>>,
+    total_in: u64,
+}
+
+impl<R> RawLz4Block<R>
+where
+    R: Read,
+{
+    pub fn unpack_to(&mut self, to: impl AsRef<Path>) -> Result<(), ArchiveError> {
+        let mut writer = std::fs::File::create(to)?;
+        self.decode_to(&mut writer)?;
+
+        Ok(())
+    }
+
+    /// Reads the decompressed size from the u32 prefix of the block.
+    pub fn create_with_reader(rdr: R) -> Result<RawLz4Block<R>, ArchiveError> {
+        let block = RawLz4Block {
+            inner: Some(CountingReader::new(rdr)),
+            size: None,
+            decoded: Cursor::new(vec![]),
+            total_in: 0,
+        };
+
+        Ok(block)
+    }
+
+    /// For blocks without a size prefix. `size` is the upper bound of the decompressed data,
+    /// usually known from the container, e.g. the block size of a SquashFS image.
+    pub fn create_with_size(rdr: R, size: usize) -> Result<RawLz4Block<R>, ArchiveError> {
+        if i32::try_from(size).is_err() {
+            return Err(ArchiveError::GenericsError(
+                "lz4 blocks cannot exceed 2 GiB",
+            ));
+        }
+
+        let mut block = Self::create_with_reader(rdr)?;
+        block.size = Some(size);
+        Ok(block)
+    }
+
+    fn decode(&mut self) -> Result<(), ArchiveError> {
+        if let Some(mut rdr) = self.inner.take() {
+            let mut compressed = vec![];
+            rdr.read_to_end(&mut compressed)?;
+            let decoded = match self.size {
+                Some(size) => {
+                    let limit = compressed.len().saturating_mul(MAX_RATIO);
+                    lz4::block::decompress(&compressed, Some(size.min(limit) as i32))?
+                }
+                None => {
+                    if compressed.len() < 4 {
+                        return Err(ArchiveError::GenericsError("truncated lz4 block"));
+                    }
+                    let (prefix, compressed) = compressed.split_at(4);
+                    let size = u32::from_le_bytes(prefix.try_into().unwrap()) as usize;
+                    if size > compressed.len().saturating_mul(MAX_RATIO) || size > i32::MAX as usize
+                    {
+                        return Err(ArchiveError::GenericsError(
+                            "lz4 block is too small for its decompressed size",
+                        ));
+                    }
+                    lz4::block::decompress(compressed, Some(size as i32))?
+                }
+            };
+            self.total_in = rdr.count();
+            self.decoded = Cursor::new(decoded);
+        }
+
+        Ok(())
+    }
+}
+
+impl RawLz4Block<File> {
+    pub fn create_with_path(path: impl AsRef<Path>) -> Result<Self, ArchiveError> {
+        let rdr = std::fs::File::open(path)?;
+        Self::create_with_reader(rdr)
+    }
+}
+
+impl<R: Read> Read for RawLz4Block<R> {
+    fn read(&mut self, into: &mut [u8]) -> std::io::Result<usize> {
+        self.decode()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+        self.decoded.read(into)
+    }
+}
+
+impl<R: Read> BlockDecoder for RawLz4Block<R> {
+    fn format(&self) -> BlockFormat {
+        BlockFormat::RawLz4
+    }
+
+    fn compressed_size(&self) -> u64 {
+        match &self.inner {
+            Some(rdr) => rdr.count(),
+            None => self.total_in,
+        }
+    }
+
+    fn uncompressed_size(&self) -> u64 {
+        self.decoded.position()
+    }
+}
+
+impl<R: Read> FromReader<R> for RawLz4Block<R> {
+    fn from_reader(rdr: R) -> Result<Self, ArchiveError> {
+        Self::create_with_reader(rdr)
+    }
+}
+
+/// Writes the lz4 frame format. `level` 0 is the fast default, 3 to 16 select lz4hc, higher
+/// levels are clamped to 16.
+pub struct Lz4Encoder<W: Write> {
+    inner: lz4::Encoder<W>,
+}
+
+impl<W> Lz4Encoder<W>
+where
+    W: Write,
+{
+    pub fn create_with_writer(writer: W, level: u32) -> Result<Lz4Encoder<W>, ArchiveError> {
+        let inner = lz4::EncoderBuilder::new().level(level.min(16)).build(writer)?;
+        let encoder = Lz4Encoder { inner };
+
+        Ok(encoder)
+    }
+
+    /// Writes out whatever is still buffered plus the stream trailer and returns the writer.
+    pub fn finish(self) -> Result<W, ArchiveError> {
+        let (writer, result) = self.inner.finish();
+        result?;
+        Ok(writer)
+    }
+}
+
+impl Lz4Encoder<File> {
+    pub fn create_with_path(path: impl AsRef<Path>, level: u32) -> Result<Self, ArchiveError> {
+        let writer = std::fs::File::create(path)?;
+        Self::create_with_writer(writer, level)
+    }
+}
+
+impl<W: Write> Write for Lz4Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> BlockEncoder for Lz4Encoder<W> {
+    fn format(&self) -> BlockFormat {
+        BlockFormat::Lz4
+    }
+
+    fn close(self: Box<Self>) -> Result<(), ArchiveError> {
+        self.finish()?;
+        Ok(())
+    }
+}
@@ -1,35 +1,163 @@
-use std::io::{BufRead, BufReader, Read};
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read};
 use std::path::Path;
 
+use xz2::read::XzDecoder;
+use xz2::stream::Stream;
+
+use crate::block::{BlockDecoder, BlockFormat, CountingReader, FromReader};
 use crate::utils::error::ArchiveError;
 
-pub struct LzmaBlock<R: Read + BufRead> {
-    inner: R,
+/// Legacy `.lzma` ("LZMA-alone") streams: a 13 byte header followed by raw LZMA1 data.
+pub struct LzmaAloneBlock<R: Read> {
+    inner: XzDecoder<CountingReader<R>>,
+    total_out: u64,
+}
+
+/// Raw LZMA2 chunks without any container, as used by a number of firmware loaders.
+///
+/// LZMA2 has no streaming decoder available here, so the whole payload is decoded on the first
+/// read. A corrupt payload fails that read and every later one.
+pub struct Lzma2Block<R: Read> {
+    inner: Option<BufReader<CountingReader<R>>>,
+    decoded: Cursor<Vec<u8>>,
+    total_in: u64,
+    /// Why decoding failed, the source is consumed by then.
+    error: Option<String>,
 }
 
-impl<R> LzmaBlock<R>
+impl<R> LzmaAloneBlock<R>
 where
-    R: Read + BufRead,
+    R: Read,
 {
     pub fn unpack_to(&mut self, to: impl AsRef<Path>) -> Result<(), ArchiveError> {
         let mut writer = std::fs::File::create(to)?;
-        lzma_rs::xz_decompress(&mut self.inner, &mut writer).map_err(ArchiveError::LzmaError)?;
+        self.decode_to(&mut writer)?;
 
         Ok(())
     }
 
-    pub fn create_with_reader(
-        rdr: impl Read + BufRead,
-    ) -> Result<LzmaBlock<impl Read + BufRead>, ArchiveError> {
-        let block = LzmaBlock { inner: rdr };
+    pub fn create_with_reader(rdr: R) -> Result<LzmaAloneBlock<R>, ArchiveError> {
+        let stream = Stream::new_lzma_decoder(u64::MAX).map_err(ArchiveError::XzError)?;
+        let inner = XzDecoder::new_stream(CountingReader::new(rdr), stream);
+        let block = LzmaAloneBlock {
+            inner,
+            total_out: 0,
+        };
 
         Ok(block)
     }
+}
 
-    pub fn create_with_path(
-        path: impl AsRef<Path>,
-    ) -> Result<LzmaBlock<impl Read + BufRead>, ArchiveError> {
+impl LzmaAloneBlock<BufReader<File>> {
+    pub fn create_with_path(path: impl AsRef<Path>) -> Result<Self, ArchiveError> {
         let rdr = BufReader::new(std::fs::File::open(path)?);
         Self::create_with_reader(rdr)
     }
 }
+
+impl<R: Read> Read for LzmaAloneBlock<R> {
+    fn read(&mut self, into: &mut [u8]) -> std::io::Result<usize> {
+        let size = self.inner.read(into)?;
+        self.total_out += size as u64;
+        Ok(size)
+    }
+}
+
+impl<R: Read> BlockDecoder for LzmaAloneBlock<R> {
+    fn format(&self) -> BlockFormat {
+        BlockFormat::LzmaAlone
+    }
+
+    fn compressed_size(&self) -> u64 {
+        self.inner.get_ref().count()
+    }
+
+    fn uncompressed_size(&self) -> u64 {
+        self.total_out
+    }
+}
+
+impl<R: Read> FromReader<R> for LzmaAloneBlock<R> {
+    fn from_reader(rdr: R) -> Result<Self, ArchiveError> {
+        Self::create_with_reader(rdr)
+    }
+}
+
+impl<R> Lzma2Block<R>
+where
+    R: Read,
+{
+    pub fn unpack_to(&mut self, to: impl AsRef<Path>) -> Result<(), ArchiveError> {
+        let mut writer = std::fs::File::create(to)?;
+        self.decode_to(&mut writer)?;
+
+        Ok(())
+    }
+
+    pub fn create_with_reader(rdr: R) -> Result<Lzma2Block<R>, ArchiveError> {
+        let block = Lzma2Block {
+            inner: Some(BufReader::new(CountingReader::new(rdr))),
+            decoded: Cursor::new(vec![]),
+            total_in: 0,
+            error: None,
+        };
+
+        Ok(block)
+    }
+
+    fn decode(&mut self) -> Result<(), ArchiveError> {
+        if let Some(mut rdr) = self.inner.take() {
+            let mut decoded = vec![];
+            let result = lzma_rs::lzma2_decompress(&mut rdr, &mut decoded);
+            self.total_in = rdr.get_ref().count();
+            match result {
+                Ok(()) => self.decoded = Cursor::new(decoded),
+                Err(e) => self.error = Some(ArchiveError::LzmaError(e).to_string()),
+            }
+        }
+        if let Some(error) = &self.error {
+            return Err(ArchiveError::GenericsError2(error.clone()));
+        }
+
+        Ok(())
+    }
+}
+
+impl Lzma2Block<File> {
+    pub fn create_with_path(path: impl AsRef<Path>) -> Result<Self, ArchiveError> {
+        let rdr = std::fs::File::open(path)?;
+        Self::create_with_reader(rdr)
+    }
+}
+
+impl<R: Read> Read for Lzma2Block<R> {
+    fn read(&mut self, into: &mut [u8]) -> std::io::Result<usize> {
+        self.decode()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+        self.decoded.read(into)
+    }
+}
+
+impl<R: Read> BlockDecoder for Lzma2Block<R> {
+    fn format(&self) -> BlockFormat {
+        BlockFormat::Lzma2
+    }
+
+    fn compressed_size(&self) -> u64 {
+        match &self.inner {
+            Some(rdr) => rdr.get_ref().count(),
+            None => self.total_in,
+        }
+    }
+
+    fn uncompressed_size(&self) -> u64 {
+        self.decoded.position()
+    }
+}
+
+impl<R: Read> FromReader<R> for Lzma2Block<R> {
+    fn from_reader(rdr: R) -> Result<Self, ArchiveError> {
+        Self::create_with_reader(rdr)
+    }
+}
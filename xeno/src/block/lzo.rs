@@ -0,0 +1,335 @@
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read};
+use std::path::Path;
+
+use rust_lzo::{LZOContext, LZOError};
+
+use crate::block::{BlockDecoder, BlockFormat, CountingReader, FromReader};
+use crate::utils::error::ArchiveError;
+
+/// LZO1X never expands data by more than this, so a guessed output buffer is grown up to
+/// `MAX_RATIO` times the input before giving up.
+const MAX_RATIO: usize = 256;
+
+/// A raw LZO1X stream as found in SquashFS, UBIFS, JFFS2 and kernel images, without the lzop
+/// container. The stream does not record its decompressed size, pass it to `create_with_size`
+/// when the container knows it, otherwise the output buffer is grown until the data fits.
+/// The stream is decoded in one go on the first read.
+pub struct LzoBlock<R: Read> {
+    inner: Option<CountingReader<R>>,
+    size: Option<usize>,
+    decoded: Cursor<Vec<u8>>,
+    total_in: u64,
+}
+
+impl<R> LzoBlock<R>
+where
+    R: Read,
+{
+    pub fn unpack_to(&mut self, to: impl AsRef<Path>) -> Result<(), ArchiveError> {
+        let mut writer = std::fs::File::create(to)?;
+        self.decode_to(&mut writer)?;
+
+        Ok(())
+    }
+
+    pub fn create_with_reader(rdr: R) -> Result<LzoBlock<R>, ArchiveError> {
+        let block = LzoBlock {
+            inner: Some(CountingReader::new(rdr)),
+            size: None,
+            decoded: Cursor::new(vec![]),
+            total_in: 0,
+        };
+
+        Ok(block)
+    }
+
+    /// `size` is the upper bound of the decompressed data, the buffer never grows past what the
+    /// input can decode to.
+    pub fn create_with_size(rdr: R, size: usize) -> Result<LzoBlock<R>, ArchiveError> {
+        let mut block = Self::create_with_reader(rdr)?;
+        block.size = Some(size);
+        Ok(block)
+    }
+
+    fn decode(&mut self) -> Result<(), ArchiveError> {
+        if let Some(mut rdr) = self.inner.take() {
+            let mut compressed = vec![];
+            rdr.read_to_end(&mut compressed)?;
+            let limit = compressed.len().saturating_mul(MAX_RATIO);
+            let decoded = match self.size {
+                Some(size) => decompress(&compressed, size.min(limit))?,
+                None => {
+                    let mut size = compressed.len().saturating_mul(4).max(4096);
+                    loop {
+                        match decompress(&compressed, size) {
+                            Err(LZOError::OUTPUT_OVERRUN) if size < limit => size *= 2,
+                            result => break result?,
+                        }
+                    }
+                }
+            };
+            self.total_in = rdr.count();
+            self.decoded = Cursor::new(decoded);
+        }
+
+        Ok(())
+    }
+}
+
+fn decompress(compressed: &[u8], size: usize) -> Result<Vec<u8>, LZOError> {
+    let mut decoded = vec![0u8; size];
+    let (out, err) = LZOContext::decompress_to_slice(compressed, &mut decoded);
+    let len = out.len();
+    if err != LZOError::OK {
+        return Err(err);
+    }
+
+    decoded.truncate(len);
+    Ok(decoded)
+}
+
+impl From<LZOError> for ArchiveError {
+    fn from(err: LZOError) -> Self {
+        let reason = match err {
+            LZOError::OUTPUT_OVERRUN => "lzo output is larger than expected",
+            LZOError::INPUT_OVERRUN => "lzo input is truncated",
+            LZOError::LOOKBEHIND_OVERRUN => "lzo data refers before the start of the output",
+            LZOError::INPUT_NOT_CONSUMED => "lzo stream is followed by trailing data",
+            _ => "lzo data is corrupted",
+        };
+        ArchiveError::GenericsError(reason)
+    }
+}
+
+impl LzoBlock<File> {
+    pub fn create_with_path(path: impl AsRef<Path>) -> Result<Self, ArchiveError> {
+        let rdr = std::fs::File::open(path)?;
+        Self::create_with_reader(rdr)
+    }
+}
+
+impl<R: Read> Read for LzoBlock<R> {
+    fn read(&mut self, into: &mut [u8]) -> std::io::Result<usize> {
+        self.decode()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+        self.decoded.read(into)
+    }
+}
+
+impl<R: Read> BlockDecoder for LzoBlock<R> {
+    fn format(&self) -> BlockFormat {
+        BlockFormat::Lzo
+    }
+
+    fn compressed_size(&self) -> u64 {
+        match &self.inner {
+            Some(rdr) => rdr.count(),
+            None => self.total_in,
+        }
+    }
+
+    fn uncompressed_size(&self) -> u64 {
+        self.decoded.position()
+    }
+}
+
+impl<R: Read> FromReader<R> for LzoBlock<R> {
+    fn from_reader(rdr: R) -> Result<Self, ArchiveError> {
+        Self::create_with_reader(rdr)
+    }
+}
+
+const LZOP_MAGIC: [u8; 9] = [0x89, b'L', b'Z', b'O', 0x00, 0x0d, 0x0a, 0x1a, 0x0a];
+
+/// lzop itself never writes blocks larger than this, `next_block` refuses anything bigger
+/// rather than allocating what the header asks for.
+const LZOP_MAX_BLOCK_SIZE: usize = 64 << 20;
+
+const F_ADLER32_D: u32 = 0x0000_0001;
+const F_ADLER32_C: u32 = 0x0000_0002;
+const F_H_EXTRA_FIELD: u32 = 0x0000_0040;
+const F_CRC32_D: u32 = 0x0000_0100;
+const F_CRC32_C: u32 = 0x0000_0200;
+const F_H_FILTER: u32 = 0x0000_0800;
+
+/// The lzop container (`lzop`, and what U-Boot and the kernel mean by "lzo"): a header followed
+/// by LZO1X blocks that each record their decompressed size. Blocks are decoded one at a time.
+/// Block checksums are not verified.
+pub struct LzopBlock<R: Read> {
+    inner: CountingReader<R>,
+    flags: u32,
+    filename: Vec<u8>,
+    mtime: u64,
+    block: Cursor<Vec<u8>>,
+    done: bool,
+    total_out: u64,
+}
+
+impl<R> LzopBlock<R>
+where
+    R: Read,
+{
+    pub fn unpack_to(&mut self, to: impl AsRef<Path>) -> Result<(), ArchiveError> {
+        let mut writer = std::fs::File::create(to)?;
+        self.decode_to(&mut writer)?;
+
+        Ok(())
+    }
+
+    /// Name of the compressed file as stored in the header, may be empty.
+    pub fn filename(&self) -> &[u8] {
+        &self.filename
+    }
+
+    /// Modification time of the compressed file in seconds since the epoch.
+    pub fn mtime(&self) -> u64 {
+        self.mtime
+    }
+
+    pub fn create_with_reader(rdr: R) -> Result<LzopBlock<R>, ArchiveError> {
+        let mut rdr = CountingReader::new(rdr);
+        let mut magic = [0u8; 9];
+        rdr.read_exact(&mut magic)?;
+        if magic != LZOP_MAGIC {
+            return Err(ArchiveError::GenericsError("not an lzop stream"));
+        }
+
+        let version = read_u16(&mut rdr)?;
+        let _lib_version = read_u16(&mut rdr)?;
+        if version >= 0x0940 {
+            let _version_needed = read_u16(&mut rdr)?;
+        }
+        let method = read_u8(&mut rdr)?;
+        if !(1..=3).contains(&method) {
+            return Err(ArchiveError::GenericsError(
+                "lzop stream uses an unsupported method",
+            ));
+        }
+        if version >= 0x0940 {
+            let _level = read_u8(&mut rdr)?;
+        }
+        let flags = read_u32(&mut rdr)?;
+        if flags & F_H_FILTER != 0 {
+            let _filter = read_u32(&mut rdr)?;
+        }
+        let _mode = read_u32(&mut rdr)?;
+        let mut mtime = u64::from(read_u32(&mut rdr)?);
+        if version >= 0x0940 {
+            mtime |= u64::from(read_u32(&mut rdr)?) << 32;
+        }
+        let mut filename = vec![0u8; usize::from(read_u8(&mut rdr)?)];
+        rdr.read_exact(&mut filename)?;
+        let _header_checksum = read_u32(&mut rdr)?;
+        if flags & F_H_EXTRA_FIELD != 0 {
+            let size = read_u32(&mut rdr)?;
+            std::io::copy(
+                &mut (&mut rdr).take(u64::from(size) + 4),
+                &mut std::io::sink(),
+            )?;
+        }
+
+        let block = LzopBlock {
+            inner: rdr,
+            flags,
+            filename,
+            mtime,
+            block: Cursor::new(vec![]),
+            done: false,
+            total_out: 0,
+        };
+
+        Ok(block)
+    }
+
+    fn next_block(&mut self) -> Result<(), ArchiveError> {
+        let decoded_size = read_u32(&mut self.inner)? as usize;
+        if decoded_size == 0 {
+            self.done = true;
+            return Ok(());
+        }
+
+        let size = read_u32(&mut self.inner)? as usize;
+        if decoded_size > LZOP_MAX_BLOCK_SIZE || size > decoded_size {
+            return Err(ArchiveError::GenericsError("invalid lzop block size"));
+        }
+        let mut checksums = (self.flags & (F_ADLER32_D | F_CRC32_D)).count_ones();
+        if size < decoded_size {
+            checksums += (self.flags & (F_ADLER32_C | F_CRC32_C)).count_ones();
+        }
+        for _ in 0..checksums {
+            read_u32(&mut self.inner)?;
+        }
+
+        let mut compressed = vec![0u8; size];
+        self.inner.read_exact(&mut compressed)?;
+        let decoded = if size == decoded_size {
+            compressed
+        } else {
+            decompress(&compressed, decoded_size)?
+        };
+        self.block = Cursor::new(decoded);
+
+        Ok(())
+    }
+}
+
+fn read_u8(rdr: &mut impl Read) -> std::io::Result<u8> {
+    let mut buf = [0u8; 1];
+    rdr.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u16(rdr: &mut impl Read) -> std::io::Result<u16> {
+    let mut buf = [0u8; 2];
+    rdr.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_u32(rdr: &mut impl Read) -> std::io::Result<u32> {
+    let mut buf = [0u8; 4];
+    rdr.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+impl LzopBlock<BufReader<File>> {
+    pub fn create_with_path(path: impl AsRef<Path>) -> Result<Self, ArchiveError> {
+        let rdr = BufReader::new(std::fs::File::open(path)?);
+        Self::create_with_reader(rdr)
+    }
+}
+
+impl<R: Read> Read for LzopBlock<R> {
+    fn read(&mut self, into: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let size = self.block.read(into)?;
+            if size > 0 || self.done || into.is_empty() {
+                self.total_out += size as u64;
+                return Ok(size);
+            }
+
+            self.next_block()
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+        }
+    }
+}
+
+impl<R: Read> BlockDecoder for LzopBlock<R> {
+    fn format(&self) -> BlockFormat {
+        BlockFormat::Lzop
+    }
+
+    fn compressed_size(&self) -> u64 {
+        self.inner.count()
+    }
+
+    fn uncompressed_size(&self) -> u64 {
+        self.total_out
+    }
+}
+
+impl<R: Read> FromReader<R> for LzopBlock<R> {
+    fn from_reader(rdr: R) -> Result<Self, ArchiveError> {
+        Self::create_with_reader(rdr)
+    }
+}
@@ -1,8 +1,290 @@
-mod deflate;
-mod gzip;
-mod lz4;
-mod lzma;
-mod zlib;
-mod zstd;
-mod snappy;
-mod brotli;
+use std::io::{BufRead, BufReader, Read, Write};
+
+use crate::utils::error::ArchiveError;
+
+pub mod brotli;
+pub mod bzip2;
+pub mod deflate;
+pub mod gzip;
+pub mod lz4;
+pub mod lzma;
+pub mod lzo;
+pub mod snappy;
+pub mod xz;
+pub mod zlib;
+pub mod zstd;
+
+use self::brotli::{BrotliBlock, BrotliEncoder};
+use self::bzip2::{Bzip2Block, Bzip2Encoder};
+use self::deflate::{FlateBlock, FlateEncoder};
+use self::gzip::{GzipBlock, GzipEncoder};
+use self::lz4::{Lz4Block, Lz4Encoder, RawLz4Block};
+use self::lzma::{Lzma2Block, LzmaAloneBlock};
+use self::lzo::{LzoBlock, LzopBlock};
+use self::snappy::{RawSnappyBlock, SnappyBlock, SnappyEncoder};
+use self::xz::{XzBlock, XzEncoder};
+use self::zlib::{ZlibBlock, ZlibEncoder};
+use self::zstd::{ZstdBlock, ZstdEncoder};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockFormat {
+    Brotli,
+    Bzip2,
+    Deflate,
+    Gzip,
+    Lz4,
+    LzmaAlone,
+    Lzma2,
+    Lzo,
+    Lzop,
+    RawLz4,
+    RawSnappy,
+    Snappy,
+    Xz,
+    Zlib,
+    Zstd,
+}
+
+impl BlockFormat {
+    /// Guesses the codec from the first bytes of a stream.
+    ///
+    /// Raw deflate, raw LZMA2, raw lz4 and snappy blocks, LZO1X and brotli carry no magic and
+    /// are never detected.
+    pub fn detect(magic: &[u8]) -> Option<BlockFormat> {
+        match magic {
+            [0x1f, 0x8b, ..] => Some(BlockFormat::Gzip),
+            [b'B', b'Z', b'h', b'1'..=b'9', ..] => Some(BlockFormat::Bzip2),
+            [0xfd, b'7', b'z', b'X', b'Z', 0x00, ..] => Some(BlockFormat::Xz),
+            [0x04, 0x22, 0x4d, 0x18, ..] => Some(BlockFormat::Lz4),
+            // Legacy frame written by `lz4 -l`, still used for compressed kernels.
+            [0x02, 0x21, 0x4c, 0x18, ..] => Some(BlockFormat::Lz4),
+            [0x28, 0xb5, 0x2f, 0xfd, ..] => Some(BlockFormat::Zstd),
+            [0x89, b'L', b'Z', b'O', 0x00, 0x0d, 0x0a, 0x1a, 0x0a, ..] => Some(BlockFormat::Lzop),
+            [0xff, 0x06, 0x00, 0x00, b's', b'N', b'a', b'P', b'p', b'Y', ..] => {
+                Some(BlockFormat::Snappy)
+            }
+            // lc=3, lp=0, pb=2 is what every LZMA-alone encoder in the wild writes.
+            [0x5d, 0x00, 0x00, ..] => Some(BlockFormat::LzmaAlone),
+            [cmf, flg, ..] if is_zlib_header(*cmf, *flg) => Some(BlockFormat::Zlib),
+            _ => None,
+        }
+    }
+}
+
+fn is_zlib_header(cmf: u8, flg: u8) -> bool {
+    // CM must be deflate and the header checksum must hold, see RFC 1950.
+    cmf & 0x0f == 8 && (u16::from(cmf) << 8 | u16::from(flg)) % 31 == 0
+}
+
+/// Common interface of every decoder in `block/`.
+///
+/// Decoders are plain `Read` streams of the uncompressed data, so they can be chained into
+/// container formats or other decoders without temporary files.
+pub trait BlockDecoder: Read {
+    fn format(&self) -> BlockFormat;
+
+    /// Number of bytes pulled from the compressed source so far. Decoders read ahead, so this
+    /// may run past the end of the compressed data when it is followed by something else.
+    fn compressed_size(&self) -> u64;
+
+    /// Number of decoded bytes produced so far.
+    fn uncompressed_size(&self) -> u64;
+
+    fn decode_to(&mut self, writer: &mut dyn Write) -> Result<u64, ArchiveError> {
+        let size = std::io::copy(self, writer)?;
+        Ok(size)
+    }
+}
+
+/// Common interface of every encoder in `block/`, the write side of `BlockDecoder`.
+pub trait BlockEncoder: Write {
+    fn format(&self) -> BlockFormat;
+
+    /// Terminates the stream. Dropping an encoder without closing it may leave a truncated
+    /// stream behind.
+    fn close(self: Box<Self>) -> Result<(), ArchiveError>;
+}
+
+/// A block decoder that can be stacked on top of a compressed stream.
+///
+/// Container formats such as `CompressedTar` are generic over this trait, so every codec in
+/// `block/` can feed them without an intermediate file.
+pub trait FromReader<R: Read>: Read + Sized {
+    fn from_reader(rdr: R) -> Result<Self, ArchiveError>;
+}
+
+/// Picks the decoder for the given format.
+pub fn create_decoder<'a>(
+    format: BlockFormat,
+    rdr: impl Read + 'a,
+) -> Result<Box<dyn BlockDecoder + 'a>, ArchiveError> {
+    let decoder: Box<dyn BlockDecoder + 'a> = match format {
+        BlockFormat::Brotli => Box::new(BrotliBlock::create_with_reader(rdr)?),
+        BlockFormat::Bzip2 => Box::new(Bzip2Block::create_with_reader(rdr)?),
+        BlockFormat::Deflate => Box::new(FlateBlock::create_with_reader(rdr)?),
+        BlockFormat::Gzip => Box::new(GzipBlock::create_with_reader(rdr)?),
+        BlockFormat::Lz4 => Box::new(Lz4Block::create_with_reader(rdr)?),
+        BlockFormat::LzmaAlone => Box::new(LzmaAloneBlock::create_with_reader(rdr)?),
+        BlockFormat::Lzma2 => Box::new(Lzma2Block::create_with_reader(rdr)?),
+        BlockFormat::Lzo => Box::new(LzoBlock::create_with_reader(rdr)?),
+        BlockFormat::Lzop => Box::new(LzopBlock::create_with_reader(rdr)?),
+        BlockFormat::RawLz4 => Box::new(RawLz4Block::create_with_reader(rdr)?),
+        BlockFormat::RawSnappy => Box::new(RawSnappyBlock::create_with_reader(rdr)?),
+        BlockFormat::Snappy => Box::new(SnappyBlock::create_with_reader(rdr)?),
+        BlockFormat::Xz => Box::new(XzBlock::create_with_reader(rdr)?),
+        BlockFormat::Zlib => Box::new(ZlibBlock::create_with_reader(rdr)?),
+        BlockFormat::Zstd => Box::new(ZstdBlock::create_with_reader(rdr)?),
+    };
+
+    Ok(decoder)
+}
+
+/// Picks the encoder for the given format, `None` selects the codec's usual default level.
+pub fn create_encoder<'a>(
+    format: BlockFormat,
+    writer: impl Write + 'a,
+    level: Option<u32>,
+) -> Result<Box<dyn BlockEncoder + 'a>, ArchiveError> {
+    let encoder: Box<dyn BlockEncoder + 'a> = match format {
+        BlockFormat::Brotli => Box::new(BrotliEncoder::create_with_writer(
+            writer,
+            level.unwrap_or(11),
+        )?),
+        BlockFormat::Bzip2 => Box::new(Bzip2Encoder::create_with_writer(
+            writer,
+            level.unwrap_or(9),
+        )?),
+        BlockFormat::Deflate => Box::new(FlateEncoder::create_with_writer(
+            writer,
+            level.unwrap_or(6),
+        )?),
+        BlockFormat::Gzip => Box::new(GzipEncoder::create_with_writer(writer, level.unwrap_or(6))?),
+        BlockFormat::Lz4 => Box::new(Lz4Encoder::create_with_writer(writer, level.unwrap_or(0))?),
+        BlockFormat::Snappy => Box::new(SnappyEncoder::create_with_writer(writer)?),
+        BlockFormat::Xz => Box::new(XzEncoder::create_with_writer(writer, level.unwrap_or(6))?),
+        BlockFormat::Zlib => Box::new(ZlibEncoder::create_with_writer(writer, level.unwrap_or(6))?),
+        BlockFormat::Zstd => Box::new(ZstdEncoder::create_with_writer(
+            writer,
+            level.map_or(0, zstd_level),
+        )?),
+        BlockFormat::LzmaAlone | BlockFormat::Lzma2 => {
+            return Err(ArchiveError::GenericsError(
+                "encoding raw lzma streams is not supported",
+            ))
+        }
+        BlockFormat::Lzo | BlockFormat::Lzop | BlockFormat::RawLz4 | BlockFormat::RawSnappy => {
+            return Err(ArchiveError::GenericsError(
+                "encoding lzo, raw lz4 and raw snappy is not supported",
+            ))
+        }
+    };
+
+    Ok(encoder)
+}
+
+/// Levels past the strongest zstd level are clamped to it instead of wrapping around into the
+/// negative, fast levels.
+fn zstd_level(level: u32) -> i32 {
+    let strongest = *::zstd::compression_level_range().end();
+    i32::try_from(level).map_or(strongest, |level| level.min(strongest))
+}
+
+/// Sniffs the format from the head of the stream and picks the matching decoder.
+pub fn detect_decoder<'a>(rdr: impl Read + 'a) -> Result<Box<dyn BlockDecoder + 'a>, ArchiveError> {
+    let mut rdr = BufReader::new(rdr);
+    let format = BlockFormat::detect(rdr.fill_buf()?).ok_or(ArchiveError::GenericsError(
+        "unknown block compression format",
+    ))?;
+    create_decoder(format, rdr)
+}
+
+impl<'a, R: Read + 'a> FromReader<R> for Box<dyn BlockDecoder + 'a> {
+    fn from_reader(rdr: R) -> Result<Self, ArchiveError> {
+        detect_decoder(rdr)
+    }
+}
+
+/// A skippable frame (magic `0x184D2A50` to `0x184D2A5F`) found between zstd or lz4 frames.
+/// Decoders skip them but keep them around, since tools store metadata in them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkippableFrame {
+    magic: u32,
+    payload: Vec<u8>,
+}
+
+impl SkippableFrame {
+    pub fn magic(&self) -> u32 {
+        self.magic
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+}
+
+pub(crate) fn is_skippable_magic(magic: u32) -> bool {
+    magic & 0xffff_fff0 == 0x184d_2a50
+}
+
+/// Reads the little endian magic that starts a frame, `None` at a clean end of stream.
+pub(crate) fn read_frame_magic(rdr: &mut impl Read) -> std::io::Result<Option<u32>> {
+    let mut magic = [0u8; 4];
+    let mut filled = 0;
+    while filled < magic.len() {
+        match rdr.read(&mut magic[filled..])? {
+            0 if filled == 0 => return Ok(None),
+            0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            size => filled += size,
+        }
+    }
+
+    Ok(Some(u32::from_le_bytes(magic)))
+}
+
+/// Reads the rest of a skippable frame once its magic has been consumed.
+pub(crate) fn read_skippable_frame(
+    magic: u32,
+    rdr: &mut impl Read,
+) -> std::io::Result<SkippableFrame> {
+    let mut size = [0u8; 4];
+    rdr.read_exact(&mut size)?;
+    let mut payload = vec![];
+    let size = u64::from(u32::from_le_bytes(size));
+    if rdr.take(size).read_to_end(&mut payload)? as u64 != size {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+
+    Ok(SkippableFrame { magic, payload })
+}
+
+/// Keeps track of how many bytes a decoder pulled from the compressed source.
+pub struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R> CountingReader<R> {
+    pub fn new(inner: R) -> CountingReader<R> {
+        CountingReader { inner, count: 0 }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, into: &mut [u8]) -> std::io::Result<usize> {
+        let size = self.inner.read(into)?;
+        self.count += size as u64;
+        Ok(size)
+    }
+}
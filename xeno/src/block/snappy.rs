@@ -1,12 +1,19 @@
-use std::io::{BufReader, Read, Write};
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read, Write};
 use std::path::Path;
 
 use snap::read::FrameDecoder;
 
+use crate::block::{BlockDecoder, BlockEncoder, BlockFormat, CountingReader, FromReader};
 use crate::utils::error::ArchiveError;
 
+/// The longest snappy copy, 64 bytes, takes a three byte tag, so a raw block never decodes to
+/// more than `MAX_RATIO` times its own size.
+const MAX_RATIO: usize = 32;
+
 pub struct SnappyBlock<R: Read> {
-    inner: FrameDecoder<R>,
+    inner: FrameDecoder<CountingReader<R>>,
+    total_out: u64,
 }
 
 impl<R> SnappyBlock<R>
@@ -15,20 +22,196 @@ where
 {
     pub fn unpack_to(&mut self, to: impl AsRef<Path>) -> Result<(), ArchiveError> {
         let mut writer = std::fs::File::create(to)?;
-        std::io::copy(&mut self.inner, &mut writer)?;
+        self.decode_to(&mut writer)?;
 
         Ok(())
     }
 
-    pub fn create_with_reader(rdr: impl Read) -> Result<SnappyBlock<impl Read>, ArchiveError> {
-        let inner = snap::read::FrameDecoder::new(rdr);
-        let block = SnappyBlock { inner };
+    pub fn create_with_reader(rdr: R) -> Result<SnappyBlock<R>, ArchiveError> {
+        let inner = FrameDecoder::new(CountingReader::new(rdr));
+        let block = SnappyBlock {
+            inner,
+            total_out: 0,
+        };
 
         Ok(block)
     }
+}
 
-    pub fn create_with_path(path: impl AsRef<Path>) -> Result<SnappyBlock<impl Read>, ArchiveError> {
+impl SnappyBlock<BufReader<File>> {
+    pub fn create_with_path(path: impl AsRef<Path>) -> Result<Self, ArchiveError> {
         let rdr = BufReader::new(std::fs::File::open(path)?);
         Self::create_with_reader(rdr)
     }
 }
+
+impl<R: Read> Read for SnappyBlock<R> {
+    fn read(&mut self, into: &mut [u8]) -> std::io::Result<usize> {
+        let size = self.inner.read(into)?;
+        self.total_out += size as u64;
+        Ok(size)
+    }
+}
+
+impl<R: Read> BlockDecoder for SnappyBlock<R> {
+    fn format(&self) -> BlockFormat {
+        BlockFormat::Snappy
+    }
+
+    fn compressed_size(&self) -> u64 {
+        self.inner.get_ref().count()
+    }
+
+    fn uncompressed_size(&self) -> u64 {
+        self.total_out
+    }
+}
+
+impl<R: Read> FromReader<R> for SnappyBlock<R> {
+    fn from_reader(rdr: R) -> Result<Self, ArchiveError> {
+        Self::create_with_reader(rdr)
+    }
+}
+
+/// Unframed snappy as used by SquashFS, UBIFS and plenty of firmware: a varint with the
+/// decompressed length followed by a single compressed block. The block is decoded in one go on
+/// the first read.
+pub struct RawSnappyBlock<R: Read> {
+    inner: Option<CountingReader<R>>,
+    decoded: Cursor<Vec<u8>>,
+    total_in: u64,
+}
+
+impl<R> RawSnappyBlock<R>
+where
+    R: Read,
+{
+    pub fn unpack_to(&mut self, to: impl AsRef<Path>) -> Result<(), ArchiveError> {
+        let mut writer = std::fs::File::create(to)?;
+        self.decode_to(&mut writer)?;
+
+        Ok(())
+    }
+
+    pub fn create_with_reader(rdr: R) -> Result<RawSnappyBlock<R>, ArchiveError> {
+        let block = RawSnappyBlock {
+            inner: Some(CountingReader::new(rdr)),
+            decoded: Cursor::new(vec![]),
+            total_in: 0,
+        };
+
+        Ok(block)
+    }
+
+    fn decode(&mut self) -> Result<(), ArchiveError> {
+        if let Some(mut rdr) = self.inner.take() {
+            let mut compressed = vec![];
+            rdr.read_to_end(&mut compressed)?;
+            let size = snap::raw::decompress_len(&compressed).map_err(ArchiveError::SnappyError)?;
+            if size > compressed.len().saturating_mul(MAX_RATIO) {
+                return Err(ArchiveError::GenericsError(
+                    "snappy block is too small for its decompressed size",
+                ));
+            }
+            let decoded = snap::raw::Decoder::new()
+                .decompress_vec(&compressed)
+                .map_err(ArchiveError::SnappyError)?;
+            self.total_in = rdr.count();
+            self.decoded = Cursor::new(decoded);
+        }
+
+        Ok(())
+    }
+}
+
+impl RawSnappyBlock<File> {
+    pub fn create_with_path(path: impl AsRef<Path>) -> Result<Self, ArchiveError> {
+        let rdr = std::fs::File::open(path)?;
+        Self::create_with_reader(rdr)
+    }
+}
+
+impl<R: Read> Read for RawSnappyBlock<R> {
+    fn read(&mut self, into: &mut [u8]) -> std::io::Result<usize> {
+        self.decode()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+        self.decoded.read(into)
+    }
+}
+
+impl<R: Read> BlockDecoder for RawSnappyBlock<R> {
+    fn format(&self) -> BlockFormat {
+        BlockFormat::RawSnappy
+    }
+
+    fn compressed_size(&self) -> u64 {
+        match &self.inner {
+            Some(rdr) => rdr.count(),
+            None => self.total_in,
+        }
+    }
+
+    fn uncompressed_size(&self) -> u64 {
+        self.decoded.position()
+    }
+}
+
+impl<R: Read> FromReader<R> for RawSnappyBlock<R> {
+    fn from_reader(rdr: R) -> Result<Self, ArchiveError> {
+        Self::create_with_reader(rdr)
+    }
+}
+
+/// Writes the framed snappy format, which has no compression levels.
+pub struct SnappyEncoder<W: Write> {
+    inner: snap::write::FrameEncoder<W>,
+}
+
+impl<W> SnappyEncoder<W>
+where
+    W: Write,
+{
+    pub fn create_with_writer(writer: W) -> Result<SnappyEncoder<W>, ArchiveError> {
+        let inner = snap::write::FrameEncoder::new(writer);
+        let encoder = SnappyEncoder { inner };
+
+        Ok(encoder)
+    }
+
+    /// Writes out whatever is still buffered plus the stream trailer and returns the writer.
+    pub fn finish(self) -> Result<W, ArchiveError> {
+        let writer = self
+            .inner
+            .into_inner()
+            .map_err(|e| ArchiveError::Io(e.into_error()))?;
+        Ok(writer)
+    }
+}
+
+impl SnappyEncoder<File> {
+    pub fn create_with_path(path: impl AsRef<Path>) -> Result<Self, ArchiveError> {
+        let writer = std::fs::File::create(path)?;
+        Self::create_with_writer(writer)
+    }
+}
+
+impl<W: Write> Write for SnappyEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> BlockEncoder for SnappyEncoder<W> {
+    fn format(&self) -> BlockFormat {
+        BlockFormat::Snappy
+    }
+
+    fn close(self: Box<Self>) -> Result<(), ArchiveError> {
+        self.finish()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,123 @@
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+
+use xz2::read::XzDecoder;
+
+use crate::block::{BlockDecoder, BlockEncoder, BlockFormat, CountingReader, FromReader};
+use crate::utils::error::ArchiveError;
+
+pub struct XzBlock<R: Read> {
+    inner: XzDecoder<CountingReader<R>>,
+    total_out: u64,
+}
+
+impl<R> XzBlock<R>
+where
+    R: Read,
+{
+    pub fn unpack_to(&mut self, to: impl AsRef<Path>) -> Result<(), ArchiveError> {
+        let mut writer = std::fs::File::create(to)?;
+        self.decode_to(&mut writer)?;
+
+        Ok(())
+    }
+
+    pub fn create_with_reader(rdr: R) -> Result<XzBlock<R>, ArchiveError> {
+        // xz streams are decoded incrementally, so large payloads such as `.tar.xz` never have
+        // to be held in memory. Concatenated streams are decoded back to back like `xz -d`.
+        let inner = XzDecoder::new_multi_decoder(CountingReader::new(rdr));
+        let block = XzBlock {
+            inner,
+            total_out: 0,
+        };
+
+        Ok(block)
+    }
+}
+
+impl XzBlock<BufReader<File>> {
+    pub fn create_with_path(path: impl AsRef<Path>) -> Result<Self, ArchiveError> {
+        let rdr = BufReader::new(std::fs::File::open(path)?);
+        Self::create_with_reader(rdr)
+    }
+}
+
+impl<R: Read> Read for XzBlock<R> {
+    fn read(&mut self, into: &mut [u8]) -> std::io::Result<usize> {
+        let size = self.inner.read(into)?;
+        self.total_out += size as u64;
+        Ok(size)
+    }
+}
+
+impl<R: Read> BlockDecoder for XzBlock<R> {
+    fn format(&self) -> BlockFormat {
+        BlockFormat::Xz
+    }
+
+    fn compressed_size(&self) -> u64 {
+        self.inner.get_ref().count()
+    }
+
+    fn uncompressed_size(&self) -> u64 {
+        self.total_out
+    }
+}
+
+impl<R: Read> FromReader<R> for XzBlock<R> {
+    fn from_reader(rdr: R) -> Result<Self, ArchiveError> {
+        Self::create_with_reader(rdr)
+    }
+}
+
+/// `level` is the xz preset, from 0 to 9, higher levels are clamped to 9.
+pub struct XzEncoder<W: Write> {
+    inner: xz2::write::XzEncoder<W>,
+}
+
+impl<W> XzEncoder<W>
+where
+    W: Write,
+{
+    pub fn create_with_writer(writer: W, level: u32) -> Result<XzEncoder<W>, ArchiveError> {
+        let inner = xz2::write::XzEncoder::new(writer, level.min(9));
+        let encoder = XzEncoder { inner };
+
+        Ok(encoder)
+    }
+
+    /// Writes out whatever is still buffered plus the stream trailer and returns the writer.
+    pub fn finish(self) -> Result<W, ArchiveError> {
+        let writer = self.inner.finish()?;
+        Ok(writer)
+    }
+}
+
+impl XzEncoder<File> {
+    pub fn create_with_path(path: impl AsRef<Path>, level: u32) -> Result<Self, ArchiveError> {
+        let writer = std::fs::File::create(path)?;
+        Self::create_with_writer(writer, level)
+    }
+}
+
+impl<W: Write> Write for XzEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> BlockEncoder for XzEncoder<W> {
+    fn format(&self) -> BlockFormat {
+        BlockFormat::Xz
+    }
+
+    fn close(self: Box<Self>) -> Result<(), ArchiveError> {
+        self.finish()?;
+        Ok(())
+    }
+}
@@ -1,12 +1,15 @@
+use std::fs::File;
 use std::io::{BufReader, Read, Write};
 use std::path::Path;
 
 use flate2::read::ZlibDecoder;
 
+use crate::block::{BlockDecoder, BlockEncoder, BlockFormat, CountingReader, FromReader};
 use crate::utils::error::ArchiveError;
 
 pub struct ZlibBlock<R: Read> {
-    inner: ZlibDecoder<R>,
+    inner: ZlibDecoder<CountingReader<R>>,
+    total_out: u64,
 }
 
 impl<R> ZlibBlock<R>
@@ -15,36 +18,104 @@ where
 {
     pub fn unpack_to(&mut self, to: impl AsRef<Path>) -> Result<(), ArchiveError> {
         let mut writer = std::fs::File::create(to)?;
-        let mut buf = [0u8; 4096];
-        loop {
-            let bytes_read = match self.inner.read(&mut buf) {
-                Ok(size) => size,
-                Err(e) => {
-                    let err = ArchiveError::Io(e);
-                    return Err(err);
-                }
-            };
-
-            if bytes_read == 0 {
-                break;
-            }
-
-            writer.write_all(&buf[..bytes_read])?;
-        }
+        self.decode_to(&mut writer)?;
 
         Ok(())
     }
 
-    pub fn create_with_reader(rdr: impl Read) -> Result<ZlibBlock<impl Read>, ArchiveError> {
-        let reader = ZlibDecoder::new(rdr);
-
-        let block = ZlibBlock { inner: reader };
+    pub fn create_with_reader(rdr: R) -> Result<ZlibBlock<R>, ArchiveError> {
+        let inner = ZlibDecoder::new(CountingReader::new(rdr));
+        let block = ZlibBlock {
+            inner,
+            total_out: 0,
+        };
 
         Ok(block)
     }
+}
 
-    pub fn create_with_path(path: impl AsRef<Path>) -> Result<ZlibBlock<impl Read>, ArchiveError> {
+impl ZlibBlock<BufReader<File>> {
+    pub fn create_with_path(path: impl AsRef<Path>) -> Result<Self, ArchiveError> {
         let rdr = BufReader::new(std::fs::File::open(path)?);
         Self::create_with_reader(rdr)
     }
 }
+
+impl<R: Read> Read for ZlibBlock<R> {
+    fn read(&mut self, into: &mut [u8]) -> std::io::Result<usize> {
+        let size = self.inner.read(into)?;
+        self.total_out += size as u64;
+        Ok(size)
+    }
+}
+
+impl<R: Read> BlockDecoder for ZlibBlock<R> {
+    fn format(&self) -> BlockFormat {
+        BlockFormat::Zlib
+    }
+
+    fn compressed_size(&self) -> u64 {
+        self.inner.get_ref().count()
+    }
+
+    fn uncompressed_size(&self) -> u64 {
+        self.total_out
+    }
+}
+
+impl<R: Read> FromReader<R> for ZlibBlock<R> {
+    fn from_reader(rdr: R) -> Result<Self, ArchiveError> {
+        Self::create_with_reader(rdr)
+    }
+}
+
+/// `level` ranges from 0 (store) to 9 (best), higher levels are clamped to 9.
+pub struct ZlibEncoder<W: Write> {
+    inner: flate2::write::ZlibEncoder<W>,
+}
+
+impl<W> ZlibEncoder<W>
+where
+    W: Write,
+{
+    pub fn create_with_writer(writer: W, level: u32) -> Result<ZlibEncoder<W>, ArchiveError> {
+        let inner = flate2::write::ZlibEncoder::new(writer, flate2::Compression::new(level.min(9)));
+        let encoder = ZlibEncoder { inner };
+
+        Ok(encoder)
+    }
+
+    /// Writes out whatever is still buffered plus the stream trailer and returns the writer.
+    pub fn finish(self) -> Result<W, ArchiveError> {
+        let writer = self.inner.finish()?;
+        Ok(writer)
+    }
+}
+
+impl ZlibEncoder<File> {
+    pub fn create_with_path(path: impl AsRef<Path>, level: u32) -> Result<Self, ArchiveError> {
+        let writer = std::fs::File::create(path)?;
+        Self::create_with_writer(writer, level)
+    }
+}
+
+impl<W: Write> Write for ZlibEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> BlockEncoder for ZlibEncoder<W> {
+    fn format(&self) -> BlockFormat {
+        BlockFormat::Zlib
+    }
+
+    fn close(self: Box<Self>) -> Result<(), ArchiveError> {
+        self.finish()?;
+        Ok(())
+    }
+}
@@ -1,35 +1,478 @@
-use std::io::{BufRead, BufReader, Read};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Chain, Cursor, Read, Seek, SeekFrom, Write};
 use std::path::Path;
 
+use zstd::Decoder;
+
+use crate::block::{
+    is_skippable_magic, read_frame_magic, read_skippable_frame, BlockDecoder, BlockEncoder,
+    BlockFormat, CountingReader, FromReader, SkippableFrame,
+};
 use crate::utils::error::ArchiveError;
 
-pub struct ZstdBlock<R: Read + BufRead> {
-    inner: R,
+const ZSTD_MAGIC: u32 = 0xfd2f_b528;
+const SEEKABLE_MAGIC: u32 = 0x8f92_eab1;
+const SEEK_TABLE_MAGIC: u32 = 0x184d_2a5e;
+
+/// The frame magic and header are read ahead to tell zstd and skippable frames apart and to
+/// pick the dictionary, then handed back to the zstd decoder in front of the rest of the stream.
+type FrameReader<R> = Chain<Cursor<Vec<u8>>, BufReader<CountingReader<R>>>;
+
+enum ZstdState<R: Read> {
+    Frame(Decoder<'static, FrameReader<R>>),
+    Done(BufReader<CountingReader<R>>),
+}
+
+/// Decodes every frame of a zstd stream. Skippable frames in between are skipped and kept as
+/// metadata, see `skippable_frames`.
+///
+/// Frames compressed with a dictionary name it by ID in their header, the matching one is
+/// picked from the dictionaries passed to `create_with_dictionaries`.
+pub struct ZstdBlock<R: Read> {
+    state: Option<ZstdState<R>>,
+    dictionaries: HashMap<u32, Vec<u8>>,
+    missing_dictionary: Option<u32>,
+    skippable_frames: Vec<SkippableFrame>,
+    total_out: u64,
 }
 
 impl<R> ZstdBlock<R>
 where
-    R: Read + BufRead,
+    R: Read,
 {
     pub fn unpack_to(&mut self, to: impl AsRef<Path>) -> Result<(), ArchiveError> {
         let mut writer = std::fs::File::create(to)?;
-        zstd::stream::copy_decode(&mut self.inner, &mut writer).map_err(ArchiveError::Io)?;
+        self.decode_to(&mut writer)?;
 
         Ok(())
     }
 
-    pub fn create_with_reader(
-        rdr: impl Read + BufRead,
-    ) -> Result<ZstdBlock<impl Read + BufRead>, ArchiveError> {
-        let block = ZstdBlock { inner: rdr };
+    /// Skippable frames seen so far. The whole list is only known once the stream has been
+    /// decoded to the end.
+    pub fn skippable_frames(&self) -> &[SkippableFrame] {
+        &self.skippable_frames
+    }
+
+    pub fn create_with_reader(rdr: R) -> Result<ZstdBlock<R>, ArchiveError> {
+        Self::create_with_dictionaries(rdr, Vec::<Vec<u8>>::new())
+    }
+
+    /// Like `create_with_reader`, with dictionaries for frames that need one. Only dictionaries
+    /// in the zstd format carry an ID, raw content dictionaries are rejected.
+    ///
+    /// The first frame header is checked right away, so a missing dictionary is reported here
+    /// rather than on the first read.
+    pub fn create_with_dictionaries(
+        rdr: R,
+        dictionaries: impl IntoIterator<Item = impl Into<Vec<u8>>>,
+    ) -> Result<ZstdBlock<R>, ArchiveError> {
+        let mut by_id = HashMap::new();
+        for dictionary in dictionaries {
+            let dictionary = dictionary.into();
+            let id = zstd::zstd_safe::get_dict_id_from_dict(&dictionary).ok_or(
+                ArchiveError::GenericsError("zstd dictionary has no dictionary ID"),
+            )?;
+            by_id.insert(id.get(), dictionary);
+        }
+
+        let buffer_size = zstd::zstd_safe::DCtx::in_size();
+        let rdr = BufReader::with_capacity(buffer_size, CountingReader::new(rdr));
+        let mut block = ZstdBlock {
+            state: None,
+            dictionaries: by_id,
+            missing_dictionary: None,
+            skippable_frames: vec![],
+            total_out: 0,
+        };
+        let state = block.next_frame(rdr).map_err(|e| block.map_error(e))?;
+        block.state = Some(state);
+
+        Ok(block)
+    }
+
+    /// IDs of the dictionaries this decoder can use.
+    pub fn dictionary_ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.dictionaries.keys().copied()
+    }
+
+    fn map_error(&self, e: std::io::Error) -> ArchiveError {
+        match self.missing_dictionary {
+            Some(id) => ArchiveError::MissingZstdDictionary(id),
+            None => e.into(),
+        }
+    }
+
+    fn next_frame(
+        &mut self,
+        mut rdr: BufReader<CountingReader<R>>,
+    ) -> std::io::Result<ZstdState<R>> {
+        loop {
+            let magic = match read_frame_magic(&mut rdr)? {
+                Some(magic) => magic,
+                None => return Ok(ZstdState::Done(rdr)),
+            };
+
+            if magic == ZSTD_MAGIC {
+                let (header, id) = read_frame_header(&mut rdr)?;
+                let dictionary = match id {
+                    0 => &[][..],
+                    id => match self.dictionaries.get(&id) {
+                        Some(dictionary) => dictionary.as_slice(),
+                        None => {
+                            self.missing_dictionary = Some(id);
+                            return Err(std::io::Error::new(
+                                std::io::ErrorKind::InvalidData,
+                                ArchiveError::MissingZstdDictionary(id).to_string(),
+                            ));
+                        }
+                    },
+                };
+
+                let mut prefix = magic.to_le_bytes().to_vec();
+                prefix.extend(header);
+                let rdr = Cursor::new(prefix).chain(rdr);
+                let decoder = Decoder::with_dictionary(rdr, dictionary)?.single_frame();
+                return Ok(ZstdState::Frame(decoder));
+            } else if is_skippable_magic(magic) {
+                let frame = read_skippable_frame(magic, &mut rdr)?;
+                self.skippable_frames.push(frame);
+            } else {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("unknown zstd frame magic {:#010x}", magic),
+                ));
+            }
+        }
+    }
+}
+
+/// Reads the frame header up to and including the dictionary ID, `0` when there is none.
+fn read_frame_header(rdr: &mut impl Read) -> std::io::Result<(Vec<u8>, u32)> {
+    let mut header = vec![0u8];
+    rdr.read_exact(&mut header)?;
+    let descriptor = header[0];
+    let window_size = if descriptor & 0x20 == 0 { 1 } else { 0 };
+    let id_size = [0, 1, 2, 4][usize::from(descriptor & 0x03)];
+    header.resize(1 + window_size + id_size, 0);
+    rdr.read_exact(&mut header[1..])?;
+
+    let mut id = [0u8; 4];
+    id[..id_size].copy_from_slice(&header[1 + window_size..]);
+    Ok((header, u32::from_le_bytes(id)))
+}
+
+impl ZstdBlock<BufReader<File>> {
+    pub fn create_with_path(path: impl AsRef<Path>) -> Result<Self, ArchiveError> {
+        let rdr = BufReader::new(std::fs::File::open(path)?);
+        Self::create_with_reader(rdr)
+    }
+}
+
+impl<R: Read> Read for ZstdBlock<R> {
+    fn read(&mut self, into: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let state = match self.state.take() {
+                Some(ZstdState::Frame(mut decoder)) => {
+                    let size = decoder.read(into)?;
+                    if size > 0 || into.is_empty() {
+                        self.state = Some(ZstdState::Frame(decoder));
+                        self.total_out += size as u64;
+                        return Ok(size);
+                    }
+
+                    let (_, rdr) = decoder.finish().into_inner();
+                    self.next_frame(rdr)?
+                }
+                Some(ZstdState::Done(rdr)) => {
+                    self.state = Some(ZstdState::Done(rdr));
+                    return Ok(0);
+                }
+                None => {
+                    return Err(std::io::Error::other(
+                        "zstd stream is unusable after a previous error",
+                    ))
+                }
+            };
+            self.state = Some(state);
+        }
+    }
+}
+
+impl<R: Read> BlockDecoder for ZstdBlock<R> {
+    fn format(&self) -> BlockFormat {
+        BlockFormat::Zstd
+    }
+
+    fn decode_to(&mut self, writer: &mut dyn Write) -> Result<u64, ArchiveError> {
+        std::io::copy(self, writer).map_err(|e| self.map_error(e))
+    }
+
+    fn compressed_size(&self) -> u64 {
+        match &self.state {
+            Some(ZstdState::Done(rdr)) => rdr.get_ref().count(),
+            Some(ZstdState::Frame(decoder)) => decoder.get_ref().get_ref().1.get_ref().count(),
+            None => 0,
+        }
+    }
+
+    fn uncompressed_size(&self) -> u64 {
+        self.total_out
+    }
+}
+
+impl<R: Read> FromReader<R> for ZstdBlock<R> {
+    fn from_reader(rdr: R) -> Result<Self, ArchiveError> {
+        Self::create_with_reader(rdr)
+    }
+}
+
+/// One frame of a zstd seekable archive as listed in its seek table.
+#[derive(Debug, Clone, Copy)]
+pub struct SeekTableEntry {
+    compressed_offset: u64,
+    compressed_size: u32,
+    decompressed_offset: u64,
+    decompressed_size: u32,
+    checksum: Option<u32>,
+}
+
+impl SeekTableEntry {
+    pub fn compressed_offset(&self) -> u64 {
+        self.compressed_offset
+    }
+
+    pub fn compressed_size(&self) -> u32 {
+        self.compressed_size
+    }
+
+    pub fn decompressed_offset(&self) -> u64 {
+        self.decompressed_offset
+    }
+
+    pub fn decompressed_size(&self) -> u32 {
+        self.decompressed_size
+    }
+
+    /// The low 32 bits of the XXH64 of the decompressed frame, if the table records them.
+    pub fn checksum(&self) -> Option<u32> {
+        self.checksum
+    }
+}
+
+/// Random access into the zstd seekable format: independent frames followed by a seek table
+/// in a skippable frame. Only the frame covering the current position gets decoded.
+pub struct ZstdSeekableBlock<R: Read + Seek> {
+    inner: R,
+    frames: Vec<SeekTableEntry>,
+    pos: u64,
+    cached: Option<(usize, Vec<u8>)>,
+}
+
+impl<R> ZstdSeekableBlock<R>
+where
+    R: Read + Seek,
+{
+    pub fn create_with_reader(mut rdr: R) -> Result<ZstdSeekableBlock<R>, ArchiveError> {
+        // Seek_Table_Footer: Number_Of_Frames, Seek_Table_Descriptor, Seekable_Magic_Number.
+        let mut footer = [0u8; 9];
+        rdr.seek(SeekFrom::End(-(footer.len() as i64)))?;
+        rdr.read_exact(&mut footer)?;
+        let num_frames = u32::from_le_bytes(footer[0..4].try_into().unwrap());
+        let descriptor = footer[4];
+        if u32::from_le_bytes(footer[5..9].try_into().unwrap()) != SEEKABLE_MAGIC {
+            return Err(ArchiveError::GenericsError("not a zstd seekable archive"));
+        }
+
+        let has_checksum = descriptor & 0x80 != 0;
+        let entry_size = if has_checksum { 12 } else { 8 };
+        let table_size = u64::from(num_frames) * entry_size + footer.len() as u64;
+        // The seek table sits in a skippable frame with an 8 byte header.
+        rdr.seek(SeekFrom::End(-(table_size as i64) - 8))?;
+        let mut header = [0u8; 8];
+        rdr.read_exact(&mut header)?;
+        if u32::from_le_bytes(header[0..4].try_into().unwrap()) != SEEK_TABLE_MAGIC
+            || u64::from(u32::from_le_bytes(header[4..8].try_into().unwrap())) != table_size
+        {
+            return Err(ArchiveError::GenericsError("corrupted zstd seek table"));
+        }
+
+        let mut table = vec![0u8; (table_size as usize) - footer.len()];
+        rdr.read_exact(&mut table)?;
+        let mut frames = Vec::with_capacity(num_frames as usize);
+        let (mut compressed_offset, mut decompressed_offset) = (0u64, 0u64);
+        for entry in table.chunks_exact(entry_size as usize) {
+            let compressed_size = u32::from_le_bytes(entry[0..4].try_into().unwrap());
+            let decompressed_size = u32::from_le_bytes(entry[4..8].try_into().unwrap());
+            let checksum = if has_checksum {
+                Some(u32::from_le_bytes(entry[8..12].try_into().unwrap()))
+            } else {
+                None
+            };
+            frames.push(SeekTableEntry {
+                compressed_offset,
+                compressed_size,
+                decompressed_offset,
+                decompressed_size,
+                checksum,
+            });
+            compressed_offset += u64::from(compressed_size);
+            decompressed_offset += u64::from(decompressed_size);
+        }
+
+        let block = ZstdSeekableBlock {
+            inner: rdr,
+            frames,
+            pos: 0,
+            cached: None,
+        };
 
         Ok(block)
     }
 
-    pub fn create_with_path(
-        path: impl AsRef<Path>,
-    ) -> Result<ZstdBlock<impl Read + BufRead>, ArchiveError> {
+    pub fn frames(&self) -> &[SeekTableEntry] {
+        &self.frames
+    }
+
+    /// Total size of the decompressed data.
+    pub fn uncompressed_size(&self) -> u64 {
+        self.frames
+            .last()
+            .map(|frame| frame.decompressed_offset + u64::from(frame.decompressed_size))
+            .unwrap_or_default()
+    }
+
+    fn frame(&mut self, index: usize) -> std::io::Result<&[u8]> {
+        if !matches!(&self.cached, Some((cached, _)) if *cached == index) {
+            let frame = self.frames[index];
+            let mut compressed = vec![0u8; frame.compressed_size as usize];
+            self.inner.seek(SeekFrom::Start(frame.compressed_offset))?;
+            self.inner.read_exact(&mut compressed)?;
+            let decompressed =
+                zstd::bulk::decompress(&compressed, frame.decompressed_size as usize)?;
+            if decompressed.len() != frame.decompressed_size as usize {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "zstd frame {index} holds {} bytes, the seek table says {}",
+                        decompressed.len(),
+                        frame.decompressed_size
+                    ),
+                ));
+            }
+            self.cached = Some((index, decompressed));
+        }
+
+        Ok(self
+            .cached
+            .as_ref()
+            .map(|(_, data)| data.as_slice())
+            .unwrap())
+    }
+}
+
+impl ZstdSeekableBlock<BufReader<File>> {
+    pub fn create_with_path(path: impl AsRef<Path>) -> Result<Self, ArchiveError> {
         let rdr = BufReader::new(std::fs::File::open(path)?);
         Self::create_with_reader(rdr)
     }
 }
+
+impl<R: Read + Seek> Read for ZstdSeekableBlock<R> {
+    fn read(&mut self, into: &mut [u8]) -> std::io::Result<usize> {
+        let pos = self.pos;
+        let index = self.frames.partition_point(|frame| {
+            frame.decompressed_offset + u64::from(frame.decompressed_size) <= pos
+        });
+        if index >= self.frames.len() {
+            return Ok(0);
+        }
+
+        let offset = (pos - self.frames[index].decompressed_offset) as usize;
+        let data = self.frame(index)?;
+        let size = into.len().min(data.len() - offset);
+        into[..size].copy_from_slice(&data[offset..offset + size]);
+        self.pos += size as u64;
+        Ok(size)
+    }
+}
+
+impl<R: Read + Seek> Seek for ZstdSeekableBlock<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => self.uncompressed_size().checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        self.pos = pos.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+/// `level` ranges from 1 to 22, 0 selects the zstd default; negative levels trade ratio for speed.
+pub struct ZstdEncoder<W: Write> {
+    inner: zstd::stream::write::Encoder<'static, W>,
+}
+
+impl<W> ZstdEncoder<W>
+where
+    W: Write,
+{
+    pub fn create_with_writer(writer: W, level: i32) -> Result<ZstdEncoder<W>, ArchiveError> {
+        let inner = zstd::stream::write::Encoder::new(writer, level)?;
+        let encoder = ZstdEncoder { inner };
+
+        Ok(encoder)
+    }
+
+    /// Compresses against a trained dictionary; its ID is recorded in every frame header.
+    pub fn create_with_dictionary(
+        writer: W,
+        level: i32,
+        dictionary: &[u8],
+    ) -> Result<ZstdEncoder<W>, ArchiveError> {
+        let inner = zstd::stream::write::Encoder::with_dictionary(writer, level, dictionary)?;
+        let encoder = ZstdEncoder { inner };
+
+        Ok(encoder)
+    }
+
+    /// Writes out whatever is still buffered plus the stream trailer and returns the writer.
+    pub fn finish(self) -> Result<W, ArchiveError> {
+        let writer = self.inner.finish()?;
+        Ok(writer)
+    }
+}
+
+impl ZstdEncoder<File> {
+    pub fn create_with_path(path: impl AsRef<Path>, level: i32) -> Result<Self, ArchiveError> {
+        let writer = std::fs::File::create(path)?;
+        Self::create_with_writer(writer, level)
+    }
+}
+
+impl<W: Write> Write for ZstdEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> BlockEncoder for ZstdEncoder<W> {
+    fn format(&self) -> BlockFormat {
+        BlockFormat::Zstd
+    }
+
+    fn close(self: Box<Self>) -> Result<(), ArchiveError> {
+        self.finish()?;
+        Ok(())
+    }
+}
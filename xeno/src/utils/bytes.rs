@@ -0,0 +1,82 @@
+//! Fixed-width integers at an offset of an on-disk structure.
+//!
+//! The plain readers panic past the end of `data`, callers check the length of the structure
+//! first. The `get_` variants return `None` instead, for data that is still being probed.
+
+fn array<const N: usize>(data: &[u8], offset: usize) -> [u8; N] {
+    let mut bytes = [0; N];
+    bytes.copy_from_slice(&data[offset..offset + N]);
+    bytes
+}
+
+fn get_array<const N: usize>(data: &[u8], offset: usize) -> Option<[u8; N]> {
+    let bytes = data.get(offset..offset.checked_add(N)?)?;
+    bytes.try_into().ok()
+}
+
+pub(crate) fn le16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(array(data, offset))
+}
+
+pub(crate) fn le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(array(data, offset))
+}
+
+pub(crate) fn le64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(array(data, offset))
+}
+
+pub(crate) fn be16(data: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes(array(data, offset))
+}
+
+pub(crate) fn be32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(array(data, offset))
+}
+
+pub(crate) fn be64(data: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(array(data, offset))
+}
+
+/// For formats written in the byte order of the machine that built them.
+pub(crate) fn u16_at(data: &[u8], offset: usize, big_endian: bool) -> u16 {
+    match big_endian {
+        true => be16(data, offset),
+        false => le16(data, offset),
+    }
+}
+
+pub(crate) fn u32_at(data: &[u8], offset: usize, big_endian: bool) -> u32 {
+    match big_endian {
+        true => be32(data, offset),
+        false => le32(data, offset),
+    }
+}
+
+pub(crate) fn get_be32(data: &[u8], offset: usize) -> Option<u32> {
+    get_array(data, offset).map(u32::from_be_bytes)
+}
+
+pub(crate) fn get_u16_at(data: &[u8], offset: usize, big_endian: bool) -> Option<u16> {
+    let bytes = get_array(data, offset)?;
+    Some(match big_endian {
+        true => u16::from_be_bytes(bytes),
+        false => u16::from_le_bytes(bytes),
+    })
+}
+
+pub(crate) fn get_u32_at(data: &[u8], offset: usize, big_endian: bool) -> Option<u32> {
+    let bytes = get_array(data, offset)?;
+    Some(match big_endian {
+        true => u32::from_be_bytes(bytes),
+        false => u32::from_le_bytes(bytes),
+    })
+}
+
+pub(crate) fn get_u64_at(data: &[u8], offset: usize, big_endian: bool) -> Option<u64> {
+    let bytes = get_array(data, offset)?;
+    Some(match big_endian {
+        true => u64::from_be_bytes(bytes),
+        false => u64::from_le_bytes(bytes),
+    })
+}
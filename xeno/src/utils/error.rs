@@ -1,4 +1,4 @@
-use std::{ffi::OsString, path::PathBuf};
+use std::ffi::OsString;
 
 use thiserror::Error;
 use unrar::archive::OpenArchive;
@@ -13,6 +13,8 @@ pub enum ArchiveError {
     OsString(OsString),
     #[error("The archive is encrypted")]
     Encrypted,
+    #[error("The password is incorrect")]
+    InvalidPassword,
     #[error("7zip error: {0}")]
     SevenZipError(#[source] sevenz_rust::Error),
     #[error("rar error: {0}")]
@@ -29,6 +31,15 @@ pub enum ArchiveError {
     NtfsError(#[source] ntfs::NtfsError),
     #[error("lzma error: {0}")]
     LzmaError(#[source] lzma_rs::error::Error),
+    #[error("xz error: {0}")]
+    XzError(#[source] xz2::stream::Error),
+    #[error("snappy error: {0}")]
+    SnappyError(#[source] snap::Error),
+    #[error("zstd dictionary {0} is needed but was not provided")]
+    MissingZstdDictionary(u32),
+
+    #[error("{0} checksum mismatch")]
+    ChecksumMismatch(String),
 
     #[error("{0}")]
     GenericsError(&'static str),
@@ -0,0 +1,148 @@
+//! Just enough of a flattened device tree reader for the formats that embed one, such as FIT
+//! images and Android boot images.
+
+use crate::utils::bytes::get_be32;
+use crate::utils::error::ArchiveError;
+
+pub(crate) const FDT_MAGIC: u32 = 0xd00d_feed;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+#[derive(Debug, Clone)]
+pub(crate) struct FdtProperty {
+    pub name: String,
+    /// Offset of the value from the start of the blob.
+    pub offset: usize,
+    pub len: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct FdtNode {
+    pub name: String,
+    pub properties: Vec<FdtProperty>,
+    pub children: Vec<FdtNode>,
+}
+
+impl FdtNode {
+    pub fn child(&self, name: &str) -> Option<&FdtNode> {
+        self.children.iter().find(|child| child.name == name)
+    }
+
+    pub fn property(&self, name: &str) -> Option<&FdtProperty> {
+        self.properties.iter().find(|prop| prop.name == name)
+    }
+}
+
+/// A parsed device tree, the property values stay in the blob.
+pub(crate) struct Fdt<'a> {
+    blob: &'a [u8],
+    pub root: FdtNode,
+}
+
+impl<'a> Fdt<'a> {
+    pub fn value(&self, prop: &FdtProperty) -> &'a [u8] {
+        &self.blob[prop.offset..prop.offset + prop.len]
+    }
+
+    /// A property as a NUL terminated string.
+    pub fn string(&self, node: &FdtNode, name: &str) -> Option<String> {
+        let value = self.value(node.property(name)?);
+        let value = value.split(|c| *c == 0).next().unwrap_or_default();
+        Some(String::from_utf8_lossy(value).into_owned())
+    }
+
+    /// A property made of one or two big endian cells.
+    pub fn number(&self, node: &FdtNode, name: &str) -> Option<u64> {
+        let value = self.value(node.property(name)?);
+        match value.len() {
+            4 => Some(u64::from(get_be32(value, 0)?)),
+            8 => Some(u64::from(get_be32(value, 0)?) << 32 | u64::from(get_be32(value, 4)?)),
+            _ => None,
+        }
+    }
+}
+
+/// Total size of the blob as recorded in its header, `None` if this is not a device tree.
+pub(crate) fn total_size(header: &[u8]) -> Option<usize> {
+    if get_be32(header, 0)? != FDT_MAGIC {
+        return None;
+    }
+    Some(get_be32(header, 4)? as usize)
+}
+
+pub(crate) fn parse(blob: &[u8]) -> Result<Fdt<'_>, ArchiveError> {
+    let size = total_size(blob).ok_or(ArchiveError::GenericsError("not a device tree"))?;
+    let header = |offset| get_be32(blob, offset).map(|value| value as usize);
+    let (structs, strings) = match (header(8), header(12)) {
+        (Some(structs), Some(strings)) if size <= blob.len() => (structs, strings),
+        _ => return Err(corrupted()),
+    };
+
+    let mut stack: Vec<FdtNode> = vec![];
+    let mut root = None;
+    let mut offset = structs;
+    while root.is_none() {
+        let token = get_be32(blob, offset).ok_or_else(corrupted)?;
+        offset += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let name = c_string(blob, offset).ok_or_else(corrupted)?;
+                offset = align(offset + name.len() + 1);
+                stack.push(FdtNode {
+                    name,
+                    ..Default::default()
+                });
+            }
+            FDT_END_NODE => {
+                let node = stack.pop().ok_or_else(corrupted)?;
+                match stack.last_mut() {
+                    Some(parent) => parent.children.push(node),
+                    None => root = Some(node),
+                }
+            }
+            FDT_PROP => {
+                let (len, name) = match (header(offset), header(offset + 4)) {
+                    (Some(len), Some(name)) => (len, name),
+                    _ => return Err(corrupted()),
+                };
+                let name = c_string(blob, strings + name);
+                let node = stack.last_mut();
+                match (name, node) {
+                    (Some(name), Some(node)) if offset + 8 + len <= size => {
+                        node.properties.push(FdtProperty {
+                            name,
+                            offset: offset + 8,
+                            len,
+                        });
+                    }
+                    _ => return Err(corrupted()),
+                }
+                offset = align(offset + 8 + len);
+            }
+            FDT_NOP => {}
+            FDT_END => break,
+            _ => return Err(corrupted()),
+        }
+    }
+
+    let root = root.ok_or_else(corrupted)?;
+    Ok(Fdt { blob, root })
+}
+
+fn corrupted() -> ArchiveError {
+    ArchiveError::GenericsError("device tree is corrupted")
+}
+
+fn c_string(blob: &[u8], offset: usize) -> Option<String> {
+    let rest = blob.get(offset..)?;
+    let end = rest.iter().position(|c| *c == 0)?;
+    Some(String::from_utf8_lossy(&rest[..end]).into_owned())
+}
+
+fn align(offset: usize) -> usize {
+    (offset + 3) & !3
+}
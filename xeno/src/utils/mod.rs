@@ -1 +1,3 @@
+pub(crate) mod bytes;
 pub mod error;
+pub(crate) mod fdt;
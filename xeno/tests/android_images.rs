@@ -0,0 +1,421 @@
+use std::io::{Cursor, Write};
+
+use sha2::{Digest, Sha256};
+
+use xeno_rs::archive::android_boot::{AndroidBootImage, AndroidBootSection};
+use xeno_rs::archive::android_ota::{PayloadArchive, PayloadOperationType};
+use xeno_rs::archive::android_sparse::AndroidSparseImage;
+use xeno_rs::block::{create_encoder, BlockFormat};
+use xeno_rs::utils::error::ArchiveError;
+
+mod common;
+
+use common::pad;
+
+/// A newc cpio archive of `(name, mode, data)` records.
+fn cpio(files: &[(&str, u32, &[u8])]) -> Vec<u8> {
+    let mut out = vec![];
+    let trailer: (&str, u32, &[u8]) = ("TRAILER!!!", 0, b"");
+    for (ino, (name, mode, data)) in files.iter().chain([&trailer]).enumerate() {
+        let fields = [ino as u32, *mode, 0, 0, 1, 0, data.len() as u32, 0, 0, 0, 0];
+        out.extend(b"070701");
+        for field in fields {
+            out.extend(format!("{:08x}", field).as_bytes());
+        }
+        out.extend(format!("{:08x}{:08x}", name.len() + 1, 0).as_bytes());
+        out.extend(name.as_bytes());
+        out.push(0);
+        out.resize((out.len() + 3) & !3, 0);
+        out.extend(*data);
+        out.resize((out.len() + 3) & !3, 0);
+    }
+    out
+}
+
+fn compress(format: BlockFormat, data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut encoder = create_encoder(format, &mut out, None).unwrap();
+    encoder.write_all(data).unwrap();
+    encoder.close().unwrap();
+    out
+}
+
+fn put(image: &mut Vec<u8>, offset: usize, data: &[u8]) {
+    if image.len() < offset + data.len() {
+        image.resize(offset + data.len(), 0);
+    }
+    image[offset..offset + data.len()].copy_from_slice(data);
+}
+
+fn ramdisk() -> Vec<u8> {
+    cpio(&[
+        ("init", 0o100750, b"#!/system/bin/sh\n"),
+        ("system", 0o040755, b""),
+        ("system/bin", 0o040755, b""),
+        ("system/bin/sh", 0o100755, b"shell"),
+        ("bin", 0o120777, b"system/bin"),
+    ])
+}
+
+#[test]
+fn boot_image_v2() {
+    let page_size = 2048;
+    let kernel = b"kernel image".repeat(300);
+    let ramdisk = compress(BlockFormat::Gzip, &ramdisk());
+    let second = b"second stage".to_vec();
+    let dtb = b"\xd0\x0d\xfe\xed device tree".to_vec();
+
+    let mut image = vec![];
+    put(&mut image, 0, b"ANDROID!");
+    put(&mut image, 8, &(kernel.len() as u32).to_le_bytes());
+    put(&mut image, 16, &(ramdisk.len() as u32).to_le_bytes());
+    put(&mut image, 24, &(second.len() as u32).to_le_bytes());
+    put(&mut image, 36, &(page_size as u32).to_le_bytes());
+    put(&mut image, 40, &2u32.to_le_bytes());
+    put(&mut image, 48, b"test-board");
+    put(&mut image, 64, b"console=ttyS0 ");
+    put(&mut image, 608, b"androidboot.hardware=test");
+    put(&mut image, 1644, &1660u32.to_le_bytes());
+    put(&mut image, 1648, &(dtb.len() as u32).to_le_bytes());
+    for section in [&kernel, &ramdisk, &second, &dtb] {
+        pad(&mut image, page_size);
+        image.extend(section);
+    }
+
+    let mut archive = AndroidBootImage::create_with_reader(Cursor::new(&image)).unwrap();
+    assert!(!archive.is_vendor_boot());
+    assert_eq!(archive.header_version(), 2);
+    assert_eq!(archive.name(), "test-board");
+    assert_eq!(archive.cmdline(), "console=ttyS0 androidboot.hardware=test");
+    let sections: Vec<_> = archive
+        .entries()
+        .unwrap()
+        .map(|entry| entry.unwrap().section())
+        .collect();
+    assert_eq!(
+        sections,
+        [
+            AndroidBootSection::Kernel,
+            AndroidBootSection::Ramdisk,
+            AndroidBootSection::Second,
+            AndroidBootSection::Dtb,
+            AndroidBootSection::Cmdline,
+        ]
+    );
+
+    let dir = tempfile::tempdir().unwrap();
+    archive.unpack_all(dir.path()).unwrap();
+    let out = dir.path();
+    assert_eq!(std::fs::read(out.join("kernel")).unwrap(), kernel);
+    assert_eq!(std::fs::read(out.join("second")).unwrap(), second);
+    assert_eq!(std::fs::read(out.join("dtb")).unwrap(), dtb);
+    assert_eq!(
+        std::fs::read_to_string(out.join("cmdline")).unwrap(),
+        "console=ttyS0 androidboot.hardware=test"
+    );
+    assert_eq!(
+        std::fs::read(out.join("ramdisk/system/bin/sh")).unwrap(),
+        b"shell"
+    );
+    assert_eq!(
+        std::fs::read_link(out.join("ramdisk/bin")).unwrap(),
+        std::path::Path::new("system/bin")
+    );
+}
+
+#[test]
+fn vendor_boot_image_v4() {
+    let page_size = 4096;
+    let first = compress(BlockFormat::Lz4, &ramdisk());
+    let second = compress(
+        BlockFormat::Gzip,
+        &cpio(&[("lib/modules/test.ko", 0o100644, b"module")]),
+    );
+    let mut ramdisks = first.clone();
+    ramdisks.extend(&second);
+    let dtb = b"\xd0\x0d\xfe\xed vendor tree".to_vec();
+    let bootconfig = b"androidboot.serialno=1234\n".to_vec();
+
+    let mut table = vec![];
+    for (name, offset, size) in [("", 0, first.len()), ("dlkm", first.len(), second.len())] {
+        let mut entry = vec![0u8; 108];
+        put(&mut entry, 0, &(size as u32).to_le_bytes());
+        put(&mut entry, 4, &(offset as u32).to_le_bytes());
+        put(&mut entry, 8, &1u32.to_le_bytes());
+        put(&mut entry, 12, name.as_bytes());
+        table.extend(entry);
+    }
+
+    let mut image = vec![];
+    put(&mut image, 0, b"VNDRBOOT");
+    put(&mut image, 8, &4u32.to_le_bytes());
+    put(&mut image, 12, &(page_size as u32).to_le_bytes());
+    put(&mut image, 24, &(ramdisks.len() as u32).to_le_bytes());
+    put(&mut image, 28, b"vendor=1");
+    put(&mut image, 2080, b"vendor-board");
+    put(&mut image, 2096, &2128u32.to_le_bytes());
+    put(&mut image, 2100, &(dtb.len() as u32).to_le_bytes());
+    put(&mut image, 2112, &(table.len() as u32).to_le_bytes());
+    put(&mut image, 2116, &2u32.to_le_bytes());
+    put(&mut image, 2120, &108u32.to_le_bytes());
+    put(&mut image, 2124, &(bootconfig.len() as u32).to_le_bytes());
+    for section in [&ramdisks, &dtb, &table, &bootconfig] {
+        pad(&mut image, page_size);
+        image.extend(section);
+    }
+
+    let mut archive = AndroidBootImage::create_with_reader(Cursor::new(&image)).unwrap();
+    assert!(archive.is_vendor_boot());
+    assert_eq!(archive.name(), "vendor-board");
+    assert_eq!(archive.cmdline(), "vendor=1");
+
+    let dir = tempfile::tempdir().unwrap();
+    archive.unpack_all(dir.path()).unwrap();
+    let out = dir.path();
+    assert_eq!(
+        std::fs::read(out.join("vendor_ramdisk_0/init")).unwrap(),
+        b"#!/system/bin/sh\n"
+    );
+    assert_eq!(
+        std::fs::read(out.join("vendor_ramdisk_dlkm/lib/modules/test.ko")).unwrap(),
+        b"module"
+    );
+    assert_eq!(std::fs::read(out.join("dtb")).unwrap(), dtb);
+    assert_eq!(std::fs::read(out.join("bootconfig")).unwrap(), bootconfig);
+}
+
+fn sparse_chunk(out: &mut Vec<u8>, chunk_type: u16, blocks: u32, data: &[u8]) {
+    out.extend(chunk_type.to_le_bytes());
+    out.extend(0u16.to_le_bytes());
+    out.extend(blocks.to_le_bytes());
+    out.extend((12 + data.len() as u32).to_le_bytes());
+    out.extend(data);
+}
+
+#[test]
+fn sparse_image() {
+    let block_size = 4096usize;
+    let raw_block: Vec<u8> = (0..block_size * 2).map(|i| (i % 253) as u8).collect();
+    let mut expected = raw_block.clone();
+    expected.extend([0xaa, 0xbb, 0xcc, 0xdd].repeat(block_size / 4 * 3));
+    expected.extend(vec![0u8; block_size * 4]);
+    let mut crc = flate2::Crc::new();
+    crc.update(&expected);
+
+    let mut image = vec![];
+    image.extend(0xed26_ff3a_u32.to_le_bytes());
+    image.extend([1, 0, 0, 0, 28, 0, 12, 0]);
+    image.extend((block_size as u32).to_le_bytes());
+    image.extend(9u32.to_le_bytes());
+    image.extend(4u32.to_le_bytes());
+    image.extend(0u32.to_le_bytes());
+    sparse_chunk(&mut image, 0xcac1, 2, &raw_block);
+    sparse_chunk(&mut image, 0xcac2, 3, &[0xaa, 0xbb, 0xcc, 0xdd]);
+    sparse_chunk(&mut image, 0xcac3, 4, &[]);
+    sparse_chunk(&mut image, 0xcac4, 0, &crc.sum().to_le_bytes());
+
+    let mut sparse = AndroidSparseImage::create_with_reader(image.as_slice()).unwrap();
+    assert_eq!(sparse.raw_size(), expected.len() as u64);
+    let mut raw = vec![];
+    assert_eq!(sparse.unpack_to(&mut raw).unwrap(), expected.len() as u64);
+    assert_eq!(raw, expected);
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("system.img");
+    let mut sparse = AndroidSparseImage::create_with_reader(image.as_slice()).unwrap();
+    sparse.unpack_file(&path).unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), expected);
+
+    let mut corrupted = image.clone();
+    let len = corrupted.len();
+    corrupted[len - 1] ^= 1;
+    let mut sparse = AndroidSparseImage::create_with_reader(corrupted.as_slice()).unwrap();
+    assert!(matches!(
+        sparse.unpack_to(std::io::sink()),
+        Err(ArchiveError::ChecksumMismatch(_))
+    ));
+}
+
+fn varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn proto_varint(out: &mut Vec<u8>, field: u64, value: u64) {
+    varint(out, field << 3);
+    varint(out, value);
+}
+
+fn proto_bytes(out: &mut Vec<u8>, field: u64, value: &[u8]) {
+    varint(out, field << 3 | 2);
+    varint(out, value.len() as u64);
+    out.extend(value);
+}
+
+/// An `InstallOperation` writing `blobs[index]` to a single extent.
+fn operation(kind: u64, blob: Option<(u64, &[u8])>, start: u64, blocks: u64) -> Vec<u8> {
+    let mut op = vec![];
+    proto_varint(&mut op, 1, kind);
+    if let Some((offset, data)) = blob {
+        proto_varint(&mut op, 2, offset);
+        proto_varint(&mut op, 3, data.len() as u64);
+        proto_bytes(&mut op, 8, &Sha256::digest(data));
+    }
+    let mut extent = vec![];
+    proto_varint(&mut extent, 1, start);
+    proto_varint(&mut extent, 2, blocks);
+    proto_bytes(&mut op, 6, &extent);
+    op
+}
+
+fn partition(name: &str, size: u64, operations: &[Vec<u8>]) -> Vec<u8> {
+    let mut partition = vec![];
+    proto_bytes(&mut partition, 1, name.as_bytes());
+    let mut info = vec![];
+    proto_varint(&mut info, 1, size);
+    proto_bytes(&mut partition, 7, &info);
+    for op in operations {
+        proto_bytes(&mut partition, 8, op);
+    }
+    partition
+}
+
+#[test]
+fn ota_payload() {
+    let block_size = 4096u64;
+    let first: Vec<u8> = (0..block_size * 2).map(|i| (i % 249) as u8).collect();
+    let second = b"bzip2 block".repeat(373)[..block_size as usize].to_vec();
+    let third = b"xz block".repeat(512);
+    let blobs = [
+        first.clone(),
+        compress(BlockFormat::Bzip2, &second),
+        compress(BlockFormat::Xz, &third),
+    ];
+    let offsets: Vec<u64> = blobs
+        .iter()
+        .scan(0, |offset, blob| {
+            let start = *offset;
+            *offset += blob.len() as u64;
+            Some(start)
+        })
+        .collect();
+
+    let system = partition(
+        "system",
+        block_size * 6,
+        &[
+            operation(0, Some((offsets[0], &blobs[0])), 0, 2),
+            operation(1, Some((offsets[1], &blobs[1])), 2, 1),
+            operation(6, None, 3, 1),
+            operation(8, Some((offsets[2], &blobs[2])), 5, 1),
+        ],
+    );
+    // SOURCE_COPY, only an incremental OTA can use it.
+    let vendor = partition("vendor", block_size, &[operation(4, None, 0, 1)]);
+    let mut manifest = vec![];
+    proto_varint(&mut manifest, 3, block_size);
+    proto_bytes(&mut manifest, 13, &system);
+    proto_bytes(&mut manifest, 13, &vendor);
+
+    let mut payload = b"CrAU".to_vec();
+    payload.extend(2u64.to_be_bytes());
+    payload.extend((manifest.len() as u64).to_be_bytes());
+    payload.extend(4u32.to_be_bytes());
+    payload.extend(&manifest);
+    payload.extend([0u8; 4]);
+    for blob in &blobs {
+        payload.extend(blob);
+    }
+
+    let mut ota = Cursor::new(vec![]);
+    let mut zip = zip::ZipWriter::new(&mut ota);
+    let stored =
+        zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    zip.start_file("payload_properties.txt", stored).unwrap();
+    zip.write_all(b"FILE_HASH=\n").unwrap();
+    zip.start_file("payload.bin", stored).unwrap();
+    zip.write_all(&payload).unwrap();
+    zip.finish().unwrap();
+    drop(zip);
+
+    let mut archive = PayloadArchive::create_with_zip(ota).unwrap();
+    assert_eq!(archive.major_version(), 2);
+    let entries: Vec<_> = archive.entries().unwrap().map(Result::unwrap).collect();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].partition_name(), "system");
+    assert!(!entries[0].is_delta());
+    assert_eq!(
+        entries[1].operation_types(),
+        [PayloadOperationType::Delta(4)]
+    );
+
+    let mut expected = first;
+    expected.extend(&second);
+    expected.resize(block_size as usize * 5, 0);
+    expected.extend(&third);
+    let dir = tempfile::tempdir().unwrap();
+    match archive.unpack_all(dir.path()) {
+        Err(ArchiveError::ExtractFailed { sources }) => assert_eq!(sources.len(), 1),
+        result => panic!("unexpected result {:?}", result),
+    }
+    assert_eq!(
+        std::fs::read(dir.path().join("system.img")).unwrap(),
+        expected
+    );
+}
+
+#[test]
+fn ota_payload_corrupt_header() {
+    // a manifest that claims far more than the payload holds
+    let mut payload = b"CrAU".to_vec();
+    payload.extend(2u64.to_be_bytes());
+    payload.extend((1u64 << 62).to_be_bytes());
+    payload.extend(0u32.to_be_bytes());
+    payload.extend([0u8; 64]);
+    assert!(PayloadArchive::create_with_reader(Cursor::new(&payload)).is_err());
+
+    // and one whose size overflows the data offset
+    payload[12..20].copy_from_slice(&u64::MAX.to_be_bytes());
+    payload[20..24].copy_from_slice(&u32::MAX.to_be_bytes());
+    assert!(PayloadArchive::create_with_reader(Cursor::new(&payload)).is_err());
+}
+
+#[test]
+fn ota_payload_corrupt_operations() {
+    let block_size = 4096u64;
+    let blob = vec![0x5a; block_size as usize];
+
+    // data far past the end of the payload
+    let mut huge = vec![];
+    proto_varint(&mut huge, 1, 0);
+    proto_varint(&mut huge, 2, 0);
+    proto_varint(&mut huge, 3, 1 << 62);
+    let mut extent = vec![];
+    proto_varint(&mut extent, 1, 0);
+    proto_varint(&mut extent, 2, 1);
+    proto_bytes(&mut huge, 6, &extent);
+    // a destination whose byte offset overflows
+    let far = operation(0, Some((0, &blob)), u64::MAX / 2, 1);
+
+    let mut manifest = vec![];
+    proto_varint(&mut manifest, 3, block_size);
+    proto_bytes(&mut manifest, 13, &partition("boot", block_size, &[huge]));
+    proto_bytes(&mut manifest, 13, &partition("dtbo", block_size, &[far]));
+
+    let mut payload = b"CrAU".to_vec();
+    payload.extend(2u64.to_be_bytes());
+    payload.extend((manifest.len() as u64).to_be_bytes());
+    payload.extend(0u32.to_be_bytes());
+    payload.extend(&manifest);
+    payload.extend(&blob);
+
+    let mut archive = PayloadArchive::create_with_reader(Cursor::new(payload)).unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    match archive.unpack_all(dir.path()) {
+        Err(ArchiveError::ExtractFailed { sources }) => assert_eq!(sources.len(), 2),
+        result => panic!("unexpected result {:?}", result),
+    }
+}
@@ -0,0 +1,556 @@
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+use xeno_rs::archive::tar::TarZstdArchive;
+use xeno_rs::block::brotli::{BrotliBlock, BrotliEncoder};
+use xeno_rs::block::bzip2::{Bzip2Block, Bzip2Encoder};
+use xeno_rs::block::deflate::{FlateBlock, FlateEncoder};
+use xeno_rs::block::gzip::{GzipBlock, GzipEncoder};
+use xeno_rs::block::lz4::{Lz4Block, Lz4Encoder, RawLz4Block};
+use xeno_rs::block::lzma::{Lzma2Block, LzmaAloneBlock};
+use xeno_rs::block::lzo::{LzoBlock, LzopBlock};
+use xeno_rs::block::snappy::{RawSnappyBlock, SnappyBlock, SnappyEncoder};
+use xeno_rs::block::xz::{XzBlock, XzEncoder};
+use xeno_rs::block::zlib::{ZlibBlock, ZlibEncoder};
+use xeno_rs::block::zstd::{ZstdBlock, ZstdEncoder, ZstdSeekableBlock};
+use xeno_rs::block::{create_decoder, create_encoder, detect_decoder, BlockDecoder, BlockFormat};
+use xeno_rs::utils::error::ArchiveError;
+
+fn sample() -> Vec<u8> {
+    // Mix of repetitive and noisy data so every codec has something to chew on.
+    let mut data = Vec::new();
+    let mut state = 0x2545_f491_u32;
+    for i in 0..64 * 1024 {
+        if i % 3 == 0 {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            data.push(state as u8);
+        } else {
+            data.push(b"firmware"[i % 8]);
+        }
+    }
+    data
+}
+
+fn check(decoder: &mut dyn BlockDecoder, compressed_len: usize, expected: &[u8]) {
+    let mut decoded = vec![];
+    decoder.read_to_end(&mut decoded).unwrap();
+    assert_eq!(decoded, expected);
+    assert_eq!(decoder.uncompressed_size(), expected.len() as u64);
+    assert_eq!(decoder.compressed_size(), compressed_len as u64);
+}
+
+#[test]
+fn gzip_roundtrip() {
+    let data = sample();
+    let mut encoder = GzipEncoder::create_with_writer(vec![], 9).unwrap();
+    encoder.write_all(&data).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    let mut decoder = GzipBlock::create_with_reader(Cursor::new(&compressed)).unwrap();
+    check(&mut decoder, compressed.len(), &data);
+}
+
+#[test]
+fn zlib_roundtrip() {
+    let data = sample();
+    let mut encoder = ZlibEncoder::create_with_writer(vec![], 1).unwrap();
+    encoder.write_all(&data).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    let mut decoder = ZlibBlock::create_with_reader(Cursor::new(&compressed)).unwrap();
+    check(&mut decoder, compressed.len(), &data);
+}
+
+#[test]
+fn deflate_roundtrip() {
+    let data = sample();
+    let mut encoder = FlateEncoder::create_with_writer(vec![], 6).unwrap();
+    encoder.write_all(&data).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    let mut decoder = FlateBlock::create_with_reader(Cursor::new(&compressed)).unwrap();
+    check(&mut decoder, compressed.len(), &data);
+}
+
+#[test]
+fn bzip2_roundtrip() {
+    let data = sample();
+    let mut encoder = Bzip2Encoder::create_with_writer(vec![], 9).unwrap();
+    encoder.write_all(&data).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    let mut decoder = Bzip2Block::create_with_reader(Cursor::new(&compressed)).unwrap();
+    check(&mut decoder, compressed.len(), &data);
+}
+
+#[test]
+fn xz_roundtrip() {
+    let data = sample();
+    let mut encoder = XzEncoder::create_with_writer(vec![], 6).unwrap();
+    encoder.write_all(&data).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    let mut decoder = XzBlock::create_with_reader(Cursor::new(&compressed)).unwrap();
+    check(&mut decoder, compressed.len(), &data);
+}
+
+#[test]
+fn lz4_roundtrip() {
+    let data = sample();
+    let mut encoder = Lz4Encoder::create_with_writer(vec![], 9).unwrap();
+    encoder.write_all(&data).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    let mut decoder = Lz4Block::create_with_reader(Cursor::new(&compressed)).unwrap();
+    check(&mut decoder, compressed.len(), &data);
+}
+
+#[test]
+fn zstd_roundtrip() {
+    let data = sample();
+    let mut encoder = ZstdEncoder::create_with_writer(vec![], 19).unwrap();
+    encoder.write_all(&data).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    let mut decoder = ZstdBlock::create_with_reader(Cursor::new(&compressed)).unwrap();
+    check(&mut decoder, compressed.len(), &data);
+}
+
+#[test]
+fn snappy_roundtrip() {
+    let data = sample();
+    let mut encoder = SnappyEncoder::create_with_writer(vec![]).unwrap();
+    encoder.write_all(&data).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    let mut decoder = SnappyBlock::create_with_reader(Cursor::new(&compressed)).unwrap();
+    check(&mut decoder, compressed.len(), &data);
+}
+
+#[test]
+fn brotli_roundtrip() {
+    let data = sample();
+    let mut encoder = BrotliEncoder::create_with_writer(vec![], 5).unwrap();
+    encoder.write_all(&data).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    let mut decoder = BrotliBlock::create_with_reader(Cursor::new(&compressed)).unwrap();
+    check(&mut decoder, compressed.len(), &data);
+}
+
+#[test]
+fn detected_roundtrip() {
+    let data = sample();
+    let formats = [
+        BlockFormat::Gzip,
+        BlockFormat::Zlib,
+        BlockFormat::Bzip2,
+        BlockFormat::Xz,
+        BlockFormat::Lz4,
+        BlockFormat::Zstd,
+        BlockFormat::Snappy,
+    ];
+    for format in formats {
+        let mut compressed = vec![];
+        let mut encoder = create_encoder(format, &mut compressed, None).unwrap();
+        encoder.write_all(&data).unwrap();
+        encoder.close().unwrap();
+
+        let mut decoder = detect_decoder(Cursor::new(&compressed)).unwrap();
+        assert_eq!(decoder.format(), format);
+        let mut decoded = vec![];
+        decoder.decode_to(&mut decoded).unwrap();
+        assert_eq!(decoded, data);
+    }
+}
+
+#[test]
+fn block_formats_from_magic() {
+    let cases: [(&[u8], Option<BlockFormat>); 12] = [
+        (&[0x1f, 0x8b, 0x08], Some(BlockFormat::Gzip)),
+        (b"BZh9", Some(BlockFormat::Bzip2)),
+        (b"\xfd7zXZ\x00", Some(BlockFormat::Xz)),
+        (&[0x04, 0x22, 0x4d, 0x18], Some(BlockFormat::Lz4)),
+        (&[0x02, 0x21, 0x4c, 0x18], Some(BlockFormat::Lz4)),
+        (&[0x28, 0xb5, 0x2f, 0xfd], Some(BlockFormat::Zstd)),
+        (b"\x89LZO\x00\r\n\x1a\n", Some(BlockFormat::Lzop)),
+        (b"\xff\x06\x00\x00sNaPpY", Some(BlockFormat::Snappy)),
+        (&[0x5d, 0x00, 0x00, 0x80], Some(BlockFormat::LzmaAlone)),
+        (&[0x78, 0x9c], Some(BlockFormat::Zlib)),
+        // a zlib method byte with a broken header checksum
+        (&[0x78, 0x9d], None),
+        (b"BZh", None),
+    ];
+    for (magic, format) in cases {
+        assert_eq!(BlockFormat::detect(magic), format, "{magic:x?}");
+    }
+    assert!(detect_decoder(Cursor::new(b"plain text")).is_err());
+}
+
+#[test]
+fn block_decoders_stream_and_count() {
+    let data = sample();
+    let mut compressed = vec![];
+    lzma_rs::lzma_compress(&mut &data[..], &mut compressed).unwrap();
+
+    // picked at run time, read in small pieces
+    let mut decoder = create_decoder(BlockFormat::LzmaAlone, Cursor::new(&compressed)).unwrap();
+    assert_eq!(decoder.format(), BlockFormat::LzmaAlone);
+    let mut decoded = vec![];
+    let mut buf = [0u8; 1000];
+    loop {
+        let read = decoder.read(&mut buf).unwrap();
+        if read == 0 {
+            break;
+        }
+        decoded.extend_from_slice(&buf[..read]);
+        assert_eq!(decoder.uncompressed_size(), decoded.len() as u64);
+    }
+    assert_eq!(decoded, data);
+    assert_eq!(decoder.compressed_size(), compressed.len() as u64);
+
+    // into any writer
+    let mut decoder = LzmaAloneBlock::create_with_reader(Cursor::new(&compressed)).unwrap();
+    let mut decoded = vec![];
+    assert_eq!(decoder.decode_to(&mut decoded).unwrap(), data.len() as u64);
+    assert_eq!(decoded, data);
+
+    // or into a new file
+    let out = tempfile::tempdir().unwrap();
+    let mut decoder = LzmaAloneBlock::create_with_reader(Cursor::new(&compressed)).unwrap();
+    decoder.unpack_to(out.path().join("data")).unwrap();
+    assert_eq!(std::fs::read(out.path().join("data")).unwrap(), data);
+}
+
+#[test]
+fn gzip_multi_member() {
+    let mut compressed = vec![];
+    for (name, part) in [("first", &b"hello "[..]), ("second", &b"world"[..])] {
+        let mut encoder = flate2::GzBuilder::new()
+            .filename(name)
+            .mtime(1_600_000_000)
+            .write(vec![], flate2::Compression::default());
+        encoder.write_all(part).unwrap();
+        compressed.extend(encoder.finish().unwrap());
+    }
+
+    let mut decoder = GzipBlock::create_with_reader(Cursor::new(&compressed)).unwrap();
+    let header = decoder.header().unwrap();
+    assert_eq!(header.filename(), Some(&b"first"[..]));
+    assert_eq!(header.mtime(), 1_600_000_000);
+    check(&mut decoder, compressed.len(), b"hello world");
+}
+
+fn skippable_frame(magic: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame = magic.to_le_bytes().to_vec();
+    frame.extend((payload.len() as u32).to_le_bytes());
+    frame.extend(payload);
+    frame
+}
+
+#[test]
+fn zstd_concatenated_frames() {
+    let mut compressed = vec![];
+    compressed.extend(zstd::encode_all(&b"hello "[..], 3).unwrap());
+    compressed.extend(skippable_frame(0x184d_2a53, b"metadata"));
+    compressed.extend(zstd::encode_all(&b"world"[..], 3).unwrap());
+
+    let mut decoder = ZstdBlock::create_with_reader(Cursor::new(&compressed)).unwrap();
+    check(&mut decoder, compressed.len(), b"hello world");
+    let frames = decoder.skippable_frames();
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0].magic(), 0x184d_2a53);
+    assert_eq!(frames[0].payload(), b"metadata");
+}
+
+#[test]
+fn lzma2_roundtrip() {
+    let data = sample();
+    let mut compressed = vec![];
+    lzma_rs::lzma2_compress(&mut &data[..], &mut compressed).unwrap();
+
+    let mut decoder = Lzma2Block::create_with_reader(Cursor::new(&compressed)).unwrap();
+    check(&mut decoder, compressed.len(), &data);
+}
+
+#[test]
+fn lzma2_corrupt_input_keeps_failing() {
+    let data = sample();
+    let mut compressed = vec![];
+    lzma_rs::lzma2_compress(&mut &data[..], &mut compressed).unwrap();
+    compressed.truncate(compressed.len() / 2);
+
+    let mut decoder = Lzma2Block::create_with_reader(Cursor::new(&compressed)).unwrap();
+    let mut buf = [0u8; 100];
+    for _ in 0..2 {
+        let err = decoder.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
+
+#[test]
+fn zstd_seekable() {
+    let data = sample();
+    let mut compressed = vec![];
+    let mut table = vec![];
+    for chunk in data.chunks(10_000) {
+        let frame = zstd::encode_all(chunk, 3).unwrap();
+        table.extend((frame.len() as u32).to_le_bytes());
+        table.extend((chunk.len() as u32).to_le_bytes());
+        compressed.extend(frame);
+    }
+    let frames = data.chunks(10_000).count() as u32;
+    table.extend(frames.to_le_bytes());
+    table.push(0);
+    table.extend(0x8f92_eab1_u32.to_le_bytes());
+    compressed.extend(skippable_frame(0x184d_2a5e, &table));
+
+    let mut decoder = ZstdSeekableBlock::create_with_reader(Cursor::new(&compressed)).unwrap();
+    assert_eq!(decoder.frames().len(), frames as usize);
+    assert_eq!(decoder.uncompressed_size(), data.len() as u64);
+
+    let mut part = vec![0u8; 5_000];
+    decoder.seek(SeekFrom::Start(37_500)).unwrap();
+    decoder.read_exact(&mut part).unwrap();
+    assert_eq!(part, &data[37_500..42_500]);
+
+    let mut tail = vec![];
+    decoder.seek(SeekFrom::End(-100)).unwrap();
+    decoder.read_to_end(&mut tail).unwrap();
+    assert_eq!(tail, &data[data.len() - 100..]);
+}
+
+#[test]
+fn zstd_seekable_frames_shorter_than_the_table() {
+    let data = sample();
+    let frame = zstd::encode_all(&data[..10_000], 3).unwrap();
+    let mut table = vec![];
+    table.extend((frame.len() as u32).to_le_bytes());
+    // the table promises more than the frame holds
+    table.extend(10_500_u32.to_le_bytes());
+    table.extend(1_u32.to_le_bytes());
+    table.push(0);
+    table.extend(0x8f92_eab1_u32.to_le_bytes());
+    let mut compressed = frame;
+    compressed.extend(skippable_frame(0x184d_2a5e, &table));
+
+    let mut decoder = ZstdSeekableBlock::create_with_reader(Cursor::new(&compressed)).unwrap();
+    decoder.seek(SeekFrom::Start(10_200)).unwrap();
+    let err = decoder.read(&mut [0u8; 100]).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn lz4_concatenated_and_legacy_frames() {
+    let data = sample();
+    let mut compressed = vec![];
+    let mut encoder = Lz4Encoder::create_with_writer(vec![], 0).unwrap();
+    encoder.write_all(&data[..1000]).unwrap();
+    compressed.extend(encoder.finish().unwrap());
+    compressed.extend(skippable_frame(0x184d_2a50, b"note"));
+
+    // Legacy frame: magic, then blocks of compressed size and raw lz4 block data.
+    compressed.extend(0x184c_2102_u32.to_le_bytes());
+    for chunk in data[1000..].chunks(30_000) {
+        let block = lz4::block::compress(chunk, None, false).unwrap();
+        compressed.extend((block.len() as u32).to_le_bytes());
+        compressed.extend(block);
+    }
+
+    let mut decoder = detect_decoder(Cursor::new(&compressed[..])).unwrap();
+    assert_eq!(decoder.format(), BlockFormat::Lz4);
+    let mut decoded = vec![];
+    decoder.read_to_end(&mut decoded).unwrap();
+    assert_eq!(decoded, data);
+
+    let mut decoder = Lz4Block::create_with_reader(Cursor::new(&compressed)).unwrap();
+    check(&mut decoder, compressed.len(), &data);
+    assert_eq!(decoder.skippable_frames()[0].payload(), b"note");
+}
+
+#[test]
+fn zstd_dictionaries() {
+    let samples: Vec<Vec<u8>> = (0..200)
+        .map(|i| format!("device-{i} firmware=v{} board=rev{}", i % 7, i % 3).into_bytes())
+        .collect();
+    let dictionary = zstd::dict::from_samples(&samples, 4096).unwrap();
+    let id = zstd::zstd_safe::get_dict_id_from_dict(&dictionary)
+        .unwrap()
+        .get();
+
+    let mut tarball = tar::Builder::new(vec![]);
+    let mut header = tar::Header::new_gnu();
+    header.set_size(samples[42].len() as u64);
+    header.set_cksum();
+    tarball
+        .append_data(&mut header, "config", &samples[42][..])
+        .unwrap();
+    let tarball = tarball.into_inner().unwrap();
+    let mut compressor = zstd::bulk::Compressor::with_dictionary(3, &dictionary).unwrap();
+    let compressed = compressor.compress(&tarball).unwrap();
+
+    match ZstdBlock::create_with_reader(Cursor::new(&compressed)) {
+        Err(ArchiveError::MissingZstdDictionary(missing)) => assert_eq!(missing, id),
+        _ => panic!("expected a missing dictionary error"),
+    }
+
+    let other = zstd::dict::from_samples(&samples[..100], 2048).unwrap();
+    let mut decoder =
+        ZstdBlock::create_with_dictionaries(Cursor::new(&compressed), [other, dictionary.clone()])
+            .unwrap();
+    check(&mut decoder, compressed.len(), &tarball);
+
+    let mut archive =
+        TarZstdArchive::create_with_dictionaries(Cursor::new(&compressed), [dictionary]).unwrap();
+    let mut entries = archive.entries().unwrap();
+    let mut entry = entries.next().unwrap().unwrap();
+    let mut content = vec![];
+    entry.read_to_end(&mut content).unwrap();
+    assert_eq!(content, samples[42]);
+}
+
+#[test]
+fn raw_blocks() {
+    let data = sample();
+
+    let compressed = snap::raw::Encoder::new().compress_vec(&data).unwrap();
+    let mut decoder = RawSnappyBlock::create_with_reader(Cursor::new(&compressed)).unwrap();
+    check(&mut decoder, compressed.len(), &data);
+
+    let compressed = lz4::block::compress(&data, None, true).unwrap();
+    let mut decoder = RawLz4Block::create_with_reader(Cursor::new(&compressed)).unwrap();
+    check(&mut decoder, compressed.len(), &data);
+
+    let compressed = lz4::block::compress(&data, None, false).unwrap();
+    let mut decoder = RawLz4Block::create_with_size(Cursor::new(&compressed), 128 * 1024).unwrap();
+    check(&mut decoder, compressed.len(), &data);
+
+    let mut compressed = Vec::with_capacity(rust_lzo::worst_compress(data.len()));
+    let mut context = rust_lzo::LZOContext::new();
+    assert!(context.compress(&data, &mut compressed) == rust_lzo::LZOError::OK);
+    let mut decoder = LzoBlock::create_with_reader(Cursor::new(&compressed)).unwrap();
+    check(&mut decoder, compressed.len(), &data);
+    let mut decoder = LzoBlock::create_with_size(Cursor::new(&compressed), data.len()).unwrap();
+    check(&mut decoder, compressed.len(), &data);
+    // a size far past anything the input decodes to is not allocated
+    let mut decoder = LzoBlock::create_with_size(Cursor::new(&compressed), usize::MAX).unwrap();
+    check(&mut decoder, compressed.len(), &data);
+}
+
+#[test]
+fn raw_blocks_with_oversized_sizes() {
+    // A tiny lz4 block whose prefix claims 2 GiB.
+    let mut compressed = lz4::block::compress(b"tiny", None, true).unwrap();
+    compressed[..4].copy_from_slice(&0x7fff_ffffu32.to_le_bytes());
+    let mut decoder = RawLz4Block::create_with_reader(Cursor::new(&compressed)).unwrap();
+    assert!(decoder.read_to_end(&mut vec![]).is_err());
+
+    let mut decoder = RawLz4Block::create_with_reader(Cursor::new(&compressed[..2])).unwrap();
+    assert!(decoder.read_to_end(&mut vec![]).is_err());
+
+    // A raw snappy block whose preamble claims 4 GiB.
+    let mut compressed = vec![0xff, 0xff, 0xff, 0xff, 0x0f];
+    compressed.extend(snap::raw::Encoder::new().compress_vec(b"tiny").unwrap());
+    let mut decoder = RawSnappyBlock::create_with_reader(Cursor::new(&compressed)).unwrap();
+    assert!(decoder.read_to_end(&mut vec![]).is_err());
+    // while the best ratio snappy reaches still decodes
+    let zeros = vec![0u8; 1 << 20];
+    let compressed = snap::raw::Encoder::new().compress_vec(&zeros).unwrap();
+    let mut decoder = RawSnappyBlock::create_with_reader(Cursor::new(&compressed)).unwrap();
+    check(&mut decoder, compressed.len(), &zeros);
+    let compressed = lz4::block::compress(&zeros, None, true).unwrap();
+    let mut decoder = RawLz4Block::create_with_reader(Cursor::new(&compressed)).unwrap();
+    check(&mut decoder, compressed.len(), &zeros);
+
+    // An lzop stream with one block that claims to decode to 4 GiB.
+    let mut stream = vec![0x89, b'L', b'Z', b'O', 0x00, 0x0d, 0x0a, 0x1a, 0x0a];
+    stream.extend([0x10, 0x30, 0x20, 0x60, 0x09, 0x40, 1, 5]);
+    stream.extend(0u32.to_be_bytes()); // flags
+    stream.extend(0o100644u32.to_be_bytes());
+    stream.extend([0u8; 8]); // mtime
+    stream.push(0);
+    stream.extend(0u32.to_be_bytes()); // header checksum
+    stream.extend(u32::MAX.to_be_bytes());
+    stream.extend(u32::MAX.to_be_bytes());
+    stream.extend([0u8; 16]);
+    let mut decoder = LzopBlock::create_with_reader(Cursor::new(&stream)).unwrap();
+    assert!(decoder.read_to_end(&mut vec![]).is_err());
+}
+
+#[test]
+fn zstd_levels_past_the_strongest() {
+    let data = sample();
+    let encode = |level| {
+        let mut compressed = vec![];
+        let mut encoder = create_encoder(BlockFormat::Zstd, &mut compressed, Some(level)).unwrap();
+        encoder.write_all(&data).unwrap();
+        encoder.close().unwrap();
+        compressed
+    };
+
+    let strongest = *zstd::compression_level_range().end() as u32;
+    let compressed = encode(u32::MAX);
+    assert_eq!(compressed, encode(strongest));
+    assert_eq!(zstd::decode_all(Cursor::new(&compressed)).unwrap(), data);
+}
+
+#[test]
+fn levels_out_of_range_round_trip() {
+    let data = sample();
+    let formats = [
+        BlockFormat::Gzip,
+        BlockFormat::Zlib,
+        BlockFormat::Deflate,
+        BlockFormat::Bzip2,
+        BlockFormat::Xz,
+        BlockFormat::Brotli,
+        BlockFormat::Lz4,
+        BlockFormat::Zstd,
+    ];
+    for format in formats {
+        for level in [0, 10, 20, u32::MAX] {
+            let mut compressed = vec![];
+            let mut encoder = create_encoder(format, &mut compressed, Some(level)).unwrap();
+            encoder.write_all(&data).unwrap();
+            encoder.close().unwrap();
+
+            let mut decoder = create_decoder(format, Cursor::new(&compressed)).unwrap();
+            let mut decoded = vec![];
+            decoder.decode_to(&mut decoded).unwrap();
+            assert!(decoded == data, "{:?} at level {}", format, level);
+        }
+    }
+}
+
+/// Takes `left` bytes, then fails every write.
+struct Full {
+    left: usize,
+}
+
+impl Write for Full {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.left == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::StorageFull, "full"));
+        }
+        let size = buf.len().min(self.left);
+        self.left -= size;
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn brotli_finish_reports_the_final_block() {
+    // nothing reaches the writer before the final block
+    let mut encoder = BrotliEncoder::create_with_writer(Full { left: 4 }, 11).unwrap();
+    encoder.write_all(b"short").unwrap();
+    assert!(encoder.finish().is_err());
+
+    let mut encoder = BrotliEncoder::create_with_writer(Full { left: 1 << 20 }, 11).unwrap();
+    encoder.write_all(b"short").unwrap();
+    assert!(encoder.finish().unwrap().left < 1 << 20);
+}
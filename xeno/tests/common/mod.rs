@@ -0,0 +1,45 @@
+//! Helpers shared by the integration tests, each test binary uses a different subset.
+#![allow(dead_code)]
+
+use std::path::Path;
+
+/// CRC-32 as zip, gzip and U-Boot store it.
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32fast::hash(data)
+}
+
+/// The kernel's `crc32_le(seed, data)`, the bare register without the inversions of [`crc32`].
+/// JFFS2 seeds it with 0, UBI and UBIFS with `0xffffffff`, and the PKWARE cipher feeds it one
+/// byte at a time.
+pub fn crc32_le(seed: u32, data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new_with_initial(!seed);
+    hasher.update(data);
+    !hasher.finalize()
+}
+
+/// Pads `data` with zeros to a multiple of `align`.
+pub fn pad(data: &mut Vec<u8>, align: usize) {
+    data.resize(data.len().div_ceil(align) * align, 0);
+}
+
+#[cfg(unix)]
+pub fn get_xattr(path: &Path, name: &str) -> Option<Vec<u8>> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+    let name = std::ffi::CString::new(name).unwrap();
+    let mut value = vec![0u8; 256];
+    let size = unsafe {
+        libc::lgetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_mut_ptr().cast(),
+            value.len(),
+        )
+    };
+    if size < 0 {
+        return None;
+    }
+    value.truncate(size as usize);
+    Some(value)
+}
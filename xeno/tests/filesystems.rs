@@ -0,0 +1,1521 @@
+use std::io::{Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+
+use xeno_rs::archive::cramfs::CramfsArchive;
+use xeno_rs::archive::jffs2::Jffs2Archive;
+use xeno_rs::archive::romfs::RomfsArchive;
+use xeno_rs::archive::squashfs::{
+    SquashFSArchive, SquashFSCompression, SquashFSWriteOptions, SquashFSWriter,
+};
+use xeno_rs::archive::ubi::{UbiArchive, UbiVolumeType};
+use xeno_rs::archive::{Entry, FileType};
+
+mod common;
+
+#[cfg(unix)]
+use common::get_xattr;
+use common::{crc32, crc32_le};
+
+/// Lays out JFFS2 nodes the way mkfs.jffs2 does, in either endianness.
+struct Jffs2Writer {
+    big_endian: bool,
+    out: Vec<u8>,
+}
+
+impl Jffs2Writer {
+    fn u16(&self, value: u16) -> [u8; 2] {
+        match self.big_endian {
+            true => value.to_be_bytes(),
+            false => value.to_le_bytes(),
+        }
+    }
+
+    fn u32(&self, value: u32) -> [u8; 4] {
+        match self.big_endian {
+            true => value.to_be_bytes(),
+            false => value.to_le_bytes(),
+        }
+    }
+
+    fn header(&self, node_type: u16, len: usize) -> Vec<u8> {
+        let mut header = vec![];
+        header.extend(self.u16(0x1985));
+        header.extend(self.u16(node_type));
+        header.extend(self.u32(len as u32));
+        let crc = crc32_le(0, &header);
+        header.extend(self.u32(crc));
+        header
+    }
+
+    fn push(&mut self, mut node: Vec<u8>, obsolete: bool) {
+        if obsolete {
+            // Obsoleting a node only clears a bit on flash, the header CRC stays as it was.
+            node[if self.big_endian { 2 } else { 3 }] &= !0x20;
+        }
+        self.out.extend(node);
+        self.out.resize((self.out.len() + 3) & !3, 0xff);
+    }
+
+    fn dirent(&mut self, pino: u32, version: u32, ino: u32, name: &str) {
+        let mut node = self.header(0xe001, 40 + name.len());
+        for value in [pino, version, ino, 0] {
+            node.extend(self.u32(value));
+        }
+        node.extend([name.len() as u8, 0, 0, 0]);
+        let crc = crc32_le(0, &node);
+        node.extend(self.u32(crc));
+        node.extend(self.u32(crc32_le(0, name.as_bytes())));
+        node.extend(name.as_bytes());
+        self.push(node, false);
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn inode(
+        &mut self,
+        ino: u32,
+        version: u32,
+        mode: u32,
+        isize: u32,
+        offset: u32,
+        compression: u8,
+        data: &[u8],
+        dsize: u32,
+        obsolete: bool,
+    ) {
+        let mut node = self.header(0xe002, 68 + data.len());
+        for value in [ino, version, mode] {
+            node.extend(self.u32(value));
+        }
+        node.extend(self.u16(1000));
+        node.extend(self.u16(100));
+        for value in [isize, 1_600_000_000, 1_600_000_000, 1_600_000_000, offset] {
+            node.extend(self.u32(value));
+        }
+        node.extend(self.u32(data.len() as u32));
+        node.extend(self.u32(dsize));
+        node.extend([compression, 0, 0, 0]);
+        let crc = crc32_le(0, &node);
+        node.extend(self.u32(crc32_le(0, data)));
+        node.extend(self.u32(crc));
+        node.extend(data);
+        self.push(node, obsolete);
+    }
+}
+
+fn zlib(data: &[u8]) -> Vec<u8> {
+    let mut encoder = flate2::write::ZlibEncoder::new(vec![], flate2::Compression::default());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// rtime with every repeat count at 0, the simplest valid encoding.
+fn rtime(data: &[u8]) -> Vec<u8> {
+    data.iter().flat_map(|byte| [*byte, 0]).collect()
+}
+
+fn lzo(data: &[u8]) -> Vec<u8> {
+    let mut context = rust_lzo::LZOContext::new();
+    let mut compressed = Vec::with_capacity(rust_lzo::worst_compress(data.len()));
+    assert!(context.compress(data, &mut compressed) == rust_lzo::LZOError::OK);
+    compressed
+}
+
+fn jffs2_image(big_endian: bool) -> Vec<u8> {
+    let mut fs = Jffs2Writer {
+        big_endian,
+        out: vec![],
+    };
+    let page: Vec<u8> = (0..4096u32).map(|i| (i % 241) as u8).collect();
+    let tail = b"tail of the file".to_vec();
+
+    // A cleanmarker at the start of the erase block.
+    let cleanmarker = fs.header(0x2003, 12);
+    fs.push(cleanmarker, false);
+    fs.dirent(1, 1, 2, "etc");
+    fs.inode(2, 1, 0o040755, 0, 0, 0, &[], 0, false);
+    fs.dirent(2, 2, 3, "data.bin");
+    let size = page.len() as u32 * 2 + tail.len() as u32;
+    fs.inode(3, 1, 0o100644, size, 0, 6, &zlib(&page), 4096, false);
+    fs.inode(3, 2, 0o100644, size, 4096, 7, &lzo(&page), 4096, false);
+    fs.inode(
+        3,
+        3,
+        0o100644,
+        size,
+        8192,
+        2,
+        &rtime(&tail),
+        tail.len() as u32,
+        false,
+    );
+    // An obsolete rewrite of the first page, it must not win.
+    fs.inode(3, 4, 0o100644, size, 0, 1, &[], 4096, true);
+    fs.dirent(1, 3, 4, "link");
+    fs.inode(4, 1, 0o120777, 12, 0, 0, b"etc/data.bin", 12, false);
+    fs.dirent(1, 4, 5, "console");
+    let dev = fs.u32((5 << 8) | 1);
+    fs.inode(5, 1, 0o020600, 4, 0, 0, &dev, 4, false);
+    fs.dirent(2, 5, 3, "hardlink.bin");
+    // A file that got deleted again.
+    fs.dirent(1, 6, 6, "removed");
+    fs.inode(6, 1, 0o100644, 3, 0, 0, b"old", 3, false);
+    fs.dirent(1, 7, 0, "removed");
+    // A file rewritten with a newer inode version, shorter than before.
+    fs.dirent(1, 8, 7, "motd");
+    fs.inode(7, 1, 0o100644, 11, 0, 0, b"hello world", 11, false);
+    fs.inode(7, 2, 0o100600, 5, 0, 0, b"HELLO", 5, false);
+    // Names no directory can hold, linked to that file.
+    fs.dirent(1, 9, 7, "..");
+    fs.dirent(1, 10, 7, "../escape");
+
+    // The rest of the erase block is erased flash.
+    fs.out.resize(0x10000, 0xff);
+    fs.out
+}
+
+#[test]
+fn jffs2_both_endiannesses() {
+    let page: Vec<u8> = (0..4096u32).map(|i| (i % 241) as u8).collect();
+    let mut expected = page.clone();
+    expected.extend(&page);
+    expected.extend(b"tail of the file");
+
+    for big_endian in [false, true] {
+        let image = jffs2_image(big_endian);
+        let mut archive = Jffs2Archive::create_with_reader(Cursor::new(image)).unwrap();
+        assert_eq!(archive.is_big_endian(), big_endian);
+
+        let entries: Vec<_> = archive.entries().unwrap().map(Result::unwrap).collect();
+        let paths: Vec<_> = entries.iter().map(|e| e.path_name().unwrap()).collect();
+        assert!(!paths.contains(&Path::new("removed").to_path_buf()));
+        assert!(paths.iter().all(|path| !path.starts_with("..")));
+        let find = |name: &str| {
+            entries
+                .iter()
+                .find(|e| e.path_name().unwrap() == Path::new(name))
+                .unwrap()
+        };
+        assert_eq!(find("etc").file_type(), FileType::Directory);
+        assert_eq!(find("etc/data.bin").size(), expected.len() as u64);
+        assert_eq!(find("etc/data.bin").uid().unwrap(), Some(1000));
+        assert_eq!(
+            find("link").sym_link(),
+            Some(Path::new("etc/data.bin").to_path_buf())
+        );
+        assert_eq!(find("console").file_type(), FileType::CharacterDevice);
+        assert_eq!(find("console").device(), Some((5, 1)));
+        assert_eq!(find("etc/hardlink.bin").file_type(), FileType::HardLink);
+        assert_eq!(find("motd").unix_mode(), 0o600);
+
+        let dir = tempfile::tempdir().unwrap();
+        archive.unpack_all(dir.path()).unwrap();
+        let out = dir.path();
+        assert_eq!(std::fs::read(out.join("etc/data.bin")).unwrap(), expected);
+        assert_eq!(
+            std::fs::read(out.join("etc/hardlink.bin")).unwrap(),
+            expected
+        );
+        assert_eq!(std::fs::read(out.join("link")).unwrap(), expected);
+        assert_eq!(std::fs::read(out.join("motd")).unwrap(), b"HELLO");
+        assert!(!out.parent().unwrap().join("escape").exists());
+    }
+}
+
+fn ubifs_node(node_type: u8, sqnum: u64, body: &[u8]) -> Vec<u8> {
+    let mut node = vec![];
+    node.extend(0x0610_1831u32.to_le_bytes());
+    node.extend([0; 4]);
+    node.extend(sqnum.to_le_bytes());
+    node.extend((24 + body.len() as u32).to_le_bytes());
+    node.extend([node_type, 0, 0, 0]);
+    node.extend(body);
+    let crc = crc32_le(!0, &node[8..]);
+    node[4..8].copy_from_slice(&crc.to_le_bytes());
+    node
+}
+
+/// A key in the simple format: inode number, then the key type and block or name hash.
+fn ubifs_key(inum: u32, key_type: u32, value: u32) -> Vec<u8> {
+    let mut key = inum.to_le_bytes().to_vec();
+    key.extend(((key_type << 29) | value).to_le_bytes());
+    key
+}
+
+fn ubifs_inode(inum: u32, mode: u32, size: u64, nlink: u32, data: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let key = ubifs_key(inum, 0, 0);
+    let mut body = key.clone();
+    body.extend([0; 16]);
+    body.extend(size.to_le_bytes());
+    for _ in 0..3 {
+        body.extend(1_600_000_000u64.to_le_bytes());
+    }
+    body.extend([0; 12]);
+    for value in [nlink, 1000, 100, mode, 0, data.len() as u32, 0, 0, 0, 0] {
+        body.extend(value.to_le_bytes());
+    }
+    body.extend([0; 28]);
+    body.extend(data);
+    (key, ubifs_node(0, u64::from(inum), &body))
+}
+
+fn ubifs_dent(parent: u32, hash: u32, inum: u32, dent_type: u8, name: &str) -> (Vec<u8>, Vec<u8>) {
+    let key = ubifs_key(parent, 2, hash);
+    let mut body = key.clone();
+    body.extend([0; 8]);
+    body.extend(u64::from(inum).to_le_bytes());
+    body.extend([0, dent_type]);
+    body.extend((name.len() as u16).to_le_bytes());
+    body.extend([0; 4]);
+    body.extend(name.as_bytes());
+    body.push(0);
+    (key, ubifs_node(2, 100 + u64::from(hash), &body))
+}
+
+fn ubifs_data(
+    inum: u32,
+    block: u32,
+    compression: u16,
+    size: usize,
+    data: &[u8],
+) -> (Vec<u8>, Vec<u8>) {
+    let key = ubifs_key(inum, 1, block);
+    let mut body = key.clone();
+    body.extend([0; 8]);
+    body.extend((size as u32).to_le_bytes());
+    body.extend(compression.to_le_bytes());
+    body.extend([0; 2]);
+    body.extend(data);
+    (key, ubifs_node(1, 200 + u64::from(block), &body))
+}
+
+fn ubifs_index(level: u16, branches: &[(u32, u32, u32, Vec<u8>)]) -> Vec<u8> {
+    let mut body = vec![];
+    body.extend((branches.len() as u16).to_le_bytes());
+    body.extend(level.to_le_bytes());
+    for (lnum, offs, len, key) in branches {
+        for value in [lnum, offs, len] {
+            body.extend(value.to_le_bytes());
+        }
+        body.extend(key);
+    }
+    ubifs_node(9, 1000 + u64::from(level), &body)
+}
+
+fn ubifs_master(sqnum: u64, root_lnum: u32, root_offs: u32) -> Vec<u8> {
+    let mut body = vec![0u8; 488];
+    body[24..28].copy_from_slice(&root_lnum.to_le_bytes());
+    body[28..32].copy_from_slice(&root_offs.to_le_bytes());
+    ubifs_node(7, sqnum, &body)
+}
+
+fn raw_deflate(data: &[u8]) -> Vec<u8> {
+    let mut encoder = flate2::write::DeflateEncoder::new(vec![], flate2::Compression::default());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// A UBIFS volume with a two level index, holding a file with one block per compressor and a
+/// hole, a symlink, a device and a hard link.
+fn ubifs_volume(leb_size: usize, page: &[u8], tail: &[u8]) -> Vec<u8> {
+    let mut leaves = vec![
+        ubifs_inode(1, 0o040755, 160, 3, &[]),
+        ubifs_dent(1, 10, 65, 1, "etc"),
+        ubifs_dent(1, 20, 67, 2, "link"),
+        ubifs_dent(1, 30, 68, 4, "console"),
+        ubifs_inode(65, 0o040755, 160, 2, &[]),
+        ubifs_dent(65, 10, 66, 0, "data.bin"),
+        ubifs_dent(65, 20, 66, 0, "hardlink.bin"),
+        // A name no directory can hold, linked to the same file.
+        ubifs_dent(65, 30, 66, 0, "../../escape"),
+    ];
+    let size = 3 * page.len() + tail.len();
+    leaves.push(ubifs_inode(66, 0o100640, size as u64, 2, &[]));
+    leaves.push(ubifs_data(66, 0, 2, page.len(), &raw_deflate(page)));
+    leaves.push(ubifs_data(66, 1, 1, page.len(), &lzo(page)));
+    // Block 2 is a hole.
+    let zstd = zstd::bulk::compress(tail, 3).unwrap();
+    leaves.push(ubifs_data(66, 3, 3, tail.len(), &zstd));
+    leaves.push(ubifs_inode(67, 0o120777, 12, 1, b"etc/data.bin"));
+    leaves.push(ubifs_inode(
+        68,
+        0o020600,
+        0,
+        1,
+        &((5 << 8) | 1u32).to_le_bytes(),
+    ));
+
+    // Leaves, the level 0 index nodes and the root all go to LEB 3.
+    let mut leb = vec![];
+    let place = |leb: &mut Vec<u8>, node: &[u8]| {
+        let offs = leb.len() as u32;
+        leb.extend(node);
+        leb.resize((leb.len() + 7) & !7, 0);
+        (offs, node.len() as u32)
+    };
+    let mut branches = vec![];
+    for (key, node) in &leaves {
+        let (offs, len) = place(&mut leb, node);
+        branches.push((3, offs, len, key.clone()));
+    }
+    let mut roots = vec![];
+    for chunk in branches.chunks(7) {
+        let node = ubifs_index(0, chunk);
+        let (offs, len) = place(&mut leb, &node);
+        roots.push((3, offs, len, chunk[0].3.clone()));
+    }
+    let (root_offs, _) = place(&mut leb, &ubifs_index(1, &roots));
+
+    let mut sb = vec![0u8; 104];
+    sb[12..16].copy_from_slice(&(leb_size as u32).to_le_bytes());
+    sb[16..20].copy_from_slice(&4u32.to_le_bytes());
+    sb[60..62].copy_from_slice(&2u16.to_le_bytes());
+
+    let mut volume = vec![0xffu8; leb_size * 4];
+    let sb = ubifs_node(6, 1, &sb);
+    volume[..sb.len()].copy_from_slice(&sb);
+    // An older master node pointing at garbage, followed by the current one.
+    let mut masters = ubifs_master(5, 3, 8);
+    masters.extend(ubifs_master(9, 3, root_offs));
+    for lnum in [1, 2] {
+        volume[leb_size * lnum..leb_size * lnum + masters.len()].copy_from_slice(&masters);
+    }
+    volume[leb_size * 3..leb_size * 3 + leb.len()].copy_from_slice(&leb);
+    volume
+}
+
+fn ubi_ec_header() -> Vec<u8> {
+    let mut header = b"UBI#".to_vec();
+    header.extend([1, 0, 0, 0]);
+    header.extend(7u64.to_be_bytes());
+    for value in [64u32, 128, 0x1234] {
+        header.extend(value.to_be_bytes());
+    }
+    header.extend([0; 32]);
+    header.extend(crc32_le(!0, &header).to_be_bytes());
+    header
+}
+
+fn ubi_vid_header(vol_type: u8, vol_id: u32, lnum: u32, data_size: u32, sqnum: u64) -> Vec<u8> {
+    let mut header = b"UBI!".to_vec();
+    header.extend([1, vol_type, 0, 0]);
+    for value in [vol_id, lnum, 0, data_size, 1, 0, 0, 0] {
+        header.extend(value.to_be_bytes());
+    }
+    header.extend(sqnum.to_be_bytes());
+    header.extend([0; 12]);
+    header.extend(crc32_le(!0, &header).to_be_bytes());
+    header
+}
+
+fn ubi_vtbl_record(reserved_pebs: u32, vol_type: u8, name: &str) -> Vec<u8> {
+    let mut record = vec![];
+    for value in [reserved_pebs, 1, 0] {
+        record.extend(value.to_be_bytes());
+    }
+    record.extend([vol_type, 0]);
+    record.extend((name.len() as u16).to_be_bytes());
+    let mut padded = name.as_bytes().to_vec();
+    padded.resize(128, 0);
+    record.extend(padded);
+    record.extend([0; 24]);
+    record.extend(crc32_le(!0, &record).to_be_bytes());
+    record
+}
+
+#[test]
+fn ubi_ubifs_to_file_tree() {
+    const PEB_SIZE: usize = 16 * 1024;
+    const LEB_SIZE: usize = PEB_SIZE - 128;
+    let page: Vec<u8> = (0..4096u32).map(|i| (i % 251) as u8).collect();
+    let tail = b"tail of the file".to_vec();
+    let volume = ubifs_volume(LEB_SIZE, &page, &tail);
+    let kernel: Vec<u8> = (0..1000u32).map(|i| (i * 7) as u8).collect();
+
+    let mut vtbl = ubi_vtbl_record(8, 1, "rootfs");
+    vtbl.extend(ubi_vtbl_record(1, 2, "kernel"));
+    vtbl.resize(LEB_SIZE, 0);
+
+    let mut pebs: Vec<Vec<u8>> = vec![];
+    let mut peb = |vid: Option<Vec<u8>>, data: &[u8]| {
+        let mut block = ubi_ec_header();
+        block.resize(PEB_SIZE, 0xff);
+        if let Some(vid) = vid {
+            block[64..128].copy_from_slice(&vid);
+            block[128..128 + data.len()].copy_from_slice(data);
+        }
+        pebs.push(block);
+    };
+    peb(Some(ubi_vid_header(1, 0x7fff_efff, 0, 0, 1)), &vtbl);
+    peb(Some(ubi_vid_header(1, 0x7fff_efff, 1, 0, 2)), &vtbl);
+    // The volume's blocks out of order, with a stale copy of LEB 3 and a free block in between.
+    for (lnum, sqnum) in [(3u32, 40u64), (0, 10), (2, 12), (1, 11)] {
+        let data = &volume[lnum as usize * LEB_SIZE..(lnum as usize + 1) * LEB_SIZE];
+        peb(Some(ubi_vid_header(1, 0, lnum, 0, sqnum)), data);
+        if lnum == 3 {
+            peb(None, &[]);
+        }
+    }
+    peb(Some(ubi_vid_header(1, 0, 3, 0, 20)), &vec![0x55; LEB_SIZE]);
+    peb(
+        Some(ubi_vid_header(2, 1, 0, kernel.len() as u32, 30)),
+        &kernel,
+    );
+    let mut image = vec![0u8; 512];
+    for block in pebs {
+        image.extend(block);
+    }
+    // An erased block at the end of the dump.
+    image.extend(vec![0xff; PEB_SIZE]);
+
+    let mut rdr = Cursor::new(image);
+    rdr.set_position(512);
+    let mut ubi = UbiArchive::create_with_reader(rdr).unwrap();
+    assert_eq!(ubi.peb_size(), PEB_SIZE as u64);
+    assert_eq!(ubi.image_sequence(), 0x1234);
+    let volumes: Vec<_> = ubi.entries().unwrap().map(Result::unwrap).collect();
+    assert_eq!(volumes.len(), 2);
+    assert_eq!(volumes[0].path_name().unwrap(), Path::new("rootfs"));
+    assert_eq!(volumes[0].volume_type(), UbiVolumeType::Dynamic);
+    assert_eq!(volumes[0].size(), volume.len() as u64);
+    assert_eq!(volumes[1].volume_type(), UbiVolumeType::Static);
+    assert_eq!(volumes[1].size(), kernel.len() as u64);
+
+    let dir = tempfile::tempdir().unwrap();
+    ubi.unpack_all(dir.path()).unwrap();
+    assert_eq!(std::fs::read(dir.path().join("rootfs")).unwrap(), volume);
+    assert_eq!(std::fs::read(dir.path().join("kernel")).unwrap(), kernel);
+
+    let mut ubifs = ubi.open_ubifs(&volumes[0]).unwrap();
+    assert_eq!(ubifs.leb_size(), LEB_SIZE as u64);
+    let entries: Vec<_> = ubifs.entries().unwrap().map(Result::unwrap).collect();
+    let find = |name: &str| {
+        entries
+            .iter()
+            .find(|e| e.path_name().unwrap() == Path::new(name))
+            .unwrap()
+    };
+    let mut expected = page.repeat(2);
+    expected.extend(vec![0; 4096]);
+    expected.extend(&tail);
+    assert_eq!(find("etc").file_type(), FileType::Directory);
+    assert_eq!(find("etc/data.bin").size(), expected.len() as u64);
+    assert_eq!(find("etc/data.bin").unix_mode(), 0o640);
+    assert_eq!(find("etc/data.bin").gid().unwrap(), Some(100));
+    assert_eq!(find("etc/hardlink.bin").file_type(), FileType::HardLink);
+    assert_eq!(
+        find("link").sym_link(),
+        Some(Path::new("etc/data.bin").to_path_buf())
+    );
+    assert_eq!(find("console").device(), Some((5, 1)));
+    assert!(entries
+        .iter()
+        .all(|e| !e.path_name().unwrap().to_string_lossy().contains("..")));
+
+    let dir = tempfile::tempdir().unwrap();
+    ubifs.unpack_all(dir.path()).unwrap();
+    let out = dir.path();
+    assert!(!out.parent().unwrap().join("escape").exists());
+    assert_eq!(std::fs::read(out.join("etc/data.bin")).unwrap(), expected);
+    assert_eq!(
+        std::fs::read(out.join("etc/hardlink.bin")).unwrap(),
+        expected
+    );
+    assert_eq!(std::fs::read(out.join("link")).unwrap(), expected);
+}
+
+/// Lays out a cramfs image the way mkcramfs does on a host of either byte order.
+struct CramfsWriter {
+    big_endian: bool,
+    out: Vec<u8>,
+}
+
+impl CramfsWriter {
+    fn u32(&self, value: u32) -> [u8; 4] {
+        match self.big_endian {
+            true => value.to_be_bytes(),
+            false => value.to_le_bytes(),
+        }
+    }
+
+    fn put_u32(&mut self, at: usize, value: u32) {
+        let bytes = self.u32(value);
+        self.out[at..at + 4].copy_from_slice(&bytes);
+    }
+
+    fn inode(&self, mode: u32, size: u32, name_words: u32, offset: usize) -> Vec<u8> {
+        let offset = offset as u32 / 4;
+        let words = match self.big_endian {
+            true => [
+                (mode << 16) | 1000,
+                (size << 8) | 100,
+                (name_words << 26) | offset,
+            ],
+            false => [
+                mode | (1000 << 16),
+                size | (100 << 24),
+                name_words | (offset << 6),
+            ],
+        };
+        words.iter().flat_map(|word| self.u32(*word)).collect()
+    }
+
+    fn dirent(&self, name: &str, mode: u32, size: u32, offset: usize) -> Vec<u8> {
+        let mut name = name.as_bytes().to_vec();
+        name.resize(name.len().div_ceil(4) * 4, 0);
+        let mut dirent = self.inode(mode, size, name.len() as u32 / 4, offset);
+        dirent.extend(name);
+        dirent
+    }
+
+    fn align(&mut self) -> usize {
+        self.out.resize(self.out.len().div_ceil(4) * 4, 0);
+        self.out.len()
+    }
+
+    /// A file stored the classic way, every block ending where the next pointer says.
+    fn file(&mut self, blocks: &[&[u8]]) -> usize {
+        let start = self.align();
+        self.out.resize(start + blocks.len() * 4, 0);
+        for (index, block) in blocks.iter().enumerate() {
+            // An empty block is a hole.
+            if !block.is_empty() {
+                self.out.extend(zlib(block));
+            }
+            let end = self.out.len() as u32;
+            self.put_u32(start + index * 4, end);
+        }
+        start
+    }
+}
+
+fn cramfs_image(big_endian: bool, page: &[u8], tail: &[u8]) -> Vec<u8> {
+    let mut fs = CramfsWriter {
+        big_endian,
+        out: vec![0; 76],
+    };
+
+    let names = ["big", "bin", "console", "link"];
+    let root_size: usize = names.iter().map(|n| 12 + n.len().div_ceil(4) * 4).sum();
+    fs.out.resize(76 + root_size, 0);
+    let bin = fs.out.len();
+    fs.out.resize(bin + 16, 0);
+
+    let hole = vec![0u8; 0];
+    let sh = fs.file(&[page, &hole, tail]);
+    let link = fs.file(&[b"bin/sh"]);
+
+    // Extended block pointers: a direct uncompressed block, a direct compressed one and a
+    // classic one that starts where the compressed one ends.
+    let big = fs.align();
+    fs.out.resize(big + 12, 0);
+    let first = fs.align();
+    fs.out.extend(page);
+    let pointer = (1 << 31) | (1 << 30) | (first as u32 >> 2);
+    fs.put_u32(big, pointer);
+    let second = fs.align();
+    let compressed = zlib(&page[..2048].repeat(2));
+    let len = compressed.len() as u16;
+    fs.out.extend(match big_endian {
+        true => len.to_be_bytes(),
+        false => len.to_le_bytes(),
+    });
+    fs.out.extend(compressed);
+    fs.put_u32(big + 4, (1 << 30) | (second as u32 >> 2));
+    fs.out.extend(zlib(tail));
+    let end = fs.out.len() as u32;
+    fs.put_u32(big + 8, end);
+    fs.align();
+
+    let mut root = vec![];
+    let big_size = 2 * page.len() + tail.len();
+    root.extend(fs.dirent("big", 0o100644, big_size as u32, big));
+    root.extend(fs.dirent("bin", 0o040755, 16, bin));
+    root.extend(fs.dirent("console", 0o020600, (5 << 8) | 1, 0));
+    root.extend(fs.dirent("link", 0o120777, 6, link));
+    fs.out[76..76 + root_size].copy_from_slice(&root);
+    let size = 2 * page.len() + tail.len();
+    let sh = fs.dirent("sh", 0o100755, size as u32, sh);
+    fs.out[bin..bin + 16].copy_from_slice(&sh);
+
+    let size = fs.out.len() as u32;
+    let mut superblock = fs.u32(0x28cd_3d45).to_vec();
+    // Version 2 with extended block pointers.
+    for value in [size, 0x801, 0] {
+        superblock.extend(fs.u32(value));
+    }
+    superblock.extend(b"Compressed ROMFS");
+    for value in [0, 7, 20, 6] {
+        superblock.extend(fs.u32(value));
+    }
+    superblock.extend(b"cramfs test\0\0\0\0\0");
+    superblock.extend(fs.inode(0o040755, root_size as u32, 0, 76));
+    fs.out[..76].copy_from_slice(&superblock);
+    let crc = crc32(&fs.out);
+    fs.put_u32(32, crc);
+    fs.out
+}
+
+#[test]
+fn cramfs_both_endiannesses() {
+    let page: Vec<u8> = (0..4096u32).map(|i| (i % 239) as u8).collect();
+    let tail = b"tail of the file".to_vec();
+    let mut sh = page.clone();
+    sh.extend(vec![0; 4096]);
+    sh.extend(&tail);
+    let mut big = page.clone();
+    big.extend(page[..2048].repeat(2));
+    big.extend(&tail);
+
+    for big_endian in [false, true] {
+        let image = cramfs_image(big_endian, &page, &tail);
+        let mut archive = CramfsArchive::create_with_reader(Cursor::new(image.clone())).unwrap();
+        assert_eq!(archive.is_big_endian(), big_endian);
+        assert_eq!(archive.name(), "cramfs test");
+        assert_eq!(archive.edition(), Some(7));
+
+        let entries: Vec<_> = archive.entries().unwrap().map(Result::unwrap).collect();
+        let paths: Vec<_> = entries.iter().map(|e| e.path_name().unwrap()).collect();
+        assert_eq!(
+            paths,
+            ["big", "bin", "console", "link", "bin/sh"].map(PathBuf::from)
+        );
+        assert_eq!(entries[1].file_type(), FileType::Directory);
+        assert_eq!(entries[2].file_type(), FileType::CharacterDevice);
+        assert_eq!(entries[2].device(), Some((5, 1)));
+        assert_eq!(entries[3].sym_link(), Some(PathBuf::from("bin/sh")));
+        assert_eq!(entries[4].unix_mode(), 0o755);
+        assert_eq!(entries[4].uid().unwrap(), Some(1000));
+        assert_eq!(entries[4].gid().unwrap(), Some(100));
+
+        let dir = tempfile::tempdir().unwrap();
+        archive.unpack_all(dir.path()).unwrap();
+        let out = dir.path();
+        assert_eq!(std::fs::read(out.join("bin/sh")).unwrap(), sh);
+        assert_eq!(std::fs::read(out.join("big")).unwrap(), big);
+        assert_eq!(std::fs::read(out.join("link")).unwrap(), sh);
+
+        // A flipped bit anywhere fails the CRC.
+        let mut corrupted = image;
+        corrupted[200] ^= 1;
+        assert!(CramfsArchive::create_with_reader(Cursor::new(corrupted)).is_err());
+    }
+}
+
+/// Lays out a romfs image the way genromfs does, with the next pointers patched in afterwards.
+struct RomfsWriter {
+    out: Vec<u8>,
+}
+
+impl RomfsWriter {
+    fn pad(&mut self) {
+        self.out.resize(self.out.len().div_ceil(16) * 16, 0);
+    }
+
+    fn node(&mut self, kind: u32, spec: u32, name: &str, data: &[u8]) -> usize {
+        let header = self.out.len();
+        for value in [kind, spec, data.len() as u32, 0] {
+            self.out.extend(value.to_be_bytes());
+        }
+        self.out.extend(name.as_bytes());
+        self.out.push(0);
+        self.pad();
+        self.out.extend(data);
+        self.pad();
+        header
+    }
+
+    fn set(&mut self, at: usize, value: u32) {
+        self.out[at..at + 4].copy_from_slice(&value.to_be_bytes());
+    }
+
+    fn chain(&mut self, headers: &[usize]) {
+        for pair in headers.windows(2) {
+            let kind = u32::from_be_bytes(self.out[pair[0]..pair[0] + 4].try_into().unwrap());
+            self.set(pair[0], kind | pair[1] as u32);
+        }
+    }
+}
+
+#[test]
+fn romfs_tree() {
+    let mut fs = RomfsWriter {
+        out: b"-rom1fs-".to_vec(),
+    };
+    fs.out.extend([0; 8]);
+    fs.out.extend(b"rom test\0");
+    fs.pad();
+
+    let root = fs.node(1, 0, ".", &[]);
+    let parent = fs.node(0, root as u32, "..", &[]);
+    let bin = fs.node(1, 0, "bin", &[]);
+    let console = fs.node(5, (5 << 16) | 1, "console", &[]);
+    let hello = fs.node(2 | 8, 0, "hello", b"#!/bin/sh\necho hello\n");
+    let link = fs.node(3, 0, "link", b"hello");
+    let again = fs.node(0, hello as u32, "again", &[]);
+    fs.chain(&[root, parent, bin, console, hello, link, again]);
+    fs.set(root + 4, root as u32);
+
+    let bin_dot = fs.node(1, 0, ".", &[]);
+    let bin_parent = fs.node(0, root as u32, "..", &[]);
+    let tool = fs.node(2, 0, "tool", b"tool data");
+    fs.chain(&[bin_dot, bin_parent, tool]);
+    fs.set(bin + 4, bin_dot as u32);
+    fs.set(bin_dot + 4, bin_dot as u32);
+
+    fs.out.resize(fs.out.len().div_ceil(1024) * 1024, 0);
+    let size = fs.out.len() as u32;
+    fs.set(8, size);
+    let sum = fs.out[..512].chunks_exact(4).fold(0u32, |sum, word| {
+        sum.wrapping_add(u32::from_be_bytes(word.try_into().unwrap()))
+    });
+    fs.set(12, 0u32.wrapping_sub(sum));
+
+    let mut archive = RomfsArchive::create_with_reader(Cursor::new(fs.out)).unwrap();
+    assert_eq!(archive.name(), "rom test");
+    let entries: Vec<_> = archive.entries().unwrap().map(Result::unwrap).collect();
+    let paths: Vec<_> = entries.iter().map(|e| e.path_name().unwrap()).collect();
+    assert_eq!(
+        paths,
+        ["bin", "console", "hello", "link", "bin/tool", "again"].map(PathBuf::from)
+    );
+    assert_eq!(entries[1].device(), Some((5, 1)));
+    assert_eq!(entries[2].unix_mode(), 0o755);
+    assert_eq!(entries[3].sym_link(), Some(PathBuf::from("hello")));
+    assert_eq!(entries[5].file_type(), FileType::HardLink);
+    assert_eq!(entries[5].hand_link(), Some(PathBuf::from("hello")));
+
+    let dir = tempfile::tempdir().unwrap();
+    archive.unpack_all(dir.path()).unwrap();
+    let out = dir.path();
+    assert_eq!(std::fs::read(out.join("bin/tool")).unwrap(), b"tool data");
+    assert_eq!(
+        std::fs::read(out.join("again")).unwrap(),
+        b"#!/bin/sh\necho hello\n"
+    );
+    assert_eq!(
+        std::fs::read_link(out.join("link")).unwrap(),
+        Path::new("hello")
+    );
+}
+
+fn squashfs_image(build: impl FnOnce(&mut backhand::FilesystemWriter)) -> Vec<u8> {
+    let mut fs = backhand::FilesystemWriter::default();
+    let compressor =
+        backhand::FilesystemCompressor::new(backhand::compression::Compressor::Gzip, None).unwrap();
+    fs.set_compressor(compressor);
+    build(&mut fs);
+    let mut image = Cursor::new(vec![]);
+    fs.write(&mut image).unwrap();
+    image.into_inner()
+}
+
+#[test]
+fn squashfs_devices() {
+    let image = squashfs_image(|fs| {
+        let header = backhand::NodeHeader::new(0o755, 0, 0, 1_600_000_000);
+        fs.push_dir("dev", header).unwrap();
+        let header = backhand::NodeHeader::new(0o620, 0, 5, 1_600_000_100);
+        fs.push_char_device((4 << 8) | 64, "dev/ttyS0", header)
+            .unwrap();
+        let header = backhand::NodeHeader::new(0o660, 1000, 6, 1_600_000_200);
+        // Minor 300 only fits the extended encoding.
+        let minor = 300u32;
+        let dev = (8 << 8) | (minor & 0xff) | ((minor & !0xff) << 12);
+        fs.push_block_device(dev, "dev/sda", header).unwrap();
+    });
+
+    let mut archive = SquashFSArchive::create_with_reader(Cursor::new(image)).unwrap();
+    let entries: Vec<_> = archive.entries().unwrap().map(Result::unwrap).collect();
+    let find = |name: &str| {
+        entries
+            .iter()
+            .find(|e| e.path_name().unwrap() == Path::new(name))
+            .unwrap()
+    };
+    let tty = find("dev/ttyS0");
+    assert_eq!(tty.file_type(), FileType::CharacterDevice);
+    assert_eq!(tty.device(), Some((4, 64)));
+    assert_eq!(tty.unix_mode(), 0o620);
+    assert_eq!(tty.gid().unwrap(), Some(5));
+    assert_eq!(
+        tty.mtime().unwrap().assume_utc().unix_timestamp(),
+        1_600_000_100
+    );
+    let sda = find("dev/sda");
+    assert_eq!(sda.file_type(), FileType::BlockDevice);
+    assert_eq!(sda.device(), Some((8, 300)));
+    assert_eq!(sda.uid().unwrap(), Some(1000));
+    assert_eq!(sda.gid().unwrap(), Some(6));
+
+    let dir = tempfile::tempdir().unwrap();
+    archive.unpack_all(dir.path()).unwrap();
+    let manifest: Vec<_> = archive
+        .device_manifest()
+        .iter()
+        .map(|d| d.to_string())
+        .collect();
+    if manifest.is_empty() {
+        // Privileged, the real nodes got created.
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{FileTypeExt, MetadataExt};
+            let tty = std::fs::metadata(dir.path().join("dev/ttyS0")).unwrap();
+            assert!(tty.file_type().is_char_device());
+            assert_eq!(tty.rdev(), libc::makedev(4, 64));
+            let sda = std::fs::metadata(dir.path().join("dev/sda")).unwrap();
+            assert!(sda.file_type().is_block_device());
+        }
+    } else {
+        assert!(dir.path().join("dev/ttyS0").is_file());
+        assert_eq!(
+            manifest,
+            [
+                "/dev/sda b 660 1000 6 8 300 - - -",
+                "/dev/ttyS0 c 620 0 5 4 64 - - -"
+            ]
+        );
+    }
+}
+
+/// SquashFS 4 image with uncompressed tables, for the inode types backhand cannot write.
+#[derive(Default)]
+struct SquashfsWriter {
+    data: Vec<u8>,
+    inodes: Vec<u8>,
+    dirs: Vec<u8>,
+    inode_count: u32,
+}
+
+impl SquashfsWriter {
+    const DATA_START: u32 = 96;
+
+    /// Appends an inode, returning its offset in the inode table.
+    fn inode(&mut self, kind: u16, mode: u16, ids: (u16, u16), number: u32, body: &[u8]) -> u16 {
+        let offset = self.inodes.len() as u16;
+        self.inodes.extend(kind.to_le_bytes());
+        self.inodes.extend(mode.to_le_bytes());
+        self.inodes.extend(ids.0.to_le_bytes());
+        self.inodes.extend(ids.1.to_le_bytes());
+        self.inodes.extend((1_600_000_000 + number).to_le_bytes());
+        self.inodes.extend(number.to_le_bytes());
+        self.inodes.extend(body);
+        self.inode_count += 1;
+        offset
+    }
+
+    /// Appends a listing of (name, inode offset, inode number, basic type), returning its
+    /// offset in the directory table and its size as the directory inode stores it.
+    fn listing(&mut self, entries: &[(&str, u16, u32, u16)]) -> (u16, u32) {
+        let offset = self.dirs.len() as u16;
+        if entries.is_empty() {
+            return (offset, 3);
+        }
+        self.dirs.extend((entries.len() as u32 - 1).to_le_bytes());
+        self.dirs.extend(0u32.to_le_bytes());
+        self.dirs.extend(entries[0].2.to_le_bytes());
+        for (name, inode, number, kind) in entries {
+            self.dirs.extend(inode.to_le_bytes());
+            self.dirs
+                .extend(((*number - entries[0].2) as i16).to_le_bytes());
+            self.dirs.extend(kind.to_le_bytes());
+            self.dirs.extend((name.len() as u16 - 1).to_le_bytes());
+            self.dirs.extend(name.as_bytes());
+        }
+        (offset, (self.dirs.len() - offset as usize + 3) as u32)
+    }
+
+    fn basic_dir(&mut self, number: u32, entries: &[(&str, u16, u32, u16)]) -> u16 {
+        let (offset, size) = self.listing(entries);
+        let mut body = vec![];
+        body.extend(0u32.to_le_bytes());
+        body.extend(2u32.to_le_bytes());
+        body.extend((size as u16).to_le_bytes());
+        body.extend(offset.to_le_bytes());
+        body.extend(0u32.to_le_bytes());
+        self.inode(1, 0o755, (0, 0), number, &body)
+    }
+
+    fn extended_dir(&mut self, number: u32, xattr: u32, entries: &[(&str, u16, u32, u16)]) -> u16 {
+        let (offset, size) = self.listing(entries);
+        let mut body = vec![];
+        body.extend(2u32.to_le_bytes());
+        body.extend(size.to_le_bytes());
+        body.extend(0u32.to_le_bytes());
+        body.extend(0u32.to_le_bytes());
+        body.extend(0u16.to_le_bytes());
+        body.extend(offset.to_le_bytes());
+        body.extend(xattr.to_le_bytes());
+        self.inode(8, 0o750, (0, 0), number, &body)
+    }
+
+    /// A file stored in one uncompressed block.
+    fn extended_file(&mut self, number: u32, links: u32, xattr: u32, content: &[u8]) -> u16 {
+        let mut body = vec![];
+        body.extend(u64::from(Self::DATA_START + self.data.len() as u32).to_le_bytes());
+        body.extend((content.len() as u64).to_le_bytes());
+        body.extend(0u64.to_le_bytes());
+        body.extend(links.to_le_bytes());
+        body.extend(u32::MAX.to_le_bytes());
+        body.extend(0u32.to_le_bytes());
+        body.extend(xattr.to_le_bytes());
+        body.extend((content.len() as u32 | 1 << 24).to_le_bytes());
+        self.data.extend(content);
+        self.inode(9, 0o4755, (1, 2), number, &body)
+    }
+
+    fn symlink(&mut self, number: u32, target: &str) -> u16 {
+        let mut body = vec![];
+        body.extend(1u32.to_le_bytes());
+        body.extend((target.len() as u32).to_le_bytes());
+        body.extend(target.as_bytes());
+        self.inode(3, 0o777, (0, 0), number, &body)
+    }
+
+    fn device(&mut self, kind: u16, number: u32, (major, minor): (u32, u32)) -> u16 {
+        let mut body = vec![];
+        body.extend(1u32.to_le_bytes());
+        body.extend(((major << 8) | minor).to_le_bytes());
+        self.inode(kind, 0o600, (0, 0), number, &body)
+    }
+
+    fn metadata_block(data: &[u8]) -> Vec<u8> {
+        let mut block = (data.len() as u16 | 0x8000).to_le_bytes().to_vec();
+        block.extend(data);
+        block
+    }
+
+    /// Lays out the tables behind the data, `xattrs` holding one list of
+    /// (type, name, value) per xattr index.
+    fn finish(self, root: u16, ids: &[u32], xattrs: &[&[(u16, &str, &[u8])]]) -> Vec<u8> {
+        let mut out = vec![0; Self::DATA_START as usize];
+        out.extend(&self.data);
+        let inode_table = out.len() as u64;
+        out.extend(Self::metadata_block(&self.inodes));
+        let dir_table = out.len() as u64;
+        out.extend(Self::metadata_block(&self.dirs));
+        let id_block = out.len() as u64;
+        let id_bytes: Vec<u8> = ids.iter().flat_map(|id| id.to_le_bytes()).collect();
+        out.extend(Self::metadata_block(&id_bytes));
+        let id_table = out.len() as u64;
+        out.extend(id_block.to_le_bytes());
+
+        let kv_start = out.len() as u64;
+        let mut kv = vec![];
+        let mut id_entries = vec![];
+        for list in xattrs {
+            let start = kv.len();
+            for (kind, name, value) in *list {
+                kv.extend(kind.to_le_bytes());
+                kv.extend((name.len() as u16).to_le_bytes());
+                kv.extend(name.as_bytes());
+                kv.extend((value.len() as u32).to_le_bytes());
+                kv.extend(*value);
+            }
+            id_entries.extend((start as u64).to_le_bytes());
+            id_entries.extend((list.len() as u32).to_le_bytes());
+            id_entries.extend(((kv.len() - start) as u32).to_le_bytes());
+        }
+        out.extend(Self::metadata_block(&kv));
+        let ids_block = out.len() as u64;
+        out.extend(Self::metadata_block(&id_entries));
+        let xattr_table = out.len() as u64;
+        out.extend(kv_start.to_le_bytes());
+        out.extend((xattrs.len() as u32).to_le_bytes());
+        out.extend(0u32.to_le_bytes());
+        out.extend(ids_block.to_le_bytes());
+
+        let bytes_used = out.len() as u64;
+        let mut sb = vec![];
+        sb.extend(b"hsqs");
+        sb.extend(self.inode_count.to_le_bytes());
+        sb.extend(1_600_000_000u32.to_le_bytes());
+        sb.extend(131_072u32.to_le_bytes());
+        sb.extend(0u32.to_le_bytes());
+        sb.extend(1u16.to_le_bytes());
+        sb.extend(17u16.to_le_bytes());
+        sb.extend(0u16.to_le_bytes());
+        sb.extend((ids.len() as u16).to_le_bytes());
+        sb.extend(4u16.to_le_bytes());
+        sb.extend(0u16.to_le_bytes());
+        sb.extend(u64::from(root).to_le_bytes());
+        sb.extend(bytes_used.to_le_bytes());
+        sb.extend(id_table.to_le_bytes());
+        sb.extend(xattr_table.to_le_bytes());
+        sb.extend(inode_table.to_le_bytes());
+        sb.extend(dir_table.to_le_bytes());
+        sb.extend(u64::MAX.to_le_bytes());
+        sb.extend(u64::MAX.to_le_bytes());
+        out[..sb.len()].copy_from_slice(&sb);
+        out
+    }
+}
+
+#[test]
+fn squashfs_all_node_types() {
+    // version 2 file capability with cap_net_raw permitted and effective
+    let capability: &[u8] = &[
+        0x01, 0x00, 0x00, 0x02, 0x00, 0x20, 0x00, 0x00, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+    let mut fs = SquashfsWriter::default();
+    let busybox = fs.extended_file(1, 2, 0, b"#!busybox\n");
+    let sh = fs.symlink(2, "busybox");
+    let bin = fs.basic_dir(3, &[("busybox", busybox, 1, 2), ("sh", sh, 2, 3)]);
+    let console = fs.device(5, 4, (5, 1));
+    let sda = fs.device(4, 5, (8, 0));
+    let dev = fs.basic_dir(6, &[("console", console, 4, 5), ("sda", sda, 5, 4)]);
+    let etc = fs.extended_dir(7, 1, &[]);
+    let sbin = fs.basic_dir(8, &[("busybox", busybox, 1, 2)]);
+    let root = fs.basic_dir(
+        9,
+        &[
+            ("bin", bin, 3, 1),
+            ("dev", dev, 6, 1),
+            ("etc", etc, 7, 1),
+            ("sbin", sbin, 8, 1),
+        ],
+    );
+    let image = fs.finish(
+        root,
+        &[0, 1000, 100],
+        &[
+            &[(2, "capability", capability), (0, "comment", b"hello")],
+            &[(0, "dir", b"yes")],
+        ],
+    );
+
+    let mut archive = SquashFSArchive::create_with_reader(Cursor::new(image)).unwrap();
+    let entries: Vec<_> = archive.entries().unwrap().map(Result::unwrap).collect();
+    let paths: Vec<_> = entries.iter().map(|e| e.path_name().unwrap()).collect();
+    assert_eq!(
+        paths,
+        [
+            "",
+            "bin",
+            "bin/busybox",
+            "bin/sh",
+            "dev",
+            "dev/console",
+            "dev/sda",
+            "etc",
+            "sbin",
+            "sbin/busybox"
+        ]
+        .map(PathBuf::from)
+    );
+    let busybox = &entries[2];
+    assert_eq!(busybox.file_type(), FileType::RegularFile);
+    assert_eq!(busybox.uid().unwrap(), Some(1000));
+    assert_eq!(busybox.gid().unwrap(), Some(100));
+    assert_eq!(
+        busybox.xattrs(),
+        [
+            ("security.capability".to_string(), capability.to_vec()),
+            ("user.comment".to_string(), b"hello".to_vec())
+        ]
+    );
+    assert_eq!(entries[3].sym_link(), Some(PathBuf::from("busybox")));
+    assert_eq!(
+        entries[7].xattrs(),
+        [("user.dir".to_string(), b"yes".to_vec())]
+    );
+    let link = &entries[9];
+    assert_eq!(link.file_type(), FileType::HardLink);
+    assert_eq!(link.hand_link(), Some(PathBuf::from("bin/busybox")));
+
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("root");
+    archive.unpack_all(&root).unwrap();
+    assert_eq!(
+        std::fs::read_link(root.join("bin/sh")).unwrap(),
+        Path::new("busybox")
+    );
+    assert_eq!(std::fs::read(root.join("bin/sh")).unwrap(), b"#!busybox\n");
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        let file = std::fs::metadata(root.join("bin/busybox")).unwrap();
+        let link = std::fs::metadata(root.join("sbin/busybox")).unwrap();
+        assert_eq!(file.ino(), link.ino());
+        assert_eq!(file.nlink(), 2);
+        assert_eq!(file.mtime(), 1_600_000_001);
+        assert_eq!(
+            std::fs::symlink_metadata(root.join("bin/sh"))
+                .unwrap()
+                .mtime(),
+            1_600_000_002
+        );
+        assert_eq!(
+            std::fs::metadata(root.join("bin")).unwrap().mtime(),
+            1_600_000_003
+        );
+        let etc = std::fs::metadata(root.join("etc")).unwrap();
+        assert_eq!(etc.mode() & 0o7777, 0o750);
+        assert_eq!(etc.mtime(), 1_600_000_007);
+
+        // Ownership and xattrs are best effort without privileges.
+        if file.uid() == 1000 {
+            assert_eq!(file.gid(), 100);
+            assert_eq!(file.mode() & 0o7777, 0o4755);
+        }
+        if let Some(comment) = get_xattr(&root.join("bin/busybox"), "user.comment") {
+            assert_eq!(comment, b"hello");
+            assert_eq!(get_xattr(&root.join("etc"), "user.dir").unwrap(), b"yes");
+        }
+        if file.uid() == 1000 {
+            if let Some(cap) = get_xattr(&root.join("bin/busybox"), "security.capability") {
+                assert_eq!(cap, capability);
+            }
+        }
+    }
+}
+
+#[test]
+fn squashfs_owned_entries_stream_contents() {
+    // two full blocks and a tail that lands in a fragment
+    let kernel: Vec<u8> = (0..300_000u32).map(|i| (i * 13 % 241) as u8).collect();
+    let image = squashfs_image(|fs| {
+        let header = backhand::NodeHeader::new(0o755, 0, 0, 1_600_000_000);
+        fs.push_dir("boot", header).unwrap();
+        fs.push_file(Cursor::new(kernel.clone()), "boot/vmlinux", header)
+            .unwrap();
+        fs.push_file(
+            Cursor::new(b"console=ttyS0".to_vec()),
+            "boot/cmdline",
+            header,
+        )
+        .unwrap();
+    });
+
+    let mut archive = SquashFSArchive::create_with_reader(Cursor::new(image)).unwrap();
+    let out = tempfile::tempdir().unwrap();
+    let mut inodes = vec![];
+    for entry in archive.entries().unwrap() {
+        let entry = entry.unwrap();
+        inodes.push(entry.inode());
+        if entry.file_type() == FileType::RegularFile {
+            let mut contents = vec![];
+            let mut reader = archive.read_entry(&entry).unwrap();
+            // small reads cross the block boundaries
+            let mut buf = [0; 4096];
+            loop {
+                let read = reader.read(&mut buf).unwrap();
+                if read == 0 {
+                    break;
+                }
+                contents.extend_from_slice(&buf[..read]);
+            }
+            assert_eq!(contents.len() as u64, entry.size());
+        } else {
+            assert!(archive.read_entry(&entry).is_err());
+        }
+        archive.unpack_file(&entry, out.path()).unwrap();
+    }
+    inodes.sort();
+    inodes.dedup();
+    assert_eq!(inodes.len(), 4);
+
+    let entries: Vec<_> = archive.entries().unwrap().map(Result::unwrap).collect();
+    let vmlinux = entries
+        .iter()
+        .find(|e| e.path_name().unwrap() == Path::new("boot/vmlinux"))
+        .unwrap();
+    let mut contents = vec![];
+    archive
+        .read_entry(vmlinux)
+        .unwrap()
+        .read_to_end(&mut contents)
+        .unwrap();
+    assert!(contents == kernel);
+    assert!(std::fs::read(out.path().join("boot/vmlinux")).unwrap() == kernel);
+    assert_eq!(
+        std::fs::read(out.path().join("boot/cmdline")).unwrap(),
+        b"console=ttyS0"
+    );
+}
+
+fn read_squashfs_tree(image: Vec<u8>) -> (tempfile::TempDir, SquashFSArchive) {
+    let mut archive = SquashFSArchive::create_with_reader(Cursor::new(image)).unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    archive.unpack_all(dir.path()).unwrap();
+    (dir, archive)
+}
+
+#[test]
+fn squashfs_writer_from_dir_tree() {
+    let source = tempfile::tempdir().unwrap();
+    let busybox: Vec<u8> = (0..300_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    std::fs::create_dir_all(source.path().join("bin")).unwrap();
+    std::fs::create_dir_all(source.path().join("tmp")).unwrap();
+    std::fs::write(source.path().join("bin/busybox"), &busybox).unwrap();
+    std::fs::write(source.path().join("passwd"), b"root:x:0:0::/root:/bin/sh\n").unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::os::unix::fs::symlink("busybox", source.path().join("bin/sh")).unwrap();
+        let perms = std::fs::Permissions::from_mode(0o1777);
+        std::fs::set_permissions(source.path().join("tmp"), perms).unwrap();
+    }
+
+    for compression in [
+        SquashFSCompression::Gzip,
+        SquashFSCompression::Xz,
+        SquashFSCompression::Lzo,
+        SquashFSCompression::Lz4,
+        SquashFSCompression::Zstd,
+    ] {
+        let options = SquashFSWriteOptions {
+            compression,
+            block_size: 64 * 1024,
+            ..Default::default()
+        };
+        let mut writer = SquashFSWriter::create_with_writer(Cursor::new(vec![]), options).unwrap();
+        writer.push_dir_tree(source.path()).unwrap();
+        let image = writer.finish().unwrap().into_inner();
+
+        let (dir, _) = read_squashfs_tree(image);
+        let root = dir.path();
+        assert_eq!(std::fs::read(root.join("bin/busybox")).unwrap(), busybox);
+        assert_eq!(
+            std::fs::read(root.join("passwd")).unwrap(),
+            b"root:x:0:0::/root:/bin/sh\n"
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            assert_eq!(
+                std::fs::read_link(root.join("bin/sh")).unwrap(),
+                Path::new("busybox")
+            );
+            let tmp = std::fs::metadata(root.join("tmp")).unwrap();
+            assert_eq!(tmp.mode() & 0o7777, 0o1777, "{:?}", compression);
+        }
+    }
+
+    let build = || {
+        let options = SquashFSWriteOptions::reproducible();
+        let mut writer = SquashFSWriter::create_with_writer(Cursor::new(vec![]), options).unwrap();
+        writer.push_dir_tree(source.path()).unwrap();
+        writer.finish().unwrap().into_inner()
+    };
+    let image = build();
+    let touched = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_600_000_000);
+    let passwd = std::fs::File::options()
+        .write(true)
+        .open(source.path().join("passwd"))
+        .unwrap();
+    passwd.set_modified(touched).unwrap();
+    assert_eq!(image, build());
+}
+
+#[test]
+fn squashfs_writer_reproducible_from_entries() {
+    let image = squashfs_image(|fs| {
+        let header = backhand::NodeHeader::new(0o755, 0, 0, 1_600_000_000);
+        fs.push_dir("etc", header).unwrap();
+        fs.push_file(Cursor::new(b"ttyS0\n".to_vec()), "etc/securetty", header)
+            .unwrap();
+        fs.push_symlink("/proc/mounts", "etc/mtab", header).unwrap();
+    });
+    let mut source = SquashFSArchive::create_with_reader(Cursor::new(image)).unwrap();
+    let entries: Vec<_> = source.entries().unwrap().map(Result::unwrap).collect();
+
+    let repack = |mtime| {
+        let options = SquashFSWriteOptions {
+            uid: Some(1000),
+            gid: Some(100),
+            mtime,
+            ..Default::default()
+        };
+        let mut writer = SquashFSWriter::create_with_writer(Cursor::new(vec![]), options).unwrap();
+        for entry in &entries {
+            let contents: &[u8] = match entry.file_type() {
+                FileType::RegularFile => b"ttyS0\nttyS1\n",
+                _ => b"",
+            };
+            let mtime = entry.mtime().unwrap().assume_utc().unix_timestamp();
+            writer
+                .push_entry(entry, entry.unix_mode(), mtime as u32, contents)
+                .unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    };
+
+    let kept = repack(None);
+    let mut archive = SquashFSArchive::create_with_reader(Cursor::new(kept)).unwrap();
+    for entry in archive.entries().unwrap().map(Result::unwrap) {
+        if entry.path_name().unwrap() != Path::new("") {
+            let mtime = entry.mtime().unwrap().assume_utc().unix_timestamp();
+            assert_eq!(mtime, 1_600_000_000);
+        }
+    }
+
+    let image = repack(Some(1_700_000_000));
+    assert_eq!(image, repack(Some(1_700_000_000)));
+
+    let mut archive = SquashFSArchive::create_with_reader(Cursor::new(image.clone())).unwrap();
+    for entry in archive.entries().unwrap().map(Result::unwrap) {
+        assert_eq!(entry.uid().unwrap(), Some(1000));
+        assert_eq!(entry.gid().unwrap(), Some(100));
+        if entry.path_name().unwrap() == Path::new("") {
+            continue;
+        }
+        assert_eq!(
+            entry.mtime().unwrap().assume_utc().unix_timestamp(),
+            1_700_000_000
+        );
+    }
+    let (dir, _) = read_squashfs_tree(image);
+    assert_eq!(
+        std::fs::read(dir.path().join("etc/securetty")).unwrap(),
+        b"ttyS0\nttyS1\n"
+    );
+    assert_eq!(
+        std::fs::read_link(dir.path().join("etc/mtab")).unwrap(),
+        Path::new("/proc/mounts")
+    );
+}
+
+#[test]
+fn squashfs_vendor_magic_at_offset() {
+    let mut image = squashfs_image(|fs| {
+        fs.set_kind(backhand::kind::Kind::from_target("be_v4_0").unwrap());
+        let header = backhand::NodeHeader::new(0o755, 0, 0, 1_600_000_000);
+        fs.push_dir("etc", header).unwrap();
+        let header = backhand::NodeHeader::new(0o644, 0, 0, 1_600_000_000);
+        fs.push_file(Cursor::new(b"tplink\n".to_vec()), "etc/hostname", header)
+            .unwrap();
+    });
+    // DD-WRT's big endian magic
+    image[..4].copy_from_slice(b"tqsh");
+
+    // 3.x superblocks from older firmware, which can be found but not read
+    let legacy = |magic: &[u8; 4], minor: u16, be: bool| {
+        let u16_bytes = |value: u16| match be {
+            true => value.to_be_bytes(),
+            false => value.to_le_bytes(),
+        };
+        let mut legacy = [0u8; 96];
+        legacy[..4].copy_from_slice(magic);
+        legacy[28..30].copy_from_slice(&u16_bytes(3));
+        legacy[30..32].copy_from_slice(&u16_bytes(minor));
+        legacy[34..36].copy_from_slice(&u16_bytes(16));
+        let (block_size, bytes_used) = match be {
+            true => ((64u32 << 10).to_be_bytes(), 96u64.to_be_bytes()),
+            false => ((64u32 << 10).to_le_bytes(), 96u64.to_le_bytes()),
+        };
+        legacy[51..55].copy_from_slice(&block_size);
+        legacy[63..71].copy_from_slice(&bytes_used);
+        legacy
+    };
+
+    // a bootloader with a stray magic, two 3.x images and the root filesystem
+    let mut blob = b"U-Boot hsqs".to_vec();
+    blob.resize(0x1000, 0xff);
+    blob.extend_from_slice(&legacy(b"sqsh", 1, true));
+    blob.resize(0x2000, 0xff);
+    blob.extend_from_slice(&legacy(b"hsqs", 0, false));
+    blob.resize(0x10003, 0xff);
+    blob.extend_from_slice(&image);
+
+    let found = SquashFSArchive::find(&mut Cursor::new(&blob)).unwrap();
+    assert_eq!(found.len(), 3);
+    assert_eq!(found[0].offset(), 0x1000);
+    assert!(found[0].is_big_endian());
+    assert_eq!(found[0].version(), (3, 1));
+    assert_eq!(found[0].compressor(), None);
+    assert_eq!(found[0].bytes_used(), 96);
+    assert_eq!(found[1].offset(), 0x2000);
+    assert!(!found[1].is_big_endian());
+    assert_eq!(found[1].version(), (3, 0));
+    assert_eq!(found[1].bytes_used(), 96);
+    assert_eq!(found[2].offset(), 0x10003);
+    assert_eq!(found[2].magic(), b"tqsh");
+    assert!(found[2].is_big_endian());
+    assert_eq!(found[2].version(), (4, 0));
+    assert_eq!(found[2].compressor(), Some(1));
+    let bytes_used = u64::from_be_bytes(image[40..48].try_into().unwrap());
+    assert_eq!(found[2].bytes_used(), bytes_used);
+
+    for offset in [0x1000, 0x2000] {
+        let legacy =
+            SquashFSArchive::create_with_reader_at_offset(Cursor::new(blob.clone()), offset);
+        let error = legacy.err().unwrap().to_string();
+        assert!(error.contains("unsupported 3.x layout"), "{}", error);
+    }
+
+    let mut archive =
+        SquashFSArchive::create_with_reader_at_offset(Cursor::new(blob), 0x10003).unwrap();
+    let out = tempfile::tempdir().unwrap();
+    archive.unpack_all(out.path()).unwrap();
+    assert_eq!(
+        std::fs::read(out.path().join("etc/hostname")).unwrap(),
+        b"tplink\n"
+    );
+}
+
+/// LZMA the way squashfs patches for 2.6 kernels wrote it, optionally without the unpacked
+/// size in the header.
+struct VendorLzma {
+    short_header: bool,
+}
+
+impl backhand::compression::CompressionAction for VendorLzma {
+    fn decompress(
+        &self,
+        _: &[u8],
+        _: &mut Vec<u8>,
+        _: backhand::compression::Compressor,
+    ) -> Result<(), backhand::BackhandError> {
+        unreachable!("only used to write images")
+    }
+
+    fn compress(
+        &self,
+        bytes: &[u8],
+        _: backhand::FilesystemCompressor,
+        _: u32,
+    ) -> Result<Vec<u8>, backhand::BackhandError> {
+        let mut out = vec![];
+        lzma_rs::lzma_compress(&mut &bytes[..], &mut out)?;
+        if self.short_header {
+            out.drain(5..13);
+        }
+        Ok(out)
+    }
+}
+
+#[test]
+fn squashfs_vendor_lzma() {
+    static LZMA: VendorLzma = VendorLzma {
+        short_header: false,
+    };
+    static GZIP_ID: VendorLzma = VendorLzma { short_header: true };
+
+    let contents: Vec<u8> = b"busybox ".repeat(2000);
+    let cases = [
+        (&LZMA, backhand::compression::Compressor::Lzma),
+        (&GZIP_ID, backhand::compression::Compressor::Gzip),
+    ];
+    for (codec, compressor) in cases {
+        let mut fs = backhand::FilesystemWriter::default();
+        fs.set_kind(backhand::kind::Kind::new(codec));
+        fs.set_compressor(backhand::FilesystemCompressor::new(compressor, None).unwrap());
+        let header = backhand::NodeHeader::new(0o755, 0, 0, 1_600_000_000);
+        fs.push_dir("bin", header).unwrap();
+        let header = backhand::NodeHeader::new(0o755, 0, 0, 1_600_000_000);
+        fs.push_file(Cursor::new(contents.clone()), "bin/busybox", header)
+            .unwrap();
+        let mut image = Cursor::new(vec![]);
+        fs.write(&mut image).unwrap();
+
+        let mut archive = SquashFSArchive::create_with_reader(image).unwrap();
+        let out = tempfile::tempdir().unwrap();
+        archive.unpack_all(out.path()).unwrap();
+        assert_eq!(
+            std::fs::read(out.path().join("bin/busybox")).unwrap(),
+            contents
+        );
+    }
+}
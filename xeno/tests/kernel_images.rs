@@ -0,0 +1,281 @@
+use std::io::{Cursor, Write};
+
+use sha2::{Digest, Sha256};
+use xeno_rs::archive::fit::FitArchive;
+use xeno_rs::archive::uimage::UImageArchive;
+use xeno_rs::archive::zimage::ZImageArchive;
+use xeno_rs::block::BlockFormat;
+use xeno_rs::utils::error::ArchiveError;
+
+mod common;
+
+use common::crc32;
+
+fn kernel() -> Vec<u8> {
+    (0..200_000u32)
+        .flat_map(|i| (i % 251).to_le_bytes())
+        .collect()
+}
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn uimage(image_type: u8, compression: u8, data: &[u8]) -> Vec<u8> {
+    let mut header = vec![];
+    header.extend(0x2705_1956_u32.to_be_bytes());
+    header.extend([0u8; 4]);
+    header.extend(1_600_000_000_u32.to_be_bytes());
+    header.extend((data.len() as u32).to_be_bytes());
+    header.extend(0x8000_8000_u32.to_be_bytes());
+    header.extend(0x8000_8040_u32.to_be_bytes());
+    header.extend(crc32(data).to_be_bytes());
+    header.extend([5, 2, image_type, compression]);
+    let mut name = b"Linux-test".to_vec();
+    name.resize(32, 0);
+    header.extend(name);
+    let crc = crc32(&header);
+    header[4..8].copy_from_slice(&crc.to_be_bytes());
+    header.extend(data);
+    header
+}
+
+fn lzop(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x89, b'L', b'Z', b'O', 0x00, 0x0d, 0x0a, 0x1a, 0x0a];
+    // Version, library version, version needed, method, level.
+    out.extend([0x10, 0x30, 0x20, 0x60, 0x09, 0x40, 1, 5]);
+    out.extend(0u32.to_be_bytes()); // flags
+    out.extend(0o100644u32.to_be_bytes());
+    out.extend([0u8; 8]); // mtime
+    out.push(0);
+    out.extend(0u32.to_be_bytes()); // header checksum
+    let mut context = rust_lzo::LZOContext::new();
+    for chunk in data.chunks(256 * 1024) {
+        let mut compressed = Vec::with_capacity(rust_lzo::worst_compress(chunk.len()));
+        assert!(context.compress(chunk, &mut compressed) == rust_lzo::LZOError::OK);
+        out.extend((chunk.len() as u32).to_be_bytes());
+        out.extend((compressed.len() as u32).to_be_bytes());
+        out.extend(compressed);
+    }
+    out.extend(0u32.to_be_bytes());
+    out
+}
+
+#[test]
+fn uimage_kernel() {
+    let kernel = kernel();
+    let image = uimage(2, 1, &gzip(&kernel));
+    let mut archive = UImageArchive::create_with_reader(Cursor::new(&image)).unwrap();
+    assert_eq!(archive.header().name(), "Linux-test");
+    assert_eq!(archive.header().load_address(), 0x8000_8000);
+    assert_eq!(
+        archive.header().compression().unwrap(),
+        Some(BlockFormat::Gzip)
+    );
+
+    let dir = tempfile::tempdir().unwrap();
+    archive.unpack_all(dir.path()).unwrap();
+    assert_eq!(std::fs::read(dir.path().join("kernel")).unwrap(), kernel);
+
+    let mut corrupted = image.clone();
+    *corrupted.last_mut().unwrap() ^= 1;
+    assert!(matches!(
+        UImageArchive::create_with_reader(Cursor::new(&corrupted)),
+        Err(ArchiveError::ChecksumMismatch(_))
+    ));
+}
+
+#[test]
+fn uimage_multi() {
+    let kernel = kernel();
+    let compressed = lzop(&kernel);
+    let ramdisk = b"initramfs".to_vec();
+    let mut data = vec![];
+    data.extend((compressed.len() as u32).to_be_bytes());
+    data.extend((ramdisk.len() as u32).to_be_bytes());
+    data.extend(0u32.to_be_bytes());
+    data.extend(&compressed);
+    data.resize((data.len() + 3) & !3, 0);
+    data.extend(&ramdisk);
+
+    let mut archive = UImageArchive::create_with_reader(Cursor::new(uimage(4, 4, &data))).unwrap();
+    let entries: Vec<_> = archive.entries().unwrap().map(Result::unwrap).collect();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].compression(), Some(BlockFormat::Lzop));
+    assert_eq!(entries[1].compression(), None);
+
+    let dir = tempfile::tempdir().unwrap();
+    archive.unpack_all(dir.path()).unwrap();
+    assert_eq!(std::fs::read(dir.path().join("image-0")).unwrap(), kernel);
+    assert_eq!(std::fs::read(dir.path().join("image-1")).unwrap(), ramdisk);
+}
+
+#[test]
+fn zimage_gzip_payload() {
+    let kernel = kernel();
+    let mut image = vec![0u8; 0x24];
+    image.extend(0x016f_2818_u32.to_le_bytes());
+    image.extend(0u32.to_le_bytes());
+    image.extend(0x0040_0000_u32.to_le_bytes());
+    // Some decompressor code, with a stray gzip magic that does not decode.
+    image.extend([0x1f, 0x8b, 0x08, 0xff, 0x00, 0x13, 0x37]);
+    image.resize(0x400, 0xe1);
+    image.extend(gzip(&kernel));
+    image.extend((kernel.len() as u32).to_le_bytes());
+    image.extend([0u8; 64]);
+
+    let mut archive = ZImageArchive::create_with_reader(Cursor::new(&image)).unwrap();
+    assert!(!archive.is_big_endian());
+    assert_eq!(archive.end(), 0x0040_0000);
+    let entry = archive.entries().unwrap().next().unwrap().unwrap();
+    assert_eq!(entry.offset(), 0x400);
+    assert_eq!(entry.compression(), BlockFormat::Gzip);
+
+    let dir = tempfile::tempdir().unwrap();
+    archive.unpack_all(dir.path()).unwrap();
+    assert_eq!(std::fs::read(dir.path().join("Image")).unwrap(), kernel);
+}
+
+#[test]
+fn zimage_lzo_payload() {
+    // CONFIG_KERNEL_LZO wraps the kernel with `lzop -9`, the lzop container and not raw LZO1X
+    let kernel = kernel();
+    let mut image = vec![0u8; 0x24];
+    image.extend(0x016f_2818_u32.to_le_bytes());
+    image.extend(0u32.to_le_bytes());
+    image.extend(0x0040_0000_u32.to_le_bytes());
+    image.resize(0x800, 0xe1);
+    image.extend(lzop(&kernel));
+    image.extend((kernel.len() as u32).to_le_bytes());
+
+    let mut archive = ZImageArchive::create_with_reader(Cursor::new(&image)).unwrap();
+    let entry = archive.entries().unwrap().next().unwrap().unwrap();
+    assert_eq!(entry.offset(), 0x800);
+    assert_eq!(entry.compression(), BlockFormat::Lzop);
+
+    let dir = tempfile::tempdir().unwrap();
+    archive.unpack_all(dir.path()).unwrap();
+    assert_eq!(std::fs::read(dir.path().join("Image")).unwrap(), kernel);
+}
+
+/// Minimal device tree writer, enough to lay out a FIT image.
+#[derive(Default)]
+struct FdtWriter {
+    structs: Vec<u8>,
+    strings: Vec<u8>,
+}
+
+impl FdtWriter {
+    fn begin(&mut self, name: &str) {
+        self.structs.extend(1u32.to_be_bytes());
+        self.structs.extend(name.as_bytes());
+        self.structs.push(0);
+        self.align();
+    }
+
+    fn end(&mut self) {
+        self.structs.extend(2u32.to_be_bytes());
+    }
+
+    fn prop(&mut self, name: &str, value: &[u8]) {
+        let offset = self.strings.len() as u32;
+        self.strings.extend(name.as_bytes());
+        self.strings.push(0);
+        self.structs.extend(3u32.to_be_bytes());
+        self.structs.extend((value.len() as u32).to_be_bytes());
+        self.structs.extend(offset.to_be_bytes());
+        self.structs.extend(value);
+        self.align();
+    }
+
+    fn string(&mut self, name: &str, value: &str) {
+        self.prop(name, format!("{}\0", value).as_bytes());
+    }
+
+    fn align(&mut self) {
+        self.structs.resize((self.structs.len() + 3) & !3, 0);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.structs.extend(9u32.to_be_bytes());
+        let structs_offset = 40 + 16;
+        let strings_offset = structs_offset + self.structs.len();
+        let total = strings_offset + self.strings.len();
+        let mut blob = vec![];
+        for value in [
+            0xd00d_feed,
+            total as u32,
+            structs_offset as u32,
+            strings_offset as u32,
+            40,
+            17,
+            16,
+            0,
+            self.strings.len() as u32,
+            self.structs.len() as u32,
+        ] {
+            blob.extend(u32::to_be_bytes(value));
+        }
+        blob.extend([0u8; 16]);
+        blob.extend(self.structs);
+        blob.extend(self.strings);
+        blob
+    }
+}
+
+#[test]
+fn fit_images() {
+    let kernel = kernel();
+    let compressed = gzip(&kernel);
+    let fdt_blob = b"\xd0\x0d\xfe\xedfake".to_vec();
+
+    let mut fdt = FdtWriter::default();
+    fdt.begin("");
+    fdt.string("description", "test FIT");
+    fdt.begin("images");
+    fdt.begin("kernel-1");
+    fdt.string("type", "kernel");
+    fdt.string("compression", "gzip");
+    fdt.prop("data", &compressed);
+    fdt.prop("load", &0x8000_8000_u32.to_be_bytes());
+    fdt.prop("entry", &0x8000_8000_u32.to_be_bytes());
+    fdt.begin("hash-1");
+    fdt.string("algo", "sha256");
+    fdt.prop("value", &Sha256::digest(&compressed));
+    fdt.end();
+    fdt.begin("hash-2");
+    fdt.string("algo", "crc32");
+    fdt.prop("value", &crc32(&compressed).to_be_bytes());
+    fdt.end();
+    fdt.end();
+    fdt.begin("fdt-1");
+    fdt.string("type", "flat_dt");
+    fdt.prop("data-offset", &0u32.to_be_bytes());
+    fdt.prop("data-size", &(fdt_blob.len() as u32).to_be_bytes());
+    fdt.end();
+    fdt.end();
+    fdt.begin("configurations");
+    fdt.string("default", "conf-1");
+    fdt.end();
+    fdt.end();
+    let mut image = fdt.finish();
+    image.resize((image.len() + 3) & !3, 0);
+    image.extend(&fdt_blob);
+
+    let mut archive = FitArchive::create_with_reader(Cursor::new(&image)).unwrap();
+    assert_eq!(archive.description(), Some("test FIT"));
+    assert_eq!(archive.default_configuration(), Some("conf-1"));
+    let entries: Vec<_> = archive.entries().unwrap().map(Result::unwrap).collect();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].image_type(), Some("kernel"));
+    assert_eq!(entries[0].load_address(), Some(0x8000_8000));
+    assert_eq!(entries[0].hashes().len(), 2);
+    archive.verify(&entries[0]).unwrap();
+
+    let dir = tempfile::tempdir().unwrap();
+    archive.unpack_all(dir.path()).unwrap();
+    assert_eq!(std::fs::read(dir.path().join("kernel-1")).unwrap(), kernel);
+    assert_eq!(std::fs::read(dir.path().join("fdt-1")).unwrap(), fdt_blob);
+}
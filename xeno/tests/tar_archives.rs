@@ -0,0 +1,809 @@
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+
+use xeno_rs::archive::tar::{
+    CompressedTar, IndexedTar, TarArchive, TarBrotliArchive, TarDetectArchive, TarFormat,
+    TarGzArchive, TarGzWriter, TarIndex, TarLz4Archive, TarLzmaArchive, TarSnappyArchive,
+    TarWriteOptions, TarWriter, TarXzArchive,
+};
+use xeno_rs::archive::{Entry, ExtractOption, FileType};
+use xeno_rs::block::gzip::GzipEncoder;
+use xeno_rs::block::{create_encoder, BlockFormat};
+
+mod common;
+
+use common::{get_xattr, pad};
+
+/// An old GNU sparse file with six regions, two of them in an extension block.
+fn gnu_sparse(out: &mut Vec<u8>, regions: &[(u64, u64)], real_size: u64) {
+    let mut header = tar::Header::new_gnu();
+    header.set_path("var/log/lastlog").unwrap();
+    header.set_entry_type(tar::EntryType::GNUSparse);
+    header.set_mode(0o644);
+    header.set_mtime(1_700_000_000);
+    let stored: u64 = regions.iter().map(|(_, length)| length).sum();
+    header.set_size(stored);
+    let gnu = header.as_gnu_mut().unwrap();
+    for (block, (offset, length)) in gnu.sparse.iter_mut().zip(regions) {
+        block.set_offset(*offset);
+        block.set_length(*length);
+    }
+    gnu.set_real_size(real_size);
+    gnu.set_is_extended(true);
+    header.set_cksum();
+    out.extend_from_slice(header.as_bytes());
+
+    let mut ext = tar::GnuExtSparseHeader::new();
+    for (block, (offset, length)) in ext.sparse_mut().iter_mut().zip(&regions[4..]) {
+        block.set_offset(*offset);
+        block.set_length(*length);
+    }
+    out.extend_from_slice(ext.as_bytes());
+
+    for (index, (_, length)) in regions.iter().enumerate() {
+        out.extend(std::iter::repeat_n(b'a' + index as u8, *length as usize));
+    }
+    pad(out, 512);
+}
+
+/// Reads at most 7 bytes at a time, so blocks arrive split across reads.
+struct Trickle(Cursor<Vec<u8>>);
+
+impl Read for Trickle {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(7);
+        self.0.read(&mut buf[..len])
+    }
+}
+
+fn sparse_contents(regions: &[(u64, u64)], real_size: u64) -> Vec<u8> {
+    let mut contents = vec![0; real_size as usize];
+    for (index, (offset, length)) in regions.iter().enumerate() {
+        let range = *offset as usize..(offset + length) as usize;
+        contents[range].fill(b'a' + index as u8);
+    }
+    contents
+}
+
+#[test]
+fn tar_pax_metadata_and_sparse_maps() {
+    let mut builder = tar::Builder::new(vec![]);
+    let global = b"27 comment=firmware rootfs\n";
+    let mut header = tar::Header::new_ustar();
+    header.set_entry_type(tar::EntryType::XGlobalHeader);
+    header.set_size(global.len() as u64);
+    builder
+        .append_data(&mut header, "pax_global_header", &global[..])
+        .unwrap();
+
+    let mut header = tar::Header::new_ustar();
+    header.set_entry_type(tar::EntryType::Directory);
+    header.set_mode(0o750);
+    header.set_mtime(1_600_000_000);
+    header.set_size(0);
+    builder
+        .append_data(&mut header, "etc", std::io::empty())
+        .unwrap();
+
+    builder
+        .append_pax_extensions([
+            ("mtime", &b"1700000000.123456789"[..]),
+            ("atime", b"1700000100.5"),
+            ("uname", b"builder"),
+            ("SCHILY.xattr.user.comment", b"from star"),
+            // bsdtar: percent-encoded name, base64 value without padding
+            ("LIBARCHIVE.xattr.user.with%20space", b"YmFzZTY0"),
+            (
+                "SCHILY.acl.access",
+                b"user::rwx,user:joe:r--:1000,group::r-x,mask::r-x,other::r-x",
+            ),
+        ])
+        .unwrap();
+    let mut header = tar::Header::new_ustar();
+    header.set_mode(0o4755);
+    header.set_uid(1000);
+    header.set_gid(100);
+    header.set_username("ignored").unwrap();
+    header.set_groupname("users").unwrap();
+    header.set_mtime(1);
+    header.set_size(8);
+    builder
+        .append_data(&mut header, "etc/passwd", &b"root:x:0"[..])
+        .unwrap();
+
+    let mut header = tar::Header::new_ustar();
+    header.set_entry_type(tar::EntryType::Char);
+    header.set_mode(0o620);
+    header.set_device_major(4).unwrap();
+    header.set_device_minor(64).unwrap();
+    header.set_size(0);
+    builder
+        .append_data(&mut header, "dev/ttyS0", std::io::empty())
+        .unwrap();
+
+    // PAX sparse 0.1, the regions packed back to back under a made up name
+    let pax01 = [(0, 3), (4096, 2)];
+    builder
+        .append_pax_extensions([
+            ("GNU.sparse.size", &b"8192"[..]),
+            ("GNU.sparse.numblocks", b"2"),
+            ("GNU.sparse.map", b"0,3,4096,2"),
+            ("GNU.sparse.name", b"var/db/pax01"),
+        ])
+        .unwrap();
+    let mut header = tar::Header::new_ustar();
+    header.set_mode(0o600);
+    header.set_size(5);
+    builder
+        .append_data(&mut header, "GNUSparseFile.0/pax01", &b"aaabb"[..])
+        .unwrap();
+
+    // PAX sparse 1.0, the map leads the data
+    let pax10 = [(1000, 4)];
+    builder
+        .append_pax_extensions([
+            ("GNU.sparse.major", &b"1"[..]),
+            ("GNU.sparse.minor", b"0"),
+            ("GNU.sparse.realsize", b"2000"),
+            ("GNU.sparse.name", b"var/db/pax10"),
+        ])
+        .unwrap();
+    let mut data = b"1\n1000\n4\n".to_vec();
+    pad(&mut data, 512);
+    data.extend_from_slice(b"aaaa");
+    let mut header = tar::Header::new_ustar();
+    header.set_mode(0o600);
+    header.set_size(data.len() as u64);
+    builder
+        .append_data(&mut header, "GNUSparseFile.0/pax10", &data[..])
+        .unwrap();
+
+    let mut tarball = builder.into_inner().unwrap();
+    // the trailing zero blocks
+    tarball.truncate(tarball.len() - 1024);
+    let gnu = [
+        (0, 512),
+        (4096, 512),
+        (8192, 512),
+        (12288, 512),
+        (16384, 512),
+        (20480, 100),
+        // GNU tar ends a trailing hole with an empty region
+        (32768, 0),
+    ];
+    gnu_sparse(&mut tarball, &gnu, 32768);
+    tarball.extend_from_slice(&[0; 1024]);
+
+    let mut archive =
+        TarArchive::<Cursor<Vec<u8>>>::create_with_reader(Cursor::new(tarball.clone())).unwrap();
+    let mut seen = vec![];
+    for entry in archive.entries().unwrap() {
+        let mut entry = entry.unwrap();
+        let path = entry.path_name().unwrap();
+        // the global header applies to everything after it
+        if entry.file_type() != FileType::Other {
+            let comment = entry
+                .pax_extensions()
+                .iter()
+                .find(|(key, _)| key == "comment");
+            assert_eq!(comment.unwrap().1, b"firmware rootfs");
+        }
+        match path.to_str().unwrap() {
+            "etc/passwd" => {
+                assert_eq!(entry.unix_mode().unwrap(), 0o4755);
+                let mtime = entry.mtime().unwrap().assume_utc();
+                assert_eq!(mtime.unix_timestamp(), 1_700_000_000);
+                assert_eq!(mtime.nanosecond(), 123_456_789);
+                let atime = entry.atime().unwrap().assume_utc();
+                assert_eq!(atime.nanosecond(), 500_000_000);
+                assert_eq!(entry.username().as_deref(), Some("builder"));
+                assert_eq!(entry.groupname().as_deref(), Some("users"));
+                assert_eq!(
+                    entry.xattrs(),
+                    [
+                        ("user.comment".to_string(), b"from star".to_vec()),
+                        ("user.with space".to_string(), b"base64".to_vec()),
+                    ]
+                );
+                assert!(entry.acl_access().unwrap().contains("joe"));
+            }
+            "dev/ttyS0" => assert_eq!(entry.device(), Some((4, 64))),
+            "var/db/pax01" | "var/db/pax10" | "var/log/lastlog" => {
+                let (regions, real_size): (&[(u64, u64)], u64) = match path.to_str().unwrap() {
+                    "var/db/pax01" => (&pax01, 8192),
+                    "var/db/pax10" => (&pax10, 2000),
+                    _ => (&gnu, 32768),
+                };
+                assert_eq!(entry.sparse_map().unwrap(), regions);
+                assert_eq!(entry.size(), real_size);
+                let mut contents = vec![];
+                entry.read_to_end(&mut contents).unwrap();
+                assert!(contents == sparse_contents(regions, real_size));
+            }
+            _ => {}
+        }
+        seen.push(path);
+    }
+    assert_eq!(seen.len(), 7);
+
+    // decoders hand out short reads, the extension blocks still have to be found
+    let mut archive =
+        TarArchive::<Trickle>::create_with_reader(Trickle(Cursor::new(tarball.clone()))).unwrap();
+    let mut entries = archive.entries().unwrap().map(Result::unwrap);
+    let entry = entries.find(|entry| entry.path_name().unwrap().ends_with("lastlog"));
+    assert_eq!(entry.unwrap().sparse_map().unwrap(), gnu);
+
+    let out = tempfile::tempdir().unwrap();
+    let mut archive =
+        TarArchive::<Cursor<Vec<u8>>>::create_with_reader(Cursor::new(tarball.clone())).unwrap();
+    let options = [
+        ExtractOption::Owner,
+        ExtractOption::Permissions,
+        ExtractOption::Time,
+        ExtractOption::XAttr,
+        ExtractOption::ACL,
+    ];
+    archive
+        .unpack_all_with_options(out.path(), &options)
+        .unwrap();
+
+    let passwd = out.path().join("etc/passwd");
+    let meta = std::fs::metadata(&passwd).unwrap();
+    assert_eq!(meta.permissions().mode() & 0o7777, 0o4755);
+    assert_eq!((meta.uid(), meta.gid()), (1000, 100));
+    assert_eq!(
+        (meta.mtime(), meta.mtime_nsec()),
+        (1_700_000_000, 123_456_789)
+    );
+    assert_eq!(
+        (meta.atime(), meta.atime_nsec()),
+        (1_700_000_100, 500_000_000)
+    );
+    assert_eq!(get_xattr(&passwd, "user.comment").unwrap(), b"from star");
+    assert_eq!(get_xattr(&passwd, "user.with space").unwrap(), b"base64");
+    if let Some(acl) = get_xattr(&passwd, "system.posix_acl_access") {
+        // version, then user::rwx, user:1000:r--, group::r-x, mask::r-x and other::r-x
+        assert_eq!(acl.len(), 4 + 5 * 8);
+        assert_eq!(acl[12..20], [2, 0, 4, 0, 0xe8, 3, 0, 0]);
+    }
+    let etc = std::fs::metadata(out.path().join("etc")).unwrap();
+    assert_eq!(etc.permissions().mode() & 0o7777, 0o750);
+    assert_eq!(etc.mtime(), 1_600_000_000);
+    assert_eq!(
+        std::fs::read(out.path().join("var/log/lastlog")).unwrap(),
+        sparse_contents(&gnu, 32768)
+    );
+    assert_eq!(
+        std::fs::read(out.path().join("var/db/pax01")).unwrap(),
+        sparse_contents(&pax01, 8192)
+    );
+
+    // without the options only the basic permission bits and nothing else survive
+    let plain = tempfile::tempdir().unwrap();
+    let mut archive =
+        TarArchive::<Cursor<Vec<u8>>>::create_with_reader(Cursor::new(tarball)).unwrap();
+    archive.unpack_all_with_options(plain.path(), &[]).unwrap();
+    let passwd = plain.path().join("etc/passwd");
+    let meta = std::fs::metadata(&passwd).unwrap();
+    assert_eq!(meta.permissions().mode() & 0o7777, 0o755);
+    assert_ne!(meta.mtime(), 1_700_000_000);
+    assert!(get_xattr(&passwd, "user.comment").is_none());
+}
+
+#[test]
+fn tar_extract_options_guard_the_destination() {
+    let mut builder = tar::Builder::new(vec![]);
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Directory);
+    header.set_mode(0o755);
+    header.set_size(0);
+    builder
+        .append_data(&mut header, "usr/lib", std::io::empty())
+        .unwrap();
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Symlink);
+    header.set_mode(0o777);
+    header.set_size(0);
+    builder.append_link(&mut header, "lib", "usr/lib").unwrap();
+    builder.append_link(&mut header, "escape", "/").unwrap();
+    let mut header = tar::Header::new_gnu();
+    header.set_mode(0o644);
+    header.set_mtime(1);
+    header.set_size(3);
+    builder
+        .append_data(&mut header, "lib/libc.so", &b"elf"[..])
+        .unwrap();
+    builder
+        .append_data(&mut header, "escape/tmp/xeno-escaped", &b"elf"[..])
+        .unwrap();
+    builder
+        .append_data(&mut header, "etc/hosts", &b"new"[..])
+        .unwrap();
+    let tarball = builder.into_inner().unwrap();
+
+    let out = tempfile::tempdir().unwrap();
+    std::fs::create_dir(out.path().join("etc")).unwrap();
+    std::fs::write(out.path().join("etc/hosts"), b"old").unwrap();
+    let mut archive =
+        TarArchive::<Cursor<Vec<u8>>>::create_with_reader(Cursor::new(tarball.clone())).unwrap();
+    let options = [ExtractOption::SecureSymlinks, ExtractOption::NoOverwrite];
+    assert!(archive
+        .unpack_all_with_options(out.path(), &options)
+        .is_err());
+    assert!(!out.path().join("usr/lib/libc.so").exists());
+    assert_eq!(std::fs::read(out.path().join("etc/hosts")).unwrap(), b"old");
+
+    // by default symlinks inside the destination are followed and files overwritten
+    let mut archive =
+        TarArchive::<Cursor<Vec<u8>>>::create_with_reader(Cursor::new(tarball)).unwrap();
+    assert!(archive.unpack_all(out.path()).is_err());
+    assert_eq!(
+        std::fs::read(out.path().join("usr/lib/libc.so")).unwrap(),
+        b"elf"
+    );
+    assert!(!Path::new("/tmp/xeno-escaped").exists());
+    assert_eq!(std::fs::read(out.path().join("etc/hosts")).unwrap(), b"new");
+    assert_eq!(
+        std::fs::metadata(out.path().join("etc/hosts"))
+            .unwrap()
+            .mtime(),
+        1
+    );
+}
+
+#[test]
+fn tar_hard_links_cannot_escape_through_symlinks() {
+    let outside = tempfile::tempdir().unwrap();
+    std::fs::write(outside.path().join("passwd"), b"root:x:0:0").unwrap();
+
+    let mut builder = tar::Builder::new(vec![]);
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Symlink);
+    header.set_mode(0o777);
+    header.set_size(0);
+    builder
+        .append_link(&mut header, "d", outside.path())
+        .unwrap();
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Link);
+    header.set_size(0);
+    builder.append_link(&mut header, "x", "d/passwd").unwrap();
+    let mut header = tar::Header::new_gnu();
+    header.set_mode(0o644);
+    header.set_size(5);
+    builder
+        .append_data(&mut header, "x", &b"owned"[..])
+        .unwrap();
+    let tarball = builder.into_inner().unwrap();
+
+    let out = tempfile::tempdir().unwrap();
+    let mut archive =
+        TarArchive::<Cursor<Vec<u8>>>::create_with_reader(Cursor::new(tarball)).unwrap();
+    assert!(archive.unpack_all(out.path()).is_err());
+    assert_eq!(
+        std::fs::read(outside.path().join("passwd")).unwrap(),
+        b"root:x:0:0"
+    );
+    assert_eq!(std::fs::read(out.path().join("x")).unwrap(), b"owned");
+
+    // a hard link already on disk is replaced rather than written through
+    std::fs::remove_file(out.path().join("x")).unwrap();
+    std::fs::hard_link(outside.path().join("passwd"), out.path().join("x")).unwrap();
+    let mut builder = tar::Builder::new(vec![]);
+    let mut header = tar::Header::new_gnu();
+    header.set_mode(0o644);
+    header.set_size(5);
+    builder
+        .append_data(&mut header, "x", &b"owned"[..])
+        .unwrap();
+    let tarball = builder.into_inner().unwrap();
+    let mut archive =
+        TarArchive::<Cursor<Vec<u8>>>::create_with_reader(Cursor::new(tarball)).unwrap();
+    archive.unpack_all(out.path()).unwrap();
+    assert_eq!(
+        std::fs::read(outside.path().join("passwd")).unwrap(),
+        b"root:x:0:0"
+    );
+    assert_eq!(std::fs::read(out.path().join("x")).unwrap(), b"owned");
+}
+
+#[test]
+fn tar_modes_obey_the_umask_unless_permissions_are_restored() {
+    let status = std::fs::read_to_string("/proc/self/status").unwrap();
+    let umask = status
+        .lines()
+        .find_map(|line| line.strip_prefix("Umask:"))
+        .map(|mask| u32::from_str_radix(mask.trim(), 8).unwrap())
+        .unwrap();
+
+    let mut builder = tar::Builder::new(vec![]);
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Directory);
+    header.set_mode(0o777);
+    header.set_size(0);
+    builder
+        .append_data(&mut header, "shared", std::io::empty())
+        .unwrap();
+    let mut header = tar::Header::new_gnu();
+    header.set_mode(0o4777);
+    header.set_size(2);
+    builder
+        .append_data(&mut header, "shared/tool", &b"#!"[..])
+        .unwrap();
+    let tarball = builder.into_inner().unwrap();
+
+    let mode = |path: &Path| std::fs::metadata(path).unwrap().mode() & 0o7777;
+    let out = tempfile::tempdir().unwrap();
+    let mut archive =
+        TarArchive::<Cursor<Vec<u8>>>::create_with_reader(Cursor::new(tarball.clone())).unwrap();
+    archive.unpack_all_with_options(out.path(), &[]).unwrap();
+    assert_eq!(mode(&out.path().join("shared")), 0o777 & !umask);
+    assert_eq!(mode(&out.path().join("shared/tool")), 0o777 & !umask);
+
+    let out = tempfile::tempdir().unwrap();
+    let mut archive =
+        TarArchive::<Cursor<Vec<u8>>>::create_with_reader(Cursor::new(tarball)).unwrap();
+    let options = [ExtractOption::Permissions];
+    archive
+        .unpack_all_with_options(out.path(), &options)
+        .unwrap();
+    assert_eq!(mode(&out.path().join("shared")), 0o777);
+    assert_eq!(mode(&out.path().join("shared/tool")), 0o4777);
+}
+
+#[test]
+fn tar_index_opens_files_without_rescanning() {
+    let mut builder = tar::Builder::new(vec![]);
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Directory);
+    header.set_mode(0o755);
+    header.set_size(0);
+    builder
+        .append_data(&mut header, "./data/", std::io::empty())
+        .unwrap();
+
+    let big: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    let mut header = tar::Header::new_gnu();
+    header.set_mode(0o644);
+    header.set_mtime(1_700_000_000);
+    header.set_size(big.len() as u64);
+    builder
+        .append_data(&mut header, "./data/big.bin", &big[..])
+        .unwrap();
+
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Link);
+    header.set_size(0);
+    builder
+        .append_link(&mut header, "data/alias", "./data/big.bin")
+        .unwrap();
+
+    // a GNU long name header ahead of the entry's own
+    let long = format!("data/{}", "n".repeat(150));
+    let mut header = tar::Header::new_gnu();
+    header.set_mode(0o644);
+    header.set_size(4);
+    builder
+        .append_data(&mut header, &long, &b"long"[..])
+        .unwrap();
+
+    let pax10 = [(1000, 4)];
+    builder
+        .append_pax_extensions([
+            ("GNU.sparse.major", &b"1"[..]),
+            ("GNU.sparse.minor", b"0"),
+            ("GNU.sparse.realsize", b"2000"),
+            ("GNU.sparse.name", b"var/db/pax10"),
+        ])
+        .unwrap();
+    let mut data = b"1\n1000\n4\n".to_vec();
+    pad(&mut data, 512);
+    data.extend_from_slice(b"aaaa");
+    let mut header = tar::Header::new_ustar();
+    header.set_mode(0o600);
+    header.set_size(data.len() as u64);
+    builder
+        .append_data(&mut header, "GNUSparseFile.0/pax10", &data[..])
+        .unwrap();
+
+    let tarball = builder.into_inner().unwrap();
+    let gnu = [
+        (0, 512),
+        (4096, 512),
+        (8192, 512),
+        (12288, 512),
+        (16384, 100),
+        (20000, 0),
+    ];
+    // junk ahead of the archive, the index is relative to its first header
+    let mut image = vec![0xaa; 1000];
+    image.extend_from_slice(&tarball[..tarball.len() - 1024]);
+    gnu_sparse(&mut image, &gnu, 20000);
+    image.extend_from_slice(&[0; 1024]);
+
+    let mut reader = Cursor::new(image.clone());
+    reader.seek(SeekFrom::Start(1000)).unwrap();
+    let mut archive = IndexedTar::create_with_reader(reader).unwrap();
+    let index = archive.index().clone();
+    assert_eq!(index.len(), 6);
+    let entry = index.get("data/big.bin").unwrap();
+    assert_eq!(entry.file_type(), FileType::RegularFile);
+    assert_eq!(entry.size(), big.len() as u64);
+    assert_eq!(entry.header_offset(), 512);
+    assert_eq!(entry.data_offset(), 1024);
+    let mtime = entry.mtime().unwrap().assume_utc();
+    assert_eq!(mtime.unix_timestamp(), 1_700_000_000);
+    assert_eq!(index.get("data").unwrap().file_type(), FileType::Directory);
+    let sparse = index.get("var/log/lastlog").unwrap();
+    assert_eq!(sparse.stored_size(), 4 * 512 + 100);
+    assert_eq!(sparse.size(), 20000);
+
+    let read = |archive: &mut IndexedTar<Cursor<Vec<u8>>>, path: &str| {
+        let mut contents = vec![];
+        let mut file = archive.open_file(path).unwrap();
+        file.read_to_end(&mut contents).unwrap();
+        contents
+    };
+    assert_eq!(read(&mut archive, "./data/big.bin"), big);
+    assert_eq!(read(&mut archive, "data/alias"), big);
+    assert_eq!(read(&mut archive, &long), b"long");
+    assert_eq!(
+        read(&mut archive, "var/db/pax10"),
+        sparse_contents(&pax10, 2000)
+    );
+    assert_eq!(
+        read(&mut archive, "var/log/lastlog"),
+        sparse_contents(&gnu, 20000)
+    );
+    assert!(archive.open_file("data").is_err());
+    assert!(archive.open_file("data/missing").is_err());
+
+    // a saved index opens the archive without reading it again
+    let mut saved = vec![];
+    index.write_to(&mut saved).unwrap();
+    let loaded = TarIndex::read_from(&saved[..]).unwrap();
+    assert_eq!(loaded, index);
+    let listed: Vec<_> = loaded
+        .entries()
+        .map(|entry| entry.unwrap().path_name().unwrap())
+        .collect();
+    assert_eq!(listed[0], Path::new("data"));
+    assert_eq!(listed[5], Path::new("var/log/lastlog"));
+
+    let mut reader = Cursor::new(image.clone());
+    reader.seek(SeekFrom::Start(1000)).unwrap();
+    let mut archive = IndexedTar::create_with_index(reader, loaded.clone()).unwrap();
+    assert_eq!(
+        read(&mut archive, "var/log/lastlog"),
+        sparse_contents(&gnu, 20000)
+    );
+
+    let out = tempfile::tempdir().unwrap();
+    archive.unpack_all(out.path()).unwrap();
+    assert_eq!(std::fs::read(out.path().join("data/alias")).unwrap(), big);
+
+    // the index no longer matches once the archive changes
+    let mut grown = image;
+    grown.extend_from_slice(&[0; 512]);
+    let mut reader = Cursor::new(grown);
+    reader.seek(SeekFrom::Start(1000)).unwrap();
+    assert!(IndexedTar::create_with_index(reader, loaded).is_err());
+    assert!(TarIndex::read_from(&b"not an index"[..]).is_err());
+}
+
+/// A tree with a long path, links, a named pipe and a sparse file.
+fn writer_source(root: &Path) -> String {
+    let long = format!("{}/{}", "d".repeat(120), "f".repeat(150));
+    std::fs::create_dir_all(root.join("bin")).unwrap();
+    std::fs::create_dir_all(root.join(&long).parent().unwrap()).unwrap();
+    std::fs::write(root.join(&long), b"deep").unwrap();
+    std::fs::write(root.join("bin/busybox"), b"#!/bin/sh\n").unwrap();
+    let executable = std::fs::Permissions::from_mode(0o700);
+    std::fs::set_permissions(root.join("bin/busybox"), executable).unwrap();
+    std::os::unix::fs::symlink("busybox", root.join("bin/sh")).unwrap();
+    std::fs::hard_link(root.join("bin/busybox"), root.join("bin/zz-busybox")).unwrap();
+    let fifo = std::ffi::CString::new(root.join("initctl").as_os_str().as_encoded_bytes());
+    assert_eq!(unsafe { libc::mkfifo(fifo.unwrap().as_ptr(), 0o600) }, 0);
+
+    let mut sparse = std::fs::File::create(root.join("disk.img")).unwrap();
+    sparse.set_len(1 << 20).unwrap();
+    sparse.write_all(b"head").unwrap();
+    sparse.seek(SeekFrom::Start(600_000)).unwrap();
+    sparse.write_all(b"middle").unwrap();
+    long
+}
+
+#[test]
+fn tar_writer_round_trips_host_trees() {
+    let source = tempfile::tempdir().unwrap();
+    let long = writer_source(source.path());
+    let disk = std::fs::read(source.path().join("disk.img")).unwrap();
+
+    for format in [TarFormat::Gnu, TarFormat::Pax] {
+        let options = TarWriteOptions {
+            format,
+            uid: Some(0),
+            gid: Some(0),
+            username: Some("root".to_string()),
+            groupname: Some("root".to_string()),
+            mtime: Some(1_700_000_000),
+            normalize_mode: true,
+            sparse: true,
+        };
+        let write = || {
+            let mut writer = TarWriter::create_with_writer(vec![], options.clone()).unwrap();
+            writer.push_dir_tree(source.path()).unwrap();
+            writer.finish().unwrap()
+        };
+        let tarball = write();
+        // nothing but the input and the options end up in the archive
+        assert_eq!(tarball, write(), "{format:?}");
+
+        let mut archive =
+            TarArchive::<Cursor<Vec<u8>>>::create_with_reader(Cursor::new(tarball)).unwrap();
+        let mut paths = vec![];
+        for entry in archive.entries().unwrap() {
+            let entry = entry.unwrap();
+            let path = entry.path_name().unwrap();
+            assert_eq!(entry.uid().unwrap(), Some(0));
+            assert_eq!(entry.username().as_deref(), Some("root"));
+            let mtime = entry.mtime().unwrap().assume_utc();
+            assert_eq!(mtime.unix_timestamp(), 1_700_000_000);
+            match path.to_str().unwrap() {
+                "bin/busybox" => assert_eq!(entry.unix_mode().unwrap(), 0o755),
+                "bin/sh" => assert_eq!(entry.sym_link().unwrap(), Path::new("busybox")),
+                "bin/zz-busybox" => {
+                    assert_eq!(entry.hand_link().unwrap(), Path::new("bin/busybox"))
+                }
+                "initctl" => assert_eq!(entry.file_type(), FileType::NamedPipe),
+                "disk.img" => {
+                    assert_eq!(entry.size(), 1 << 20);
+                    let regions = entry.sparse_map().unwrap();
+                    assert_eq!(regions[0], (0, 512));
+                    assert_eq!(regions[1], (599_552, 512));
+                }
+                _ => {}
+            }
+            paths.push(path);
+        }
+        assert_eq!(paths[0], Path::new("bin"));
+        assert!(paths.contains(&Path::new(&long).to_path_buf()));
+        assert_eq!(paths.len(), 8);
+
+        let mut archive =
+            TarArchive::<Cursor<Vec<u8>>>::create_with_reader(Cursor::new(write())).unwrap();
+        let out = tempfile::tempdir().unwrap();
+        archive.unpack_all(out.path()).unwrap();
+        assert_eq!(std::fs::read(out.path().join("disk.img")).unwrap(), disk);
+        assert_eq!(std::fs::read(out.path().join(&long)).unwrap(), b"deep");
+    }
+
+    // ustar has no room for the long path
+    let options = TarWriteOptions {
+        format: TarFormat::Ustar,
+        ..Default::default()
+    };
+    let mut writer = TarWriter::create_with_writer(vec![], options).unwrap();
+    assert!(writer.push_dir_tree(source.path()).is_err());
+}
+
+#[test]
+fn tar_writer_compresses_entries_of_other_archives() {
+    let source = tempfile::tempdir().unwrap();
+    std::fs::create_dir(source.path().join("etc")).unwrap();
+    std::fs::write(source.path().join("etc/hostname"), b"router\n").unwrap();
+    let hostname = std::fs::File::options()
+        .write(true)
+        .open(source.path().join("etc/hostname"))
+        .unwrap();
+    hostname
+        .set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_600_000_000))
+        .unwrap();
+    let mut writer = TarWriter::create_with_writer(vec![], TarWriteOptions::default()).unwrap();
+    writer.push_dir_tree(source.path()).unwrap();
+    let tarball = writer.finish().unwrap();
+
+    // re-pack the entries of an indexed archive into a gzip tarball
+    let mut archive = IndexedTar::create_with_reader(Cursor::new(tarball)).unwrap();
+    let encoder = GzipEncoder::create_with_writer(vec![], 9).unwrap();
+    let mut writer = TarGzWriter::create_with_writer(encoder, TarWriteOptions::default()).unwrap();
+    for entry in archive.entries().unwrap() {
+        let entry = entry.unwrap();
+        let path = entry.path_name().unwrap();
+        let mtime = entry.mtime().unwrap().assume_utc().unix_timestamp() as u64;
+        match entry.file_type() {
+            FileType::RegularFile => {
+                let contents = archive.open_file(&path).unwrap();
+                writer
+                    .push_entry(&entry, entry.unix_mode(), mtime, contents)
+                    .unwrap();
+            }
+            _ => writer
+                .push_entry(&entry, entry.unix_mode(), mtime, std::io::empty())
+                .unwrap(),
+        }
+    }
+    let compressed = writer.finish().unwrap().finish().unwrap();
+    let mut archive = TarGzArchive::create_with_reader(Cursor::new(compressed.clone())).unwrap();
+    let hostname = archive
+        .entries()
+        .unwrap()
+        .map(Result::unwrap)
+        .find(|entry| entry.path_name().unwrap() == Path::new("etc/hostname"))
+        .unwrap();
+    let mtime = hostname.mtime().unwrap().assume_utc().unix_timestamp();
+    assert_eq!(mtime, 1_600_000_000);
+    let mut archive = TarGzArchive::create_with_reader(Cursor::new(compressed)).unwrap();
+    let out = tempfile::tempdir().unwrap();
+    archive.unpack_all(out.path()).unwrap();
+    assert_eq!(
+        std::fs::read(out.path().join("etc/hostname")).unwrap(),
+        b"router\n"
+    );
+
+    // any encoder picked at run time
+    for format in [BlockFormat::Bzip2, BlockFormat::Xz, BlockFormat::Zstd] {
+        let mut compressed = vec![];
+        let options = TarWriteOptions::default();
+        let mut writer =
+            TarWriter::create_with_encoder(format, &mut compressed, None, options).unwrap();
+        writer.push_dir_tree(source.path()).unwrap();
+        writer.close().unwrap();
+
+        let mut archive = TarDetectArchive::create_with_reader(Cursor::new(compressed)).unwrap();
+        let paths: Vec<_> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path_name().unwrap())
+            .collect();
+        assert_eq!(paths, [Path::new("etc"), Path::new("etc/hostname")]);
+    }
+}
+
+/// Lists and extracts a tarball holding `etc/hostname`, whatever its codec.
+fn list_and_unpack<D: Read>(open: impl Fn() -> CompressedTar<D>) {
+    let paths: Vec<_> = open()
+        .entries()
+        .unwrap()
+        .map(|entry| entry.unwrap().path_name().unwrap())
+        .collect();
+    assert_eq!(paths, [Path::new("etc/hostname")]);
+
+    let out = tempfile::tempdir().unwrap();
+    open().unpack_all(out.path()).unwrap();
+    let hostname = std::fs::read(out.path().join("etc/hostname")).unwrap();
+    assert_eq!(hostname, b"router\n");
+}
+
+#[test]
+fn tar_codecs_round_trip() {
+    let mut builder = tar::Builder::new(vec![]);
+    let mut header = tar::Header::new_gnu();
+    header.set_mode(0o644);
+    header.set_size(7);
+    builder
+        .append_data(&mut header, "etc/hostname", &b"router\n"[..])
+        .unwrap();
+    let tarball = builder.into_inner().unwrap();
+    let compress = |format| {
+        let mut compressed = vec![];
+        let mut encoder = create_encoder(format, &mut compressed, None).unwrap();
+        encoder.write_all(&tarball).unwrap();
+        encoder.close().unwrap();
+        Cursor::new(compressed)
+    };
+    // there is no LZMA-alone encoder in `block/`
+    let mut lzma = vec![];
+    lzma_rs::lzma_compress(&mut &tarball[..], &mut lzma).unwrap();
+
+    list_and_unpack(|| TarXzArchive::create_with_reader(compress(BlockFormat::Xz)).unwrap());
+    list_and_unpack(|| TarLzmaArchive::create_with_reader(Cursor::new(lzma.clone())).unwrap());
+    list_and_unpack(|| TarLz4Archive::create_with_reader(compress(BlockFormat::Lz4)).unwrap());
+    list_and_unpack(|| {
+        TarBrotliArchive::create_with_reader(compress(BlockFormat::Brotli)).unwrap()
+    });
+    list_and_unpack(|| {
+        TarSnappyArchive::create_with_reader(compress(BlockFormat::Snappy)).unwrap()
+    });
+}
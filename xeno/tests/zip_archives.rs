@@ -0,0 +1,486 @@
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
+
+use xeno_rs::archive::zip::{ZipArchive, ZipCompression};
+use xeno_rs::archive::{Entry, FileType};
+use xeno_rs::utils::error::ArchiveError;
+
+mod common;
+
+use common::{crc32, crc32_le};
+
+/// `attack at dawn\n` encrypted with the password `hunter2` and the salt 0..16.
+const AES_SECRET: &[u8] =
+    b"\x00\x01\x02\x03\x04\x05\x06\x07\x08\x09\x0a\x0b\x0c\x0d\x0e\x0f\xcf\xcf\
+    \x68\x63\xf6\x2c\xe0\x5e\xeb\x07\x76\xd3\xae\x78\x8b\x08\x31\x38\x1a\xf8\x3a\x54\x7d\x94\x59\
+    \x44\xf7";
+
+/// Traditional PKWARE encryption of `data`, with the 12 byte header checked against `crc`.
+fn zip_crypto(password: &[u8], crc: u32, data: &[u8]) -> Vec<u8> {
+    let mut keys = [0x1234_5678u32, 0x2345_6789, 0x3456_7890];
+    let update = |keys: &mut [u32; 3], byte: u8| {
+        keys[0] = crc32_le(keys[0], &[byte]);
+        keys[1] = (keys[1].wrapping_add(keys[0] & 0xff))
+            .wrapping_mul(134_775_813)
+            .wrapping_add(1);
+        keys[2] = crc32_le(keys[2], &[(keys[1] >> 24) as u8]);
+    };
+    for &byte in password {
+        update(&mut keys, byte);
+    }
+
+    let mut header = [0x5au8; 12];
+    header[11] = (crc >> 24) as u8;
+    let mut out = vec![];
+    for &byte in header.iter().chain(data) {
+        let temp = (keys[2] | 2) & 0xffff;
+        out.push(byte ^ ((temp * (temp ^ 1)) >> 8) as u8);
+        update(&mut keys, byte);
+    }
+    out
+}
+
+/// A stored unix entry with the fields `zip::ZipWriter` cannot write.
+#[derive(Default)]
+struct RawEntry<'a> {
+    name: &'a str,
+    data: &'a [u8],
+    password: Option<&'a [u8]>,
+    comment: &'a str,
+    /// Modification time of an extended timestamp extra field.
+    mtime: Option<i32>,
+    /// Salt, password check, ciphertext and HMAC of `data` encrypted with WinZip AE-2 AES-256.
+    aes: Option<&'a [u8]>,
+    /// Sizes and CRC in a data descriptor after the data instead of the local header.
+    descriptor: bool,
+    /// Sizes in a zip64 extra field.
+    zip64: bool,
+}
+
+fn raw_zip(entries: &[RawEntry], comment: &str) -> Vec<u8> {
+    let mut out = vec![];
+    let mut central = vec![];
+    for entry in entries {
+        let (name, data) = (entry.name, entry.data);
+        let crc = crc32(data);
+        let mut extra = vec![];
+        if let Some(mtime) = entry.mtime {
+            extra.extend([0x55, 0x54, 5, 0, 1]);
+            extra.extend(mtime.to_le_bytes());
+        }
+        let (flags, method, crc, stored) = match (entry.password, entry.aes) {
+            (_, Some(aes)) => {
+                extra.extend([0x01, 0x99, 7, 0, 2, 0, b'A', b'E', 3, 0, 0]);
+                (1u16, 99u16, 0, aes.to_vec())
+            }
+            (Some(password), None) => (1, 0, crc, zip_crypto(password, crc, data)),
+            (None, None) => (0, 0, crc, data.to_vec()),
+        };
+        let flags = flags | if entry.descriptor { 8 } else { 0 };
+        let sizes = [data.len() as u64, stored.len() as u64];
+        let fields = |out: &mut Vec<u8>, local: bool| {
+            let (crc, sizes) = match local && entry.descriptor {
+                true => (0, [0, 0]),
+                false => (crc, sizes),
+            };
+            let mut extra = extra.clone();
+            if entry.zip64 {
+                extra.extend([0x01, 0x00, 16, 0]);
+                extra.extend(sizes[0].to_le_bytes());
+                extra.extend(sizes[1].to_le_bytes());
+            }
+            let narrow = |size: u64| if entry.zip64 { u32::MAX } else { size as u32 };
+            out.extend(20u16.to_le_bytes());
+            out.extend(flags.to_le_bytes());
+            out.extend(method.to_le_bytes());
+            out.extend(0u16.to_le_bytes());
+            out.extend(0x5a21u16.to_le_bytes());
+            out.extend(crc.to_le_bytes());
+            out.extend(narrow(sizes[1]).to_le_bytes());
+            out.extend(narrow(sizes[0]).to_le_bytes());
+            out.extend((name.len() as u16).to_le_bytes());
+            out.extend((extra.len() as u16).to_le_bytes());
+            extra
+        };
+
+        let offset = out.len() as u32;
+        out.extend(b"PK\x03\x04");
+        let local_extra = fields(&mut out, true);
+        out.extend(name.as_bytes());
+        out.extend(&local_extra);
+        out.extend(&stored);
+        if entry.descriptor {
+            out.extend(b"PK\x07\x08");
+            out.extend(crc.to_le_bytes());
+            for size in [sizes[1], sizes[0]] {
+                match entry.zip64 {
+                    true => out.extend(size.to_le_bytes()),
+                    false => out.extend((size as u32).to_le_bytes()),
+                }
+            }
+        }
+
+        central.extend(b"PK\x01\x02");
+        central.extend((3u16 << 8 | 20).to_le_bytes());
+        let extra = fields(&mut central, false);
+        central.extend((entry.comment.len() as u16).to_le_bytes());
+        central.extend([0u8; 4]);
+        central.extend((0o100644u32 << 16).to_le_bytes());
+        central.extend(offset.to_le_bytes());
+        central.extend(name.as_bytes());
+        central.extend(&extra);
+        central.extend(entry.comment.as_bytes());
+    }
+
+    let central_offset = out.len() as u32;
+    out.extend(&central);
+    out.extend(b"PK\x05\x06");
+    out.extend([0u8; 4]);
+    out.extend((entries.len() as u16).to_le_bytes());
+    out.extend((entries.len() as u16).to_le_bytes());
+    out.extend((central.len() as u32).to_le_bytes());
+    out.extend(central_offset.to_le_bytes());
+    out.extend((comment.len() as u16).to_le_bytes());
+    out.extend(comment.as_bytes());
+    out
+}
+
+#[test]
+fn zip_entries_carry_metadata_and_symlinks() {
+    let mut zip = zip::ZipWriter::new(Cursor::new(vec![]));
+    zip.set_comment("build 42");
+    let mtime = zip::DateTime::from_date_and_time(2020, 2, 29, 13, 37, 10).unwrap();
+    let deflated = zip::write::FileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated)
+        .last_modified_time(mtime)
+        .unix_permissions(0o640);
+    zip.add_directory("etc/", deflated).unwrap();
+    zip.start_file("etc/hosts", deflated).unwrap();
+    zip.write_all(&b"127.0.0.1 localhost\n".repeat(50)).unwrap();
+    zip.add_symlink("etc/localhost", "hosts", deflated).unwrap();
+    let bzip2 = deflated.compression_method(zip::CompressionMethod::Bzip2);
+    zip.start_file("etc/motd", bzip2).unwrap();
+    zip.write_all(b"welcome\n").unwrap();
+    let data = zip.finish().unwrap().into_inner();
+
+    let mut archive =
+        ZipArchive::<Cursor<Vec<u8>>>::create_with_reader(Cursor::new(data), None).unwrap();
+    assert_eq!(archive.comment(), b"build 42");
+    let entries: Vec<_> = archive.entries().unwrap().map(Result::unwrap).collect();
+    assert_eq!(entries.len(), 4);
+
+    assert_eq!(entries[0].file_type(), FileType::Directory);
+    assert_eq!(
+        entries[0].mtime().unwrap().to_string(),
+        "2020-02-29 13:37:10.0"
+    );
+
+    let hosts = &entries[1];
+    assert_eq!(hosts.file_type(), FileType::RegularFile);
+    assert_eq!(hosts.size(), 1000);
+    assert!(hosts.compressed_size() < hosts.size());
+    assert_eq!(hosts.compression(), ZipCompression::Deflated);
+    assert_eq!(hosts.crc32(), crc32(&b"127.0.0.1 localhost\n".repeat(50)));
+    assert_eq!(hosts.unix_mode().unwrap() & 0o7777, 0o640);
+    assert_eq!(hosts.mtime().unwrap().to_string(), "2020-02-29 13:37:10.0");
+    assert!(!hosts.encrypted());
+
+    let link = &entries[2];
+    assert_eq!(link.file_type(), FileType::SymbolicLink);
+    assert_eq!(link.sym_link(), Some(PathBuf::from("hosts")));
+    assert_eq!(entries[3].compression(), ZipCompression::Bzip2);
+    assert_eq!(entries[3].sym_link(), None);
+}
+
+#[test]
+fn zip_entries_list_encrypted_entries_and_comments() {
+    let data = raw_zip(
+        &[
+            RawEntry {
+                name: "readme.txt",
+                data: b"plain text\n",
+                comment: "read me first",
+                mtime: Some(1_600_000_000),
+                ..Default::default()
+            },
+            RawEntry {
+                name: "secret.txt",
+                data: b"attack at dawn\n",
+                password: Some(b"hunter2"),
+                ..Default::default()
+            },
+        ],
+        "two entries",
+    );
+
+    let mut archive =
+        ZipArchive::<Cursor<Vec<u8>>>::create_with_reader(Cursor::new(data), None).unwrap();
+    assert_eq!(archive.comment(), b"two entries");
+    let entries: Vec<_> = archive.entries().unwrap().map(Result::unwrap).collect();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].comment(), "read me first");
+    assert!(!entries[0].encrypted());
+    assert_eq!(entries[0].compression(), ZipCompression::Stored);
+    assert_eq!(
+        entries[0].mtime().unwrap().to_string(),
+        "2020-09-13 12:26:40.0"
+    );
+    assert_eq!(
+        entries[0].dos_mtime().unwrap().to_string(),
+        "2025-01-01 0:00:00.0"
+    );
+    assert!(entries[1].encrypted());
+    assert_eq!(entries[1].size(), 15);
+    assert_eq!(entries[1].compressed_size(), 27);
+    assert_eq!(entries[1].crc32(), crc32(b"attack at dawn\n"));
+}
+
+fn encrypted_zip() -> Vec<u8> {
+    raw_zip(
+        &[
+            RawEntry {
+                name: "readme.txt",
+                data: b"plain text\n",
+                ..Default::default()
+            },
+            RawEntry {
+                name: "zipcrypto.txt",
+                data: b"attack at dawn\n",
+                password: Some(b"hunter2"),
+                ..Default::default()
+            },
+            RawEntry {
+                name: "docs/aes.txt",
+                data: b"attack at dawn\n",
+                aes: Some(AES_SECRET),
+                ..Default::default()
+            },
+        ],
+        "",
+    )
+}
+
+#[test]
+fn zip_passwords_are_used_on_every_read_path() {
+    let data = encrypted_zip();
+    let open = |password: Option<&[u8]>| {
+        let reader = Cursor::new(data.clone());
+        ZipArchive::<Cursor<Vec<u8>>>::create_with_reader(reader, password.map(<[u8]>::to_vec))
+            .unwrap()
+    };
+
+    let mut archive = open(None);
+    let entries: Vec<_> = archive.entries().unwrap().map(Result::unwrap).collect();
+    assert_eq!(entries.len(), 3);
+    assert!(entries[1].encrypted() && entries[2].encrypted());
+    assert_eq!(entries[2].compression(), ZipCompression::Stored);
+    let dir = tempfile::tempdir().unwrap();
+    archive
+        .unpack_file(&entries[0], dir.path().join("readme.txt"))
+        .unwrap();
+    let err = archive.unpack_file(&entries[1], dir.path().join("zipcrypto.txt"));
+    assert!(matches!(err, Err(ArchiveError::Encrypted)));
+    let err = archive.unpack_all(dir.path().join("none"));
+    assert!(matches!(err, Err(ArchiveError::Encrypted)));
+
+    let mut archive = open(Some(b"hunter3"));
+    for entry in &entries[1..] {
+        let err = archive.unpack_file(entry, dir.path().join("wrong"));
+        assert!(
+            matches!(err, Err(ArchiveError::InvalidPassword)),
+            "{:?}",
+            err
+        );
+    }
+    let err = archive.unpack_all(dir.path().join("wrong"));
+    assert!(matches!(err, Err(ArchiveError::InvalidPassword)));
+
+    let mut archive = open(Some(b"hunter2"));
+    let to = dir.path().join("all");
+    archive.unpack_all(&to).unwrap();
+    assert_eq!(
+        std::fs::read(to.join("readme.txt")).unwrap(),
+        b"plain text\n"
+    );
+    assert_eq!(
+        std::fs::read(to.join("zipcrypto.txt")).unwrap(),
+        b"attack at dawn\n"
+    );
+    assert_eq!(
+        std::fs::read(to.join("docs/aes.txt")).unwrap(),
+        b"attack at dawn\n"
+    );
+    archive
+        .unpack_file(&entries[2], dir.path().join("aes.txt"))
+        .unwrap();
+    assert_eq!(
+        std::fs::read(dir.path().join("aes.txt")).unwrap(),
+        b"attack at dawn\n"
+    );
+}
+
+#[test]
+fn zip_unpack_all_creates_symlinks_inside_the_destination() {
+    let mut zip = zip::ZipWriter::new(Cursor::new(vec![]));
+    let options = zip::write::FileOptions::default();
+    zip.add_directory("docs/", options).unwrap();
+    zip.start_file("readme.txt", options.unix_permissions(0o600))
+        .unwrap();
+    zip.write_all(b"plain text\n").unwrap();
+    zip.add_symlink("docs/latest", "../readme.txt", options)
+        .unwrap();
+    zip.start_file("../escape.txt", options).unwrap();
+    zip.write_all(b"outside\n").unwrap();
+    let data = zip.finish().unwrap().into_inner();
+
+    let mut archive =
+        ZipArchive::<Cursor<Vec<u8>>>::create_with_reader(Cursor::new(data), None).unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let to = dir.path().join("out");
+    archive.unpack_all(&to).unwrap();
+    archive.unpack_all(&to).unwrap();
+
+    let link = to.join("docs/latest");
+    assert_eq!(
+        std::fs::read_link(&link).unwrap(),
+        PathBuf::from("../readme.txt")
+    );
+    assert_eq!(std::fs::read(&link).unwrap(), b"plain text\n");
+    let mode = std::fs::metadata(to.join("readme.txt"))
+        .unwrap()
+        .permissions();
+    assert_eq!(
+        std::os::unix::fs::PermissionsExt::mode(&mode) & 0o777,
+        0o600
+    );
+    assert!(!dir.path().join("escape.txt").exists());
+}
+
+fn damaged_zip() -> Vec<u8> {
+    raw_zip(
+        &[
+            RawEntry {
+                name: "stored.txt",
+                data: b"stored PK\x07\x08 with a fake descriptor inside\n",
+                descriptor: true,
+                ..Default::default()
+            },
+            RawEntry {
+                name: "large.bin",
+                data: b"zip64 sizes\n",
+                zip64: true,
+                ..Default::default()
+            },
+            RawEntry {
+                name: "streamed64.bin",
+                data: b"zip64 descriptor\n",
+                descriptor: true,
+                zip64: true,
+                ..Default::default()
+            },
+            RawEntry {
+                name: "secret.txt",
+                data: b"attack at dawn\n",
+                password: Some(b"hunter2"),
+                comment: "kept from the central directory",
+                ..Default::default()
+            },
+            RawEntry {
+                name: "truncated.txt",
+                data: &[b'x'; 64],
+                ..Default::default()
+            },
+        ],
+        "",
+    )
+}
+
+#[test]
+fn zip_prepended_data_and_zip64_open_normally() {
+    let mut sfx = b"MZ\x90\x00 self-extractor stub".repeat(10);
+    sfx.extend(damaged_zip());
+    let mut archive =
+        ZipArchive::<Cursor<Vec<u8>>>::create_with_reader(Cursor::new(sfx), None).unwrap();
+    let entries: Vec<_> = archive.entries().unwrap().map(Result::unwrap).collect();
+    assert_eq!(entries.len(), 5);
+    assert_eq!(entries[2].size(), 17);
+    assert!(archive.damaged().is_empty());
+
+    let dir = tempfile::tempdir().unwrap();
+    for entry in &entries[..3] {
+        let to = dir.path().join(entry.path_name().unwrap());
+        archive.unpack_file(entry, &to).unwrap();
+    }
+    assert_eq!(
+        std::fs::read(dir.path().join("large.bin")).unwrap(),
+        b"zip64 sizes\n"
+    );
+}
+
+#[test]
+fn zip_recovery_rebuilds_the_central_directory() {
+    let data = damaged_zip();
+    let find = |needle: &[u8]| {
+        let at = data.windows(needle.len()).position(|w| w == needle);
+        at.unwrap()
+    };
+    let stub = b"#!/bin/sh\nexit 0\n";
+
+    // a download cut off in the data of the last entry
+    let mut truncated = stub.to_vec();
+    truncated.extend(&data[..find(b"truncated.txt") + 40]);
+    let archive =
+        ZipArchive::<Cursor<Vec<u8>>>::recover_with_reader(Cursor::new(truncated), None).unwrap();
+    let damaged = archive.damaged().to_vec();
+    assert_eq!(damaged.len(), 1);
+    assert_eq!(damaged[0].path(), Path::new("truncated.txt"));
+    assert_eq!(
+        damaged[0].offset(),
+        (stub.len() + find(b"truncated.txt") - 30) as u64
+    );
+    assert_eq!(damaged[0].reason(), "the data is cut off");
+
+    // a central directory cut off after the encrypted entry's record
+    let mut broken = stub.to_vec();
+    broken.extend(&data[..find(b"kept from the central directory") + 31]);
+    assert!(
+        ZipArchive::<Cursor<Vec<u8>>>::create_with_reader(Cursor::new(broken.clone()), None)
+            .is_err()
+    );
+
+    let password = Some(b"hunter2".to_vec());
+    let mut archive =
+        ZipArchive::<Cursor<Vec<u8>>>::recover_with_reader(Cursor::new(broken), password).unwrap();
+    assert!(archive.damaged().is_empty());
+
+    let entries: Vec<_> = archive.entries().unwrap().map(Result::unwrap).collect();
+    let names: Vec<_> = entries.iter().map(|e| e.path_name().unwrap()).collect();
+    assert_eq!(
+        names,
+        [
+            "stored.txt",
+            "large.bin",
+            "streamed64.bin",
+            "secret.txt",
+            "truncated.txt"
+        ]
+        .map(PathBuf::from)
+    );
+    assert_eq!(entries[0].size(), 42);
+    assert_eq!(entries[2].size(), 17);
+    assert!(entries[3].encrypted());
+    assert_eq!(entries[3].comment(), "kept from the central directory");
+    assert_eq!(entries[3].unix_mode(), Some(0o100644));
+
+    let dir = tempfile::tempdir().unwrap();
+    archive.unpack_all(dir.path()).unwrap();
+    let read = |name: &str| std::fs::read(dir.path().join(name)).unwrap();
+    assert_eq!(
+        read("stored.txt"),
+        b"stored PK\x07\x08 with a fake descriptor inside\n"
+    );
+    assert_eq!(read("large.bin"), b"zip64 sizes\n");
+    assert_eq!(read("streamed64.bin"), b"zip64 descriptor\n");
+    assert_eq!(read("secret.txt"), b"attack at dawn\n");
+    assert_eq!(read("truncated.txt"), [b'x'; 64]);
+}